@@ -0,0 +1,674 @@
+use std::{
+    collections::HashMap,
+    fs::{self, create_dir_all, read, rename, OpenOptions},
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair, PKCS_ECDSA_P256_SHA256};
+use ring::{
+    digest::{digest, SHA256},
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair as _, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use rustls::{
+    crypto::ring::default_provider,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+    ClientConfig, ClientConnection, RootCertStore, StreamOwned,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use x509_parser::{extensions::GeneralName, pem::Pem};
+
+use crate::{
+    handler::SharedHandler,
+    http::{read_response_head, ChunkedReader, Request, Response},
+    settings,
+};
+
+const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+/// How often and how long orders and authorizations are polled.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: usize = 30;
+const RETRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Key authorizations for the pending HTTP-01 challenges, served by the http
+/// listener to the CA.
+#[derive(Default)]
+pub struct Challenges {
+    tokens: Mutex<HashMap<String, String>>,
+}
+
+impl Challenges {
+    /// Wraps `next` to answer requests below `/.well-known/acme-challenge/`.
+    pub fn handler(self: &Arc<Self>, next: SharedHandler) -> SharedHandler {
+        let challenges = self.clone();
+        Arc::new(
+            move |request: &Request| match request.path.strip_prefix(CHALLENGE_PATH) {
+                Some(token) => match challenges.lock().get(token) {
+                    Some(key_authorization) => Response::new(200)
+                        .with_header("Content-Type", "application/octet-stream")
+                        .with_body(key_authorization.clone()),
+                    None => Response::new(404),
+                },
+                None => next.handle(request),
+            },
+        )
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, String>> {
+        self.tokens.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: String,
+    error: Option<Value>,
+}
+
+struct HttpResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn json<T: DeserializeOwned>(&self) -> Result<T, String> {
+        serde_json::from_slice(&self.body)
+            .map_err(|e| format!("Could not parse ACME response: {e}"))
+    }
+}
+
+/// Obtains certificates for `settings.domains` using the HTTP-01 challenge
+/// (RFC 8555), answered by a `Challenges` handler on the http listener.
+pub struct AcmeClient {
+    settings: settings::Acme,
+    challenges: Arc<Challenges>,
+    tls_config: Arc<ClientConfig>,
+    directory: Directory,
+    account_key: EcdsaKeyPair,
+    random: SystemRandom,
+    jwk: Value,
+    thumbprint: String,
+    /// The account URL, known once the account is registered.
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    /// Loads or creates the account key and fetches the directory.
+    pub fn new(
+        settings: &settings::Acme,
+        challenges: Arc<Challenges>,
+    ) -> Result<AcmeClient, String> {
+        if settings.domains.is_empty() {
+            return Err("No domains configured for ACME.".to_owned());
+        }
+        let tls_config = Self::tls_config(settings.ca.as_deref())?;
+        let random = SystemRandom::new();
+        let account_key = Self::account_key(&settings.account_key, &random)?;
+        let (jwk, thumbprint) = Self::jwk(&account_key);
+        let mut client = AcmeClient {
+            settings: settings.clone(),
+            challenges,
+            tls_config,
+            directory: Directory {
+                new_nonce: String::new(),
+                new_account: String::new(),
+                new_order: String::new(),
+            },
+            account_key,
+            random,
+            jwk,
+            thumbprint,
+            kid: None,
+            nonce: None,
+        };
+        let response = client.request("GET", &settings.directory, None)?;
+        if response.status != 200 {
+            return Err(format!(
+                "Could not fetch ACME directory {}: {}",
+                settings.directory, response.status
+            ));
+        }
+        client.directory = response.json()?;
+        Ok(client)
+    }
+
+    /// Whether the certificate is missing, doesn't cover every domain or
+    /// expires within `renew_before` days.
+    pub fn needs_renewal(settings: &settings::Acme) -> bool {
+        let pem = match read(&settings.certificate) {
+            Ok(pem) => pem,
+            Err(_) => return true,
+        };
+        let leaf = match Pem::iter_from_buffer(&pem).next() {
+            Some(Ok(leaf)) => leaf,
+            _ => return true,
+        };
+        let certificate = match leaf.parse_x509() {
+            Ok(certificate) => certificate,
+            Err(_) => return true,
+        };
+        let names: Vec<&str> = match certificate.subject_alternative_name() {
+            Ok(Some(extension)) => extension
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name) => Some(*name),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs() as i64);
+        let renew_at = now + i64::from(settings.renew_before) * 24 * 60 * 60;
+        let expiring = certificate.validity().not_after.timestamp() < renew_at;
+        expiring
+            || !Path::new(&settings.private_key).exists()
+            || !settings
+                .domains
+                .iter()
+                .all(|domain| names.contains(&domain.as_str()))
+    }
+
+    /// Writes an expired self-signed certificate for the domains if there is
+    /// none yet, so https can start while the first one is obtained.
+    pub fn write_placeholder(settings: &settings::Acme) -> Result<(), String> {
+        if Path::new(&settings.certificate).exists() && Path::new(&settings.private_key).exists() {
+            return Ok(());
+        }
+        let error = |e: rcgen::Error| format!("Could not create placeholder certificate: {e}");
+        let key = Self::generate_key()?;
+        let mut params = CertificateParams::new(settings.domains.clone()).map_err(error)?;
+        params.not_after = rcgen::date_time_ymd(1975, 1, 2);
+        let certificate = params.self_signed(&key).map_err(error)?;
+        write_private(&settings.private_key, key.serialize_pem().as_bytes())?;
+        write_private(&settings.certificate, certificate.pem().as_bytes())?;
+        println!(
+            "Using a placeholder certificate for {} until one is obtained.",
+            settings.domains.join(", ")
+        );
+        Ok(())
+    }
+
+    /// Orders a certificate for every configured domain and writes it with
+    /// a new private key.
+    pub fn obtain(&mut self) -> Result<(), String> {
+        println!(
+            "Requesting certificate for {} from {}.",
+            self.settings.domains.join(", "),
+            self.settings.directory
+        );
+        self.register()?;
+
+        let identifiers: Vec<Value> = self
+            .settings
+            .domains
+            .iter()
+            .map(|domain| json!({ "type": "dns", "value": domain }))
+            .collect();
+        let new_order = self.directory.new_order.clone();
+        let response = self.post(&new_order, Some(json!({ "identifiers": identifiers })))?;
+        let order_url = match response.header("Location") {
+            Some(location) => location.to_owned(),
+            None => return Err("ACME order has no Location.".to_owned()),
+        };
+        let order: Order = response.json()?;
+        for authorization in &order.authorizations {
+            self.authorize(authorization)?;
+        }
+
+        let key = Self::generate_key()?;
+        let csr = self.csr(&key)?;
+        self.post(
+            &order.finalize,
+            Some(json!({ "csr": URL_SAFE_NO_PAD.encode(csr) })),
+        )?;
+        let order: Order = self.poll(&order_url, |order: &Order| {
+            order.status != "pending" && order.status != "ready" && order.status != "processing"
+        })?;
+        let certificate = match (order.status.as_str(), order.certificate) {
+            ("valid", Some(certificate)) => certificate,
+            (status, _) => return Err(format!("ACME order ended as {status}.")),
+        };
+        let chain = self.post(&certificate, None)?.body;
+
+        write_private(&self.settings.private_key, key.serialize_pem().as_bytes())?;
+        write_private(&self.settings.certificate, &chain)?;
+        println!(
+            "Obtained certificate for {}.",
+            self.settings.domains.join(", ")
+        );
+        Ok(())
+    }
+
+    /// Spawns a thread checking the certificate right away and then every
+    /// `check_interval`, renewing it when needed and calling `renewed` after
+    /// writing it. Failures are retried after at most `RETRY_INTERVAL`.
+    pub fn start_renewal(
+        settings: settings::Acme,
+        challenges: Arc<Challenges>,
+        renewed: impl Fn() + Send + 'static,
+    ) {
+        let interval = Duration::from_secs(settings.check_interval);
+        thread::spawn(move || {
+            let mut client: Option<AcmeClient> = None;
+            loop {
+                if !Self::needs_renewal(&settings) {
+                    thread::sleep(interval);
+                    continue;
+                }
+                let result = match &mut client {
+                    Some(client) => client.obtain(),
+                    None => AcmeClient::new(&settings, challenges.clone())
+                        .and_then(|new| client.insert(new).obtain()),
+                };
+                match result {
+                    Ok(()) => {
+                        renewed();
+                        thread::sleep(interval);
+                    }
+                    Err(e) => {
+                        println!("Could not renew certificate, retrying later: {e}");
+                        thread::sleep(interval.min(RETRY_INTERVAL));
+                    }
+                }
+            }
+        });
+    }
+
+    /// Creates the account, or looks up the existing one for the key.
+    fn register(&mut self) -> Result<(), String> {
+        if self.kid.is_some() {
+            return Ok(());
+        }
+        let mut account = json!({ "termsOfServiceAgreed": true });
+        if let Some(contact) = &self.settings.contact {
+            account["contact"] = json!([contact]);
+        }
+        let new_account = self.directory.new_account.clone();
+        let response = self.post(&new_account, Some(account))?;
+        match response.header("Location") {
+            Some(location) => self.kid = Some(location.to_owned()),
+            None => return Err("ACME account has no Location.".to_owned()),
+        }
+        Ok(())
+    }
+
+    /// Publishes the key authorization of the HTTP-01 challenge and waits
+    /// for the CA to validate it.
+    fn authorize(&mut self, url: &str) -> Result<(), String> {
+        let authorization: Authorization = self.post(url, None)?.json()?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+        let domain = authorization.identifier.value;
+        let challenge = match authorization
+            .challenges
+            .into_iter()
+            .find(|challenge| challenge.kind == "http-01")
+        {
+            Some(challenge) => challenge,
+            None => return Err(format!("No http-01 challenge offered for {domain}.")),
+        };
+
+        let key_authorization = format!("{}.{}", challenge.token, self.thumbprint);
+        self.challenges
+            .lock()
+            .insert(challenge.token.clone(), key_authorization);
+        let result = self.post(&challenge.url, Some(json!({}))).and_then(|_| {
+            self.poll(url, |authorization: &Authorization| {
+                authorization.status != "pending"
+            })
+        });
+        self.challenges.lock().remove(&challenge.token);
+
+        let authorization = result?;
+        if authorization.status != "valid" {
+            let error = authorization
+                .challenges
+                .iter()
+                .find_map(|challenge| challenge.error.as_ref())
+                .map_or(String::new(), |error| format!(": {error}"));
+            return Err(format!(
+                "Authorization for {domain} ended as {}{error}",
+                authorization.status
+            ));
+        }
+        println!("Authorized {domain}.");
+        Ok(())
+    }
+
+    /// Fetches `url` until `done` returns true for it.
+    fn poll<T: DeserializeOwned>(
+        &mut self,
+        url: &str,
+        done: impl Fn(&T) -> bool,
+    ) -> Result<T, String> {
+        for _ in 0..POLL_ATTEMPTS {
+            let response = self.post(url, None)?;
+            let value = response.json()?;
+            if done(&value) {
+                return Ok(value);
+            }
+            let retry_after = response
+                .header("Retry-After")
+                .and_then(|seconds| seconds.parse().ok())
+                .map_or(POLL_INTERVAL, Duration::from_secs);
+            thread::sleep(retry_after.min(Duration::from_secs(10)));
+        }
+        Err(format!("Gave up waiting for {url}."))
+    }
+
+    /// A DER CSR for every domain, with the first one as common name.
+    fn csr(&self, key: &KeyPair) -> Result<Vec<u8>, String> {
+        let error = |e: rcgen::Error| format!("Could not create CSR: {e}");
+        let mut params = CertificateParams::new(self.settings.domains.clone()).map_err(error)?;
+        params.distinguished_name = DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, self.settings.domains[0].as_str());
+        let request = params.serialize_request(key).map_err(error)?;
+        Ok(request.der().to_vec())
+    }
+
+    /// Sends a JWS signed request, or a POST-as-GET without `payload`.
+    /// A rejected nonce is retried once with the fresh one the CA sent.
+    fn post(&mut self, url: &str, payload: Option<Value>) -> Result<HttpResponse, String> {
+        let payload = match payload {
+            Some(payload) => URL_SAFE_NO_PAD.encode(payload.to_string()),
+            None => String::new(),
+        };
+        let mut retried = false;
+        loop {
+            let nonce = self.nonce()?;
+            let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.jwk.clone(),
+            }
+            let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+            let signature = self.sign(format!("{protected}.{payload}").as_bytes())?;
+            let body =
+                json!({ "protected": protected, "payload": payload, "signature": signature });
+
+            let response = self.request("POST", url, Some(body.to_string().as_bytes()))?;
+            if response.status < 400 {
+                return Ok(response);
+            }
+            let problem: Value = response.json().unwrap_or_default();
+            if problem["type"] == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(format!(
+                "ACME request to {url} failed with {}: {}",
+                response.status,
+                problem["detail"].as_str().unwrap_or_default()
+            ));
+        }
+    }
+
+    fn nonce(&mut self) -> Result<String, String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let new_nonce = self.directory.new_nonce.clone();
+        self.request("HEAD", &new_nonce, None)?;
+        self.nonce
+            .take()
+            .ok_or_else(|| "ACME server sent no nonce.".to_owned())
+    }
+
+    /// ES256 signature as the concatenated 32 byte `r` and `s`.
+    fn sign(&self, data: &[u8]) -> Result<String, String> {
+        let signature = self
+            .account_key
+            .sign(&self.random, data)
+            .map_err(|e| format!("Could not sign ACME request: {e}"))?;
+        Ok(URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    /// A minimal HTTP/1.1 client over TLS, one connection per request.
+    fn request(
+        &mut self,
+        method: &str,
+        url: &str,
+        body: Option<&[u8]>,
+    ) -> Result<HttpResponse, String> {
+        let rest = match url.strip_prefix("https://") {
+            Some(rest) => rest,
+            None => return Err(format!("ACME URL {url} is not https.")),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .map_err(|_| format!("Invalid port in {url}."))?,
+            ),
+            None => (authority, 443),
+        };
+
+        let error = |e: std::io::Error| format!("ACME request to {url} failed: {e}");
+        let server_name = ServerName::try_from(host.to_owned())
+            .map_err(|e| format!("Invalid host in {url}: {e}"))?;
+        let connection = ClientConnection::new(self.tls_config.clone(), server_name)
+            .map_err(|e| format!("TLS handshake with {host} failed: {e}"))?;
+        let stream = TcpStream::connect((host, port)).map_err(error)?;
+        stream.set_read_timeout(Some(TIMEOUT)).map_err(error)?;
+        stream.set_write_timeout(Some(TIMEOUT)).map_err(error)?;
+        // The handshake runs on the first write.
+        let mut stream = StreamOwned::new(connection, stream);
+
+        let mut head = format!(
+            "{method} {path} HTTP/1.1\r\nHost: {authority}\r\nUser-Agent: my_server\r\nConnection: close\r\n"
+        );
+        if let Some(body) = body {
+            head.push_str(&format!(
+                "Content-Type: application/jose+json\r\nContent-Length: {}\r\n",
+                body.len()
+            ));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes()).map_err(error)?;
+        stream.write_all(body.unwrap_or_default()).map_err(error)?;
+
+        let mut reader = BufReader::new(stream);
+        let (status, headers) = read_response_head(&mut reader)?;
+        let mut response = HttpResponse {
+            status,
+            headers,
+            body: Vec::new(),
+        };
+        if let Some(nonce) = response.header("Replay-Nonce") {
+            self.nonce = Some(nonce.to_owned());
+        }
+        if method != "HEAD" {
+            let chunked = response
+                .header("Transfer-Encoding")
+                .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"));
+            let length = response
+                .header("Content-Length")
+                .and_then(|length| length.parse().ok());
+            let mut reader: Box<dyn BufRead> = Box::new(reader);
+            match (chunked, length) {
+                (true, _) => ChunkedReader::new(reader).read_to_end(&mut response.body),
+                (false, Some(length)) => reader.take(length).read_to_end(&mut response.body),
+                (false, None) => reader.read_to_end(&mut response.body),
+            }
+            .map_err(error)?;
+        }
+        Ok(response)
+    }
+
+    /// Trusts the bundled web PKI roots and the `ca` bundle if set.
+    fn tls_config(ca: Option<&str>) -> Result<Arc<ClientConfig>, String> {
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        if let Some(ca) = ca {
+            let error = |e: String| format!("Could not load ACME CA bundle {ca}: {e}");
+            let certificates = CertificateDer::pem_file_iter(ca)
+                .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| error(e.to_string()))?;
+            for certificate in certificates {
+                roots.add(certificate).map_err(|e| error(e.to_string()))?;
+            }
+        }
+        let config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| format!("Could not create TLS client for ACME: {e}"))?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Arc::new(config))
+    }
+
+    /// Reads the account key, or creates and saves a new P-256 key.
+    fn account_key(path: &str, random: &SystemRandom) -> Result<EcdsaKeyPair, String> {
+        let pkcs8 = match read(path) {
+            Ok(pem) => match PrivateKeyDer::from_pem_slice(&pem) {
+                Ok(PrivateKeyDer::Pkcs8(key)) => key.secret_pkcs8_der().to_vec(),
+                // Keys written by OpenSSL are SEC1 `EC PRIVATE KEY` files.
+                Ok(PrivateKeyDer::Sec1(key)) => sec1_to_pkcs8(key.secret_sec1_der()),
+                _ => {
+                    return Err(format!(
+                        "Could not read ACME account key {path}, it must be a P-256 key."
+                    ))
+                }
+            },
+            Err(_) => {
+                let key = Self::generate_key()?;
+                write_private(path, key.serialize_pem().as_bytes())?;
+                println!("Created ACME account key {path}.");
+                key.serialize_der()
+            }
+        };
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, random).map_err(|e| {
+            format!("Could not read ACME account key {path}, it must be a P-256 key: {e}")
+        })
+    }
+
+    fn generate_key() -> Result<KeyPair, String> {
+        KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256)
+            .map_err(|e| format!("Could not generate key: {e}"))
+    }
+
+    /// The public JWK of `key` and its RFC 7638 thumbprint.
+    fn jwk(key: &EcdsaKeyPair) -> (Value, String) {
+        // An uncompressed point: 0x04 followed by the 32 byte coordinates.
+        let point = key.public_key().as_ref();
+        let x = URL_SAFE_NO_PAD.encode(&point[1..33]);
+        let y = URL_SAFE_NO_PAD.encode(&point[33..65]);
+
+        // The thumbprint hashes the required members in lexicographic order.
+        let canonical = format!(r#"{{"crv":"P-256","kty":"EC","x":"{x}","y":"{y}"}}"#);
+        let thumbprint = digest(&SHA256, canonical.as_bytes());
+        (
+            json!({ "crv": "P-256", "kty": "EC", "x": x, "y": y }),
+            URL_SAFE_NO_PAD.encode(thumbprint),
+        )
+    }
+}
+
+/// Wraps a SEC1 P-256 key (RFC 5915) in the PKCS#8 structure ring reads.
+fn sec1_to_pkcs8(sec1: &[u8]) -> Vec<u8> {
+    // Version 0, then the id-ecPublicKey algorithm with the prime256v1 curve.
+    let mut contents = vec![0x02, 0x01, 0x00];
+    contents.extend(der(
+        0x30,
+        &[
+            0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a, 0x86, 0x48,
+            0xce, 0x3d, 0x03, 0x01, 0x07,
+        ],
+    ));
+    contents.extend(der(0x04, sec1));
+    der(0x30, &contents)
+}
+
+/// A DER value with `tag` and `contents`.
+fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![tag];
+    if contents.len() < 0x80 {
+        bytes.push(contents.len() as u8);
+    } else {
+        let length = contents.len().to_be_bytes();
+        let length: Vec<u8> = length.into_iter().skip_while(|byte| *byte == 0).collect();
+        bytes.push(0x80 | length.len() as u8);
+        bytes.extend(length);
+    }
+    bytes.extend(contents);
+    bytes
+}
+
+/// Replaces `path` with `contents` readable only by the owner. The file is
+/// written next to it first so readers never see it half written.
+fn write_private(path: &str, contents: &[u8]) -> Result<(), String> {
+    let error = |e: std::io::Error| format!("Could not write {path}: {e}");
+    if let Some(parent) = Path::new(path).parent() {
+        create_dir_all(parent).map_err(error)?;
+    }
+    let temporary = format!("{path}.tmp");
+    // A leftover file would keep its mode, so the new one must be created.
+    let _ = fs::remove_file(&temporary);
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&temporary)
+        .and_then(|mut file| file.write_all(contents))
+        .map_err(error)?;
+    rename(&temporary, path).map_err(error)
+}
@@ -0,0 +1,535 @@
+use std::{
+    cell::Cell,
+    future::Future,
+    io::{self, ErrorKind, Read, Write},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering::Relaxed},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    runtime::Handle,
+    task::{block_in_place, spawn_blocking},
+    time::timeout,
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+use crate::{
+    connection::{self, Buffered, Peer},
+    event_loop::{Parsed, RequestParser, TIMEOUT},
+    handler::SharedHandler,
+    http::{Body, Response, Stream},
+    http2::{self, Executor, PREFACE},
+    middleware::Chain,
+    request_handler::RequestHandler,
+    settings::{Http, Https},
+    sni,
+    tls_server::{self, AsyncTlsConnection, CertificateReloader, TlsServer, HELLO_TIMEOUT},
+    waker::ACCEPT_BACKOFF,
+};
+
+/// How long open connections may continue after shutdown before their
+/// reads and writes are aborted.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// An http server for tokio applications, serving like `TcpServer`.
+/// Handlers run on tokio's blocking threads, so `http.threads` and
+/// `http.mode` don't apply.
+pub struct AsyncTcpServer {
+    listener: TcpListener,
+    handler: SharedHandler,
+    h2c: bool,
+    h2c_upgrade: bool,
+}
+
+impl AsyncTcpServer {
+    /// Binds a server serving static files through `rq_handler_obj`, or
+    /// redirecting every request if `http.redirect` is set.
+    pub async fn new(
+        ip: String,
+        settings_http: &Http,
+        rq_handler_obj: Arc<RequestHandler>,
+    ) -> Result<Self, String> {
+        let handler = RequestHandler::for_listener(
+            rq_handler_obj.clone(),
+            rq_handler_obj,
+            settings_http.redirect.clone(),
+        );
+        Self::with_handler(ip, settings_http, handler).await
+    }
+
+    /// Binds a server running `handler` for every request, e.g. a `Router`.
+    pub async fn with_handler(
+        ip: String,
+        settings_http: &Http,
+        handler: SharedHandler,
+    ) -> Result<Self, String> {
+        Ok(AsyncTcpServer {
+            listener: bind(&ip, settings_http.port).await?,
+            handler,
+            h2c: settings_http.h2c,
+            h2c_upgrade: settings_http.h2c_upgrade,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serves connections until `shutdown` is cancelled, then lets open
+    /// connections finish.
+    pub async fn serve(self, shutdown: CancellationToken) {
+        let running = Arc::new(AtomicBool::new(true));
+        let handler = if self.h2c_upgrade {
+            let executor = Executor::Tokio(Handle::current());
+            http2::upgrade_handler(self.handler, executor, running.clone())
+        } else {
+            self.handler
+        };
+        let h2c = self.h2c;
+        serve(self.listener, shutdown, running, move |stream, context| {
+            let handler = handler.clone();
+            async move {
+                let peer = Peer {
+                    remote_addr: stream.peer_addr().ok(),
+                    ..Peer::default()
+                };
+                serve_http(stream, handler, peer, h2c, &context).await;
+            }
+        })
+        .await;
+        println!("AsyncTcpServer shut down.");
+    }
+}
+
+/// An https server for tokio applications, serving like `TlsServer` with the
+/// TLS backend's tokio integration.
+pub struct AsyncTlsServer {
+    listener: TcpListener,
+    handler: SharedHandler,
+    reloader: CertificateReloader,
+}
+
+impl AsyncTlsServer {
+    /// Binds a server serving static files through `rq_handler_obj`, or
+    /// redirecting every request if `https.redirect` is set.
+    pub async fn new(
+        ip: String,
+        settings_https: &Https,
+        rq_handler_obj: Arc<RequestHandler>,
+    ) -> Result<Self, String> {
+        let handler = RequestHandler::for_listener(
+            rq_handler_obj.clone(),
+            rq_handler_obj,
+            settings_https.redirect.clone(),
+        );
+        Self::with_handler(ip, settings_https, handler).await
+    }
+
+    /// Binds a server running `handler` for every request, e.g. a `Router`.
+    pub async fn with_handler(
+        ip: String,
+        settings_https: &Https,
+        handler: SharedHandler,
+    ) -> Result<Self, String> {
+        Self::with_chain(ip, settings_https, Chain::new(handler)).await
+    }
+
+    /// Binds a server running `chain` for every request. Requests failing
+    /// the certificate checks are answered inside the chain's middleware.
+    pub async fn with_chain(
+        ip: String,
+        settings_https: &Https,
+        chain: Chain,
+    ) -> Result<Self, String> {
+        let reloader = CertificateReloader::from_settings(settings_https)?;
+        let handler = Arc::new(
+            chain.wrap_handler(|handler| TlsServer::secure_handler(handler, reloader.clone())),
+        );
+        Ok(AsyncTlsServer {
+            listener: bind(&ip, settings_https.port).await?,
+            handler,
+            reloader,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Handle for replacing the certificates while the server is running.
+    pub fn reloader(&self) -> CertificateReloader {
+        self.reloader.clone()
+    }
+
+    /// Serves connections until `shutdown` is cancelled, then lets open
+    /// connections finish.
+    pub async fn serve(self, shutdown: CancellationToken) {
+        let running = Arc::new(AtomicBool::new(true));
+        let (handler, reloader) = (self.handler, self.reloader);
+        serve(self.listener, shutdown, running, move |stream, context| {
+            let handler = handler.clone();
+            let reloader = reloader.clone();
+            async move { serve_tls(stream, handler, reloader, &context).await }
+        })
+        .await;
+        println!("AsyncTlsServer shut down.");
+    }
+}
+
+/// What a connection needs from its server.
+#[derive(Clone)]
+struct Context {
+    shutdown: CancellationToken,
+    /// Cancelled once the shutdown grace period is over.
+    abort: CancellationToken,
+    /// Turned false on shutdown, for HTTP/2 connections to send GOAWAY.
+    running: Arc<AtomicBool>,
+}
+
+async fn bind(ip: &str, port: u16) -> Result<TcpListener, String> {
+    TcpListener::bind(format!("{ip}:{port}"))
+        .await
+        .map_err(|e| format!("Failed to bind to {ip}:{port}: {e}"))
+}
+
+/// Runs `connection` on a task for everything accepted on `listener` until
+/// `shutdown` is cancelled, then waits for the tasks, aborting their I/O
+/// after `SHUTDOWN_GRACE`.
+async fn serve<F, Fut>(
+    listener: TcpListener,
+    shutdown: CancellationToken,
+    running: Arc<AtomicBool>,
+    connection: F,
+) where
+    F: Fn(TcpStream, Context) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let context = Context {
+        shutdown: shutdown.clone(),
+        abort: CancellationToken::new(),
+        running,
+    };
+    let tracker = TaskTracker::new();
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    tracker.spawn(connection(stream, context.clone()));
+                }
+                Err(e) => {
+                    println!("Caught Error in async listener: {}. Not Handeled.", e);
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                }
+            },
+        }
+    }
+    drop(listener);
+
+    context.running.store(false, Relaxed);
+    tracker.close();
+    if timeout(SHUTDOWN_GRACE, tracker.wait()).await.is_err() {
+        context.abort.cancel();
+        tracker.wait().await;
+    }
+}
+
+async fn serve_tls(
+    stream: TcpStream,
+    handler: SharedHandler,
+    reloader: CertificateReloader,
+    context: &Context,
+) {
+    let remote_addr = stream.peer_addr().ok();
+    // Handshakes in progress keep the certificates they started with.
+    let certificates = reloader.current();
+    let server_name = sni::peek_server_name_async(&stream, HELLO_TIMEOUT).await;
+    let acceptor = certificates.select(server_name.as_deref()).clone();
+    let stream = match timeout(
+        HELLO_TIMEOUT,
+        tls_server::handshake_async(&acceptor, stream),
+    )
+    .await
+    {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => {
+            println!("Handshake error: {}. Not Handeled.", e);
+            return;
+        }
+        Err(_) => {
+            println!("Handshake error: timed out. Not Handeled.");
+            return;
+        }
+    };
+
+    let peer = Peer {
+        remote_addr,
+        secure: true,
+        server_name,
+        client_certificate: stream.client_certificate(),
+    };
+    if stream.alpn_protocol().as_deref() == Some(b"h2") {
+        // Small frames like WINDOW_UPDATE answers must not wait for ACKs.
+        let _ = stream.tcp_stream().set_nodelay(true);
+        let stream = Blocking::new(stream, context);
+        let running = context.running.clone();
+        let executor = Executor::Tokio(Handle::current());
+        let _ =
+            spawn_blocking(move || http2::serve(stream, handler, executor, peer, &running)).await;
+    } else {
+        serve_http(stream, handler, peer, false, context).await;
+    }
+}
+
+/// Reads a single request from `stream` and answers it, like
+/// `connection::handle` does for blocking streams. With `h2c` set, clients
+/// starting with the HTTP/2 preface are served HTTP/2 instead.
+async fn serve_http<S>(
+    mut stream: S,
+    handler: SharedHandler,
+    peer: Peer,
+    h2c: bool,
+    context: &Context,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut buffer = Vec::new();
+    let mut parser = RequestParser::default();
+    let mut chunk = vec![0; 16 * 1024];
+    let parsed = loop {
+        let read = tokio::select! {
+            read = timeout(TIMEOUT, stream.read(&mut chunk)) => match read {
+                Ok(Ok(read)) => read,
+                _ => return,
+            },
+            _ = context.shutdown.cancelled() => return,
+        };
+        buffer.extend_from_slice(&chunk[..read]);
+
+        if h2c && buffer.starts_with(PREFACE) {
+            let _ = stream.flush().await;
+            let stream = Buffered::new(buffer, Blocking::new(stream, context));
+            let running = context.running.clone();
+            let executor = Executor::Tokio(Handle::current());
+            let _ = spawn_blocking(move || http2::serve(stream, handler, executor, peer, &running))
+                .await;
+            return;
+        }
+        if h2c && read > 0 && PREFACE.starts_with(&buffer) {
+            continue;
+        }
+        match parser.parse(&buffer, read == 0) {
+            Parsed::Incomplete if read > 0 => {}
+            parsed => break parsed,
+        }
+    };
+
+    let (response, buffered) = match parsed {
+        Parsed::Complete(request, buffered) => {
+            let mut request = *request;
+            request.remote_addr = peer.remote_addr;
+            request.secure = peer.secure;
+            request.server_name = peer.server_name;
+            request.client_certificate = peer.client_certificate;
+            match spawn_blocking(move || handler.handle(&request)).await {
+                Ok(response) => (response, buffered),
+                Err(e) => {
+                    println!("Handler failed. {e}");
+                    (Response::new(500), Vec::new())
+                }
+            }
+        }
+        Parsed::Invalid(e) => {
+            println!("Could not parse request. {e}");
+            (Response::new(400), Vec::new())
+        }
+        Parsed::Incomplete => return,
+    };
+    send(stream, response, buffered, context).await;
+}
+
+/// Writes full responses from the task, and streamed or upgraded ones
+/// through `connection::send` on a blocking thread.
+async fn send<S>(mut stream: S, mut response: Response, buffered: Vec<u8>, context: &Context)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    if response.upgrade.is_some() || matches!(response.body, Body::Stream(_)) {
+        let stream = Blocking::new(stream, context);
+        let _ = spawn_blocking(move || connection::send(stream, response, buffered)).await;
+        return;
+    }
+
+    if response.status != 101 {
+        response.set_header("Connection", "close");
+    }
+    let mut output = Vec::new();
+    if response.write_to(&mut output).is_err() {
+        return;
+    }
+    let write = async {
+        stream.write_all(&output).await?;
+        stream.shutdown().await
+    };
+    let sent = tokio::select! {
+        sent = timeout(TIMEOUT, write) => sent,
+        _ = context.abort.cancelled() => Ok(Err(ErrorKind::ConnectionAborted.into())),
+    };
+    match sent {
+        Ok(Ok(())) => println!("Sent response."),
+        Ok(Err(e)) => println!("Failed to send response. {e:?}"),
+        Err(_) => println!("Failed to send response. Timed out."),
+    }
+}
+
+/// A blocking `Stream` over an async one, for the HTTP/2, streaming and
+/// upgrade code running on tokio's blocking threads. Timeouts are kept by
+/// the bridge, and all I/O fails once the shutdown grace period is over.
+struct Blocking<S> {
+    stream: S,
+    handle: Handle,
+    abort: CancellationToken,
+    read_timeout: Cell<Option<Duration>>,
+    write_timeout: Cell<Option<Duration>>,
+    nonblocking: Cell<bool>,
+}
+
+impl<S> Blocking<S> {
+    fn new(stream: S, context: &Context) -> Blocking<S> {
+        Blocking {
+            stream,
+            handle: Handle::current(),
+            abort: context.abort.clone(),
+            read_timeout: Cell::new(None),
+            write_timeout: Cell::new(None),
+            nonblocking: Cell::new(false),
+        }
+    }
+
+    /// How long an operation may wait, not at all once nonblocking.
+    fn limit(&self, timeout: &Cell<Option<Duration>>) -> Option<Duration> {
+        match self.nonblocking.get() {
+            true => Some(Duration::ZERO),
+            false => timeout.get(),
+        }
+    }
+}
+
+/// Runs `future` to completion on the current thread with `handle`'s
+/// runtime, e.g. for an upgraded stream written to from async code. A
+/// multi-threaded runtime's worker first hands its other tasks to another
+/// thread. The thread of a current_thread runtime can't wait for its own
+/// I/O, so this panics there instead of hanging.
+fn block_on<F: Future>(handle: &Handle, future: F) -> F::Output {
+    block_in_place(|| handle.block_on(future))
+}
+
+/// Runs `operation` until it completes, `limit` passes or `abort` is cancelled.
+async fn bridged<T>(
+    operation: impl Future<Output = io::Result<T>>,
+    limit: Option<Duration>,
+    abort: &CancellationToken,
+) -> io::Result<T> {
+    let operation = async {
+        match limit {
+            // What blocking sockets report when their timeout passes.
+            Some(limit) => timeout(limit, operation)
+                .await
+                .unwrap_or_else(|_| Err(ErrorKind::WouldBlock.into())),
+            None => operation.await,
+        }
+    };
+    tokio::select! {
+        result = operation => result,
+        _ = abort.cancelled() => Err(ErrorKind::ConnectionAborted.into()),
+    }
+}
+
+impl<S: AsyncRead + Unpin> Read for Blocking<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let limit = self.limit(&self.read_timeout);
+        block_on(
+            &self.handle,
+            bridged(self.stream.read(buf), limit, &self.abort),
+        )
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Write for Blocking<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let limit = self.limit(&self.write_timeout);
+        block_on(
+            &self.handle,
+            bridged(self.stream.write(buf), limit, &self.abort),
+        )
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let limit = self.limit(&self.write_timeout);
+        block_on(
+            &self.handle,
+            bridged(self.stream.flush(), limit, &self.abort),
+        )
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for Blocking<S> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.read_timeout.set(timeout);
+        Ok(())
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.write_timeout.set(timeout);
+        Ok(())
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking.set(nonblocking);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Builder;
+
+    async fn sleep_then(value: u32) -> u32 {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        value
+    }
+
+    #[test]
+    fn block_on_runs_on_the_only_worker() {
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let value = runtime.block_on(async {
+            tokio::spawn(async { block_on(&Handle::current(), sleep_then(1)) })
+                .await
+                .unwrap()
+        });
+        assert_eq!(value, 1);
+    }
+
+    #[test]
+    fn block_on_runs_beside_a_current_thread_runtime() {
+        let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+        let value = runtime.block_on(async {
+            let handle = Handle::current();
+            spawn_blocking(move || block_on(&handle, sleep_then(2)))
+                .await
+                .unwrap()
+        });
+        assert_eq!(value, 2);
+    }
+}
@@ -0,0 +1,265 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    fmt::Write as _,
+    hash::{Hash, Hasher},
+    io::{BufReader, Write},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering::Relaxed},
+        Arc, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+
+use crate::{http::read_response_head, proxy::Upstream};
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    #[default]
+    RoundRobin,
+    LeastConnections,
+    IpHash,
+}
+
+/// Runtime state of one upstream of a `Balancer`.
+pub struct UpstreamState {
+    pub upstream: Upstream,
+    active: AtomicUsize,
+    requests: AtomicU64,
+    fails: AtomicUsize,
+    /// Set by passive failure marking after `max_fails` consecutive failures.
+    down_until: Mutex<Option<Instant>>,
+    /// Result of the last active health check.
+    healthy: AtomicBool,
+}
+
+impl UpstreamState {
+    fn new(upstream: Upstream) -> UpstreamState {
+        UpstreamState {
+            upstream,
+            active: AtomicUsize::new(0),
+            requests: AtomicU64::new(0),
+            fails: AtomicUsize::new(0),
+            down_until: Mutex::new(None),
+            healthy: AtomicBool::new(true),
+        }
+    }
+
+    pub fn is_available(&self) -> bool {
+        if !self.healthy.load(Relaxed) {
+            return false;
+        }
+        match *self.down_until.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some(until) => Instant::now() >= until,
+            None => true,
+        }
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active.load(Relaxed)
+    }
+}
+
+/// An upstream picked for one request. Counts as an active connection until dropped.
+pub struct Lease {
+    state: Arc<UpstreamState>,
+}
+
+impl Lease {
+    pub fn upstream(&self) -> &Upstream {
+        &self.state.upstream
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.state.active.fetch_sub(1, Relaxed);
+    }
+}
+
+/// Active health check against every upstream of a `Balancer`.
+#[derive(Clone, Debug)]
+pub struct HealthCheck {
+    pub path: String,
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+/// Spreads requests over several upstreams.
+///
+/// Upstreams that fail `max_fails` times in a row are skipped for
+/// `fail_timeout`, and upstreams failing their active health check are
+/// skipped until a check succeeds again.
+pub struct Balancer {
+    upstreams: Vec<Arc<UpstreamState>>,
+    strategy: Strategy,
+    next: AtomicUsize,
+    max_fails: usize,
+    fail_timeout: Duration,
+}
+
+impl Balancer {
+    pub fn new(
+        upstreams: Vec<Upstream>,
+        strategy: Strategy,
+        max_fails: usize,
+        fail_timeout: Duration,
+    ) -> Balancer {
+        Balancer {
+            upstreams: upstreams
+                .into_iter()
+                .map(|upstream| Arc::new(UpstreamState::new(upstream)))
+                .collect(),
+            strategy,
+            next: AtomicUsize::new(0),
+            max_fails,
+            fail_timeout,
+        }
+    }
+
+    pub fn upstreams(&self) -> &[Arc<UpstreamState>] {
+        &self.upstreams
+    }
+
+    /// Picks an available upstream not in `exclude`, or `None` if all are down.
+    pub fn pick(&self, client: Option<IpAddr>, exclude: &[Upstream]) -> Option<Lease> {
+        let available: Vec<&Arc<UpstreamState>> = self
+            .upstreams
+            .iter()
+            .filter(|state| state.is_available() && !exclude.contains(&state.upstream))
+            .collect();
+        if available.is_empty() {
+            return None;
+        }
+
+        let state = match self.strategy {
+            Strategy::RoundRobin => available[self.next.fetch_add(1, Relaxed) % available.len()],
+            Strategy::LeastConnections => {
+                // Rotate the starting point so ties don't always go to the first upstream.
+                let start = self.next.fetch_add(1, Relaxed) % available.len();
+                available
+                    .iter()
+                    .cycle()
+                    .skip(start)
+                    .take(available.len())
+                    .min_by_key(|state| state.active.load(Relaxed))
+                    .copied()?
+            }
+            Strategy::IpHash => {
+                // Hash over every upstream so clients keep theirs while others
+                // go down, and move on to the next available one if it's down.
+                let mut hasher = DefaultHasher::new();
+                client.hash(&mut hasher);
+                let start = hasher.finish() as usize % self.upstreams.len();
+                self.upstreams
+                    .iter()
+                    .cycle()
+                    .skip(start)
+                    .take(self.upstreams.len())
+                    .find(|state| {
+                        available
+                            .iter()
+                            .any(|candidate| Arc::ptr_eq(candidate, state))
+                    })?
+            }
+        };
+
+        state.active.fetch_add(1, Relaxed);
+        state.requests.fetch_add(1, Relaxed);
+        Some(Lease {
+            state: state.clone(),
+        })
+    }
+
+    /// Passive failure marking: records whether a request to `lease` succeeded.
+    pub fn report(&self, lease: &Lease, success: bool) {
+        let state = &lease.state;
+        if success {
+            state.fails.store(0, Relaxed);
+            return;
+        }
+        let fails = state.fails.fetch_add(1, Relaxed) + 1;
+        if fails >= self.max_fails {
+            println!(
+                "Upstream {} failed {fails} times, marking down for {:?}.",
+                state.upstream, self.fail_timeout
+            );
+            *state.down_until.lock().unwrap_or_else(|e| e.into_inner()) =
+                Some(Instant::now() + self.fail_timeout);
+            state.fails.store(0, Relaxed);
+        }
+    }
+
+    /// Spawns a thread checking every upstream each `check.interval`. The
+    /// thread stops once the balancer is dropped.
+    pub fn start_health_checks(self: &Arc<Self>, check: HealthCheck) {
+        let balancer: Weak<Balancer> = Arc::downgrade(self);
+        thread::spawn(move || {
+            while let Some(upstreams) = balancer.upgrade().map(|b| b.upstreams.clone()) {
+                for state in upstreams {
+                    let healthy = Self::check(&state.upstream, &check);
+                    if healthy != state.healthy.swap(healthy, Relaxed) {
+                        let status = if healthy { "healthy" } else { "unhealthy" };
+                        println!("Upstream {} is {status}.", state.upstream);
+                    }
+                }
+                thread::sleep(check.interval);
+            }
+        });
+    }
+
+    fn check(upstream: &Upstream, check: &HealthCheck) -> bool {
+        let mut stream = match upstream.connect(check.timeout, check.timeout) {
+            Ok(stream) => stream,
+            Err(_) => return false,
+        };
+        // Bound the exchange for TCP and Unix socket upstreams alike, so a
+        // hung upstream can't stall the checks of the others.
+        if stream.set_read_timeout(Some(check.timeout)).is_err()
+            || stream.set_write_timeout(Some(check.timeout)).is_err()
+        {
+            return false;
+        }
+        let host = match upstream {
+            Upstream::Tcp(address) => address.as_str(),
+            Upstream::Unix(_) => "localhost",
+        };
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\n\r\n",
+            check.path
+        );
+        if stream.write_all(request.as_bytes()).is_err() {
+            return false;
+        }
+        match read_response_head(&mut BufReader::new(stream)) {
+            Ok((status, _)) => (200..400).contains(&status),
+            Err(_) => false,
+        }
+    }
+
+    /// One line per upstream, used by the IPC `upstreams` command.
+    pub fn status(&self) -> String {
+        let mut status = String::new();
+        for state in &self.upstreams {
+            let health = if !state.healthy.load(Relaxed) {
+                "unhealthy"
+            } else if !state.is_available() {
+                "down"
+            } else {
+                "up"
+            };
+            let _ = writeln!(
+                status,
+                "  {} {health} active={} requests={}",
+                state.upstream,
+                state.active.load(Relaxed),
+                state.requests.load(Relaxed),
+            );
+        }
+        status
+    }
+}
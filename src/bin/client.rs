@@ -31,13 +31,19 @@ fn main() {
                 println!("exit - exit the client");
                 println!("help - print this help message");
                 println!("stop - stop the server");
+                println!("upstreams - show the state of proxy upstreams");
+                println!("reload-certs - load the TLS certificates again");
+                println!("queues - show how many connections wait for a thread");
+                println!("threads - show the active and idle threads of each server");
+                println!("resize <http|https> <min> <max> - change the thread limits of a server");
+                println!("publish <path> [event=<name>] <data> - send an event to an event stream");
                 continue;
             }
             _ => {}
         }
 
         stream.write_all(command.as_bytes()).unwrap();
-        println!("{}", IpcListener::read_stream(&mut stream));
+        println!("{}", IpcListener::read_stream(&stream));
 
         if command == "stop" {
             break;
@@ -0,0 +1,70 @@
+// Compares the jobs per second of `ThreadPool` with its version before the
+// lock-free queue, whose workers took turns on a receiver behind a mutex:
+//
+//     cargo run --release --bin pool_bench -- [jobs]
+
+mod previous;
+
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicUsize, Ordering::Relaxed},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use my_server::thread_pool::ThreadPool;
+
+const QUEUE_SIZE: usize = 1024;
+const THREADS: [usize; 4] = [1, 4, 8, 16];
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Runs `jobs` jobs that only count themselves through `execute`, returning
+/// the jobs per second until the last one ran.
+fn measure(jobs: usize, execute: impl Fn(Job)) -> f64 {
+    let done = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+    for _ in 0..jobs {
+        let done = done.clone();
+        execute(Box::new(move || {
+            done.fetch_add(1, Relaxed);
+        }));
+    }
+    while done.load(Relaxed) < jobs {
+        thread::yield_now();
+    }
+    jobs as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let jobs = env::args()
+        .nth(1)
+        .and_then(|jobs| jobs.parse().ok())
+        .unwrap_or(1_000_000);
+    let cpus = thread::available_parallelism().map_or(1, |cpus| cpus.get());
+    println!("{jobs} jobs on {cpus} cpus");
+
+    let mut results = Vec::new();
+    for threads in THREADS {
+        let idle_timeout = Duration::from_secs(60);
+        let pool = previous::ThreadPool::new(threads, threads, idle_timeout, QUEUE_SIZE);
+        let before = measure(jobs, |job| pool.execute(job));
+        drop(pool);
+
+        let pool = ThreadPool::new(threads, threads, idle_timeout, QUEUE_SIZE);
+        let after = measure(jobs, |job| pool.execute(job));
+        drop(pool);
+        results.push((threads, before, after));
+    }
+
+    println!(
+        "{:>7} {:>15} {:>15}",
+        "threads", "before jobs/s", "after jobs/s"
+    );
+    for (threads, before, after) in results {
+        println!("{threads:>7} {before:>15.0} {after:>15.0}");
+    }
+}
@@ -0,0 +1,213 @@
+// The Mutex<Receiver> `ThreadPool`, before its workers took jobs from a lock-free
+// queue, without the prints around every job and what the benchmark doesn't use.
+
+use std::{
+    collections::HashMap,
+    mem,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering::Relaxed},
+        mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    thread,
+    time::Duration,
+};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+enum Message {
+    NewJob(Job),
+    /// Stops the receiving worker if the pool has more than `max` workers.
+    Retire,
+    Terminate,
+}
+
+/// Runs jobs on between `min` and `max` worker threads. Workers are started
+/// while jobs wait for one, and those above `min` stop after waiting
+/// `idle_timeout` for a job.
+pub struct ThreadPool {
+    sender: SyncSender<Message>,
+    shared: Arc<Shared>,
+}
+
+/// What the workers share with their pool.
+struct Shared {
+    receiver: Mutex<Receiver<Message>>,
+    /// Jobs waiting for a worker.
+    queued: AtomicUsize,
+    /// Workers waiting for a job.
+    idle: AtomicUsize,
+    idle_timeout: Duration,
+    workers: Mutex<Workers>,
+}
+
+struct Workers {
+    /// The thread of each running worker by id.
+    handles: HashMap<usize, thread::JoinHandle<()>>,
+    next_id: usize,
+    min: usize,
+    max: usize,
+}
+
+impl ThreadPool {
+    /// Starts `min` workers, growing to `max` under load, with at most
+    /// `queue_size` jobs waiting for one.
+    pub fn new(min: usize, max: usize, idle_timeout: Duration, queue_size: usize) -> ThreadPool {
+        let queue_size = queue_size.max(1);
+        let (sender, receiver) = sync_channel(queue_size);
+        let shared = Arc::new(Shared {
+            receiver: Mutex::new(receiver),
+            queued: AtomicUsize::new(0),
+            idle: AtomicUsize::new(0),
+            idle_timeout,
+            workers: Mutex::new(Workers {
+                handles: HashMap::new(),
+                next_id: 0,
+                min: 0,
+                max: 0,
+            }),
+        });
+
+        let pool = ThreadPool { sender, shared };
+        pool.resize(min, max);
+        pool
+    }
+
+    /// Queues `job` for the next free worker, blocking while the queue is full.
+    pub fn execute(&self, job: Job) {
+        let queued = self.shared.queued.fetch_add(1, Relaxed) + 1;
+        if queued > self.shared.idle.load(Relaxed) {
+            let mut workers = self.shared.workers();
+            if workers.handles.len() < workers.max {
+                self.shared.spawn(&mut workers);
+            }
+        }
+        self.sender.send(Message::NewJob(job)).unwrap();
+    }
+
+    /// Changes the bounds of the pool, starting workers up to `min` right
+    /// away while idle workers above `max` stop.
+    pub fn resize(&self, min: usize, max: usize) {
+        let max = max.max(min).max(1);
+        let mut workers = self.shared.workers();
+        workers.min = min;
+        workers.max = max;
+        while workers.handles.len() < min {
+            self.shared.spawn(&mut workers);
+        }
+        let excess = workers.handles.len().saturating_sub(max);
+        drop(workers);
+        // Busy workers stop once done, or after their idle timeout if the
+        // queue is too full to ask them now.
+        for _ in 0..excess {
+            let _ = self.sender.try_send(Message::Retire);
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // Workers stopping meanwhile find themselves gone and leave quietly.
+        let handles = mem::take(&mut self.shared.workers().handles);
+        // println!("Sending terminate message to all workers.");
+        for _ in 0..handles.len() {
+            if let Err(e) = self.sender.send(Message::Terminate) {
+                println!("Failed to send terminate message to worker. {e:?}");
+            }
+        }
+
+        for handle in handles.into_values() {
+            if let Err(e) = handle.join() {
+                println!("Error joining worker thread. {e:?}");
+            }
+        }
+        println!("All workers shut down.");
+    }
+}
+
+impl Shared {
+    fn workers(&self) -> MutexGuard<'_, Workers> {
+        self.workers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn spawn(self: &Arc<Self>, workers: &mut Workers) {
+        let id = workers.next_id;
+        workers.next_id += 1;
+        workers.handles.insert(id, Worker::spawn(id, self.clone()));
+    }
+}
+
+struct Worker {
+    id: usize,
+    shared: Arc<Shared>,
+}
+
+impl Worker {
+    fn spawn(id: usize, shared: Arc<Shared>) -> thread::JoinHandle<()> {
+        thread::spawn(move || Worker { id, shared }.run())
+    }
+
+    fn run(&self) {
+        loop {
+            // The lock is released before running the job, so other workers
+            // can take the next one meanwhile. No job runs while holding it,
+            // so it is safe to use even if poisoned.
+            self.shared.idle.fetch_add(1, Relaxed);
+            let received = self
+                .shared
+                .receiver
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .recv_timeout(self.shared.idle_timeout);
+            self.shared.idle.fetch_sub(1, Relaxed);
+            match received {
+                Ok(Message::NewJob(job)) => {
+                    self.shared.queued.fetch_sub(1, Relaxed);
+                    if catch_unwind(AssertUnwindSafe(job)).is_err() {
+                        println!("Job panicked, worker {} continues.", self.id);
+                    }
+                }
+                Ok(Message::Retire) => {
+                    if self.retire(|workers| workers.handles.len() > workers.max) {
+                        break;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if self.retire(|workers| workers.handles.len() > workers.min) {
+                        break;
+                    }
+                }
+                Ok(Message::Terminate) | Err(RecvTimeoutError::Disconnected) => {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Removes the worker from the pool if `surplus` holds, returning whether
+    /// it should stop.
+    fn retire(&self, surplus: impl Fn(&Workers) -> bool) -> bool {
+        let mut workers = self.shared.workers();
+        if !surplus(&workers) {
+            return false;
+        }
+        workers.handles.remove(&self.id);
+        true
+    }
+}
+
+/// Starts a replacement if the worker's thread dies anyway, from a panic
+/// outside of a job.
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            return;
+        }
+        let mut workers = self.shared.workers();
+        if let Some(handle) = workers.handles.get_mut(&self.id) {
+            println!("Worker {} died, starting a new one.", self.id);
+            *handle = Worker::spawn(self.id, self.shared.clone());
+        }
+    }
+}
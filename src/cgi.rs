@@ -0,0 +1,484 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::process::CommandExt,
+    path::{Component, Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering::Relaxed},
+        mpsc, Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    http::{Request, Response},
+    settings,
+};
+
+const SERVER_SOFTWARE: &str = concat!("my_server/", env!("CARGO_PKG_VERSION"));
+/// Longest piece of a script's stderr logged as one line.
+const MAX_STDERR_LINE: u64 = 4096;
+
+/// What a CGI script answered with.
+#[derive(Debug)]
+pub enum CgiResponse {
+    Document(Response),
+    /// A `Location` path alone (RFC 3875 6.2.2), which the server answers as
+    /// if it had been requested instead.
+    LocalRedirect(String),
+}
+
+/// Maps requests to CGI scripts, either everything under a path prefix or
+/// every script with a given file extension.
+#[derive(Clone, Debug)]
+pub struct CgiMapping {
+    pub prefix: Option<String>,
+    pub extension: Option<String>,
+    /// Program the script is passed to, e.g. `/usr/bin/python3`. Scripts
+    /// are executed directly if unset.
+    pub interpreter: Option<String>,
+    pub timeout: Duration,
+    pub max_output: usize,
+}
+
+impl CgiMapping {
+    pub fn from_settings(settings: &settings::Cgi) -> CgiMapping {
+        CgiMapping {
+            prefix: settings
+                .prefix
+                .as_ref()
+                .map(|prefix| prefix.trim_end_matches('/').to_owned()),
+            extension: settings
+                .extension
+                .as_ref()
+                .map(|extension| extension.trim_start_matches('.').to_owned()),
+            interpreter: settings.interpreter.clone(),
+            timeout: Duration::from_secs(settings.timeout),
+            max_output: settings.max_output,
+        }
+    }
+
+    /// Whether the script at `script_name` (a URL path) is handled by this mapping.
+    pub fn matches(&self, script_name: &str) -> bool {
+        if let Some(prefix) = &self.prefix {
+            if script_name.starts_with(&format!("{prefix}/")) {
+                return true;
+            }
+        }
+        match &self.extension {
+            Some(extension) => Path::new(script_name)
+                .extension()
+                .is_some_and(|ext| ext == extension.as_str()),
+            None => false,
+        }
+    }
+
+    /// Runs `script` for `request` and turns its output into a response.
+    pub fn run(&self, request: &Request, script: &Script, document_root: &Path) -> CgiResponse {
+        let env = environment(request, script, document_root);
+        let mut command = match &self.interpreter {
+            Some(interpreter) => {
+                let mut command = Command::new(interpreter);
+                command.arg(&script.filename);
+                command
+            }
+            None => Command::new(&script.filename),
+        };
+        if let Some(dir) = script.filename.parent() {
+            command.current_dir(dir);
+        }
+        command.env_clear();
+        if let Some(path) = std::env::var_os("PATH") {
+            command.env("PATH", path);
+        }
+        // In its own process group, so processes it starts are killed with it.
+        command
+            .envs(env)
+            .process_group(0)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        let output = match self.execute(command, &request.body) {
+            Ok(output) => output,
+            Err(CgiError::Timeout) => {
+                println!("CGI script {} timed out.", script.filename.display());
+                return CgiResponse::Document(Response::new(504));
+            }
+            Err(CgiError::Failed(e)) => {
+                println!("CGI script {} failed: {e}", script.filename.display());
+                return CgiResponse::Document(Response::new(502));
+            }
+        };
+
+        if let Some(location) = local_redirect(&output) {
+            return CgiResponse::LocalRedirect(location);
+        }
+        match parse_response(&output) {
+            Ok(response) => CgiResponse::Document(response),
+            Err(e) => {
+                println!(
+                    "Invalid CGI response from {}: {e}",
+                    script.filename.display()
+                );
+                CgiResponse::Document(Response::new(502))
+            }
+        }
+    }
+
+    fn execute(&self, mut command: Command, body: &[u8]) -> Result<Vec<u8>, CgiError> {
+        let mut child = command
+            .spawn()
+            .map_err(|e| CgiError::Failed(format!("Could not start script: {e}")))?;
+
+        // Feed stdin and drain stdout/stderr on separate threads so a script
+        // writing lots of output before reading its input can't deadlock.
+        let mut stdin = child.stdin.take();
+        let body = body.to_vec();
+        thread::spawn(move || {
+            if let Some(stdin) = stdin.as_mut() {
+                let _ = stdin.write_all(&body);
+            }
+        });
+        let stdout = child.stdout.take();
+        let max_output = self.max_output;
+        let overflow = Arc::new(AtomicBool::new(false));
+        let reader_overflow = overflow.clone();
+        let (output_sender, output_receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut output = Vec::new();
+            if let Some(stdout) = stdout {
+                let _ = stdout.take(max_output as u64 + 1).read_to_end(&mut output);
+            }
+            if output.len() > max_output {
+                reader_overflow.store(true, Relaxed);
+            }
+            let _ = output_sender.send(output);
+        });
+        let stderr = child.stderr.take();
+        thread::spawn(move || {
+            if let Some(stderr) = stderr {
+                log_stderr(BufReader::new(stderr));
+            }
+        });
+
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            let error = match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) if overflow.load(Relaxed) => {
+                    CgiError::Failed(format!("Output exceeds {} bytes.", self.max_output))
+                }
+                Ok(None) if Instant::now() < deadline => {
+                    thread::sleep(Duration::from_millis(10));
+                    continue;
+                }
+                Ok(None) => CgiError::Timeout,
+                Err(e) => CgiError::Failed(e.to_string()),
+            };
+            kill(&mut child);
+            return Err(error);
+        };
+
+        // Processes the script left running may still hold its stdout open,
+        // so the output is only waited for until the deadline. The stdin and
+        // stderr threads end once their pipes close.
+        let output = match output_receiver
+            .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        {
+            Ok(output) => output,
+            Err(_) => {
+                kill(&mut child);
+                return Err(CgiError::Timeout);
+            }
+        };
+        if output.len() > self.max_output {
+            kill(&mut child);
+            return Err(CgiError::Failed(format!(
+                "Output exceeds {} bytes.",
+                self.max_output
+            )));
+        }
+        if !status.success() && output.is_empty() {
+            return Err(CgiError::Failed(format!("Script exited with {status}.")));
+        }
+        Ok(output)
+    }
+}
+
+/// Kills the script's process group, including processes it started, and
+/// reaps the script.
+fn kill(child: &mut Child) {
+    // SAFETY: kill(2) has no memory safety requirements. The group id is the
+    // script's pid, as it was started with `process_group(0)`.
+    unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// The path of a local redirect response (RFC 3875 6.2.2): nothing but a
+/// `Location` header with an absolute path and no body.
+fn local_redirect(output: &[u8]) -> Option<String> {
+    let (head_end, body_start) = find_head_end(output)?;
+    if body_start != output.len() {
+        return None;
+    }
+    let head = String::from_utf8_lossy(&output[..head_end]);
+    let mut lines = head.lines();
+    let (name, value) = lines.next()?.split_once(':')?;
+    let value = value.trim();
+    match lines.next() {
+        None if name.trim().eq_ignore_ascii_case("Location") && value.starts_with('/') => {
+            Some(value.to_owned())
+        }
+        _ => None,
+    }
+}
+
+/// Logs what a script writes to stderr as it arrives, a line at a time, so
+/// a noisy script can't fill up memory. Longer lines are split.
+fn log_stderr<R: BufRead>(mut stderr: R) {
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match stderr
+            .by_ref()
+            .take(MAX_STDERR_LINE)
+            .read_until(b'\n', &mut line)
+        {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                let line = String::from_utf8_lossy(&line);
+                if !line.trim().is_empty() {
+                    println!("CGI stderr: {}", line.trim_end());
+                }
+            }
+        }
+    }
+}
+
+enum CgiError {
+    Timeout,
+    Failed(String),
+}
+
+/// A script resolved from a request path.
+#[derive(Clone, Debug)]
+pub struct Script {
+    /// URL path of the script, the CGI `SCRIPT_NAME`.
+    pub name: String,
+    /// File system path of the script.
+    pub filename: PathBuf,
+    /// Remainder of the URL path after the script, the CGI `PATH_INFO`.
+    pub path_info: String,
+}
+
+impl Script {
+    /// Walks `path` below `document_root` until it hits a file. Returns `None`
+    /// if there is no such file or the path or the file it resolves to leaves
+    /// the document root.
+    pub fn resolve(document_root: &Path, path: &str) -> Option<Script> {
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        let mut filename = document_root.to_path_buf();
+        for (i, segment) in segments.iter().enumerate() {
+            let mut components = Path::new(segment).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(_)), None) => {}
+                _ => return None,
+            }
+            filename.push(segment);
+            if filename.is_file() {
+                // Symlinks may point anywhere, so the script itself must be
+                // under the document root.
+                let canonical = filename.canonicalize().ok()?;
+                if !canonical.starts_with(document_root.canonicalize().ok()?) {
+                    return None;
+                }
+                return Some(Script {
+                    name: format!("/{}", segments[..=i].join("/")),
+                    filename: canonical,
+                    path_info: normalize_path_info(&segments[i + 1..].join("/")),
+                });
+            }
+            if !filename.is_dir() {
+                return None;
+            }
+        }
+        None
+    }
+}
+
+/// Resolves `.` and `..` segments in the path after a script, so that
+/// neither `PATH_INFO` nor `PATH_TRANSLATED` can leave the document root.
+/// Empty if nothing is left.
+pub(crate) fn normalize_path_info(path_info: &str) -> String {
+    let mut segments = Vec::new();
+    for segment in path_info.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    segments
+        .iter()
+        .map(|segment| format!("/{segment}"))
+        .collect()
+}
+
+/// The RFC 3875 meta-variables for `request`, shared with FastCGI.
+pub(crate) fn environment(
+    request: &Request,
+    script: &Script,
+    document_root: &Path,
+) -> Vec<(String, String)> {
+    let host = request.header("Host").unwrap_or_default();
+    let default_port = if request.secure { "443" } else { "80" };
+    let (server_name, server_port) = match host.rsplit_once(':') {
+        Some((name, port)) if !name.ends_with(']') || host.starts_with('[') => {
+            (name.to_owned(), port.to_owned())
+        }
+        _ => (host.to_owned(), default_port.to_owned()),
+    };
+    let remote_addr = request
+        .remote_addr
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_default();
+    let remote_port = request
+        .remote_addr
+        .map(|addr| addr.port().to_string())
+        .unwrap_or_default();
+
+    let mut env = vec![
+        ("GATEWAY_INTERFACE", "CGI/1.1".to_owned()),
+        ("SERVER_SOFTWARE", SERVER_SOFTWARE.to_owned()),
+        ("SERVER_PROTOCOL", request.version.clone()),
+        ("SERVER_NAME", server_name),
+        ("SERVER_PORT", server_port),
+        ("REQUEST_METHOD", request.method.to_string()),
+        ("REQUEST_URI", request.target()),
+        ("QUERY_STRING", request.query.clone().unwrap_or_default()),
+        ("SCRIPT_NAME", script.name.clone()),
+        ("SCRIPT_FILENAME", script.filename.display().to_string()),
+        ("PATH_INFO", script.path_info.clone()),
+        ("DOCUMENT_ROOT", document_root.display().to_string()),
+        ("REMOTE_ADDR", remote_addr.clone()),
+        ("REMOTE_HOST", remote_addr),
+        ("REMOTE_PORT", remote_port),
+        // Required by php-cgi when cgi.force_redirect is on.
+        ("REDIRECT_STATUS", "200".to_owned()),
+    ];
+    if !script.path_info.is_empty() {
+        let translated = document_root.join(script.path_info.trim_start_matches('/'));
+        env.push(("PATH_TRANSLATED", translated.display().to_string()));
+    }
+    if request.secure {
+        env.push(("HTTPS", "on".to_owned()));
+    }
+    if let Some(certificate) = &request.client_certificate {
+        env.push(("SSL_CLIENT_VERIFY", "SUCCESS".to_owned()));
+        env.push(("SSL_CLIENT_S_DN", certificate.subject.clone()));
+        env.push(("SSL_CLIENT_I_DN", certificate.issuer.clone()));
+    }
+    if !request.body.is_empty() || request.header("Content-Length").is_some() {
+        env.push(("CONTENT_LENGTH", request.body.len().to_string()));
+    }
+    if let Some(content_type) = request.header("Content-Type") {
+        env.push(("CONTENT_TYPE", content_type.to_owned()));
+    }
+    if let Some((auth_type, _)) = request
+        .header("Authorization")
+        .and_then(|auth| auth.split_once(' '))
+    {
+        env.push(("AUTH_TYPE", auth_type.to_owned()));
+    }
+
+    let mut env: Vec<(String, String)> = env
+        .into_iter()
+        .map(|(name, value)| (name.to_owned(), value))
+        .collect();
+    for (name, value) in &request.headers {
+        // These are passed as CONTENT_* above, and the credentials are not
+        // handed to scripts.
+        if ["Content-Length", "Content-Type", "Authorization", "Proxy"]
+            .iter()
+            .any(|skip| name.eq_ignore_ascii_case(skip))
+        {
+            continue;
+        }
+        let name = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+        match env.iter_mut().find(|(existing, _)| *existing == name) {
+            Some((_, existing)) => {
+                existing.push_str(", ");
+                existing.push_str(value);
+            }
+            None => env.push((name, value.clone())),
+        }
+    }
+    env
+}
+
+/// Parses a CGI response: header lines, an empty line, then the body.
+pub(crate) fn parse_response(output: &[u8]) -> Result<Response, String> {
+    match find_head_end(output) {
+        Some((head_end, body_start)) => {
+            Ok(parse_head(&output[..head_end])?.with_body(&output[body_start..]))
+        }
+        None => Err("Missing end of headers.".to_owned()),
+    }
+}
+
+/// Turns CGI response headers into a response without body. `Status` sets
+/// the status code and a `Location` without a status redirects with 302.
+pub(crate) fn parse_head(head: &[u8]) -> Result<Response, String> {
+    let mut response = Response::new(200);
+    let mut status = None;
+    for line in String::from_utf8_lossy(head).lines() {
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => return Err(format!("Malformed header line: {line}")),
+        };
+        if name.eq_ignore_ascii_case("Status") {
+            let code = value.split_whitespace().next().unwrap_or_default();
+            status = Some(
+                code.parse::<u16>()
+                    .map_err(|_| format!("Invalid Status: {value}"))?,
+            );
+        } else {
+            response.headers.push((name.to_owned(), value.to_owned()));
+        }
+    }
+
+    response.status = match status {
+        Some(status) => status,
+        None if response.header("Location").is_some() => 302,
+        None => 200,
+    };
+    if response.header("Content-Type").is_none()
+        && response.header("Location").is_none()
+        && status.is_none()
+    {
+        return Err("Response has neither Content-Type, Location nor Status.".to_owned());
+    }
+    Ok(response)
+}
+
+/// Finds the empty line ending the headers, accepting both LF and CRLF line endings.
+pub(crate) fn find_head_end(output: &[u8]) -> Option<(usize, usize)> {
+    let mut i = 0;
+    while let Some(offset) = output[i..].iter().position(|&b| b == b'\n') {
+        let line_end = i + offset;
+        let rest = &output[line_end + 1..];
+        if rest.starts_with(b"\r\n") {
+            return Some((line_end, line_end + 3));
+        }
+        if rest.starts_with(b"\n") {
+            return Some((line_end, line_end + 2));
+        }
+        i = line_end + 1;
+    }
+    None
+}
@@ -0,0 +1,136 @@
+use std::{
+    io::{self, BufReader, Read, Write},
+    net::SocketAddr,
+    os::fd::RawFd,
+    time::Duration,
+};
+
+use crate::{
+    handler::{catch_panic, Handler},
+    http::{read_request, ClientCertificate, Response, Stream},
+};
+
+/// What is known about a connection before its request is read.
+#[derive(Default)]
+pub(crate) struct Peer {
+    pub remote_addr: Option<SocketAddr>,
+    pub secure: bool,
+    pub server_name: Option<String>,
+    pub client_certificate: Option<ClientCertificate>,
+}
+
+/// Reads a single request from `stream`, runs it through `handler` and writes
+/// the response back. If the response upgrades the connection, the stream is
+/// handed over instead of being closed. Shared by `TcpServer` and `TlsServer`.
+pub(crate) fn handle<S: Stream + 'static>(mut stream: S, handler: &dyn Handler, peer: Peer) {
+    let mut reader = BufReader::new(&mut stream);
+    let response = match read_request(&mut reader) {
+        Ok(Some(mut request)) => {
+            request.remote_addr = peer.remote_addr;
+            request.secure = peer.secure;
+            request.server_name = peer.server_name;
+            request.client_certificate = peer.client_certificate;
+            catch_panic(handler, &request)
+        }
+        Ok(None) => return,
+        Err(e) => {
+            println!("Could not parse request. {e}");
+            Response::new(400)
+        }
+    };
+    // Bytes the client sent after the request, e.g. the first WebSocket frame.
+    let buffered = reader.buffer().to_vec();
+    drop(reader);
+    send(stream, response, buffered);
+}
+
+/// The response for clients turned away because the thread pool's queue is
+/// full, asking them to retry after a second.
+pub(crate) fn overloaded() -> Response {
+    Response::new(503).with_header("Retry-After", "1")
+}
+
+/// Writes `response` to `stream` and closes it, or hands the stream over to
+/// the response's upgrade with `buffered` put back in front.
+pub(crate) fn send<S: Stream + 'static>(mut stream: S, mut response: Response, buffered: Vec<u8>) {
+    if response.status != 101 {
+        response.set_header("Connection", "close");
+    }
+    let upgrade = response.upgrade.take();
+    let sent = match upgrade {
+        Some(_) => response.write_head(&mut stream),
+        None => response.write_to(&mut stream),
+    };
+    match sent {
+        Ok(_) => println!("Sent response."),
+        Err(e) => {
+            println!("Failed to send response. {e:?}");
+            return;
+        }
+    };
+
+    if let Some(upgrade) = upgrade {
+        upgrade(Box::new(Buffered::new(buffered, stream)));
+    }
+}
+
+/// A stream with bytes that were already read from it put back in front.
+pub(crate) struct Buffered<S> {
+    buffer: Vec<u8>,
+    position: usize,
+    inner: S,
+}
+
+impl<S> Buffered<S> {
+    pub(crate) fn new(buffer: Vec<u8>, inner: S) -> Buffered<S> {
+        Buffered {
+            buffer,
+            position: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: Read> Read for Buffered<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position < self.buffer.len() {
+            let read = buf.len().min(self.buffer.len() - self.position);
+            buf[..read].copy_from_slice(&self.buffer[self.position..self.position + read]);
+            self.position += read;
+            return Ok(read);
+        }
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Write> Write for Buffered<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: Stream> Stream for Buffered<S> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.inner.set_nonblocking(nonblocking)
+    }
+
+    fn poll_fd(&self) -> Option<RawFd> {
+        self.inner.poll_fd()
+    }
+
+    fn buffered(&self) -> bool {
+        self.position < self.buffer.len() || self.inner.buffered()
+    }
+}
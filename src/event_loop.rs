@@ -0,0 +1,837 @@
+use serde::Deserialize;
+
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    sync::{
+        atomic::{AtomicBool, Ordering::Relaxed},
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    connection::{self, Buffered, Peer},
+    handler::{catch_panic, SharedHandler},
+    http::{
+        body_length, read_request, Body, BodyLength, Request, Response, MAX_BODY_SIZE,
+        MAX_HEAD_SIZE,
+    },
+    http2::{self, Executor, PREFACE},
+    thread_pool::ThreadPool,
+    waker::{Waker, ACCEPT_BACKOFF},
+};
+
+/// How a server waits for its clients.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Every connection keeps a worker thread busy until it is closed.
+    #[default]
+    Threaded,
+    /// Connections wait in epoll, worker threads only run handlers and TLS
+    /// handshakes.
+    Evented,
+}
+
+const LISTENER: u64 = u64::MAX;
+const WAKER: u64 = u64::MAX - 1;
+const MAX_EVENTS: usize = 256;
+/// How often connections past their deadline are closed.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a client may take to send its request or receive the response.
+pub(crate) const TIMEOUT: Duration = Duration::from_secs(30);
+/// Most bytes of a request buffered before it is parsed: the head, the body
+/// and as much again as the head for chunk sizes and trailers.
+pub(crate) const MAX_REQUEST_SIZE: usize = 2 * MAX_HEAD_SIZE + MAX_BODY_SIZE;
+
+/// What the event loop does with connections that have data to read.
+pub(crate) enum Protocol {
+    /// Requests are read and full responses written by the event loop, only
+    /// the handler runs on `thread_pool`. Streamed or upgraded responses and
+    /// HTTP/2 connections are continued on the worker.
+    Http {
+        handler: SharedHandler,
+        h2c: bool,
+        thread_pool: Arc<ThreadPool>,
+    },
+    /// The connection is given to a function once the client starts sending,
+    /// e.g. one running the TLS handshake on a worker. It may come back
+    /// through the `Parker` while it waits for more of the request.
+    Handoff(Dispatch),
+}
+
+/// Takes over a new connection on the event loop's thread.
+pub(crate) type Dispatch = Box<dyn FnMut(TcpStream, &Parker)>;
+
+/// Called on the event loop's thread once a parked connection is readable.
+pub(crate) type Resume = Box<dyn FnOnce() + Send>;
+
+/// Hands connections back to an event loop from its workers, so they don't
+/// wait for slow clients.
+#[derive(Clone)]
+pub(crate) struct Parker {
+    parked: Sender<(TcpStream, Instant, Resume)>,
+    waker: Arc<Waker>,
+}
+
+impl Parker {
+    /// Waits for `socket` to become readable and calls `resume`, or drops it
+    /// once `deadline` passes. `socket` may be a clone of the connection's,
+    /// e.g. of a TLS stream kept by `resume`.
+    pub fn park(&self, socket: TcpStream, deadline: Instant, resume: Resume) {
+        if self.parked.send((socket, deadline, resume)).is_ok() {
+            self.waker.wake();
+        }
+    }
+}
+
+/// Serves the connections of a listener from a single thread with epoll.
+pub(crate) struct EventLoop {
+    protocol: Protocol,
+    epoll: Epoll,
+    connections: HashMap<u64, Connection>,
+    next_token: u64,
+    /// Responses workers finished, to be written by the event loop.
+    finished: Receiver<(TcpStream, Vec<u8>)>,
+    finish: Sender<(TcpStream, Vec<u8>)>,
+    /// Connections workers parked until they are readable.
+    parked: Receiver<(TcpStream, Instant, Resume)>,
+    parker: Parker,
+    running: Arc<AtomicBool>,
+    waker: Arc<Waker>,
+}
+
+struct Connection {
+    stream: TcpStream,
+    state: State,
+    deadline: Instant,
+}
+
+enum State {
+    Reading(Vec<u8>, RequestParser),
+    Writing { output: Vec<u8>, written: usize },
+    Parked(Resume),
+}
+
+pub(crate) enum Parsed {
+    Incomplete,
+    Complete(Box<Request>, Vec<u8>),
+    Invalid(String),
+}
+
+impl EventLoop {
+    /// `waker` stops the loop once `running` is false, and is woken by workers
+    /// when a response is ready.
+    pub fn new(
+        protocol: Protocol,
+        running: Arc<AtomicBool>,
+        waker: Arc<Waker>,
+    ) -> io::Result<EventLoop> {
+        let (finish, finished) = channel();
+        let (park, parked) = channel();
+        Ok(EventLoop {
+            protocol,
+            epoll: Epoll::new()?,
+            connections: HashMap::new(),
+            next_token: 0,
+            finished,
+            finish,
+            parked,
+            parker: Parker {
+                parked: park,
+                waker: waker.clone(),
+            },
+            running,
+            waker,
+        })
+    }
+
+    /// Accepts and serves connections on `listener`, which must be nonblocking,
+    /// until `running` turns false.
+    pub fn run(mut self, listener: &TcpListener) -> io::Result<()> {
+        self.epoll
+            .add(listener.as_raw_fd(), LISTENER, libc::EPOLLIN as u32)?;
+        self.epoll
+            .add(self.waker.as_raw_fd(), WAKER, libc::EPOLLIN as u32)?;
+
+        let mut events = Vec::with_capacity(MAX_EVENTS);
+        let mut last_sweep = Instant::now();
+        while self.running.load(Relaxed) {
+            self.epoll.wait(&mut events, SWEEP_INTERVAL)?;
+            for event in &events {
+                match event.u64 {
+                    LISTENER => self.accept(listener),
+                    WAKER => {
+                        self.waker.reset();
+                        while let Ok((stream, output)) = self.finished.try_recv() {
+                            let deadline = Instant::now() + TIMEOUT;
+                            self.register(stream, State::Writing { output, written: 0 }, deadline);
+                        }
+                        while let Ok((socket, deadline, resume)) = self.parked.try_recv() {
+                            self.register(socket, State::Parked(resume), deadline);
+                        }
+                    }
+                    token => self.ready(token),
+                }
+            }
+
+            if last_sweep.elapsed() >= SWEEP_INTERVAL {
+                let now = Instant::now();
+                let expired: Vec<u64> = self
+                    .connections
+                    .iter()
+                    .filter(|(_, connection)| connection.deadline < now)
+                    .map(|(token, _)| *token)
+                    .collect();
+                for token in expired {
+                    self.remove(token);
+                }
+                last_sweep = now;
+            }
+        }
+        Ok(())
+    }
+
+    fn accept(&mut self, listener: &TcpListener) {
+        loop {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    let deadline = Instant::now() + TIMEOUT;
+                    self.register(
+                        stream,
+                        State::Reading(Vec::new(), RequestParser::default()),
+                        deadline,
+                    );
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    println!("Caught Error in event loop: {}. Not Handeled.", e);
+                    thread::sleep(ACCEPT_BACKOFF);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn register(&mut self, stream: TcpStream, state: State, deadline: Instant) {
+        let events = match state {
+            State::Reading(..) | State::Parked(_) => libc::EPOLLIN,
+            State::Writing { .. } => libc::EPOLLOUT,
+        };
+        let token = self.next_token;
+        self.next_token += 1;
+        let registered = stream
+            .set_nonblocking(true)
+            .and_then(|_| self.epoll.add(stream.as_raw_fd(), token, events as u32));
+        if let Err(e) = registered {
+            println!("Could not register connection. {e}");
+            return;
+        }
+        self.connections.insert(
+            token,
+            Connection {
+                stream,
+                state,
+                deadline,
+            },
+        );
+    }
+
+    /// Removes a connection from the loop, leaving its stream blocking.
+    fn remove(&mut self, token: u64) -> Option<TcpStream> {
+        self.take(token).map(|connection| connection.stream)
+    }
+
+    fn take(&mut self, token: u64) -> Option<Connection> {
+        let connection = self.connections.remove(&token)?;
+        let _ = self.epoll.delete(connection.stream.as_raw_fd());
+        let _ = connection.stream.set_nonblocking(false);
+        Some(connection)
+    }
+
+    fn ready(&mut self, token: u64) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+        match &mut connection.state {
+            State::Writing { output, written } => {
+                if write(&mut connection.stream, output, written) {
+                    self.remove(token);
+                }
+                return;
+            }
+            State::Parked(_) => {
+                if let Some(State::Parked(resume)) = self.take(token).map(|c| c.state) {
+                    resume();
+                }
+                return;
+            }
+            State::Reading(..) => {}
+        }
+        if matches!(self.protocol, Protocol::Handoff(_)) {
+            let stream = self.remove(token);
+            if let (Some(stream), Protocol::Handoff(serve)) = (stream, &mut self.protocol) {
+                serve(stream, &self.parker);
+            }
+            return;
+        }
+
+        let State::Reading(buffer, parser) = &mut connection.state else {
+            return;
+        };
+        let closed = match fill(&mut connection.stream, buffer) {
+            Ok(closed) => closed,
+            Err(_) => {
+                self.remove(token);
+                return;
+            }
+        };
+
+        let Protocol::Http { h2c, .. } = self.protocol else {
+            return;
+        };
+        if h2c && buffer.starts_with(PREFACE) {
+            let buffer = std::mem::take(buffer);
+            self.serve_http2(token, buffer);
+            return;
+        }
+        if h2c && PREFACE.starts_with(buffer) && !closed {
+            return;
+        }
+
+        match parser.parse(buffer, closed) {
+            Parsed::Incomplete if closed => {
+                self.remove(token);
+            }
+            Parsed::Incomplete => {}
+            Parsed::Complete(request, buffered) => self.dispatch(token, *request, buffered),
+            Parsed::Invalid(e) => {
+                println!("Could not parse request. {e}");
+                self.reply(token, Response::new(400));
+            }
+        }
+    }
+
+    /// Writes `response` from the event loop and closes the connection.
+    fn reply(&mut self, token: u64, mut response: Response) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+        response.set_header("Connection", "close");
+        let mut output = Vec::new();
+        let _ = response.write_to(&mut output);
+        connection.state = State::Writing { output, written: 0 };
+        let _ = self
+            .epoll
+            .modify(connection.stream.as_raw_fd(), token, libc::EPOLLOUT as u32);
+    }
+
+    /// Runs the handler for a complete request on a worker.
+    fn dispatch(&mut self, token: u64, mut request: Request, buffered: Vec<u8>) {
+        let Protocol::Http {
+            handler,
+            thread_pool,
+            ..
+        } = &self.protocol
+        else {
+            return;
+        };
+        let (handler, thread_pool) = (handler.clone(), thread_pool.clone());
+        if thread_pool.is_full() {
+            println!("Thread pool is full, turning client away.");
+            self.reply(token, connection::overloaded());
+            return;
+        }
+        let stream = match self.remove(token) {
+            Some(stream) => stream,
+            None => return,
+        };
+        let finish = self.finish.clone();
+        let waker = self.waker.clone();
+        thread_pool.execute(Box::new(move || {
+            request.remote_addr = stream.peer_addr().ok();
+            let mut response = catch_panic(handler.as_ref(), &request);
+            if response.upgrade.is_some() || matches!(response.body, Body::Stream(_)) {
+                connection::send(stream, response, buffered);
+                return;
+            }
+
+            if response.status != 101 {
+                response.set_header("Connection", "close");
+            }
+            let mut output = Vec::new();
+            if response.write_to(&mut output).is_ok() && finish.send((stream, output)).is_ok() {
+                waker.wake();
+            }
+        }));
+    }
+
+    /// Continues a connection that started with the HTTP/2 preface on a worker.
+    fn serve_http2(&mut self, token: u64, buffer: Vec<u8>) {
+        let Protocol::Http {
+            handler,
+            thread_pool,
+            ..
+        } = &self.protocol
+        else {
+            return;
+        };
+        let (handler, thread_pool) = (handler.clone(), thread_pool.clone());
+        let executor = Executor::Pool(thread_pool.clone());
+        let stream = match self.remove(token) {
+            Some(stream) => stream,
+            None => return,
+        };
+        // Answering with a 503 would take speaking HTTP/2, so the client is closed.
+        if thread_pool.is_full() {
+            println!("Thread pool is full, closing HTTP/2 connection.");
+            return;
+        }
+        let running = self.running.clone();
+        thread_pool.execute(Box::new(move || {
+            let peer = Peer {
+                remote_addr: stream.peer_addr().ok(),
+                ..Peer::default()
+            };
+            let _ = stream.set_nodelay(true);
+            http2::serve(
+                Buffered::new(buffer, stream),
+                handler,
+                executor,
+                peer,
+                &running,
+            );
+        }));
+    }
+}
+
+/// Writes as much of `output` as the socket takes, returning whether the
+/// connection is done, i.e. the response was sent or writing failed.
+fn write(stream: &mut TcpStream, output: &[u8], written: &mut usize) -> bool {
+    while *written < output.len() {
+        match stream.write(&output[*written..]) {
+            Ok(0) => return true,
+            Ok(sent) => *written += sent,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return false,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => {
+                println!("Failed to send response. {e:?}");
+                return true;
+            }
+        }
+    }
+    println!("Sent response.");
+    true
+}
+
+/// Reads what `stream` has available into `buffer`, up to `MAX_REQUEST_SIZE`,
+/// returning whether the client closed the connection.
+pub(crate) fn fill<S: Read + ?Sized>(stream: &mut S, buffer: &mut Vec<u8>) -> io::Result<bool> {
+    let mut chunk = [0; 16 * 1024];
+    while buffer.len() < MAX_REQUEST_SIZE {
+        let room = chunk.len().min(MAX_REQUEST_SIZE - buffer.len());
+        match stream.read(&mut chunk[..room]) {
+            Ok(0) => return Ok(true),
+            Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(false)
+}
+
+/// Parses a request while it arrives into a growing buffer. Every byte is
+/// looked at once until the request is complete, and the `Request` is only
+/// built then, so slow clients don't cost a parse per read.
+#[derive(Default)]
+pub(crate) struct RequestParser {
+    lines: Lines,
+    /// Whether a request line was found, after any empty lines before it.
+    started: bool,
+    /// How the body ends, once the head is complete.
+    framing: Option<Framing>,
+}
+
+enum Framing {
+    /// The request ends at this offset into the buffer.
+    Length(usize),
+    Chunked {
+        state: Chunk,
+        decoded: usize,
+    },
+}
+
+#[derive(Clone, Copy)]
+enum Chunk {
+    Size,
+    /// Bytes of chunk data still to come.
+    Data(usize),
+    /// The line ending after the data.
+    DataEnd,
+    Trailer,
+}
+
+impl RequestParser {
+    /// Parses the request at the start of `buffer`, which must hold what was
+    /// given to earlier calls with more appended. Requests still incomplete
+    /// after `MAX_REQUEST_SIZE` bytes are invalid.
+    pub(crate) fn parse(&mut self, buffer: &[u8], closed: bool) -> Parsed {
+        match self.complete(buffer) {
+            Ok(true) => {}
+            Ok(false) if !self.started && self.lines.start == buffer.len() => {
+                return Parsed::Incomplete
+            }
+            Ok(false) if closed => {
+                return Parsed::Invalid("Connection closed inside request.".to_owned())
+            }
+            Ok(false) if buffer.len() >= MAX_REQUEST_SIZE => {
+                return Parsed::Invalid("Request too large.".to_owned())
+            }
+            Ok(false) => return Parsed::Incomplete,
+            Err(e) => return Parsed::Invalid(e),
+        }
+        let mut reader = buffer;
+        match read_request(&mut reader) {
+            Ok(Some(request)) => Parsed::Complete(Box::new(request), reader.to_vec()),
+            Ok(None) => Parsed::Incomplete,
+            Err(e) => Parsed::Invalid(e),
+        }
+    }
+
+    /// Scans the bytes added since the last call, returning whether the
+    /// request is complete.
+    fn complete(&mut self, buffer: &[u8]) -> Result<bool, String> {
+        if self.framing.is_none() {
+            loop {
+                let Some(line) = self.lines.next(buffer) else {
+                    return match buffer.len() {
+                        size if size > MAX_HEAD_SIZE => Err("Request head too large.".to_owned()),
+                        _ => Ok(false),
+                    };
+                };
+                // Empty lines in front of the request line are skipped like
+                // `read_request` does.
+                if !line.is_empty() {
+                    self.started = true;
+                } else if self.started {
+                    break;
+                }
+            }
+            let head_end = self.lines.start;
+            self.framing = Some(framing(&buffer[..head_end], head_end)?);
+        }
+
+        match self.framing.as_mut() {
+            Some(Framing::Length(end)) => Ok(buffer.len() >= *end),
+            Some(Framing::Chunked { state, decoded }) => loop {
+                *state = match *state {
+                    Chunk::Size => {
+                        let Some(line) = self.lines.next(buffer) else {
+                            return self.lines.check_pending(buffer);
+                        };
+                        let line = String::from_utf8_lossy(line);
+                        let size = line.split(';').next().unwrap_or_default().trim();
+                        let size = usize::from_str_radix(size, 16)
+                            .map_err(|_| "Invalid chunk size.".to_owned())?;
+                        *decoded += size;
+                        if *decoded > MAX_BODY_SIZE {
+                            return Err("Chunked request body is too large.".to_owned());
+                        }
+                        match size {
+                            0 => Chunk::Trailer,
+                            size => Chunk::Data(size),
+                        }
+                    }
+                    Chunk::Data(remaining) => {
+                        let read = remaining.min(buffer.len() - self.lines.start);
+                        self.lines.skip(read);
+                        match remaining - read {
+                            0 => Chunk::DataEnd,
+                            remaining => {
+                                *state = Chunk::Data(remaining);
+                                return Ok(false);
+                            }
+                        }
+                    }
+                    Chunk::DataEnd => match buffer.get(self.lines.start..self.lines.start + 2) {
+                        Some(b"\r\n") => {
+                            self.lines.skip(2);
+                            Chunk::Size
+                        }
+                        Some(_) => return Err("Chunk data not followed by CRLF.".to_owned()),
+                        None => return Ok(false),
+                    },
+                    Chunk::Trailer => match self.lines.next(buffer) {
+                        Some([]) => return Ok(true),
+                        Some(_) => Chunk::Trailer,
+                        None => return self.lines.check_pending(buffer),
+                    },
+                };
+            },
+            None => Ok(false),
+        }
+    }
+}
+
+/// How the body of the request with `head` ends, `head_end` bytes into the buffer.
+fn framing(head: &[u8], head_end: usize) -> Result<Framing, String> {
+    let head = String::from_utf8_lossy(head.trim_ascii_start());
+    let headers: Vec<(String, String)> = head
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_owned(), value.trim().to_owned()))
+        .collect();
+    Ok(match body_length(&headers)? {
+        BodyLength::Chunked => Framing::Chunked {
+            state: Chunk::Size,
+            decoded: 0,
+        },
+        BodyLength::Length(length) => Framing::Length(head_end + length),
+    })
+}
+
+/// Splits a growing buffer into lines without searching any byte twice.
+#[derive(Default)]
+struct Lines {
+    /// Where the next line starts.
+    start: usize,
+    /// How far the next line was searched for its end.
+    searched: usize,
+}
+
+impl Lines {
+    /// The next complete line without its line ending, `None` until its
+    /// end has arrived.
+    fn next<'a>(&mut self, buffer: &'a [u8]) -> Option<&'a [u8]> {
+        let from = self.searched.max(self.start);
+        let Some(end) = buffer[from..].iter().position(|byte| *byte == b'\n') else {
+            self.searched = buffer.len();
+            return None;
+        };
+        let line = &buffer[self.start..from + end];
+        self.skip(line.len() + 1);
+        Some(line.strip_suffix(b"\r").unwrap_or(line))
+    }
+
+    /// Moves past `count` bytes that aren't split into lines, e.g. chunk data.
+    fn skip(&mut self, count: usize) {
+        self.start += count;
+        self.searched = self.start;
+    }
+
+    /// Length of the incomplete line at the end of `buffer`.
+    fn pending(&self, buffer: &[u8]) -> usize {
+        buffer.len() - self.start
+    }
+
+    /// Whether the incomplete line at the end of `buffer` may still become
+    /// a chunk size or trailer line.
+    fn check_pending(&self, buffer: &[u8]) -> Result<bool, String> {
+        match self.pending(buffer) {
+            size if size > MAX_HEAD_SIZE => Err("Chunk line too long.".to_owned()),
+            _ => Ok(false),
+        }
+    }
+}
+
+/// An epoll instance watching file descriptors for readiness.
+struct Epoll {
+    fd: OwnedFd,
+}
+
+impl Epoll {
+    fn new() -> io::Result<Epoll> {
+        // SAFETY: epoll_create1 takes no pointers, a valid result is owned by us.
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` was just created and isn't owned by anything else.
+        Ok(Epoll {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    fn add(&self, fd: RawFd, token: u64, events: u32) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_ADD, fd, token, events)
+    }
+
+    fn modify(&self, fd: RawFd, token: u64, events: u32) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_MOD, fd, token, events)
+    }
+
+    fn delete(&self, fd: RawFd) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_DEL, fd, 0, 0)
+    }
+
+    fn control(&self, op: libc::c_int, fd: RawFd, token: u64, events: u32) -> io::Result<()> {
+        let mut event = libc::epoll_event { events, u64: token };
+        // SAFETY: `event` is valid for the call and the kernel copies it.
+        if unsafe { libc::epoll_ctl(self.fd.as_raw_fd(), op, fd, &mut event) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Fills `events` with the ready file descriptors, up to its capacity.
+    fn wait(&self, events: &mut Vec<libc::epoll_event>, timeout: Duration) -> io::Result<()> {
+        events.clear();
+        // SAFETY: the kernel writes at most `capacity` events into the buffer.
+        let ready = unsafe {
+            libc::epoll_wait(
+                self.fd.as_raw_fd(),
+                events.as_mut_ptr(),
+                events.capacity() as libc::c_int,
+                timeout.as_millis() as libc::c_int,
+            )
+        };
+        if ready < 0 {
+            let e = io::Error::last_os_error();
+            return match e.kind() {
+                ErrorKind::Interrupted => Ok(()),
+                _ => Err(e),
+            };
+        }
+        // SAFETY: the first `ready` events were initialized by epoll_wait.
+        unsafe { events.set_len(ready as usize) };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNKED: &str = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+
+    fn parsed(buffer: &[u8]) -> &'static str {
+        match RequestParser::default().parse(buffer, false) {
+            Parsed::Incomplete => "incomplete",
+            Parsed::Complete(..) => "complete",
+            Parsed::Invalid(_) => "invalid",
+        }
+    }
+
+    #[test]
+    fn chunked_bodies_are_complete_after_the_last_chunk() {
+        let cases = [
+            // Data ending in an empty line isn't the end of the body.
+            ("4\r\n\r\n\r\n\r\n", "incomplete"),
+            // A chunk size cut short.
+            ("4\r\nabcd\r\n1", "incomplete"),
+            ("4\r\nabcd\r\n0\r\n", "incomplete"),
+            ("4\r\nabcd\r\n0\r\nExpires: never\r\n", "incomplete"),
+            ("4\r\nabcd\r\n0\r\n\r\n", "complete"),
+            ("4\r\nabcd\r\n0\r\nExpires: never\r\n\r\n", "complete"),
+            ("x\r\n", "invalid"),
+            // Chunk data must end with CRLF.
+            ("4\r\nabcdXY0\r\n\r\n", "invalid"),
+            ("4\r\nabcd\n0\r\n\r\n", "invalid"),
+        ];
+        for (body, expected) in cases {
+            let buffer = format!("{CHUNKED}{body}");
+            assert_eq!(parsed(buffer.as_bytes()), expected, "{body:?}");
+        }
+    }
+
+    #[test]
+    fn ambiguous_framing_is_rejected() {
+        let cases = [
+            ("Transfer-Encoding: gzip, chunked\r\n", "invalid"),
+            (
+                "Transfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n",
+                "invalid",
+            ),
+            (
+                "Transfer-Encoding: chunked\r\nContent-Length: 4\r\n",
+                "invalid",
+            ),
+            ("Content-Length: 4\r\nContent-Length: 4\r\n", "invalid"),
+            ("Content-Length: 4, 4\r\n", "invalid"),
+            ("Content-Length: +4\r\n", "invalid"),
+            ("Content-Length: 4\r\n", "complete"),
+            ("transfer-encoding: Chunked\r\n", "incomplete"),
+        ];
+        for (headers, expected) in cases {
+            let buffer = format!("POST / HTTP/1.1\r\n{headers}\r\nabcd");
+            assert_eq!(parsed(buffer.as_bytes()), expected, "{headers:?}");
+        }
+    }
+
+    #[test]
+    fn requests_are_buffered_up_to_the_limit() {
+        let head = "POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n";
+        assert_eq!(parsed(format!("{head}12345").as_bytes()), "incomplete");
+        assert!(matches!(
+            RequestParser::default().parse(format!("{head}12345").as_bytes(), true),
+            Parsed::Invalid(_)
+        ));
+
+        // Chunk extensions as long as the data, so the body would still
+        // decode to less than MAX_BODY_SIZE.
+        let mut buffer = CHUNKED.as_bytes().to_vec();
+        let chunk = format!("1000;{}\r\n{}\r\n", "x".repeat(4096), "a".repeat(4096));
+        while buffer.len() < MAX_REQUEST_SIZE {
+            buffer.extend(chunk.as_bytes());
+        }
+        buffer.truncate(MAX_REQUEST_SIZE);
+        assert_eq!(parsed(&buffer[..MAX_REQUEST_SIZE - 1]), "incomplete");
+        assert_eq!(parsed(&buffer), "invalid");
+
+        let mut reader: &[u8] = &buffer;
+        let mut filled = Vec::new();
+        assert!(!fill(&mut reader, &mut filled).unwrap());
+        assert_eq!(filled.len(), MAX_REQUEST_SIZE);
+    }
+
+    #[test]
+    fn requests_are_parsed_as_they_arrive() {
+        // A large body in many small pieces is scanned once, not parsed again
+        // for every piece.
+        let length = MAX_BODY_SIZE;
+        let mut buffer =
+            format!("POST / HTTP/1.1\r\nContent-Length: {length}\r\n\r\n").into_bytes();
+        buffer.resize(buffer.len() + length, b'a');
+        buffer.extend(b"GET /next HTTP/1.1\r\n\r\n");
+        let started = Instant::now();
+        let mut parser = RequestParser::default();
+        let mut end = 0;
+        let parsed = loop {
+            end = (end + 1000).min(buffer.len());
+            match parser.parse(&buffer[..end], false) {
+                Parsed::Incomplete => assert!(end < buffer.len()),
+                parsed => break parsed,
+            }
+        };
+        let Parsed::Complete(request, buffered) = parsed else {
+            panic!("request not complete");
+        };
+        assert_eq!(request.body.len(), length);
+        assert!(buffered.starts_with(b"GET /next"));
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "{:?}",
+            started.elapsed()
+        );
+
+        // The same for chunked bodies, a byte at a time.
+        let buffer = format!("{CHUNKED}5;x=y\r\nhello\r\n6\r\n world\r\n0\r\nA: b\r\n\r\n");
+        let mut parser = RequestParser::default();
+        for end in 1..buffer.len() {
+            let parsed = parser.parse(&buffer.as_bytes()[..end], false);
+            assert!(matches!(parsed, Parsed::Incomplete), "{:?}", &buffer[..end]);
+        }
+        let Parsed::Complete(request, _) = parser.parse(buffer.as_bytes(), false) else {
+            panic!("request not complete");
+        };
+        assert_eq!(request.body, b"hello world");
+    }
+}
@@ -0,0 +1,452 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    balancer::{Balancer, Lease},
+    cgi::{environment, find_head_end, normalize_path_info, parse_head, Script},
+    http::{Request, Response, Stream, MAX_HEAD_SIZE},
+    proxy::Upstream,
+    settings,
+};
+
+const VERSION_1: u8 = 1;
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+
+const ROLE_RESPONDER: u16 = 1;
+const FLAG_KEEP_CONN: u8 = 1;
+/// Connections are never multiplexed, so every request uses the same id.
+const REQUEST_ID: u16 = 1;
+const MAX_CONTENT: usize = 0xffff;
+
+/// Sends requests to a FastCGI backend pool such as php-fpm.
+///
+/// A request is handled if its path is under `prefix` and, when set, names a
+/// script with `extension`. Such scripts are answered with 404 without asking
+/// the backend if they don't exist below `root`. Connections are kept open and
+/// reused for up to `pool_size` idle connections per backend.
+pub struct FastCgi {
+    prefix: String,
+    extension: Option<String>,
+    index: Option<String>,
+    root: PathBuf,
+    balancer: Arc<Balancer>,
+    pool: Arc<Pool>,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+}
+
+impl FastCgi {
+    pub fn new(prefix: &str, balancer: Arc<Balancer>, root: PathBuf) -> FastCgi {
+        FastCgi {
+            prefix: prefix.trim_end_matches('/').to_owned(),
+            extension: None,
+            index: None,
+            root,
+            balancer,
+            pool: Arc::new(Pool {
+                idle: Mutex::new(HashMap::new()),
+                size: 4,
+            }),
+            connect_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Builds the route for a `[[fastcgi]]` table. Script paths are resolved
+    /// against `root`, or `document_root` if it is unset.
+    pub fn from_settings(
+        settings: &settings::FastCgi,
+        document_root: &Path,
+    ) -> Result<FastCgi, String> {
+        let backends: Vec<Upstream> = settings
+            .backend
+            .iter()
+            .chain(&settings.backends)
+            .map(|address| Upstream::parse(address))
+            .collect();
+        if backends.is_empty() {
+            return Err(format!(
+                "No backend configured for fastcgi {}.",
+                settings.prefix
+            ));
+        }
+        let balancer = Balancer::new(
+            backends,
+            settings.strategy,
+            settings.max_fails,
+            Duration::from_secs(settings.fail_timeout),
+        );
+        let root = match &settings.root {
+            Some(root) => PathBuf::from(root),
+            None => document_root
+                .canonicalize()
+                .unwrap_or(document_root.to_path_buf()),
+        };
+
+        let mut fastcgi = FastCgi::new(&settings.prefix, Arc::new(balancer), root);
+        fastcgi.extension = settings
+            .extension
+            .as_ref()
+            .map(|extension| extension.trim_start_matches('.').to_owned());
+        fastcgi.index = settings.index.clone();
+        fastcgi.pool = Arc::new(Pool {
+            idle: Mutex::new(HashMap::new()),
+            size: settings.pool_size,
+        });
+        fastcgi.connect_timeout = Duration::from_secs(settings.connect_timeout);
+        fastcgi.read_timeout = Duration::from_secs(settings.read_timeout);
+        Ok(fastcgi)
+    }
+
+    pub fn balancer(&self) -> &Arc<Balancer> {
+        &self.balancer
+    }
+
+    /// The script `path` maps to, or `None` if this route doesn't handle it.
+    pub fn script(&self, path: &str) -> Option<Script> {
+        let rest = match path.strip_prefix(&self.prefix) {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') || self.prefix.is_empty() => {
+                rest
+            }
+            _ => return None,
+        };
+
+        let (name, path_info) = match &self.extension {
+            None => (self.prefix.clone(), normalize_path_info(rest)),
+            Some(extension) => {
+                let suffix = format!(".{extension}");
+                let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+                match segments.iter().position(|s| s.ends_with(&suffix)) {
+                    Some(i) => (
+                        format!("/{}", segments[..=i].join("/")),
+                        normalize_path_info(&segments[i + 1..].join("/")),
+                    ),
+                    None => match &self.index {
+                        Some(index) if path.ends_with('/') && index.ends_with(&suffix) => {
+                            (format!("{path}{index}"), String::new())
+                        }
+                        _ => return None,
+                    },
+                }
+            }
+        };
+        if name.split('/').any(|segment| segment == "..") {
+            return None;
+        }
+
+        Some(Script {
+            filename: self.root.join(name.trim_start_matches('/')),
+            name,
+            path_info,
+        })
+    }
+
+    pub fn respond(&self, request: &Request, script: &Script) -> Response {
+        // Routes for script files only bother the backend with existing ones.
+        if self.extension.is_some() && !script.filename.is_file() {
+            return Response::new(404);
+        }
+        match self.forward(request, script) {
+            Ok(response) => response,
+            Err(e) => {
+                println!("FastCGI request for {} failed: {e}", script.name);
+                match e.kind() {
+                    ErrorKind::TimedOut | ErrorKind::WouldBlock => Response::new(504),
+                    _ => Response::new(502),
+                }
+            }
+        }
+    }
+
+    fn forward(&self, request: &Request, script: &Script) -> io::Result<Response> {
+        let client = request.remote_addr.map(|addr| addr.ip());
+        let lease = match self.balancer.pick(client, &[]) {
+            Some(lease) => lease,
+            None => {
+                return Err(io::Error::new(
+                    ErrorKind::ConnectionRefused,
+                    "No backend available.",
+                ))
+            }
+        };
+        let params = environment(request, script, &self.root);
+
+        // A pooled connection may have been closed by the backend meanwhile.
+        // Requests that aren't safe to retry open a new one instead.
+        let mut pooled = match request.method.is_idempotent() {
+            true => self.pool.take(lease.upstream()),
+            false => None,
+        };
+        loop {
+            let reused = pooled.is_some();
+            let mut records = match self.exchange(&lease, pooled.take(), &params, &request.body) {
+                Ok(records) => records,
+                Err(e) if reused && is_closed(&e) => continue,
+                Err(e) => {
+                    self.balancer.report(&lease, false);
+                    return Err(e);
+                }
+            };
+            match read_head(&mut records) {
+                // The backend closed the idle connection before answering.
+                Err(e) if reused && !records.received && is_closed(&e) => continue,
+                Err(e) => {
+                    self.balancer.report(&lease, false);
+                    return Err(e);
+                }
+                Ok((response, pending)) => {
+                    self.balancer.report(&lease, true);
+                    records.lease = Some(lease);
+                    let body = Body {
+                        pending,
+                        position: 0,
+                        records,
+                    };
+                    return Ok(response.with_stream(body));
+                }
+            }
+        }
+    }
+
+    /// Sends the request over `pooled`, or a new connection if there is none.
+    fn exchange(
+        &self,
+        lease: &Lease,
+        pooled: Option<Box<dyn Stream>>,
+        params: &[(String, String)],
+        body: &[u8],
+    ) -> io::Result<Records> {
+        let upstream = lease.upstream();
+        let mut stream = match pooled {
+            Some(stream) => stream,
+            None => upstream.connect(self.connect_timeout, self.read_timeout)?,
+        };
+        send_request(&mut stream, params, body)?;
+        Ok(Records::new(stream, upstream.clone(), self.pool.clone()))
+    }
+}
+
+/// Collects stdout until the CGI headers are complete, returning the response
+/// and the start of the body. The rest of the body is streamed to the client.
+fn read_head(records: &mut Records) -> io::Result<(Response, Vec<u8>)> {
+    let mut head = Vec::new();
+    let (head_end, body_start) = loop {
+        if let Some(end) = find_head_end(&head) {
+            break end;
+        }
+        if head.len() > MAX_HEAD_SIZE {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Response headers exceed {MAX_HEAD_SIZE} bytes."),
+            ));
+        }
+        match records.next_stdout()? {
+            Some(data) => head.extend_from_slice(&data),
+            None => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Response ended before headers.",
+                ))
+            }
+        }
+    };
+    let response =
+        parse_head(&head[..head_end]).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    Ok((response, head.split_off(body_start)))
+}
+
+fn is_closed(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::BrokenPipe
+    )
+}
+
+/// Idle keep-alive connections per backend.
+struct Pool {
+    idle: Mutex<HashMap<Upstream, Vec<Box<dyn Stream>>>>,
+    size: usize,
+}
+
+impl Pool {
+    fn take(&self, upstream: &Upstream) -> Option<Box<dyn Stream>> {
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        idle.get_mut(upstream)?.pop()
+    }
+
+    fn put(&self, upstream: Upstream, stream: Box<dyn Stream>) {
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        let streams = idle.entry(upstream).or_default();
+        if streams.len() < self.size {
+            streams.push(stream);
+        }
+    }
+}
+
+fn send_request<W: Write>(
+    stream: &mut W,
+    params: &[(String, String)],
+    body: &[u8],
+) -> io::Result<()> {
+    let mut begin = Vec::with_capacity(8);
+    begin.extend_from_slice(&ROLE_RESPONDER.to_be_bytes());
+    begin.push(FLAG_KEEP_CONN);
+    begin.extend_from_slice(&[0; 5]);
+
+    let mut encoded = Vec::new();
+    for (name, value) in params {
+        encode_length(&mut encoded, name.len());
+        encode_length(&mut encoded, value.len());
+        encoded.extend_from_slice(name.as_bytes());
+        encoded.extend_from_slice(value.as_bytes());
+    }
+
+    let mut message = Vec::new();
+    write_record(&mut message, BEGIN_REQUEST, &begin);
+    write_record(&mut message, PARAMS, &encoded);
+    write_record(&mut message, PARAMS, &[]);
+    write_record(&mut message, STDIN, body);
+    if !body.is_empty() {
+        write_record(&mut message, STDIN, &[]);
+    }
+    stream.write_all(&message)?;
+    stream.flush()
+}
+
+/// Appends `content` as records of `kind`, split at the 64 KiB record limit.
+/// Empty content is written as a single empty record, which ends a stream.
+fn write_record(message: &mut Vec<u8>, kind: u8, content: &[u8]) {
+    let mut chunks: Vec<&[u8]> = content.chunks(MAX_CONTENT).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+    for chunk in chunks {
+        let padding = (8 - chunk.len() % 8) % 8;
+        message.push(VERSION_1);
+        message.push(kind);
+        message.extend_from_slice(&REQUEST_ID.to_be_bytes());
+        message.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+        message.push(padding as u8);
+        message.push(0);
+        message.extend_from_slice(chunk);
+        message.extend(std::iter::repeat_n(0, padding));
+    }
+}
+
+fn encode_length(encoded: &mut Vec<u8>, length: usize) {
+    if length < 0x80 {
+        encoded.push(length as u8);
+    } else {
+        encoded.extend_from_slice(&(length as u32 | 0x8000_0000).to_be_bytes());
+    }
+}
+
+/// Reads the records of one response from a backend connection.
+struct Records {
+    stream: Option<Box<dyn Stream>>,
+    upstream: Upstream,
+    pool: Arc<Pool>,
+    /// Held until the response is complete so the connection counts as active.
+    lease: Option<Lease>,
+    /// Whether the backend has sent anything yet.
+    received: bool,
+}
+
+impl Records {
+    fn new(stream: Box<dyn Stream>, upstream: Upstream, pool: Arc<Pool>) -> Records {
+        Records {
+            stream: Some(stream),
+            upstream,
+            pool,
+            lease: None,
+            received: false,
+        }
+    }
+
+    /// The next chunk of stdout, or `None` once the request has ended. Stderr
+    /// is logged as it arrives.
+    fn next_stdout(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            let stream = match self.stream.as_mut() {
+                Some(stream) => stream,
+                None => return Ok(None),
+            };
+            let mut header = [0; 8];
+            if stream.read(&mut header[..1])? == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            self.received = true;
+            stream.read_exact(&mut header[1..])?;
+            let kind = header[1];
+            let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+            let padding = header[6] as usize;
+            let mut content = vec![0; length + padding];
+            stream.read_exact(&mut content)?;
+            content.truncate(length);
+
+            match kind {
+                STDOUT if !content.is_empty() => return Ok(Some(content)),
+                STDERR if !content.is_empty() => {
+                    println!(
+                        "FastCGI stderr: {}",
+                        String::from_utf8_lossy(&content).trim_end()
+                    );
+                }
+                END_REQUEST => {
+                    // protocolStatus 0 is FCGI_REQUEST_COMPLETE, anything else
+                    // means the backend refused the request.
+                    let protocol_status = content.get(4).copied().unwrap_or(0);
+                    let stream = self.stream.take();
+                    if protocol_status != 0 {
+                        return Err(io::Error::new(
+                            ErrorKind::ConnectionAborted,
+                            format!("Backend rejected request with status {protocol_status}."),
+                        ));
+                    }
+                    if let Some(stream) = stream {
+                        self.pool.put(self.upstream.clone(), stream);
+                    }
+                    self.lease = None;
+                    return Ok(None);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// The streamed response body: stdout left over after the headers, then
+/// further stdout records.
+struct Body {
+    pending: Vec<u8>,
+    position: usize,
+    records: Records,
+}
+
+impl Read for Body {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.pending.len() {
+            match self.records.next_stdout()? {
+                Some(data) => {
+                    self.pending = data;
+                    self.position = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let read = buf.len().min(self.pending.len() - self.position);
+        buf[..read].copy_from_slice(&self.pending[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
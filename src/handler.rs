@@ -0,0 +1,43 @@
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::Arc,
+};
+
+use crate::http::{Request, Response};
+
+/// Anything that can turn a `Request` into a `Response`. Implemented for
+/// `RequestHandler`, `Router` and plain closures, so the servers can run
+/// arbitrary application code.
+pub trait Handler: Send + Sync {
+    fn handle(&self, request: &Request) -> Response;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&Request) -> Response + Send + Sync,
+{
+    fn handle(&self, request: &Request) -> Response {
+        self(request)
+    }
+}
+
+impl<H: Handler + ?Sized> Handler for Arc<H> {
+    fn handle(&self, request: &Request) -> Response {
+        self.as_ref().handle(request)
+    }
+}
+
+/// A handler shared between the listener thread and the workers of a `ThreadPool`.
+pub type SharedHandler = Arc<dyn Handler>;
+
+/// Runs `handler`, answering 500 if it panics so the client still gets a
+/// response.
+pub(crate) fn catch_panic(handler: &dyn Handler, request: &Request) -> Response {
+    match catch_unwind(AssertUnwindSafe(|| handler.handle(request))) {
+        Ok(response) => response,
+        Err(_) => {
+            println!("Handler panicked, answering 500.");
+            Response::new(500)
+        }
+    }
+}
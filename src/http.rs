@@ -0,0 +1,702 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{self, BufRead, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::UnixStream,
+    },
+    time::Duration,
+};
+
+/// Upper limit for the request line and headers of a single request.
+pub(crate) const MAX_HEAD_SIZE: usize = 64 * 1024;
+/// Upper limit for a request body, regardless of what Content-Length claims.
+pub(crate) const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Options,
+    Patch,
+    Other(String),
+}
+
+impl Method {
+    pub fn parse(method: &str) -> Method {
+        match method {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "OPTIONS" => Method::Options,
+            "PATCH" => Method::Patch,
+            other => Method::Other(other.to_owned()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+            Method::Patch => "PATCH",
+            Method::Other(other) => other,
+        }
+    }
+
+    /// Whether repeating the request has the same effect as sending it once
+    /// (RFC 9110 9.2.2), so it can be retried after a failure.
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            self,
+            Method::Get | Method::Head | Method::Put | Method::Delete | Method::Options
+        )
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A parsed HTTP/1.x request.
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: Method,
+    pub path: String,
+    pub query: Option<String>,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Path parameters filled in by the `Router` when a pattern matches.
+    pub params: BTreeMap<String, String>,
+    /// Address of the connected client, if known.
+    pub remote_addr: Option<SocketAddr>,
+    /// Whether the request arrived over TLS.
+    pub secure: bool,
+    /// Host name the client asked for in the TLS handshake (SNI).
+    pub server_name: Option<String>,
+    /// Certificate the client authenticated with, if it was verified.
+    pub client_certificate: Option<ClientCertificate>,
+}
+
+/// A client certificate verified during the TLS handshake.
+#[derive(Clone, Debug)]
+pub struct ClientCertificate {
+    /// Subject name, e.g. `CN=admin,O=Example`.
+    pub subject: String,
+    pub issuer: String,
+    /// SHA-256 of the certificate as colon separated hex.
+    pub fingerprint: String,
+}
+
+impl Request {
+    /// Parses a complete request, head and body, from `bytes`.
+    pub fn parse(bytes: &[u8]) -> Result<Request, String> {
+        let mut reader = bytes;
+        match read_request(&mut reader)? {
+            Some(request) => Ok(request),
+            None => Err("Empty request.".to_owned()),
+        }
+    }
+
+    /// Case insensitive lookup of the first header named `name`.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+
+    /// The request target as it appeared in the request line.
+    pub fn target(&self) -> String {
+        match &self.query {
+            Some(query) => format!("{}?{query}", self.path),
+            None => self.path.clone(),
+        }
+    }
+}
+
+/// Reads one request from `reader`. Returns `Ok(None)` if the peer closed
+/// the connection before sending anything.
+pub fn read_request<R: BufRead>(reader: &mut R) -> Result<Option<Request>, String> {
+    let mut head_size = 0;
+    let mut request_line = String::new();
+    // Tolerate empty lines in front of the request line (RFC 9112 2.2).
+    while request_line.trim_end().is_empty() {
+        request_line.clear();
+        let read = read_line(reader, &mut request_line, &mut head_size)?;
+        if read == 0 {
+            return Ok(None);
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/") => {
+            (method, target, version)
+        }
+        _ => {
+            return Err(format!(
+                "Malformed request line: {}",
+                request_line.trim_end()
+            ))
+        }
+    };
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_owned(), Some(query.to_owned())),
+        None => (target.to_owned(), None),
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        if read_line(reader, &mut line, &mut head_size)? == 0 {
+            return Err("Connection closed inside request head.".to_owned());
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        match line.split_once(':') {
+            Some((name, value)) => headers.push((name.trim().to_owned(), value.trim().to_owned())),
+            None => return Err(format!("Malformed header line: {line}")),
+        }
+    }
+
+    let body = match body_length(&headers)? {
+        BodyLength::Chunked => read_chunked(reader)?,
+        BodyLength::Length(length) => {
+            let mut body = vec![0; length];
+            reader
+                .read_exact(&mut body)
+                .map_err(|e| format!("Could not read request body: {e}"))?;
+            body
+        }
+    };
+
+    Ok(Some(Request {
+        method: Method::parse(method),
+        path,
+        query,
+        version: version.to_owned(),
+        headers,
+        body,
+        params: BTreeMap::new(),
+        remote_addr: None,
+        secure: false,
+        server_name: None,
+        client_certificate: None,
+    }))
+}
+
+/// How a request body is delimited (RFC 9112 6.3).
+pub(crate) enum BodyLength {
+    Chunked,
+    Length(usize),
+}
+
+/// Finds the body length from the request headers. Framing that a server in
+/// front could read differently is rejected: transfer codings other than a
+/// lone `chunked`, Transfer-Encoding together with Content-Length, and more
+/// than one Content-Length.
+pub(crate) fn body_length(headers: &[(String, String)]) -> Result<BodyLength, String> {
+    let field = |name: &str| -> Vec<&str> {
+        headers
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect()
+    };
+    let encodings = field("Transfer-Encoding");
+    let lengths = field("Content-Length");
+    match (encodings.as_slice(), lengths.as_slice()) {
+        ([], []) => Ok(BodyLength::Length(0)),
+        ([encoding], []) if encoding.eq_ignore_ascii_case("chunked") => Ok(BodyLength::Chunked),
+        ([], [length]) => {
+            let valid = !length.is_empty() && length.bytes().all(|b| b.is_ascii_digit());
+            match length.parse::<usize>() {
+                Ok(length) if valid && length > MAX_BODY_SIZE => {
+                    Err(format!("Request body of {length} bytes is too large."))
+                }
+                Ok(length) if valid => Ok(BodyLength::Length(length)),
+                _ => Err(format!("Invalid Content-Length: {length}")),
+            }
+        }
+        ([], _) => Err("More than one Content-Length.".to_owned()),
+        (_, []) => Err(format!(
+            "Unsupported Transfer-Encoding: {}",
+            encodings.join(", ")
+        )),
+        _ => Err("Both Transfer-Encoding and Content-Length.".to_owned()),
+    }
+}
+
+fn read_line<R: BufRead>(
+    reader: &mut R,
+    line: &mut String,
+    head_size: &mut usize,
+) -> Result<usize, String> {
+    let mut bytes = Vec::new();
+    let read = Read::take(&mut *reader, (MAX_HEAD_SIZE - *head_size) as u64 + 1)
+        .read_until(b'\n', &mut bytes)
+        .map_err(|e| format!("Could not read request: {e}"))?;
+    *head_size += read;
+    if *head_size > MAX_HEAD_SIZE {
+        return Err("Request head too large.".to_owned());
+    }
+    line.push_str(&String::from_utf8_lossy(&bytes));
+    Ok(read)
+}
+
+fn read_chunked<R: BufRead>(reader: &mut R) -> Result<Vec<u8>, String> {
+    let mut body = Vec::new();
+    Read::take(ChunkedReader::new(reader), MAX_BODY_SIZE as u64 + 1)
+        .read_to_end(&mut body)
+        .map_err(|e| format!("Could not read chunked request body: {e}"))?;
+    if body.len() > MAX_BODY_SIZE {
+        return Err("Chunked request body is too large.".to_owned());
+    }
+    Ok(body)
+}
+
+/// Percent-decodes a request path (RFC 3986 2.1). `None` for malformed
+/// escapes, invalid UTF-8, and encoded `/`, `\` or NUL, which upstreams and
+/// file systems could take as separators or the end of the path.
+pub fn decode_path(path: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut rest = path.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte != b'%' {
+            bytes.push(byte);
+            rest = tail;
+            continue;
+        }
+        let hex = tail
+            .get(..2)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+        match u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()? {
+            b'/' | b'\\' | 0 => return None,
+            decoded => bytes.push(decoded),
+        }
+        rest = &tail[2..];
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// The body of a `Response`.
+pub enum Body {
+    Full(Vec<u8>),
+    /// Copied to the client as it is read. Sent with chunked encoding unless
+    /// the response has a Content-Length header.
+    Stream(Box<dyn Read + Send>),
+}
+
+impl Body {
+    /// Length of a `Full` body, `None` for streams.
+    pub fn len(&self) -> Option<usize> {
+        match self {
+            Body::Full(bytes) => Some(bytes.len()),
+            Body::Stream(_) => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::Full(bytes) => Some(bytes),
+            Body::Stream(_) => None,
+        }
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Full(bytes) => write!(f, "Full({} bytes)", bytes.len()),
+            Body::Stream(_) => f.write_str("Stream"),
+        }
+    }
+}
+
+/// A client connection, TCP or TLS, or a connection to an upstream.
+pub trait Stream: Read + Write + Send {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Makes reads and writes fail with `WouldBlock` instead of waiting, for
+    /// callers that wait on `poll_fd` or retry later.
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+
+    /// The socket to wait on before reading, if the stream reads from one.
+    fn poll_fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    /// Whether a read can return data already taken from the socket, e.g.
+    /// decrypted TLS records, so waiting on the socket could miss it.
+    fn buffered(&self) -> bool {
+        false
+    }
+}
+
+impl Stream for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn poll_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+impl Stream for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn poll_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+impl<S: Stream + ?Sized> Stream for Box<S> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_write_timeout(timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        (**self).set_nonblocking(nonblocking)
+    }
+
+    fn poll_fd(&self) -> Option<RawFd> {
+        (**self).poll_fd()
+    }
+
+    fn buffered(&self) -> bool {
+        (**self).buffered()
+    }
+}
+
+/// Takes over the connection once the response head has been sent, e.g. after
+/// `101 Switching Protocols`, or to keep writing a body of unknown length.
+pub type Upgrade = Box<dyn FnOnce(Box<dyn Stream>) + Send>;
+
+/// An HTTP response produced by a `Handler`.
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+    pub upgrade: Option<Upgrade>,
+}
+
+impl fmt::Debug for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Response")
+            .field("status", &self.status)
+            .field("headers", &self.headers)
+            .field("body", &self.body)
+            .field("upgrade", &self.upgrade.is_some())
+            .finish()
+    }
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Body::Full(Vec::new()),
+            upgrade: None,
+        }
+    }
+
+    /// Hands the connection to `upgrade` after the response head has been
+    /// written, instead of closing it. The body is left to `upgrade`.
+    pub fn with_upgrade(
+        mut self,
+        upgrade: impl FnOnce(Box<dyn Stream>) + Send + 'static,
+    ) -> Response {
+        self.upgrade = Some(Box::new(upgrade));
+        self
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.set_header(name, value);
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Response {
+        self.body = Body::Full(body.into());
+        self
+    }
+
+    pub fn with_stream(mut self, stream: impl Read + Send + 'static) -> Response {
+        self.body = Body::Stream(Box::new(stream));
+        self
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    /// Replaces any existing header named `name`.
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.remove_header(name);
+        self.headers.push((name.to_owned(), value.to_owned()));
+    }
+
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers
+            .retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    }
+
+    /// Writes only the status line and headers, as they are, for responses
+    /// whose connection is upgraded.
+    pub fn write_head<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut head = self.status_and_headers();
+        head.push_str("\r\n");
+        writer.write_all(head.as_bytes())?;
+        writer.flush()
+    }
+
+    /// Writes the status line, headers and body to `writer`. A Content-Length
+    /// header is added to full bodies unless one is already present. 1xx, 204
+    /// and 304 responses are sent without one and without a body.
+    pub fn write_to<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        let mut head = self.status_and_headers();
+        if matches!(self.status, 100..=199 | 204 | 304) {
+            head.push_str("\r\n");
+            writer.write_all(head.as_bytes())?;
+            return writer.flush();
+        }
+        let has_length = self.header("Content-Length").is_some();
+        match &self.body {
+            Body::Full(bytes) if !has_length => {
+                head.push_str(&format!("Content-Length: {}\r\n", bytes.len()))
+            }
+            Body::Stream(_) if !has_length => head.push_str("Transfer-Encoding: chunked\r\n"),
+            _ => {}
+        }
+        head.push_str("\r\n");
+        writer.write_all(head.as_bytes())?;
+
+        match self.body {
+            Body::Full(bytes) => writer.write_all(&bytes)?,
+            Body::Stream(mut stream) if has_length => {
+                io::copy(&mut stream, writer)?;
+            }
+            Body::Stream(mut stream) => {
+                let mut buffer = vec![0; 16 * 1024];
+                loop {
+                    let read = match stream.read(&mut buffer) {
+                        Ok(0) => break,
+                        Ok(read) => read,
+                        Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e),
+                    };
+                    writer.write_all(format!("{read:x}\r\n").as_bytes())?;
+                    writer.write_all(&buffer[..read])?;
+                    writer.write_all(b"\r\n")?;
+                    writer.flush()?;
+                }
+                writer.write_all(b"0\r\n\r\n")?;
+            }
+        }
+        writer.flush()
+    }
+
+    fn status_and_headers(&self) -> String {
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason_phrase(self.status)
+        );
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head
+    }
+}
+
+/// Reads the status line and headers of a response, e.g. from an upstream server.
+pub fn read_response_head<R: BufRead>(
+    reader: &mut R,
+) -> Result<(u16, Vec<(String, String)>), String> {
+    let mut head_size = 0;
+    let mut status_line = String::new();
+    if read_line(reader, &mut status_line, &mut head_size)? == 0 {
+        return Err("Connection closed before response.".to_owned());
+    }
+    let status = match status_line.split_whitespace().nth(1).map(str::parse) {
+        Some(Ok(status)) if status_line.starts_with("HTTP/") => status,
+        _ => return Err(format!("Malformed status line: {}", status_line.trim_end())),
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        if read_line(reader, &mut line, &mut head_size)? == 0 {
+            return Err("Connection closed inside response head.".to_owned());
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            return Ok((status, headers));
+        }
+        match line.split_once(':') {
+            Some((name, value)) => headers.push((name.trim().to_owned(), value.trim().to_owned())),
+            None => return Err(format!("Malformed header line: {line}")),
+        }
+    }
+}
+
+/// Decodes a chunked message body while it is read. Input that ends inside
+/// the body, including its last empty line, fails with `UnexpectedEof`.
+pub struct ChunkedReader<R: BufRead> {
+    inner: R,
+    remaining: usize,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    pub fn new(inner: R) -> ChunkedReader<R> {
+        ChunkedReader {
+            inner,
+            remaining: 0,
+            done: false,
+        }
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            let mut head_size = 0;
+            let mut size_line = String::new();
+            read_line(&mut self.inner, &mut size_line, &mut head_size)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            if !size_line.ends_with('\n') {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            let size = size_line.trim_end().split(';').next().unwrap_or_default();
+            self.remaining = usize::from_str_radix(size.trim(), 16)
+                .map_err(|_| io::Error::new(ErrorKind::InvalidData, "Invalid chunk size."))?;
+            if self.remaining == 0 {
+                loop {
+                    let mut trailer = String::new();
+                    read_line(&mut self.inner, &mut trailer, &mut head_size)
+                        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+                    if !trailer.ends_with('\n') {
+                        return Err(ErrorKind::UnexpectedEof.into());
+                    }
+                    if trailer.trim_end().is_empty() {
+                        self.done = true;
+                        return Ok(0);
+                    }
+                }
+            }
+        }
+
+        let limit = buf.len().min(self.remaining);
+        let read = self.inner.read(&mut buf[..limit])?;
+        if read == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= read;
+        if self.remaining == 0 {
+            let mut crlf = [0; 2];
+            self.inner.read_exact(&mut crlf)?;
+            if &crlf != b"\r\n" {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Chunk data not followed by CRLF.",
+                ));
+            }
+        }
+        Ok(read)
+    }
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        421 => "Misdirected Request",
+        426 => "Upgrade Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
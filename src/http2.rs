@@ -0,0 +1,1271 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, ErrorKind, Read, Write},
+    net::TcpStream,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering::Relaxed},
+        mpsc::{channel, Receiver, Sender},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+mod hpack;
+
+use hpack::DecodeError;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+use crate::{
+    connection::Peer,
+    handler::{catch_panic, Handler, SharedHandler},
+    http::{Body, Method, Request, Response, Stream, MAX_BODY_SIZE, MAX_HEAD_SIZE},
+    thread_pool::ThreadPool,
+    waker::Waker,
+};
+
+/// Sent by clients before their first frame, see RFC 9113 section 3.4.
+pub(crate) const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+/// Largest frame payload we accept, the protocol minimum.
+const MAX_FRAME_SIZE: usize = 16 * 1024;
+/// Also caps the handlers running per connection, including those of streams
+/// the client reset, which keep running until they return.
+const MAX_CONCURRENT_STREAMS: usize = 100;
+/// Flow control window of new streams and connections until SETTINGS say otherwise.
+const DEFAULT_WINDOW: i64 = 65_535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+/// How long a connection over a stream without a socket waits for frames
+/// before sending finished responses. Others are woken when they are queued.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+/// How often a connection checks whether the server stops.
+const IDLE_POLL: Duration = Duration::from_millis(500);
+/// Connections without open streams are closed after this long.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+const PREFACE_TIMEOUT: Duration = Duration::from_secs(10);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long open streams may continue after GOAWAY.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// Header fields that only apply to a single HTTP/1.1 connection.
+const CONNECTION_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+mod frame_types {
+    pub const DATA: u8 = 0x0;
+    pub const HEADERS: u8 = 0x1;
+    pub const PRIORITY: u8 = 0x2;
+    pub const RST_STREAM: u8 = 0x3;
+    pub const SETTINGS: u8 = 0x4;
+    pub const PUSH_PROMISE: u8 = 0x5;
+    pub const PING: u8 = 0x6;
+    pub const GOAWAY: u8 = 0x7;
+    pub const WINDOW_UPDATE: u8 = 0x8;
+    pub const CONTINUATION: u8 = 0x9;
+}
+
+mod flags {
+    pub const END_STREAM: u8 = 0x1;
+    pub const ACK: u8 = 0x1;
+    pub const END_HEADERS: u8 = 0x4;
+    pub const PADDED: u8 = 0x8;
+    pub const PRIORITY: u8 = 0x20;
+}
+
+mod settings_ids {
+    pub const ENABLE_PUSH: u16 = 0x2;
+    pub const MAX_CONCURRENT_STREAMS: u16 = 0x3;
+    pub const INITIAL_WINDOW_SIZE: u16 = 0x4;
+    pub const MAX_FRAME_SIZE: u16 = 0x5;
+    pub const MAX_HEADER_LIST_SIZE: u16 = 0x6;
+}
+
+/// Codes sent in RST_STREAM and GOAWAY frames, see RFC 9113 section 7.
+pub mod error_codes {
+    pub const NO_ERROR: u32 = 0x0;
+    pub const PROTOCOL_ERROR: u32 = 0x1;
+    pub const INTERNAL_ERROR: u32 = 0x2;
+    pub const FLOW_CONTROL_ERROR: u32 = 0x3;
+    pub const STREAM_CLOSED: u32 = 0x5;
+    pub const FRAME_SIZE_ERROR: u32 = 0x6;
+    pub const REFUSED_STREAM: u32 = 0x7;
+    pub const COMPRESSION_ERROR: u32 = 0x9;
+    pub const ENHANCE_YOUR_CALM: u32 = 0xB;
+}
+
+use error_codes::*;
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: Vec<u8>,
+}
+
+/// Why the connection ends early. Errors of a single stream are answered
+/// with RST_STREAM instead.
+enum Error {
+    /// A violation by the peer, answered with GOAWAY.
+    Protocol(u32, String),
+    Io(io::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+/// Where the handlers of a connection's streams run.
+#[derive(Clone)]
+pub(crate) enum Executor {
+    /// The server's thread pool. Streams are refused while it has no worker
+    /// free or left to start, rather than waiting behind busy connections.
+    Pool(Arc<ThreadPool>),
+    /// tokio's blocking threads, like every handler of the async servers.
+    #[cfg(feature = "async")]
+    Tokio(tokio::runtime::Handle),
+}
+
+impl Executor {
+    /// Starts `job`, returning false without running it if there is no room.
+    fn execute(&self, job: Box<dyn FnOnce() + Send>) -> bool {
+        match self {
+            Executor::Pool(pool) => pool.try_execute(job),
+            #[cfg(feature = "async")]
+            Executor::Tokio(handle) => {
+                handle.spawn_blocking(job);
+                true
+            }
+        }
+    }
+}
+
+/// Send side state shared between the connection and the threads writing
+/// responses.
+struct Shared {
+    state: Mutex<SendState>,
+    changed: Condvar,
+    /// Woken when frames are queued, so the connection sends them.
+    waker: Waker,
+}
+
+struct SendState {
+    /// Connection flow control window for DATA we send.
+    window: i64,
+    /// Send windows of the streams whose response isn't complete yet.
+    streams: HashMap<u32, i64>,
+    initial_window: i64,
+    max_frame_size: usize,
+    /// Handlers still running, even if the client reset their stream.
+    handlers: usize,
+    closed: bool,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, SendState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A request whose headers have arrived but not yet its whole body.
+struct Incoming {
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+/// A header block split over HEADERS and CONTINUATION frames.
+struct PendingHeaders {
+    stream_id: u32,
+    block: Vec<u8>,
+    end_stream: bool,
+}
+
+/// Serves HTTP/2 on a connection that negotiated `h2`, until the client
+/// closes it, it stays idle or `running` turns false. Every request runs as
+/// its own job on `executor`, so slow responses don't hold up the other
+/// streams.
+pub(crate) fn serve<S: Stream>(
+    stream: S,
+    handler: SharedHandler,
+    executor: Executor,
+    peer: Peer,
+    running: &AtomicBool,
+) {
+    serve_connection(stream, handler, executor, peer, running, None);
+}
+
+/// Whether a cleartext client starts with the HTTP/2 connection preface,
+/// i.e. speaks h2c with prior knowledge. Nothing is consumed from `stream`.
+pub(crate) fn has_preface(stream: &TcpStream) -> bool {
+    let deadline = Instant::now() + PREFACE_TIMEOUT;
+    let mut buffer = [0; PREFACE.len()];
+    loop {
+        let peeked = match stream.peek(&mut buffer) {
+            Ok(peeked) => peeked,
+            Err(_) => return false,
+        };
+        if peeked == 0 || buffer[..peeked] != PREFACE[..peeked] {
+            return false;
+        }
+        if peeked == PREFACE.len() {
+            return true;
+        }
+        // Peek returns the same bytes until more arrive.
+        if Instant::now() > deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Wraps `handler` so HTTP/1.1 requests asking for `Upgrade: h2c` switch the
+/// connection to HTTP/2, with the request answered on stream 1. See RFC 7540
+/// section 3.2, requests with a body are served over HTTP/1.1 instead.
+pub(crate) fn upgrade_handler(
+    handler: SharedHandler,
+    executor: Executor,
+    running: Arc<AtomicBool>,
+) -> SharedHandler {
+    Arc::new(move |request: &Request| {
+        let settings = match upgrade_settings(request) {
+            Some(settings) if request.body.is_empty() => settings,
+            _ => return handler.handle(request),
+        };
+
+        let mut request = request.clone();
+        request.headers.retain(|(name, _)| {
+            !name.eq_ignore_ascii_case("http2-settings")
+                && !CONNECTION_HEADERS.contains(&name.to_ascii_lowercase().as_str())
+        });
+        request.version = "HTTP/2.0".to_owned();
+        let peer = Peer {
+            remote_addr: request.remote_addr,
+            secure: request.secure,
+            server_name: request.server_name.clone(),
+            client_certificate: request.client_certificate.clone(),
+        };
+        let handler = handler.clone();
+        let executor = executor.clone();
+        let running = running.clone();
+        Response::new(101)
+            .with_header("Connection", "Upgrade")
+            .with_header("Upgrade", "h2c")
+            .with_upgrade(move |stream| {
+                let upgraded = Some((request, settings));
+                serve_connection(stream, handler, executor, peer, &running, upgraded);
+            })
+    })
+}
+
+/// The decoded `HTTP2-Settings` of a request asking for `Upgrade: h2c`.
+fn upgrade_settings(request: &Request) -> Option<Vec<u8>> {
+    let has_token = |name: &str, token: &str| {
+        request.header(name).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|part| part.trim().eq_ignore_ascii_case(token))
+        })
+    };
+    if !has_token("Upgrade", "h2c")
+        || !has_token("Connection", "upgrade")
+        || !has_token("Connection", "http2-settings")
+    {
+        return None;
+    }
+    let settings = request.header("HTTP2-Settings")?;
+    let settings = URL_SAFE_NO_PAD
+        .decode(settings.trim().trim_end_matches('='))
+        .ok()?;
+    settings.len().is_multiple_of(6).then_some(settings)
+}
+
+/// Runs a connection, starting with stream 1 already open if it was upgraded
+/// from HTTP/1.1 together with the client's `HTTP2-Settings`.
+fn serve_connection<S: Stream>(
+    mut stream: S,
+    handler: SharedHandler,
+    executor: Executor,
+    peer: Peer,
+    running: &AtomicBool,
+    upgraded: Option<(Request, Vec<u8>)>,
+) {
+    let waker = match Waker::new() {
+        Ok(waker) => waker,
+        Err(e) => return println!("HTTP/2 connection failed. {e}"),
+    };
+    let (frames, queued) = channel();
+    let mut connection = Connection {
+        handler,
+        executor,
+        peer,
+        shared: Arc::new(Shared {
+            state: Mutex::new(SendState {
+                window: DEFAULT_WINDOW,
+                streams: HashMap::new(),
+                initial_window: DEFAULT_WINDOW,
+                max_frame_size: MAX_FRAME_SIZE,
+                handlers: 0,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+            waker,
+        }),
+        frames,
+        queued,
+        decoder: hpack::Decoder::new(MAX_HEAD_SIZE),
+        incoming: HashMap::new(),
+        pending_headers: None,
+        last_stream_id: 0,
+        going_away: None,
+    };
+
+    match connection.run(&mut stream, running, upgraded) {
+        Ok(()) => {}
+        Err(Error::Protocol(code, message)) => {
+            println!("HTTP/2 protocol error: {message}");
+            let _ = connection.go_away(&mut stream, code, &message);
+        }
+        Err(Error::Io(e)) => println!("HTTP/2 connection failed. {e}"),
+    }
+
+    let mut state = connection.shared.lock();
+    state.closed = true;
+    state.streams.clear();
+    connection.shared.changed.notify_all();
+}
+
+struct Connection {
+    handler: SharedHandler,
+    executor: Executor,
+    peer: Peer,
+    shared: Arc<Shared>,
+    frames: Sender<Vec<u8>>,
+    queued: Receiver<Vec<u8>>,
+    decoder: hpack::Decoder,
+    incoming: HashMap<u32, Incoming>,
+    pending_headers: Option<PendingHeaders>,
+    /// Highest stream the client opened, streams below it are closed.
+    last_stream_id: u32,
+    /// When GOAWAY was sent or received, no new streams are accepted after.
+    going_away: Option<Instant>,
+}
+
+impl Connection {
+    fn run<S: Stream>(
+        &mut self,
+        stream: &mut S,
+        running: &AtomicBool,
+        upgraded: Option<(Request, Vec<u8>)>,
+    ) -> Result<(), Error> {
+        stream.set_read_timeout(Some(PREFACE_TIMEOUT))?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+
+        // Our SETTINGS may precede the client preface, which an upgraded
+        // client only sends after the 101.
+        let mut settings = Vec::new();
+        for (id, value) in [
+            (settings_ids::ENABLE_PUSH, 0),
+            (
+                settings_ids::MAX_CONCURRENT_STREAMS,
+                MAX_CONCURRENT_STREAMS as u32,
+            ),
+            (settings_ids::MAX_HEADER_LIST_SIZE, MAX_HEAD_SIZE as u32),
+        ] {
+            settings.extend(id.to_be_bytes());
+            settings.extend(value.to_be_bytes());
+        }
+        stream.write_all(&frame(frame_types::SETTINGS, 0, 0, &settings))?;
+        stream.flush()?;
+
+        if let Some((request, settings)) = upgraded {
+            // Acknowledged implicitly by the 101, see RFC 7540 section 3.2.1.
+            self.apply_settings(&settings)?;
+            self.last_stream_id = 1;
+            let mut state = self.shared.lock();
+            let window = state.initial_window;
+            state.streams.insert(1, window);
+            drop(state);
+            self.spawn(1, request);
+        }
+
+        let mut preface = [0; PREFACE.len()];
+        stream.read_exact(&mut preface)?;
+        if preface != PREFACE {
+            return Err(Error::Protocol(
+                PROTOCOL_ERROR,
+                "Invalid connection preface.".to_owned(),
+            ));
+        }
+
+        let mut buffer = Vec::new();
+        let mut chunk = vec![0; 16 * 1024];
+        let mut last_activity = Instant::now();
+        loop {
+            // Counted first: a stream's last frame is queued before it closes.
+            let open = self.shared.lock().streams.len();
+            // Written at once, so responses aren't split into tiny records.
+            let queued: Vec<u8> = self.queued.try_iter().flatten().collect();
+            stream.write_all(&queued)?;
+            stream.flush()?;
+
+            if open > 0 {
+                last_activity = Instant::now();
+            }
+            match self.going_away {
+                Some(_) if open == 0 => return Ok(()),
+                Some(since) if since.elapsed() > SHUTDOWN_GRACE => return Ok(()),
+                Some(_) => {}
+                None if !running.load(Relaxed) => {
+                    self.go_away(stream, NO_ERROR, "")?;
+                    continue;
+                }
+                None if last_activity.elapsed() > IDLE_TIMEOUT => {
+                    return self.go_away(stream, NO_ERROR, "").map_err(Error::Io);
+                }
+                None => {}
+            }
+
+            // Waits for either frames from the client or queued responses.
+            match stream.poll_fd() {
+                _ if stream.buffered() => {}
+                Some(fd) => {
+                    if !self.shared.waker.wait_timeout(fd, IDLE_POLL)? {
+                        continue;
+                    }
+                    stream.set_read_timeout(Some(IDLE_POLL))?;
+                }
+                None => {
+                    let timeout = if open > 0 { POLL_INTERVAL } else { IDLE_POLL };
+                    stream.set_read_timeout(Some(timeout))?;
+                }
+            }
+            match stream.read(&mut chunk) {
+                Ok(0) => return Ok(()),
+                Ok(read) => {
+                    buffer.extend_from_slice(&chunk[..read]);
+                    last_activity = Instant::now();
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e.into()),
+            }
+
+            while let Some(frame) = next_frame(&mut buffer)? {
+                self.handle_frame(stream, frame)?;
+            }
+        }
+    }
+
+    fn handle_frame<S: Stream>(&mut self, stream: &mut S, received: Frame) -> Result<(), Error> {
+        if let Some(pending) = &self.pending_headers {
+            if received.kind != frame_types::CONTINUATION || received.stream_id != pending.stream_id
+            {
+                return Err(Error::Protocol(
+                    PROTOCOL_ERROR,
+                    "Expected CONTINUATION.".to_owned(),
+                ));
+            }
+        }
+        let Frame {
+            kind,
+            flags,
+            stream_id,
+            payload,
+        } = received;
+        let on_connection = stream_id == 0;
+
+        match kind {
+            frame_types::DATA if !on_connection => {
+                let length = payload.len() as u32;
+                let data = unpad(&payload, flags)?;
+                // The body is buffered, so the window is given back right away.
+                if length > 0 {
+                    stream.write_all(&window_update(0, length))?;
+                }
+                let end_stream = flags & flags::END_STREAM != 0;
+                match self.incoming.get_mut(&stream_id) {
+                    Some(incoming) if incoming.body.len() + data.len() > MAX_BODY_SIZE => {
+                        self.reject(stream, stream_id, 413)?;
+                    }
+                    Some(incoming) => {
+                        incoming.body.extend_from_slice(data);
+                        if end_stream {
+                            self.dispatch(stream_id);
+                        } else if length > 0 {
+                            stream.write_all(&window_update(stream_id, length))?;
+                        }
+                    }
+                    None if stream_id > self.last_stream_id => {
+                        return Err(Error::Protocol(
+                            PROTOCOL_ERROR,
+                            format!("DATA on idle stream {stream_id}."),
+                        ))
+                    }
+                    // Sent before the client saw our RST_STREAM.
+                    None => {}
+                }
+            }
+            frame_types::HEADERS if !on_connection => {
+                let mut fragment = unpad(&payload, flags)?;
+                if flags & flags::PRIORITY != 0 {
+                    fragment = fragment.get(5..).ok_or_else(|| {
+                        Error::Protocol(FRAME_SIZE_ERROR, "Short HEADERS frame.".to_owned())
+                    })?;
+                }
+                let pending = PendingHeaders {
+                    stream_id,
+                    block: fragment.to_vec(),
+                    end_stream: flags & flags::END_STREAM != 0,
+                };
+                if flags & flags::END_HEADERS != 0 {
+                    self.headers_complete(stream, pending)?;
+                } else {
+                    self.pending_headers = Some(pending);
+                }
+            }
+            frame_types::CONTINUATION => {
+                let mut pending = match self.pending_headers.take() {
+                    Some(pending) => pending,
+                    None => {
+                        return Err(Error::Protocol(
+                            PROTOCOL_ERROR,
+                            "Unexpected CONTINUATION.".to_owned(),
+                        ))
+                    }
+                };
+                pending.block.extend_from_slice(&payload);
+                if pending.block.len() > MAX_HEAD_SIZE {
+                    return Err(Error::Protocol(
+                        ENHANCE_YOUR_CALM,
+                        "Header block too large.".to_owned(),
+                    ));
+                }
+                if flags & flags::END_HEADERS != 0 {
+                    self.headers_complete(stream, pending)?;
+                } else {
+                    self.pending_headers = Some(pending);
+                }
+            }
+            frame_types::PRIORITY if !on_connection && payload.len() != 5 => {
+                self.reset(stream, stream_id, FRAME_SIZE_ERROR)?;
+            }
+            // Responses are sent in the order they are ready, without priorities.
+            frame_types::PRIORITY if !on_connection => {}
+            frame_types::RST_STREAM if !on_connection => {
+                if payload.len() != 4 {
+                    return Err(Error::Protocol(
+                        FRAME_SIZE_ERROR,
+                        "RST_STREAM must be 4 bytes.".to_owned(),
+                    ));
+                }
+                if stream_id > self.last_stream_id {
+                    return Err(Error::Protocol(
+                        PROTOCOL_ERROR,
+                        format!("RST_STREAM on idle stream {stream_id}."),
+                    ));
+                }
+                self.close_stream(stream_id);
+            }
+            frame_types::SETTINGS if on_connection => {
+                if flags & flags::ACK != 0 {
+                    if !payload.is_empty() {
+                        return Err(Error::Protocol(
+                            FRAME_SIZE_ERROR,
+                            "SETTINGS ack with payload.".to_owned(),
+                        ));
+                    }
+                    return Ok(());
+                }
+                self.apply_settings(&payload)?;
+                stream.write_all(&frame(frame_types::SETTINGS, flags::ACK, 0, &[]))?;
+            }
+            frame_types::PING if on_connection => {
+                if payload.len() != 8 {
+                    return Err(Error::Protocol(
+                        FRAME_SIZE_ERROR,
+                        "PING must be 8 bytes.".to_owned(),
+                    ));
+                }
+                if flags & flags::ACK == 0 {
+                    stream.write_all(&frame(frame_types::PING, flags::ACK, 0, &payload))?;
+                }
+            }
+            frame_types::GOAWAY if on_connection => {
+                self.going_away.get_or_insert_with(Instant::now);
+            }
+            frame_types::WINDOW_UPDATE => {
+                if payload.len() != 4 {
+                    return Err(Error::Protocol(
+                        FRAME_SIZE_ERROR,
+                        "WINDOW_UPDATE must be 4 bytes.".to_owned(),
+                    ));
+                }
+                let increment = (read_u32(&payload) & 0x7FFF_FFFF) as i64;
+                self.window_update(stream, stream_id, increment)?;
+            }
+            frame_types::PUSH_PROMISE => {
+                return Err(Error::Protocol(
+                    PROTOCOL_ERROR,
+                    "Clients must not push.".to_owned(),
+                ))
+            }
+            frame_types::DATA
+            | frame_types::HEADERS
+            | frame_types::PRIORITY
+            | frame_types::RST_STREAM => {
+                return Err(Error::Protocol(
+                    PROTOCOL_ERROR,
+                    format!("Frame type {kind} on stream 0."),
+                ))
+            }
+            frame_types::SETTINGS | frame_types::PING | frame_types::GOAWAY => {
+                return Err(Error::Protocol(
+                    PROTOCOL_ERROR,
+                    format!("Frame type {kind} on stream {stream_id}."),
+                ))
+            }
+            // Unknown frame types are ignored.
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Handles a complete header block, either opening a stream or ending
+    /// one with trailers.
+    fn headers_complete<S: Stream>(
+        &mut self,
+        stream: &mut S,
+        pending: PendingHeaders,
+    ) -> Result<(), Error> {
+        // Decoded even for refused streams, to keep the table in sync. A
+        // block that decodes past our MAX_HEADER_LIST_SIZE leaves it out of
+        // sync, so it ends the connection.
+        let headers = match self.decoder.decode(&pending.block) {
+            Ok(headers) => headers,
+            Err(DecodeError::Invalid(e)) => return Err(Error::Protocol(COMPRESSION_ERROR, e)),
+            Err(DecodeError::TooLarge) => {
+                return Err(Error::Protocol(
+                    ENHANCE_YOUR_CALM,
+                    "Header list too large.".to_owned(),
+                ))
+            }
+        };
+        let stream_id = pending.stream_id;
+
+        if self.incoming.contains_key(&stream_id) {
+            // Trailers, which the handlers don't see.
+            if pending.end_stream {
+                self.dispatch(stream_id);
+            } else {
+                self.reset(stream, stream_id, PROTOCOL_ERROR)?;
+            }
+            return Ok(());
+        }
+        if stream_id <= self.last_stream_id {
+            return self
+                .reset(stream, stream_id, STREAM_CLOSED)
+                .map_err(Error::Io);
+        }
+        if stream_id.is_multiple_of(2) {
+            return Err(Error::Protocol(
+                PROTOCOL_ERROR,
+                format!("Client opened even stream {stream_id}."),
+            ));
+        }
+        self.last_stream_id = stream_id;
+        if self.going_away.is_some() {
+            return Ok(());
+        }
+
+        {
+            let mut state = self.shared.lock();
+            if state.streams.len() >= MAX_CONCURRENT_STREAMS
+                || state.handlers >= MAX_CONCURRENT_STREAMS
+            {
+                drop(state);
+                return self
+                    .reset(stream, stream_id, REFUSED_STREAM)
+                    .map_err(Error::Io);
+            }
+            let window = state.initial_window;
+            state.streams.insert(stream_id, window);
+        }
+        self.incoming.insert(
+            stream_id,
+            Incoming {
+                headers,
+                body: Vec::new(),
+            },
+        );
+        if pending.end_stream {
+            self.dispatch(stream_id);
+        }
+        Ok(())
+    }
+
+    /// Runs the handler for a stream whose request is complete.
+    fn dispatch(&mut self, stream_id: u32) {
+        let incoming = match self.incoming.remove(&stream_id) {
+            Some(incoming) => incoming,
+            None => return,
+        };
+        let mut request = match request(incoming.headers, incoming.body) {
+            Ok(request) => request,
+            Err(e) => {
+                println!("Malformed HTTP/2 request. {e}");
+                self.close_stream(stream_id);
+                let _ = self.frames.send(rst_stream(stream_id, PROTOCOL_ERROR));
+                return;
+            }
+        };
+        request.remote_addr = self.peer.remote_addr;
+        request.secure = self.peer.secure;
+        request.server_name = self.peer.server_name.clone();
+        request.client_certificate = self.peer.client_certificate.clone();
+        if !self.spawn(stream_id, request) {
+            self.close_stream(stream_id);
+            let _ = self.frames.send(rst_stream(stream_id, REFUSED_STREAM));
+        }
+    }
+
+    /// Runs the handler for `request` on the executor, counted in `handlers`
+    /// until it returns. Returns false if the executor has no room for it.
+    fn spawn(&self, stream_id: u32, request: Request) -> bool {
+        let handler = self.handler.clone();
+        let shared = self.shared.clone();
+        let frames = self.frames.clone();
+        shared.lock().handlers += 1;
+        let job_shared = shared.clone();
+        // The writer is made by the job, so a refused job sends nothing.
+        let started = self.executor.execute(Box::new(move || {
+            let writer = BodyWriter::new(stream_id, job_shared.clone(), frames);
+            // Caught so a panicking upgrade or body still frees its slot.
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+                respond(handler.as_ref(), &request, writer)
+            }));
+            job_shared.lock().handlers -= 1;
+        }));
+        if !started {
+            shared.lock().handlers -= 1;
+        }
+        started
+    }
+
+    fn apply_settings(&mut self, payload: &[u8]) -> Result<(), Error> {
+        if !payload.len().is_multiple_of(6) {
+            return Err(Error::Protocol(
+                FRAME_SIZE_ERROR,
+                "SETTINGS length must be a multiple of 6.".to_owned(),
+            ));
+        }
+        let mut state = self.shared.lock();
+        for setting in payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = read_u32(&setting[2..]);
+            match id {
+                settings_ids::ENABLE_PUSH if value > 1 => {
+                    return Err(Error::Protocol(
+                        PROTOCOL_ERROR,
+                        "Invalid ENABLE_PUSH.".to_owned(),
+                    ))
+                }
+                settings_ids::INITIAL_WINDOW_SIZE => {
+                    let value = value as i64;
+                    if value > MAX_WINDOW {
+                        return Err(Error::Protocol(
+                            FLOW_CONTROL_ERROR,
+                            "INITIAL_WINDOW_SIZE too large.".to_owned(),
+                        ));
+                    }
+                    let delta = value - state.initial_window;
+                    state.initial_window = value;
+                    for window in state.streams.values_mut() {
+                        *window += delta;
+                        if *window > MAX_WINDOW {
+                            return Err(Error::Protocol(
+                                FLOW_CONTROL_ERROR,
+                                "Stream window too large.".to_owned(),
+                            ));
+                        }
+                    }
+                }
+                settings_ids::MAX_FRAME_SIZE => {
+                    if !(MAX_FRAME_SIZE as u32..=0xFF_FFFF).contains(&value) {
+                        return Err(Error::Protocol(
+                            PROTOCOL_ERROR,
+                            "Invalid MAX_FRAME_SIZE.".to_owned(),
+                        ));
+                    }
+                    state.max_frame_size = value as usize;
+                }
+                // The encoder doesn't use the dynamic table, and the other
+                // settings only limit what servers may push.
+                _ => {}
+            }
+        }
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    fn window_update<S: Stream>(
+        &mut self,
+        stream: &mut S,
+        stream_id: u32,
+        increment: i64,
+    ) -> Result<(), Error> {
+        if increment == 0 {
+            if stream_id == 0 {
+                return Err(Error::Protocol(
+                    PROTOCOL_ERROR,
+                    "WINDOW_UPDATE of 0.".to_owned(),
+                ));
+            }
+            return self
+                .reset(stream, stream_id, PROTOCOL_ERROR)
+                .map_err(Error::Io);
+        }
+        let mut state = self.shared.lock();
+        if stream_id == 0 {
+            state.window += increment;
+            if state.window > MAX_WINDOW {
+                return Err(Error::Protocol(
+                    FLOW_CONTROL_ERROR,
+                    "Connection window too large.".to_owned(),
+                ));
+            }
+        } else if let Some(window) = state.streams.get_mut(&stream_id) {
+            *window += increment;
+            if *window > MAX_WINDOW {
+                drop(state);
+                return self
+                    .reset(stream, stream_id, FLOW_CONTROL_ERROR)
+                    .map_err(Error::Io);
+            }
+        }
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    /// Answers a stream with `status` before its request is complete.
+    fn reject<S: Stream>(&mut self, stream: &mut S, stream_id: u32, status: u16) -> io::Result<()> {
+        let block = hpack::encode(&[(":status".to_owned(), status.to_string())]);
+        stream.write_all(&frame(
+            frame_types::HEADERS,
+            flags::END_HEADERS | flags::END_STREAM,
+            stream_id,
+            &block,
+        ))?;
+        self.reset(stream, stream_id, NO_ERROR)
+    }
+
+    fn reset<S: Stream>(&mut self, stream: &mut S, stream_id: u32, code: u32) -> io::Result<()> {
+        self.close_stream(stream_id);
+        stream.write_all(&rst_stream(stream_id, code))
+    }
+
+    /// Forgets a stream, failing writes to its response.
+    fn close_stream(&mut self, stream_id: u32) {
+        self.incoming.remove(&stream_id);
+        self.shared.lock().streams.remove(&stream_id);
+        self.shared.changed.notify_all();
+    }
+
+    fn go_away<S: Stream>(&mut self, stream: &mut S, code: u32, message: &str) -> io::Result<()> {
+        let mut payload = self.last_stream_id.to_be_bytes().to_vec();
+        payload.extend(code.to_be_bytes());
+        payload.extend(message.as_bytes());
+        self.going_away.get_or_insert_with(Instant::now);
+        stream.write_all(&frame(frame_types::GOAWAY, 0, 0, &payload))?;
+        stream.flush()
+    }
+}
+
+/// Builds a `Request` from the decoded header block, see RFC 9113 section 8.3.
+fn request(fields: Vec<(String, String)>, body: Vec<u8>) -> Result<Request, String> {
+    let mut pseudo: HashMap<String, String> = HashMap::new();
+    let mut headers = Vec::new();
+    let mut cookies = Vec::new();
+    for (name, value) in fields {
+        if let Some(pseudo_name) = name.strip_prefix(':') {
+            if !headers.is_empty() || !cookies.is_empty() {
+                return Err(format!("Pseudo header {name} after regular headers."));
+            }
+            if !matches!(pseudo_name, "method" | "scheme" | "authority" | "path") {
+                return Err(format!("Unknown pseudo header {name}."));
+            }
+            if pseudo.insert(name.clone(), value).is_some() {
+                return Err(format!("Duplicate pseudo header {name}."));
+            }
+            continue;
+        }
+        if name.bytes().any(|byte| byte.is_ascii_uppercase()) {
+            return Err(format!("Uppercase header name {name}."));
+        }
+        if CONNECTION_HEADERS.contains(&name.as_str()) || (name == "te" && value != "trailers") {
+            return Err(format!("Connection specific header {name}."));
+        }
+        // Cookies may be split into several fields, section 8.2.3.
+        if name == "cookie" {
+            cookies.push(value);
+        } else {
+            headers.push((name, value));
+        }
+    }
+    if !cookies.is_empty() {
+        headers.push(("cookie".to_owned(), cookies.join("; ")));
+    }
+
+    let (method, target) = match (
+        pseudo.remove(":method"),
+        pseudo.remove(":scheme"),
+        pseudo.remove(":path"),
+    ) {
+        (Some(method), Some(_), Some(path)) if !path.is_empty() => (method, path),
+        _ => return Err("Missing :method, :scheme or :path.".to_owned()),
+    };
+    if let Some(authority) = pseudo.remove(":authority") {
+        if !headers.iter().any(|(name, _)| name == "host") {
+            headers.insert(0, ("host".to_owned(), authority));
+        }
+    }
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_owned(), Some(query.to_owned())),
+        None => (target, None),
+    };
+
+    Ok(Request {
+        method: Method::parse(&method),
+        path,
+        query,
+        version: "HTTP/2.0".to_owned(),
+        headers,
+        body,
+        params: BTreeMap::new(),
+        remote_addr: None,
+        secure: false,
+        server_name: None,
+        client_certificate: None,
+    })
+}
+
+/// Runs `handler` and sends its response on the writer's stream.
+fn respond(handler: &dyn Handler, request: &Request, mut writer: BodyWriter) {
+    let mut response = catch_panic(handler, request);
+    let upgrade = response.upgrade.take();
+    let body = std::mem::replace(&mut response.body, Body::Full(Vec::new()));
+    let head_only = request.method == Method::Head;
+    let end_stream = upgrade.is_none() && (head_only || body.is_empty());
+
+    let length = match upgrade {
+        Some(_) => None,
+        None => body.len(),
+    };
+    if let Err(e) = writer.send_headers(&response, length, end_stream) {
+        println!("Failed to send response. {e:?}");
+        return;
+    }
+    if end_stream {
+        return;
+    }
+    // The body of an upgraded response, e.g. an event stream, is written
+    // by the upgrade on the stream itself.
+    if let Some(upgrade) = upgrade {
+        upgrade(Box::new(writer));
+        return;
+    }
+    let sent = match body {
+        Body::Full(bytes) => writer.write_all(&bytes),
+        Body::Stream(mut stream) => io::copy(&mut stream, &mut writer).map(|_| ()),
+    };
+    match sent {
+        Ok(()) => writer.finish(),
+        Err(e) => {
+            println!("Failed to send response. {e:?}");
+            writer.reset(INTERNAL_ERROR);
+        }
+    }
+}
+
+/// The response of one stream, sent as frames within the flow control
+/// windows. Dropping it ends the stream.
+struct BodyWriter {
+    stream_id: u32,
+    shared: Arc<Shared>,
+    frames: Sender<Vec<u8>>,
+    write_timeout: Mutex<Option<Duration>>,
+    nonblocking: AtomicBool,
+    finished: bool,
+}
+
+impl BodyWriter {
+    fn new(stream_id: u32, shared: Arc<Shared>, frames: Sender<Vec<u8>>) -> BodyWriter {
+        BodyWriter {
+            stream_id,
+            shared,
+            frames,
+            write_timeout: Mutex::new(None),
+            nonblocking: AtomicBool::new(false),
+            finished: false,
+        }
+    }
+
+    fn send_headers(
+        &mut self,
+        response: &Response,
+        length: Option<usize>,
+        end_stream: bool,
+    ) -> io::Result<()> {
+        let mut fields = vec![(":status".to_owned(), response.status.to_string())];
+        for (name, value) in &response.headers {
+            let name = name.to_ascii_lowercase();
+            if !CONNECTION_HEADERS.contains(&name.as_str()) {
+                fields.push((name, value.clone()));
+            }
+        }
+        let has_length = response.header("Content-Length").is_some()
+            || matches!(response.status, 100..=199 | 204 | 304);
+        if let (Some(length), false) = (length, has_length) {
+            fields.push(("content-length".to_owned(), length.to_string()));
+        }
+
+        let block = hpack::encode(&fields);
+        let state = self.shared.lock();
+        if !state.streams.contains_key(&self.stream_id) {
+            return Err(ErrorKind::BrokenPipe.into());
+        }
+        let mut fragments = block.chunks(state.max_frame_size).peekable();
+        let mut frames = Vec::new();
+        let mut kind = frame_types::HEADERS;
+        // An empty block still needs one frame.
+        let first: &[u8] = fragments.next().unwrap_or_default();
+        let mut fragment = Some(first);
+        while let Some(current) = fragment {
+            let mut frame_flags = 0;
+            if fragments.peek().is_none() {
+                frame_flags |= flags::END_HEADERS;
+            }
+            if kind == frame_types::HEADERS && end_stream {
+                frame_flags |= flags::END_STREAM;
+            }
+            frames.extend(frame(kind, frame_flags, self.stream_id, current));
+            kind = frame_types::CONTINUATION;
+            fragment = fragments.next();
+        }
+        drop(state);
+        self.send(frames)?;
+        if end_stream {
+            self.finished = true;
+            self.forget();
+        }
+        Ok(())
+    }
+
+    /// Ends the stream with an empty DATA frame.
+    fn finish(&mut self) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        if self.is_open() {
+            let _ = self.send(frame(
+                frame_types::DATA,
+                flags::END_STREAM,
+                self.stream_id,
+                &[],
+            ));
+        }
+        self.forget();
+    }
+
+    /// Aborts the stream, e.g. when the body could not be read.
+    fn reset(&mut self, code: u32) {
+        self.finished = true;
+        if self.is_open() {
+            let _ = self.send(rst_stream(self.stream_id, code));
+        }
+        self.forget();
+    }
+
+    fn is_open(&self) -> bool {
+        let state = self.shared.lock();
+        !state.closed && state.streams.contains_key(&self.stream_id)
+    }
+
+    /// Queued before the stream is forgotten, so the connection doesn't close
+    /// while the last frame is still on its way.
+    fn send(&self, bytes: Vec<u8>) -> io::Result<()> {
+        self.frames
+            .send(bytes)
+            .map_err(|_| io::Error::from(ErrorKind::BrokenPipe))?;
+        self.shared.waker.wake();
+        Ok(())
+    }
+
+    fn forget(&self) {
+        self.shared.lock().streams.remove(&self.stream_id);
+        self.shared.changed.notify_all();
+    }
+}
+
+impl Write for BodyWriter {
+    /// Blocks until the peer's windows allow some of `buf`, and sends as
+    /// much of it as they allow in one DATA frame.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let timeout = *self.write_timeout.lock().unwrap_or_else(|e| e.into_inner());
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.shared.lock();
+        loop {
+            let window = match state.streams.get(&self.stream_id) {
+                Some(window) if !state.closed => *window,
+                _ => return Err(ErrorKind::BrokenPipe.into()),
+            };
+            let available = window.min(state.window).min(state.max_frame_size as i64);
+            if available > 0 {
+                let length = buf.len().min(available as usize);
+                state.window -= length as i64;
+                if let Some(window) = state.streams.get_mut(&self.stream_id) {
+                    *window -= length as i64;
+                }
+                self.send(frame(frame_types::DATA, 0, self.stream_id, &buf[..length]))?;
+                return Ok(length);
+            }
+            if self.nonblocking.load(Relaxed) {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            state = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return Err(ErrorKind::TimedOut.into());
+                    }
+                    self.shared
+                        .changed
+                        .wait_timeout(state, remaining)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+                None => self
+                    .shared
+                    .changed
+                    .wait(state)
+                    .unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The request body was already read, so upgrades only write.
+impl Read for BodyWriter {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl Stream for BodyWriter {
+    fn set_read_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.write_timeout.lock().unwrap_or_else(|e| e.into_inner()) = timeout;
+        Ok(())
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking.store(nonblocking, Relaxed);
+        Ok(())
+    }
+}
+
+impl Drop for BodyWriter {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// Takes the next complete frame off the front of `buffer`.
+fn next_frame(buffer: &mut Vec<u8>) -> Result<Option<Frame>, Error> {
+    if buffer.len() < 9 {
+        return Ok(None);
+    }
+    let length = u32::from_be_bytes([0, buffer[0], buffer[1], buffer[2]]) as usize;
+    if length > MAX_FRAME_SIZE {
+        return Err(Error::Protocol(
+            FRAME_SIZE_ERROR,
+            format!("Frame of {length} bytes."),
+        ));
+    }
+    if buffer.len() < 9 + length {
+        return Ok(None);
+    }
+    let frame = Frame {
+        kind: buffer[3],
+        flags: buffer[4],
+        stream_id: read_u32(&buffer[5..9]) & 0x7FFF_FFFF,
+        payload: buffer[9..9 + length].to_vec(),
+    };
+    buffer.drain(..9 + length);
+    Ok(Some(frame))
+}
+
+fn frame(kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(9 + payload.len());
+    frame.extend(&(payload.len() as u32).to_be_bytes()[1..]);
+    frame.push(kind);
+    frame.push(flags);
+    frame.extend(stream_id.to_be_bytes());
+    frame.extend(payload);
+    frame
+}
+
+fn rst_stream(stream_id: u32, code: u32) -> Vec<u8> {
+    frame(frame_types::RST_STREAM, 0, stream_id, &code.to_be_bytes())
+}
+
+fn window_update(stream_id: u32, increment: u32) -> Vec<u8> {
+    frame(
+        frame_types::WINDOW_UPDATE,
+        0,
+        stream_id,
+        &increment.to_be_bytes(),
+    )
+}
+
+/// The payload of a DATA or HEADERS frame without its padding.
+fn unpad(payload: &[u8], frame_flags: u8) -> Result<&[u8], Error> {
+    if frame_flags & flags::PADDED == 0 {
+        return Ok(payload);
+    }
+    let padding = *payload.first().unwrap_or(&0) as usize;
+    if payload.is_empty() || padding >= payload.len() {
+        return Err(Error::Protocol(
+            PROTOCOL_ERROR,
+            "Padding exceeds the frame.".to_owned(),
+        ));
+    }
+    Ok(&payload[1..payload.len() - padding])
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
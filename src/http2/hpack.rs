@@ -0,0 +1,563 @@
+use std::{collections::VecDeque, sync::OnceLock};
+
+/// Size of the dynamic table announced in our SETTINGS, the protocol default.
+pub(super) const TABLE_SIZE: usize = 4096;
+
+/// RFC 7541 Appendix A.
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// Why a header block could not be decoded.
+#[derive(Debug)]
+pub(super) enum DecodeError {
+    Invalid(String),
+    /// The headers add up to more than the decoder's `max_list_size`.
+    TooLarge,
+}
+
+impl From<String> for DecodeError {
+    fn from(message: String) -> DecodeError {
+        DecodeError::Invalid(message)
+    }
+}
+
+/// Decodes the header blocks of one connection, keeping its dynamic table.
+pub(super) struct Decoder {
+    table: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+    /// Limit for the decoded headers of one block, counted like
+    /// SETTINGS_MAX_HEADER_LIST_SIZE.
+    max_list_size: usize,
+}
+
+impl Decoder {
+    pub(super) fn new(max_list_size: usize) -> Decoder {
+        Decoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: TABLE_SIZE,
+            max_list_size,
+        }
+    }
+
+    /// Decodes a complete header block, HEADERS and any CONTINUATION
+    /// fragments joined together. Stops as soon as the headers grow past
+    /// `max_list_size`, as small indexed references can expand to large
+    /// table entries.
+    pub(super) fn decode(&mut self, block: &[u8]) -> Result<Vec<(String, String)>, DecodeError> {
+        let mut headers = Vec::new();
+        let mut list_size = 0;
+        let mut position = 0;
+        while position < block.len() {
+            let first = block[position];
+            let header = if first & 0x80 != 0 {
+                let index = integer(block, &mut position, 7)?;
+                self.entry(index)?
+            } else if first & 0xC0 == 0x40 {
+                let header = self.literal(block, &mut position, 6)?;
+                self.insert(header.clone());
+                header
+            } else if first & 0xE0 == 0x20 {
+                if !headers.is_empty() {
+                    return Err("Table size update after a header.".to_owned().into());
+                }
+                let size = integer(block, &mut position, 5)?;
+                if size > TABLE_SIZE {
+                    return Err(format!("Table size {size} above {TABLE_SIZE}.").into());
+                }
+                self.max_size = size;
+                self.evict(0);
+                continue;
+            } else {
+                // Without indexing, or never indexed.
+                self.literal(block, &mut position, 4)?
+            };
+            list_size += entry_size(&header);
+            if list_size > self.max_list_size {
+                return Err(DecodeError::TooLarge);
+            }
+            headers.push(header);
+        }
+        Ok(headers)
+    }
+
+    fn literal(
+        &self,
+        block: &[u8],
+        position: &mut usize,
+        prefix: u8,
+    ) -> Result<(String, String), String> {
+        let name = match integer(block, position, prefix)? {
+            0 => string(block, position)?,
+            index => self.entry(index)?.0,
+        };
+        Ok((name, string(block, position)?))
+    }
+
+    fn entry(&self, index: usize) -> Result<(String, String), String> {
+        match index {
+            0 => Err("Header index 0.".to_owned()),
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.to_owned(), value.to_owned()))
+            }
+            _ => self
+                .table
+                .get(index - 62)
+                .cloned()
+                .ok_or_else(|| format!("Header index {index} out of range.")),
+        }
+    }
+
+    fn insert(&mut self, header: (String, String)) {
+        let size = entry_size(&header);
+        self.evict(size);
+        // An entry larger than the table empties it and is not added.
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front(header);
+        }
+    }
+
+    /// Drops the oldest entries until `room` more bytes fit.
+    fn evict(&mut self, room: usize) {
+        while self.size + room > self.max_size {
+            match self.table.pop_back() {
+                Some(header) => self.size -= entry_size(&header),
+                None => break,
+            }
+        }
+    }
+}
+
+/// Encodes `headers` without touching the peer's dynamic table, so blocks
+/// can be encoded on any thread in any order. Names should be lowercase.
+pub(super) fn encode(headers: &[(String, String)]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in headers {
+        let exact = STATIC_TABLE
+            .iter()
+            .position(|(n, v)| n == name && v == value);
+        if let Some(index) = exact {
+            push_integer(&mut block, index + 1, 7, 0x80);
+            continue;
+        }
+        match STATIC_TABLE.iter().position(|(n, _)| n == name) {
+            Some(index) => push_integer(&mut block, index + 1, 4, 0x00),
+            None => {
+                block.push(0x00);
+                push_string(&mut block, name);
+            }
+        }
+        push_string(&mut block, value);
+    }
+    block
+}
+
+fn entry_size((name, value): &(String, String)) -> usize {
+    name.len() + value.len() + 32
+}
+
+/// Reads an integer with an N-bit prefix, RFC 7541 section 5.1.
+fn integer(block: &[u8], position: &mut usize, prefix: u8) -> Result<usize, String> {
+    let truncated = || "Truncated header block.".to_owned();
+    let max = (1usize << prefix) - 1;
+    let mut value = (*block.get(*position).ok_or_else(truncated)? as usize) & max;
+    *position += 1;
+    if value < max {
+        return Ok(value);
+    }
+    let mut shift = 0;
+    loop {
+        let byte = *block.get(*position).ok_or_else(truncated)?;
+        *position += 1;
+        if shift > 28 {
+            return Err("Header integer too large.".to_owned());
+        }
+        value += ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+fn push_integer(block: &mut Vec<u8>, value: usize, prefix: u8, flags: u8) {
+    let max = (1usize << prefix) - 1;
+    if value < max {
+        block.push(flags | value as u8);
+        return;
+    }
+    block.push(flags | max as u8);
+    let mut rest = value - max;
+    while rest >= 0x80 {
+        block.push((rest & 0x7F) as u8 | 0x80);
+        rest >>= 7;
+    }
+    block.push(rest as u8);
+}
+
+/// Reads a string literal, Huffman coded or not.
+fn string(block: &[u8], position: &mut usize) -> Result<String, String> {
+    let huffman = block.get(*position).is_some_and(|byte| byte & 0x80 != 0);
+    let length = integer(block, position, 7)?;
+    let bytes = block
+        .get(*position..*position + length)
+        .ok_or_else(|| "Truncated header block.".to_owned())?;
+    *position += length;
+    let bytes = if huffman {
+        huffman_decode(bytes)?
+    } else {
+        bytes.to_vec()
+    };
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn push_string(block: &mut Vec<u8>, string: &str) {
+    push_integer(block, string.len(), 7, 0x00);
+    block.extend_from_slice(string.as_bytes());
+}
+
+/// Children of a node in the Huffman tree, either another node or a symbol.
+#[derive(Clone, Copy)]
+enum Branch {
+    None,
+    Node(usize),
+    Symbol(u16),
+}
+
+fn huffman_tree() -> &'static Vec<[Branch; 2]> {
+    static TREE: OnceLock<Vec<[Branch; 2]>> = OnceLock::new();
+    TREE.get_or_init(|| {
+        let mut tree = vec![[Branch::None; 2]];
+        for symbol in 0..HUFFMAN_CODES.len() {
+            let (code, length) = (HUFFMAN_CODES[symbol], HUFFMAN_LENGTHS[symbol]);
+            let mut node = 0;
+            for bit in (0..length).rev() {
+                let side = ((code >> bit) & 1) as usize;
+                if bit == 0 {
+                    tree[node][side] = Branch::Symbol(symbol as u16);
+                    break;
+                }
+                node = match tree[node][side] {
+                    Branch::Node(next) => next,
+                    _ => {
+                        tree.push([Branch::None; 2]);
+                        tree[node][side] = Branch::Node(tree.len() - 1);
+                        tree.len() - 1
+                    }
+                };
+            }
+        }
+        tree
+    })
+}
+
+/// RFC 7541 section 5.2: the padding must be a prefix of the EOS code, at
+/// most 7 bits of ones.
+fn huffman_decode(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let tree = huffman_tree();
+    let mut decoded = Vec::new();
+    let mut node = 0;
+    let mut pending = 0;
+    let mut all_ones = true;
+    for byte in bytes {
+        for bit in (0..8).rev() {
+            let side = ((byte >> bit) & 1) as usize;
+            pending += 1;
+            all_ones &= side == 1;
+            match tree[node][side] {
+                Branch::Node(next) => node = next,
+                Branch::Symbol(256) | Branch::None => {
+                    return Err("Invalid Huffman code.".to_owned())
+                }
+                Branch::Symbol(symbol) => {
+                    decoded.push(symbol as u8);
+                    node = 0;
+                    pending = 0;
+                    all_ones = true;
+                }
+            }
+        }
+    }
+    if pending > 7 || !all_ones {
+        return Err("Invalid Huffman padding.".to_owned());
+    }
+    Ok(decoded)
+}
+
+/// RFC 7541 Appendix B, indexed by symbol with EOS last.
+const HUFFMAN_CODES: [u32; 257] = [
+    0x1ff8, 0x7fffd8, 0xfffffe2, 0xfffffe3, 0xfffffe4, 0xfffffe5, 0xfffffe6, 0xfffffe7, 0xfffffe8,
+    0xffffea, 0x3ffffffc, 0xfffffe9, 0xfffffea, 0x3ffffffd, 0xfffffeb, 0xfffffec, 0xfffffed,
+    0xfffffee, 0xfffffef, 0xffffff0, 0xffffff1, 0xffffff2, 0x3ffffffe, 0xffffff3, 0xffffff4,
+    0xffffff5, 0xffffff6, 0xffffff7, 0xffffff8, 0xffffff9, 0xffffffa, 0xffffffb, 0x14, 0x3f8,
+    0x3f9, 0xffa, 0x1ff9, 0x15, 0xf8, 0x7fa, 0x3fa, 0x3fb, 0xf9, 0x7fb, 0xfa, 0x16, 0x17, 0x18,
+    0x0, 0x1, 0x2, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e, 0x1f, 0x5c, 0xfb, 0x7ffc, 0x20, 0xffb,
+    0x3fc, 0x1ffa, 0x21, 0x5d, 0x5e, 0x5f, 0x60, 0x61, 0x62, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6a, 0x6b, 0x6c, 0x6d, 0x6e, 0x6f, 0x70, 0x71, 0x72, 0xfc, 0x73, 0xfd, 0x1ffb, 0x7fff0,
+    0x1ffc, 0x3ffc, 0x22, 0x7ffd, 0x3, 0x23, 0x4, 0x24, 0x5, 0x25, 0x26, 0x27, 0x6, 0x74, 0x75,
+    0x28, 0x29, 0x2a, 0x7, 0x2b, 0x76, 0x2c, 0x8, 0x9, 0x2d, 0x77, 0x78, 0x79, 0x7a, 0x7b, 0x7ffe,
+    0x7fc, 0x3ffd, 0x1ffd, 0xffffffc, 0xfffe6, 0x3fffd2, 0xfffe7, 0xfffe8, 0x3fffd3, 0x3fffd4,
+    0x3fffd5, 0x7fffd9, 0x3fffd6, 0x7fffda, 0x7fffdb, 0x7fffdc, 0x7fffdd, 0x7fffde, 0xffffeb,
+    0x7fffdf, 0xffffec, 0xffffed, 0x3fffd7, 0x7fffe0, 0xffffee, 0x7fffe1, 0x7fffe2, 0x7fffe3,
+    0x7fffe4, 0x1fffdc, 0x3fffd8, 0x7fffe5, 0x3fffd9, 0x7fffe6, 0x7fffe7, 0xffffef, 0x3fffda,
+    0x1fffdd, 0xfffe9, 0x3fffdb, 0x3fffdc, 0x7fffe8, 0x7fffe9, 0x1fffde, 0x7fffea, 0x3fffdd,
+    0x3fffde, 0xfffff0, 0x1fffdf, 0x3fffdf, 0x7fffeb, 0x7fffec, 0x1fffe0, 0x1fffe1, 0x3fffe0,
+    0x1fffe2, 0x7fffed, 0x3fffe1, 0x7fffee, 0x7fffef, 0xfffea, 0x3fffe2, 0x3fffe3, 0x3fffe4,
+    0x7ffff0, 0x3fffe5, 0x3fffe6, 0x7ffff1, 0x3ffffe0, 0x3ffffe1, 0xfffeb, 0x7fff1, 0x3fffe7,
+    0x7ffff2, 0x3fffe8, 0x1ffffec, 0x3ffffe2, 0x3ffffe3, 0x3ffffe4, 0x7ffffde, 0x7ffffdf,
+    0x3ffffe5, 0xfffff1, 0x1ffffed, 0x7fff2, 0x1fffe3, 0x3ffffe6, 0x7ffffe0, 0x7ffffe1, 0x3ffffe7,
+    0x7ffffe2, 0xfffff2, 0x1fffe4, 0x1fffe5, 0x3ffffe8, 0x3ffffe9, 0xffffffd, 0x7ffffe3, 0x7ffffe4,
+    0x7ffffe5, 0xfffec, 0xfffff3, 0xfffed, 0x1fffe6, 0x3fffe9, 0x1fffe7, 0x1fffe8, 0x7ffff3,
+    0x3fffea, 0x3fffeb, 0x1ffffee, 0x1ffffef, 0xfffff4, 0xfffff5, 0x3ffffea, 0x7ffff4, 0x3ffffeb,
+    0x7ffffe6, 0x3ffffec, 0x3ffffed, 0x7ffffe7, 0x7ffffe8, 0x7ffffe9, 0x7ffffea, 0x7ffffeb,
+    0xffffffe, 0x7ffffec, 0x7ffffed, 0x7ffffee, 0x7ffffef, 0x7fffff0, 0x3ffffee, 0x3fffffff,
+];
+
+const HUFFMAN_LENGTHS: [u8; 257] = [
+    13, 23, 28, 28, 28, 28, 28, 28, 28, 24, 30, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 30, 28,
+    28, 28, 28, 28, 28, 28, 28, 28, 6, 10, 10, 12, 13, 6, 8, 11, 10, 10, 8, 11, 8, 6, 6, 6, 5, 5,
+    5, 6, 6, 6, 6, 6, 6, 6, 7, 8, 15, 6, 12, 10, 13, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7,
+    7, 7, 7, 7, 7, 7, 7, 7, 8, 7, 8, 13, 19, 13, 14, 6, 15, 5, 6, 5, 6, 5, 6, 6, 6, 5, 7, 7, 6, 6,
+    6, 5, 6, 7, 6, 5, 5, 6, 7, 7, 7, 7, 7, 15, 11, 14, 13, 28, 20, 22, 20, 20, 22, 22, 22, 23, 22,
+    23, 23, 23, 23, 23, 24, 23, 24, 24, 22, 23, 24, 23, 23, 23, 23, 21, 22, 23, 22, 23, 23, 24, 22,
+    21, 20, 22, 22, 23, 23, 21, 23, 22, 22, 24, 21, 22, 23, 23, 21, 21, 22, 21, 23, 22, 23, 23, 20,
+    22, 22, 22, 23, 22, 22, 23, 26, 26, 20, 19, 22, 23, 22, 25, 26, 26, 26, 27, 27, 26, 24, 25, 19,
+    21, 26, 27, 27, 26, 27, 24, 21, 21, 26, 26, 28, 27, 27, 27, 20, 24, 20, 21, 22, 21, 21, 23, 22,
+    22, 25, 25, 24, 24, 26, 23, 26, 27, 26, 26, 27, 27, 27, 27, 27, 28, 27, 27, 27, 27, 27, 26, 30,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        let digits: Vec<u8> = text.bytes().filter(u8::is_ascii_hexdigit).collect();
+        digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    fn fields(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// The requests of RFC 7541 appendix C.3 and, Huffman coded, C.4.
+    fn decodes_requests(blocks: [&str; 3]) {
+        let mut decoder = Decoder::new(usize::MAX);
+        let first = fields(&[
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", "/"),
+            (":authority", "www.example.com"),
+        ]);
+        assert_eq!(decoder.decode(&hex(blocks[0])).unwrap(), first);
+        assert_eq!(decoder.size, 57);
+
+        let mut second = first.clone();
+        second.push(("cache-control".to_owned(), "no-cache".to_owned()));
+        assert_eq!(decoder.decode(&hex(blocks[1])).unwrap(), second);
+        assert_eq!(decoder.size, 110);
+
+        let third = fields(&[
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":path", "/index.html"),
+            (":authority", "www.example.com"),
+            ("custom-key", "custom-value"),
+        ]);
+        assert_eq!(decoder.decode(&hex(blocks[2])).unwrap(), third);
+        assert_eq!(decoder.size, 164);
+        assert_eq!(decoder.entry(62).unwrap(), third[4]);
+        assert_eq!(decoder.entry(64).unwrap(), first[3]);
+    }
+
+    #[test]
+    fn requests_without_huffman() {
+        decodes_requests([
+            "8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+            "8286 84be 5808 6e6f 2d63 6163 6865",
+            "8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+        ]);
+    }
+
+    #[test]
+    fn requests_with_huffman() {
+        decodes_requests([
+            "8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff",
+            "8286 84be 5886 a8eb 1064 9cbf",
+            "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+        ]);
+    }
+
+    /// The responses of RFC 7541 appendix C.6, which evict entries from a
+    /// 256 byte table.
+    #[test]
+    fn responses_evict_old_entries() {
+        let mut decoder = Decoder::new(usize::MAX);
+        // A table size update to 256.
+        let mut first = hex("3fe1 01");
+        first.extend(hex(
+            "4882 6402 5885 aec3 771a 4b61 96d0 7abe 9410 54d4 44a8 2005 9504 0b81 66e0 82a6
+             2d1b ff6e 919d 29ad 1718 63c7 8f0b 97c8 e9ae 82ae 43d3",
+        ));
+        let mut expected = fields(&[
+            (":status", "302"),
+            ("cache-control", "private"),
+            ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+            ("location", "https://www.example.com"),
+        ]);
+        assert_eq!(decoder.decode(&first).unwrap(), expected);
+        assert_eq!(decoder.size, 222);
+
+        expected[0].1 = "307".to_owned();
+        assert_eq!(
+            decoder.decode(&hex("4883 640e ffc1 c0bf")).unwrap(),
+            expected
+        );
+        assert_eq!(decoder.size, 222);
+
+        let third = decoder
+            .decode(&hex(
+                "88c1 6196 d07a be94 1054 d444 a820 0595 040b 8166 e084 a62d 1bff c05a 839b d9ab
+                 77ad 94e7 821d d7f2 e6c7 b335 dfdf cd5b 3960 d5af 2708 7f36 72c1 ab27 0fb5 291f
+                 9587 3160 65c0 03ed 4ee5 b106 3d50 07",
+            ))
+            .unwrap();
+        assert_eq!(
+            third,
+            fields(&[
+                (":status", "200"),
+                ("cache-control", "private"),
+                ("date", "Mon, 21 Oct 2013 20:13:22 GMT"),
+                ("location", "https://www.example.com"),
+                ("content-encoding", "gzip"),
+                (
+                    "set-cookie",
+                    "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1"
+                ),
+            ])
+        );
+        assert_eq!(decoder.table.len(), 3);
+        assert_eq!(decoder.size, 215);
+    }
+
+    #[test]
+    fn encoded_blocks_decode() {
+        let headers = fields(&[
+            (":status", "200"),
+            (":status", "418"),
+            ("content-type", "text/plain"),
+            ("x-request-id", "abc"),
+        ]);
+        let mut decoder = Decoder::new(usize::MAX);
+        assert_eq!(decoder.decode(&encode(&headers)).unwrap(), headers);
+        // Nothing is added to the peer's table.
+        assert_eq!(decoder.size, 0);
+    }
+
+    #[test]
+    fn invalid_blocks_are_rejected() {
+        let mut decoder = Decoder::new(usize::MAX);
+        // Index 0, an index past the tables, a truncated literal and an
+        // integer that overflows.
+        for block in [
+            "80",
+            "be",
+            "4005 6375 73",
+            "ff ff ff ff ff ff ff ff ff ff ff 7f",
+        ] {
+            assert!(decoder.decode(&hex(block)).is_err(), "{block}");
+        }
+        // A table size update after a header, or above our setting.
+        assert!(decoder.decode(&hex("82 20")).is_err());
+        assert!(decoder.decode(&hex("3fe2 1f")).is_err());
+        // Huffman padding that isn't all ones.
+        assert!(decoder.decode(&hex("0081 00 8100")).is_err());
+    }
+
+    #[test]
+    fn decoding_stops_at_the_list_size() {
+        let mut decoder = Decoder::new(64 * 1024);
+        // Adds a 4000 byte header to the dynamic table, then references it
+        // once per byte of a 16KB frame.
+        let mut block = vec![0x40, 0x01, b'x'];
+        push_integer(&mut block, 4000, 7, 0x00);
+        block.extend(vec![b'v'; 4000]);
+        let literal = block.len();
+        block.extend(vec![0xBE; 16 * 1024]);
+        assert!(matches!(decoder.decode(&block), Err(DecodeError::TooLarge)));
+
+        // Up to the limit is fine.
+        let mut decoder = Decoder::new(3 * (4000 + 33));
+        block.truncate(literal + 2);
+        assert_eq!(decoder.decode(&block).unwrap().len(), 3);
+    }
+}
@@ -1,5 +1,6 @@
 use ctrlc::set_handler;
 use std::{
+    collections::BTreeMap,
     io::{BufRead, BufReader, ErrorKind, Write},
     os::unix::net::{UnixListener, UnixStream},
     process::exit,
@@ -8,17 +9,28 @@ use std::{
         Arc,
     },
     thread::sleep,
-    time::Duration,
 };
 
+use crate::waker::{Waker, ACCEPT_BACKOFF};
+
 pub const SOCKET_PATH: &str = "/tmp/my_server.sock";
 
 pub mod ipc_commands {
     pub const STOP: &str = "stop";
+    pub const UPSTREAMS: &str = "upstreams";
+    pub const PUBLISH: &str = "publish";
+    pub const RELOAD_CERTS: &str = "reload-certs";
+    pub const QUEUES: &str = "queues";
+    pub const THREADS: &str = "threads";
+    pub const RESIZE: &str = "resize";
 }
 
+/// Runs a command with the arguments following its name and returns the reply.
+type Command = Box<dyn Fn(&str) -> String + Send>;
+
 pub struct IpcListener {
     listener: UnixListener,
+    commands: BTreeMap<String, Command>,
 }
 
 impl IpcListener {
@@ -30,50 +42,80 @@ impl IpcListener {
                 exit(1);
             }
         };
-        IpcListener { listener }
+        IpcListener {
+            listener,
+            commands: BTreeMap::new(),
+        }
+    }
+
+    /// Registers `command` to run when a client sends `name`, optionally
+    /// followed by arguments.
+    pub fn add_command(&mut self, name: &str, command: impl Fn(&str) -> String + Send + 'static) {
+        self.commands.insert(name.to_owned(), Box::new(command));
     }
 
     pub fn listen_block(&self) {
         let stop = Arc::new(AtomicBool::new(false));
+        let waker = Arc::new(Waker::new().expect("Error creating IpcListener waker."));
         let stop_clone = stop.clone();
+        let waker_clone = waker.clone();
 
-        set_handler(move || stop_clone.store(true, Relaxed))
-            .expect("Error setting Ctrl-C handler.");
+        set_handler(move || {
+            stop_clone.store(true, Relaxed);
+            waker_clone.wake();
+        })
+        .expect("Error setting Ctrl-C handler.");
 
         self.listener
             .set_nonblocking(true)
             .expect("Failed to set nonblocking unix listener.");
-        for stream in self.listener.incoming() {
-            match stream {
-                Ok(mut stream) => loop {
-                    let message = IpcListener::read_stream(&stream);
-                    println!("IPC listener recieved: {message}");
-                    match message.trim() {
-                        ipc_commands::STOP => {
-                            stream
-                                .write_all(b"Stopping blocking IpcListener.")
-                                .expect("Error writing to stream.");
-                            return;
-                        }
-                        _ => {
-                            stream
-                                .write_all(b"Unknown command.")
-                                .expect("Error writing to stream.");
-                        }
-                    }
-                },
-                Err(e) => match e.kind() {
-                    ErrorKind::WouldBlock => {}
-                    _ => {
-                        println!("Error: {}", e);
-                    }
-                },
+        loop {
+            if let Err(e) = waker.wait(&self.listener) {
+                println!("Error: {}", e);
+                break;
             }
-            sleep(Duration::from_millis(80));
             if stop.load(Relaxed) {
                 println!("IPC listener recieved termination signal. Stopping.");
                 break;
             }
+            loop {
+                match self.listener.accept() {
+                    Ok((mut stream, _)) => loop {
+                        let message = IpcListener::read_stream(&stream);
+                        if message.is_empty() {
+                            // The client disconnected.
+                            break;
+                        }
+                        println!("IPC listener recieved: {message}");
+                        let (name, args) = match message.trim().split_once(' ') {
+                            Some((name, args)) => (name, args.trim()),
+                            None => (message.trim(), ""),
+                        };
+                        let reply = match name {
+                            ipc_commands::STOP => {
+                                stream
+                                    .write_all(b"Stopping blocking IpcListener.")
+                                    .expect("Error writing to stream.");
+                                return;
+                            }
+                            name => match self.commands.get(name) {
+                                Some(command) => command(args),
+                                None => "Unknown command.".to_owned(),
+                            },
+                        };
+                        if let Err(e) = stream.write_all(reply.as_bytes()) {
+                            println!("Error writing to IPC stream: {e}");
+                            break;
+                        }
+                    },
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        println!("Error: {}", e);
+                        sleep(ACCEPT_BACKOFF);
+                        break;
+                    }
+                }
+            }
         }
     }
 
@@ -88,6 +130,12 @@ impl IpcListener {
     }
 }
 
+impl Default for IpcListener {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Drop for IpcListener {
     fn drop(&mut self) {
         let _ = std::fs::remove_file(SOCKET_PATH);
@@ -1,7 +1,25 @@
+#[cfg(feature = "acme")]
+pub mod acme;
+#[cfg(feature = "async")]
+pub mod async_server;
+pub mod balancer;
+pub mod cgi;
+mod connection;
+pub mod event_loop;
+pub mod fastcgi;
+pub mod handler;
+pub mod http;
+mod http2;
 pub mod ipc_listener;
+pub mod middleware;
+pub mod proxy;
 pub mod request_handler;
+pub mod router;
 pub mod settings;
+pub mod sni;
+pub mod sse;
 pub mod tcp_server;
 pub mod thread_pool;
 pub mod tls_server;
-
+mod waker;
+pub mod websocket;
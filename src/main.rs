@@ -1,8 +1,21 @@
 use std::sync::Arc;
 
+#[cfg(feature = "acme")]
+use my_server::acme::{AcmeClient, Challenges};
 use my_server::{
-    ipc_listener::IpcListener, request_handler::RequestHandler, settings::Settings,
-    tcp_server::TcpServer, tls_server::TlsServer,
+    cgi::CgiMapping,
+    fastcgi::FastCgi,
+    handler::SharedHandler,
+    ipc_listener::{ipc_commands, IpcListener},
+    middleware::Chain,
+    proxy::{self, ReverseProxy},
+    request_handler::RequestHandler,
+    router::Router,
+    settings::Settings,
+    sse::{self, EventStream},
+    tcp_server::TcpServer,
+    thread_pool,
+    tls_server::TlsServer,
 };
 
 fn main() {
@@ -16,45 +29,150 @@ fn main() {
     let settings = Arc::new(settings);
 
     let request_handler = match RequestHandler::new(settings.server.document_root.clone()) {
-        Ok(request_handler) => request_handler,
+        Ok(request_handler) => {
+            request_handler.with_cgi(settings.cgi.iter().map(CgiMapping::from_settings).collect())
+        }
         Err(err) => {
             println!("Error creating Request Handler: {err:?}");
             return;
         }
     };
-    let request_handler = Arc::new(request_handler);
-
-    let _tcp_server = match TcpServer::new(
-        settings.server.ip.clone(),
-        &settings.http,
-        request_handler.clone(),
-    ) {
-        Ok(mut tcp_server) => {
-            tcp_server.start_thread();
-            Some(tcp_server)
+    let mut fastcgi = Vec::new();
+    for route in &settings.fastcgi {
+        match FastCgi::from_settings(route, request_handler.document_root()) {
+            Ok(route) => fastcgi.push(route),
+            Err(err) => {
+                println!("Error creating FastCgi route: {err}");
+                return;
+            }
         }
-        Err(err) => {
-            println!("Error creating TcpServer: {:?}", err);
-            None
+    }
+    let request_handler = Arc::new(request_handler.with_fastcgi(fastcgi));
+
+    let mut proxies = Vec::new();
+    for proxy in &settings.proxy {
+        match ReverseProxy::from_settings(proxy) {
+            Ok(proxy) => proxies.push(proxy),
+            Err(err) => {
+                println!("Error creating ReverseProxy: {err}");
+                return;
+            }
         }
+    }
+    let event_streams: Vec<EventStream> = settings
+        .sse
+        .iter()
+        .map(EventStream::from_settings)
+        .collect();
+    let mut router = Router::new();
+    for proxy in &proxies {
+        router = router.any(&format!("{}/*path", proxy.prefix()), proxy.clone());
+    }
+    for stream in &event_streams {
+        router = router.get(stream.path(), stream.clone());
+    }
+    let site: SharedHandler = Arc::new(router.fallback(request_handler.clone()));
+
+    let http_handler: SharedHandler = Arc::new(Chain::from_settings(
+        RequestHandler::for_listener(
+            request_handler.clone(),
+            site.clone(),
+            settings.http.redirect.clone(),
+        ),
+        &settings,
+    ));
+    // The CA fetches the challenges over http, even if it redirects to https.
+    #[cfg(feature = "acme")]
+    let challenges = Arc::new(Challenges::default());
+    #[cfg(feature = "acme")]
+    let http_handler = match settings.acme {
+        Some(_) => challenges.handler(http_handler),
+        None => http_handler,
     };
+    #[cfg(not(feature = "acme"))]
+    if settings.acme.is_some() {
+        println!("Warning: [acme] is set, but my_server was built without the acme feature.");
+    }
+    let _tcp_server =
+        match TcpServer::with_handler(settings.server.ip.clone(), &settings.http, http_handler) {
+            Ok(mut tcp_server) => {
+                tcp_server.start_thread();
+                Some(tcp_server)
+            }
+            Err(err) => {
+                println!("Error creating TcpServer: {:?}", err);
+                None
+            }
+        };
 
-    let _tls_server = match TlsServer::new(
-        settings.server.ip.clone(),
-        &settings.https,
-        request_handler.clone(),
-    ) {
-        Ok(mut tls_server) => {
-            tls_server.start_thread();
-            Some(tls_server)
-        }
-        Err(err) => {
-            println!("Error creating TlsServer: {:?}", err);
-            None
+    // https starts with the certificate on disk while ACME renews it.
+    #[cfg(feature = "acme")]
+    if let Some(acme) = &settings.acme {
+        if let Err(err) = AcmeClient::write_placeholder(acme) {
+            println!("Error writing placeholder certificate: {err}");
         }
-    };
+    }
+
+    let https_handler = Chain::from_settings(
+        RequestHandler::for_listener(
+            request_handler.clone(),
+            site.clone(),
+            settings.https.redirect.clone(),
+        ),
+        &settings,
+    );
+    let _tls_server =
+        match TlsServer::with_chain(settings.server.ip.clone(), &settings.https, https_handler) {
+            Ok(mut tls_server) => {
+                tls_server.start_thread();
+                Some(tls_server)
+            }
+            Err(err) => {
+                println!("Error creating TlsServer: {:?}", err);
+                None
+            }
+        };
+
+    let reloader = _tls_server.as_ref().map(TlsServer::reloader);
+    #[cfg(feature = "acme")]
+    if let (Some(acme), Some(reloader)) = (&settings.acme, reloader.clone()) {
+        AcmeClient::start_renewal(acme.clone(), challenges, move || {
+            if let Err(err) = reloader.reload() {
+                println!("Error reloading renewed certificate: {err}");
+            }
+        });
+    }
+
+    let mut pools = Vec::new();
+    if let Some(tcp_server) = &_tcp_server {
+        pools.push(("http", tcp_server.thread_pool()));
+    }
+    if let Some(tls_server) = &_tls_server {
+        pools.push(("https", tls_server.thread_pool()));
+    }
 
-    IpcListener::new().listen_block();
+    let mut ipc_listener = IpcListener::new();
+    ipc_listener.add_command(ipc_commands::UPSTREAMS, move |_| proxy::status(&proxies));
+    ipc_listener.add_command(ipc_commands::PUBLISH, move |args| {
+        sse::publish(&event_streams, args)
+    });
+    ipc_listener.add_command(ipc_commands::RELOAD_CERTS, move |_| match &reloader {
+        Some(reloader) => match reloader.reload() {
+            Ok(()) => "Reloaded TLS certificates.".to_owned(),
+            Err(e) => format!("Could not reload TLS certificates, keeping the previous ones: {e}"),
+        },
+        None => "TlsServer is not running.".to_owned(),
+    });
+    let queues = pools.clone();
+    ipc_listener.add_command(ipc_commands::QUEUES, move |_| thread_pool::status(&queues));
+    let threads = pools.clone();
+    ipc_listener.add_command(ipc_commands::THREADS, move |_| {
+        thread_pool::workers(&threads)
+    });
+    ipc_listener.add_command(ipc_commands::RESIZE, move |args| {
+        thread_pool::resize(&pools, args)
+    });
+    ipc_listener.listen_block();
 
     println!("Stopping Server...");
 }
@@ -0,0 +1,470 @@
+use std::{collections::BTreeMap, io::Write, sync::Arc, time::Instant};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use flate2::{write::GzEncoder, Compression as GzLevel};
+
+use crate::{
+    handler::{Handler, SharedHandler},
+    http::{Body, Request, Response},
+    settings,
+};
+
+/// Hooks run around a `Handler`.
+///
+/// `before` runs in the order the middleware was added to a `Chain`; returning
+/// a response skips the remaining middleware and the handler. `after` runs in
+/// reverse order for every middleware whose `before` ran without
+/// short-circuiting, so the first middleware added sees the final response.
+/// `handle` wraps both and can be overridden instead.
+pub trait Middleware: Send + Sync {
+    fn before(&self, _request: &Request) -> Option<Response> {
+        None
+    }
+
+    fn after(&self, _request: &Request, _response: &mut Response) {}
+
+    /// Runs this middleware around `next`, the rest of the chain. Override it
+    /// to keep state across the call, e.g. a timer.
+    fn handle(&self, request: &Request, next: &dyn Fn(&Request) -> Response) -> Response {
+        if let Some(response) = self.before(request) {
+            return response;
+        }
+        let mut response = next(request);
+        self.after(request, &mut response);
+        response
+    }
+}
+
+/// A handler wrapped in a list of middleware.
+pub struct Chain {
+    middleware: Vec<Arc<dyn Middleware>>,
+    handler: SharedHandler,
+}
+
+impl Chain {
+    pub fn new(handler: SharedHandler) -> Chain {
+        Chain {
+            middleware: Vec::new(),
+            handler,
+        }
+    }
+
+    /// Builds the default chain from Settings.toml: logging, HSTS, security
+    /// headers, authentication, extra headers and compression, each only if
+    /// enabled.
+    pub fn from_settings(handler: SharedHandler, settings: &settings::Settings) -> Chain {
+        let mut chain = Chain::new(handler);
+        if settings.middleware.logging {
+            chain = chain.with(Logger);
+        }
+        // Added before authentication so that 401 responses carry them too.
+        if let Some(hsts) = &settings.https.hsts {
+            chain = chain.with(Hsts::from_settings(hsts));
+        }
+        if let Some(security_headers) = &settings.security_headers {
+            chain = chain.with(SecurityHeaders::from_settings(security_headers));
+        }
+        let settings = &settings.middleware;
+        if let Some(auth) = &settings.auth {
+            chain = chain.with(BasicAuth::new(&auth.username, &auth.password, &auth.realm));
+        }
+        if !settings.headers.is_empty() {
+            chain = chain.with(Headers::new(settings.headers.clone()));
+        }
+        if settings.compression {
+            chain = chain.with(Compression::default());
+        }
+        chain
+    }
+
+    pub fn with(mut self, middleware: impl Middleware + 'static) -> Chain {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Puts `wrap` around the handler, inside the middleware, so responses
+    /// from checks a server adds still pass through the middleware.
+    pub fn wrap_handler(mut self, wrap: impl FnOnce(SharedHandler) -> SharedHandler) -> Chain {
+        self.handler = wrap(self.handler);
+        self
+    }
+
+    /// Runs the middleware from `index` on, then the handler.
+    fn run(&self, index: usize, request: &Request) -> Response {
+        match self.middleware.get(index) {
+            Some(middleware) => middleware.handle(request, &|request| self.run(index + 1, request)),
+            None => self.handler.handle(request),
+        }
+    }
+}
+
+impl Handler for Chain {
+    fn handle(&self, request: &Request) -> Response {
+        self.run(0, request)
+    }
+}
+
+/// Prints one line per request with the status, body size and time taken.
+pub struct Logger;
+
+impl Middleware for Logger {
+    fn handle(&self, request: &Request, next: &dyn Fn(&Request) -> Response) -> Response {
+        let start = Instant::now();
+        let response = next(request);
+        let elapsed = start.elapsed().as_micros();
+        let size = match response.body.len() {
+            Some(len) => format!("{len} bytes"),
+            None => "streamed".to_owned(),
+        };
+        let client = match &request.client_certificate {
+            Some(certificate) => format!(" [{}]", certificate.subject),
+            None => String::new(),
+        };
+        println!(
+            "{} {} -> {} ({size}, {elapsed} us){client}",
+            request.method,
+            request.target(),
+            response.status,
+        );
+        response
+    }
+}
+
+/// Adds a fixed set of headers to every response, replacing existing ones.
+pub struct Headers {
+    headers: BTreeMap<String, String>,
+}
+
+impl Headers {
+    pub fn new(headers: BTreeMap<String, String>) -> Headers {
+        Headers { headers }
+    }
+}
+
+impl Middleware for Headers {
+    fn after(&self, _request: &Request, response: &mut Response) {
+        for (name, value) in &self.headers {
+            response.set_header(name, value);
+        }
+    }
+}
+
+/// Sends `Strict-Transport-Security` with responses to requests made over
+/// TLS. Browsers ignore the header on plain http, so it is left out there.
+pub struct Hsts {
+    value: String,
+}
+
+impl Hsts {
+    pub fn new(max_age: u64, include_subdomains: bool, preload: bool) -> Hsts {
+        let mut value = format!("max-age={max_age}");
+        if include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if preload {
+            value.push_str("; preload");
+        }
+        Hsts { value }
+    }
+
+    pub fn from_settings(settings: &settings::Hsts) -> Hsts {
+        if settings.preload && (!settings.include_subdomains || settings.max_age < 31536000) {
+            println!(
+                "Warning: HSTS preload lists need include_subdomains and a max_age of at least a year."
+            );
+        }
+        Hsts::new(
+            settings.max_age,
+            settings.include_subdomains,
+            settings.preload,
+        )
+    }
+}
+
+impl Middleware for Hsts {
+    fn after(&self, request: &Request, response: &mut Response) {
+        if request.secure {
+            response.set_header("Strict-Transport-Security", &self.value);
+        }
+    }
+}
+
+/// Adds `X-Content-Type-Options`, `X-Frame-Options`, `Referrer-Policy`,
+/// `Permissions-Policy` and optionally `Content-Security-Policy` to every
+/// response that doesn't set them itself.
+pub struct SecurityHeaders {
+    headers: Vec<(String, String)>,
+}
+
+impl SecurityHeaders {
+    pub fn from_settings(settings: &settings::SecurityHeaders) -> SecurityHeaders {
+        let headers = [
+            ("X-Content-Type-Options", "nosniff"),
+            ("X-Frame-Options", settings.frame_options.as_str()),
+            ("Referrer-Policy", settings.referrer_policy.as_str()),
+            ("Permissions-Policy", settings.permissions_policy.as_str()),
+            (
+                "Content-Security-Policy",
+                settings
+                    .content_security_policy
+                    .as_deref()
+                    .unwrap_or_default(),
+            ),
+        ];
+        SecurityHeaders {
+            headers: headers
+                .into_iter()
+                .filter(|(_, value)| !value.is_empty())
+                .map(|(name, value)| (name.to_owned(), value.to_owned()))
+                .collect(),
+        }
+    }
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        SecurityHeaders::from_settings(&settings::SecurityHeaders::default())
+    }
+}
+
+impl Middleware for SecurityHeaders {
+    fn after(&self, _request: &Request, response: &mut Response) {
+        for (name, value) in &self.headers {
+            if response.header(name).is_none() {
+                response.set_header(name, value);
+            }
+        }
+    }
+}
+
+/// HTTP Basic authentication with a single set of credentials.
+pub struct BasicAuth {
+    expected: String,
+    realm: String,
+}
+
+impl BasicAuth {
+    pub fn new(username: &str, password: &str, realm: &str) -> BasicAuth {
+        BasicAuth {
+            expected: STANDARD.encode(format!("{username}:{password}")),
+            realm: realm.to_owned(),
+        }
+    }
+}
+
+impl Middleware for BasicAuth {
+    fn before(&self, request: &Request) -> Option<Response> {
+        let authorized = request
+            .header("Authorization")
+            .and_then(|value| value.strip_prefix("Basic "))
+            .is_some_and(|credentials| {
+                constant_time_eq(credentials.trim().as_bytes(), self.expected.as_bytes())
+            });
+        if authorized {
+            None
+        } else {
+            Some(Response::new(401).with_header(
+                "WWW-Authenticate",
+                &format!("Basic realm=\"{}\"", self.realm),
+            ))
+        }
+    }
+}
+
+/// Compares without stopping at the first difference, so the time taken
+/// doesn't reveal how much of the credentials a guess got right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Gzip compresses response bodies for clients that accept it.
+pub struct Compression {
+    /// Bodies smaller than this are sent as is.
+    pub min_size: usize,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression { min_size: 256 }
+    }
+}
+
+impl Middleware for Compression {
+    fn after(&self, request: &Request, response: &mut Response) {
+        let accepts_gzip = request.header("Accept-Encoding").is_some_and(accepts_gzip);
+        let body = match response.body.as_bytes() {
+            Some(body) if accepts_gzip && body.len() >= self.min_size => body,
+            _ => return,
+        };
+        if response.header("Content-Encoding").is_some() {
+            return;
+        }
+
+        let mut encoder = GzEncoder::new(Vec::new(), GzLevel::default());
+        let compressed = match encoder.write_all(body).and_then(|_| encoder.finish()) {
+            Ok(compressed) => compressed,
+            Err(e) => {
+                println!("Could not compress response. {e}");
+                return;
+            }
+        };
+        response.body = Body::Full(compressed);
+        response.remove_header("Content-Length");
+        response.set_header("Content-Encoding", "gzip");
+        add_vary(response, "Accept-Encoding");
+    }
+}
+
+/// Whether an `Accept-Encoding` value allows gzip. A `gzip` entry takes
+/// precedence over `*`, and a q-value of 0 refuses the coding.
+fn accepts_gzip(encodings: &str) -> bool {
+    let mut gzip = None;
+    let mut any = None;
+    for entry in encodings.split(',') {
+        let mut parts = entry.split(';');
+        let coding = parts.next().unwrap_or_default().trim();
+        let quality = parts
+            .filter_map(|parameter| parameter.split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+            .map_or(1.0, |(_, value)| value.trim().parse().unwrap_or(0.0));
+        if coding.eq_ignore_ascii_case("gzip") {
+            gzip = Some(quality);
+        } else if coding == "*" {
+            any = Some(quality);
+        }
+    }
+    gzip.or(any).is_some_and(|quality: f32| quality > 0.0)
+}
+
+/// Adds `field` to the response's `Vary` header, keeping the fields already listed.
+fn add_vary(response: &mut Response, field: &str) {
+    let vary = match response.header("Vary") {
+        Some(vary)
+            if vary.split(',').any(|listed| {
+                listed.trim() == "*" || listed.trim().eq_ignore_ascii_case(field)
+            }) =>
+        {
+            return
+        }
+        Some(vary) => format!("{vary}, {field}"),
+        None => field.to_owned(),
+    };
+    response.set_header("Vary", &vary);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gzip_is_accepted_by_q_value() {
+        assert!(accepts_gzip("gzip"));
+        assert!(accepts_gzip("gzip;q=1.0"));
+        assert!(accepts_gzip("deflate, GZIP; Q=0.5"));
+        assert!(accepts_gzip("br, *"));
+        assert!(!accepts_gzip("gzip;q=0, *"));
+        assert!(!accepts_gzip("*;q=0"));
+        assert!(!accepts_gzip("deflate, br"));
+    }
+
+    #[test]
+    fn vary_is_appended_to() {
+        let mut response = Response::new(200).with_header("Vary", "Origin");
+        add_vary(&mut response, "Accept-Encoding");
+        assert_eq!(response.header("Vary"), Some("Origin, Accept-Encoding"));
+        add_vary(&mut response, "accept-encoding");
+        assert_eq!(response.header("Vary"), Some("Origin, Accept-Encoding"));
+    }
+
+    fn request(secure: bool) -> Request {
+        let mut request = Request::parse(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").unwrap();
+        request.secure = secure;
+        request
+    }
+
+    #[test]
+    fn hsts_is_only_sent_over_tls() {
+        let hsts = Hsts::from_settings(&settings::Hsts {
+            max_age: 600,
+            include_subdomains: false,
+            preload: false,
+        });
+        let mut response = Response::new(200);
+        hsts.after(&request(false), &mut response);
+        assert_eq!(response.header("Strict-Transport-Security"), None);
+        hsts.after(&request(true), &mut response);
+        assert_eq!(
+            response.header("Strict-Transport-Security"),
+            Some("max-age=600")
+        );
+    }
+
+    #[test]
+    fn hsts_value_lists_the_enabled_directives() {
+        let value = |hsts: Hsts| {
+            let mut response = Response::new(200);
+            hsts.after(&request(true), &mut response);
+            response
+                .header("Strict-Transport-Security")
+                .unwrap()
+                .to_owned()
+        };
+        assert_eq!(
+            value(Hsts::new(31536000, true, false)),
+            "max-age=31536000; includeSubDomains"
+        );
+        assert_eq!(value(Hsts::new(0, false, true)), "max-age=0; preload");
+        assert_eq!(
+            value(Hsts::from_settings(&settings::Hsts {
+                max_age: 63072000,
+                include_subdomains: true,
+                preload: true,
+            })),
+            "max-age=63072000; includeSubDomains; preload"
+        );
+    }
+
+    #[test]
+    fn security_headers_keep_the_handlers_values() {
+        let security_headers = SecurityHeaders::from_settings(&settings::SecurityHeaders {
+            content_security_policy: Some("default-src 'self'".to_owned()),
+            ..settings::SecurityHeaders::default()
+        });
+        let mut response = Response::new(200)
+            .with_header("X-Frame-Options", "SAMEORIGIN")
+            .with_header("Content-Security-Policy", "default-src *");
+        security_headers.after(&request(false), &mut response);
+        assert_eq!(response.header("X-Frame-Options"), Some("SAMEORIGIN"));
+        assert_eq!(
+            response.header("Content-Security-Policy"),
+            Some("default-src *")
+        );
+        assert_eq!(response.header("X-Content-Type-Options"), Some("nosniff"));
+        assert_eq!(
+            response.header("Referrer-Policy"),
+            Some("strict-origin-when-cross-origin")
+        );
+
+        // Without a policy configured, none is added.
+        let mut response = Response::new(200);
+        SecurityHeaders::default().after(&request(false), &mut response);
+        assert_eq!(response.header("Content-Security-Policy"), None);
+        assert_eq!(response.header("X-Frame-Options"), Some("DENY"));
+    }
+
+    #[test]
+    fn basic_auth_checks_the_credentials() {
+        let auth = BasicAuth::new("admin", "secret", "site");
+        let with = |credentials: &str| {
+            let mut request = request(false);
+            request
+                .headers
+                .push(("Authorization".to_owned(), format!("Basic {credentials}")));
+            auth.before(&request).map(|response| response.status)
+        };
+        assert_eq!(with(&STANDARD.encode("admin:secret")), None);
+        assert_eq!(with(&STANDARD.encode("admin:secreT")), Some(401));
+        assert_eq!(with(&STANDARD.encode("admin:secret2")), Some(401));
+        assert_eq!(auth.before(&request(false)).unwrap().status, 401);
+    }
+}
@@ -0,0 +1,457 @@
+use std::{
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    balancer::{Balancer, HealthCheck, Lease, Strategy},
+    connection::Buffered,
+    handler::Handler,
+    http::{read_response_head, Body, ChunkedReader, Method, Request, Response, Stream},
+    settings, websocket,
+};
+
+/// Headers that only apply to a single connection and are never forwarded.
+const HOP_BY_HOP: [&str; 9] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// Address of an upstream server, `host:port` or `unix:/path/to.sock`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Upstream {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl Upstream {
+    pub fn parse(address: &str) -> Upstream {
+        match address.strip_prefix("unix:") {
+            Some(path) => Upstream::Unix(PathBuf::from(path)),
+            None => Upstream::Tcp(address.to_owned()),
+        }
+    }
+
+    pub fn connect(
+        &self,
+        connect_timeout: Duration,
+        read_timeout: Duration,
+    ) -> io::Result<Box<dyn Stream>> {
+        match self {
+            Upstream::Tcp(address) => {
+                let mut last_error = io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("{address} did not resolve to any address."),
+                );
+                for addr in address.to_socket_addrs()? {
+                    match TcpStream::connect_timeout(&addr, connect_timeout) {
+                        Ok(stream) => {
+                            stream.set_read_timeout(Some(read_timeout))?;
+                            stream.set_write_timeout(Some(read_timeout))?;
+                            return Ok(Box::new(stream));
+                        }
+                        Err(e) => last_error = e,
+                    }
+                }
+                Err(last_error)
+            }
+            Upstream::Unix(path) => {
+                let stream = UnixStream::connect(path)?;
+                stream.set_read_timeout(Some(read_timeout))?;
+                stream.set_write_timeout(Some(read_timeout))?;
+                Ok(Box::new(stream))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Upstream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Upstream::Tcp(address) => f.write_str(address),
+            Upstream::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Forwards requests under a path prefix to upstream HTTP servers and
+/// streams their responses back.
+#[derive(Clone)]
+pub struct ReverseProxy {
+    prefix: String,
+    balancer: Arc<Balancer>,
+    strip_prefix: bool,
+    websocket: bool,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+}
+
+impl ReverseProxy {
+    pub fn new(prefix: &str, upstream: Upstream) -> ReverseProxy {
+        let balancer = Balancer::new(vec![upstream], Strategy::RoundRobin, 1, Duration::ZERO);
+        ReverseProxy::with_balancer(prefix, Arc::new(balancer))
+    }
+
+    pub fn with_balancer(prefix: &str, balancer: Arc<Balancer>) -> ReverseProxy {
+        ReverseProxy {
+            prefix: prefix.trim_end_matches('/').to_owned(),
+            balancer,
+            strip_prefix: false,
+            websocket: false,
+            connect_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Builds a proxy for a `[[proxy]]` table and starts its health checks, if any.
+    pub fn from_settings(settings: &settings::Proxy) -> Result<ReverseProxy, String> {
+        let upstreams: Vec<Upstream> = settings
+            .upstream
+            .iter()
+            .chain(&settings.upstreams)
+            .map(|address| Upstream::parse(address))
+            .collect();
+        if upstreams.is_empty() {
+            return Err(format!(
+                "No upstream configured for proxy {}.",
+                settings.prefix
+            ));
+        }
+
+        let balancer = Arc::new(Balancer::new(
+            upstreams,
+            settings.strategy,
+            settings.max_fails,
+            Duration::from_secs(settings.fail_timeout),
+        ));
+        if let Some(check) = &settings.health_check {
+            balancer.start_health_checks(HealthCheck {
+                path: check.path.clone(),
+                interval: Duration::from_secs(check.interval),
+                timeout: Duration::from_secs(check.timeout),
+            });
+        }
+
+        let mut proxy = ReverseProxy::with_balancer(&settings.prefix, balancer);
+        proxy.strip_prefix = settings.strip_prefix;
+        proxy.websocket = settings.websocket;
+        proxy.connect_timeout = Duration::from_secs(settings.connect_timeout);
+        proxy.read_timeout = Duration::from_secs(settings.read_timeout);
+        Ok(proxy)
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn balancer(&self) -> &Arc<Balancer> {
+        &self.balancer
+    }
+
+    /// Removes the route prefix from the forwarded path.
+    pub fn strip_prefix(mut self, strip_prefix: bool) -> ReverseProxy {
+        self.strip_prefix = strip_prefix;
+        self
+    }
+
+    /// Tunnels WebSocket upgrade requests to the upstream instead of
+    /// answering them like plain requests.
+    pub fn websocket(mut self, websocket: bool) -> ReverseProxy {
+        self.websocket = websocket;
+        self
+    }
+
+    pub fn timeouts(mut self, connect_timeout: Duration, read_timeout: Duration) -> ReverseProxy {
+        self.connect_timeout = connect_timeout;
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    fn forward(&self, request: &Request) -> io::Result<Response> {
+        let (lease, mut stream) = self.connect(request)?;
+        let target = self.upstream_target(request);
+        let response = write_request(&mut stream, request, &target, None)
+            .and_then(|_| read_response(BufReader::new(stream), request));
+        self.balancer.report(&lease, response.is_ok());
+        response.map(|response| keep_lease(response, lease))
+    }
+
+    /// Forwards a WebSocket handshake. If the upstream switches protocols,
+    /// the client connection is relayed to it until either side closes.
+    fn tunnel(&self, request: &Request) -> io::Result<Response> {
+        let (lease, mut stream) = self.connect(request)?;
+        let target = self.upstream_target(request);
+        let head = write_request(&mut stream, request, &target, Some("websocket")).and_then(|_| {
+            let mut reader = BufReader::new(stream);
+            read_head(&mut reader).map(|(status, headers)| (status, headers, reader))
+        });
+        self.balancer.report(&lease, head.is_ok());
+        let (status, headers, reader) = head?;
+        if status != 101 {
+            let response = upstream_response(status, headers, reader, request)?;
+            return Ok(keep_lease(response, lease));
+        }
+
+        let mut response = Response::new(101);
+        let connection = connection_options(&headers);
+        for (name, value) in headers {
+            if !is_hop_by_hop(&name, &connection) {
+                response.headers.push((name, value));
+            }
+        }
+        let upstream = Buffered::new(reader.buffer().to_vec(), reader.into_inner());
+        Ok(response
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade")
+            .with_upgrade(move |client| {
+                let _lease = lease;
+                if let Err(e) = websocket::relay(client, Box::new(upstream)) {
+                    println!("WebSocket tunnel to {} failed: {e}", _lease.upstream());
+                }
+            }))
+    }
+
+    /// Connects to an upstream picked by the balancer. Connection failures
+    /// are retried on the next upstream, since nothing has been sent yet.
+    fn connect(&self, request: &Request) -> io::Result<(Lease, Box<dyn Stream>)> {
+        let client = request.remote_addr.map(|addr| addr.ip());
+        let mut tried = Vec::new();
+        loop {
+            let lease = match self.balancer.pick(client, &tried) {
+                Some(lease) => lease,
+                None if tried.is_empty() => {
+                    return Err(io::Error::new(
+                        ErrorKind::ConnectionRefused,
+                        "No upstream available.",
+                    ))
+                }
+                None => {
+                    return Err(io::Error::new(
+                        ErrorKind::ConnectionRefused,
+                        "All upstreams failed.",
+                    ))
+                }
+            };
+            match lease
+                .upstream()
+                .connect(self.connect_timeout, self.read_timeout)
+            {
+                Ok(stream) => return Ok((lease, stream)),
+                Err(e) => {
+                    println!("Could not connect to upstream {}: {e}", lease.upstream());
+                    self.balancer.report(&lease, false);
+                    tried.push(lease.upstream().clone());
+                }
+            }
+        }
+    }
+
+    fn upstream_target(&self, request: &Request) -> String {
+        let target = request.target();
+        if !self.strip_prefix {
+            return target;
+        }
+        match target.strip_prefix(&self.prefix) {
+            Some(rest) if rest.starts_with('/') => rest.to_owned(),
+            Some(rest) => format!("/{rest}"),
+            None => target,
+        }
+    }
+}
+
+/// Status of every proxy's upstreams, used by the IPC `upstreams` command.
+pub fn status(proxies: &[ReverseProxy]) -> String {
+    if proxies.is_empty() {
+        return "No proxies configured.".to_owned();
+    }
+    proxies
+        .iter()
+        .map(|proxy| format!("{}\n{}", proxy.prefix, proxy.balancer.status()))
+        .collect::<Vec<_>>()
+        .join("")
+}
+
+/// Keeps the lease, and with it the active connection count, until the body
+/// has been streamed to the client.
+fn keep_lease(mut response: Response, lease: Lease) -> Response {
+    if let Body::Stream(stream) = response.body {
+        response.body = Body::Stream(Box::new(LeasedStream {
+            inner: stream,
+            _lease: lease,
+        }));
+    }
+    response
+}
+
+struct LeasedStream<S> {
+    inner: S,
+    _lease: Lease,
+}
+
+impl<S: Read> Read for LeasedStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Handler for ReverseProxy {
+    fn handle(&self, request: &Request) -> Response {
+        let response = if self.websocket && websocket::is_upgrade(request) {
+            self.tunnel(request)
+        } else {
+            self.forward(request)
+        };
+        match response {
+            Ok(response) => response,
+            Err(e) => {
+                println!("Proxy for {} failed: {e}", self.prefix);
+                match e.kind() {
+                    ErrorKind::TimedOut | ErrorKind::WouldBlock => Response::new(504),
+                    _ => Response::new(502),
+                }
+            }
+        }
+    }
+}
+
+/// Writes `request` to an upstream with hop-by-hop headers removed and the
+/// `X-Forwarded-*` headers added. With `upgrade` set, the upstream is asked
+/// to switch to that protocol instead of closing the connection.
+pub(crate) fn write_request<W: Write>(
+    stream: &mut W,
+    request: &Request,
+    target: &str,
+    upgrade: Option<&str>,
+) -> io::Result<()> {
+    let mut head = format!("{} {target} HTTP/1.1\r\n", request.method);
+    let mut forwarded_for = None;
+    let connection = connection_options(&request.headers);
+    for (name, value) in &request.headers {
+        if is_hop_by_hop(name, &connection) || name.eq_ignore_ascii_case("Content-Length") {
+            continue;
+        }
+        if name.eq_ignore_ascii_case("X-Forwarded-For") {
+            forwarded_for = Some(value.clone());
+            continue;
+        }
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+
+    if let Some(addr) = request.remote_addr {
+        let forwarded_for = match forwarded_for {
+            Some(previous) => format!("{previous}, {}", addr.ip()),
+            None => addr.ip().to_string(),
+        };
+        head.push_str(&format!("X-Forwarded-For: {forwarded_for}\r\n"));
+    }
+    let proto = if request.secure { "https" } else { "http" };
+    head.push_str(&format!("X-Forwarded-Proto: {proto}\r\n"));
+    if let Some(host) = request.header("Host") {
+        head.push_str(&format!("X-Forwarded-Host: {host}\r\n"));
+    }
+    if !request.body.is_empty()
+        || matches!(request.method, Method::Post | Method::Put | Method::Patch)
+    {
+        head.push_str(&format!("Content-Length: {}\r\n", request.body.len()));
+    }
+    match upgrade {
+        Some(protocol) => head.push_str(&format!(
+            "Upgrade: {protocol}\r\nConnection: Upgrade\r\n\r\n"
+        )),
+        None => head.push_str("Connection: close\r\n\r\n"),
+    }
+
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&request.body)?;
+    stream.flush()
+}
+
+/// Reads the upstream response head and hands the body on as a stream.
+pub(crate) fn read_response<R: BufRead + Send + 'static>(
+    mut reader: R,
+    request: &Request,
+) -> io::Result<Response> {
+    let (status, headers) = read_head(&mut reader)?;
+    upstream_response(status, headers, reader, request)
+}
+
+/// Reads an upstream's response head. Waiting for its first byte separately
+/// keeps a read timeout an `io::Error`, so a silent upstream maps to 504.
+fn read_head<R: BufRead>(reader: &mut R) -> io::Result<(u16, Vec<(String, String)>)> {
+    reader.fill_buf()?;
+    read_response_head(reader).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+}
+
+/// Builds the response for an upstream's status and headers, with the rest
+/// of `reader` as its body.
+fn upstream_response<R: BufRead + Send + 'static>(
+    status: u16,
+    headers: Vec<(String, String)>,
+    reader: R,
+    request: &Request,
+) -> io::Result<Response> {
+    let mut response = Response::new(status);
+    let mut chunked = false;
+    let connection = connection_options(&headers);
+    for (name, value) in headers {
+        if name.eq_ignore_ascii_case("Transfer-Encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        }
+        if !is_hop_by_hop(&name, &connection) {
+            response.headers.push((name, value));
+        }
+    }
+
+    let no_body = request.method == Method::Head
+        || status == 204
+        || status == 304
+        || (100..200).contains(&status);
+    if no_body {
+        return Ok(response);
+    }
+
+    if chunked {
+        response.remove_header("Content-Length");
+        return Ok(response.with_stream(ChunkedReader::new(reader)));
+    }
+    Ok(
+        match response.header("Content-Length").map(str::parse::<u64>) {
+            Some(Ok(length)) => response.with_stream(reader.take(length)),
+            _ => {
+                response.remove_header("Content-Length");
+                response.with_stream(reader)
+            }
+        },
+    )
+}
+
+/// The header names listed in `Connection`, which only apply to this hop
+/// as well (RFC 9110, section 7.6.1).
+fn connection_options(headers: &[(String, String)]) -> Vec<String> {
+    headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Connection"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(|option| option.trim().to_owned())
+        .filter(|option| !option.is_empty())
+        .collect()
+}
+
+fn is_hop_by_hop(name: &str, connection: &[String]) -> bool {
+    HOP_BY_HOP
+        .iter()
+        .copied()
+        .chain(connection.iter().map(String::as_str))
+        .any(|header| header.eq_ignore_ascii_case(name))
+}
@@ -1,37 +1,29 @@
-use std::{collections::BTreeMap, error::Error, fs::read_to_string, path::PathBuf};
+use std::{
+    error::Error,
+    fs::read,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-#[derive(Clone)]
-pub struct HttpCodes {
-    codes: BTreeMap<u16, &'static str>,
-}
-
-impl HttpCodes {
-    pub fn new() -> Self {
-        let mut codes = BTreeMap::new();
-        codes.insert(200, "HTTP/1.1 200 OK");
-        codes.insert(301, "HTTP/1.1 301 Moved Permanently");
-        codes.insert(400, "HTTP/1.1 400 Bad Request");
-        codes.insert(404, "HTTP/1.1 404 Not Found");
-        codes.insert(501, "HTTP/1.1 501 Not Implemented");
-        HttpCodes { codes }
-    }
-
-    pub fn get(&self, code: u16) -> &str {
-        match self.codes.get(&code) {
-            Some(text) => text,
-            None => "Unknown",
-        }
-    }
-}
+use crate::{
+    cgi::{CgiMapping, CgiResponse, Script},
+    fastcgi::FastCgi,
+    handler::{Handler, SharedHandler},
+    http::{Method, Request, Response},
+};
 
 #[derive(Clone)]
 pub struct RequestHandler {
-    http_codes: HttpCodes,
     document_root: PathBuf,
+    cgi: Vec<CgiMapping>,
+    fastcgi: Vec<Arc<FastCgi>>,
 }
 
 type Result<T> = std::result::Result<T, Box<dyn Error>>;
 
+/// Local redirects from CGI scripts followed for one request.
+const MAX_LOCAL_REDIRECTS: usize = 10;
+
 impl RequestHandler {
     pub fn new(document_root: String) -> Result<Self> {
         let path = PathBuf::from(&document_root);
@@ -40,60 +32,132 @@ impl RequestHandler {
         }
 
         Ok(RequestHandler {
-            http_codes: HttpCodes::new(),
             document_root: path,
+            cgi: Vec::new(),
+            fastcgi: Vec::new(),
         })
     }
 
-    pub fn handle(&self, request: String) -> String {
-        if request.starts_with("GET") {
-            let path = match request.splitn(3, " ").nth(1) {
-                Some(item) => {
-                    let item = item.replace("/", "");
-                    self.get_path(&item)
+    /// Runs scripts matched by any of `mappings` as CGI programs instead of
+    /// serving them as files.
+    pub fn with_cgi(mut self, mappings: Vec<CgiMapping>) -> Self {
+        self.cgi = mappings;
+        self
+    }
+
+    /// Passes requests matching any of `routes` on to their FastCGI backends.
+    pub fn with_fastcgi(mut self, routes: Vec<FastCgi>) -> Self {
+        self.fastcgi = routes.into_iter().map(Arc::new).collect();
+        self
+    }
+
+    pub fn document_root(&self) -> &Path {
+        &self.document_root
+    }
+
+    pub fn serve(&self, request: &Request) -> Response {
+        self.serve_redirected(request, 0)
+    }
+
+    /// Serves `request`, which CGI scripts have locally redirected
+    /// `redirects` times.
+    fn serve_redirected(&self, request: &Request, redirects: usize) -> Response {
+        for fastcgi in &self.fastcgi {
+            if let Some(script) = fastcgi.script(&request.path) {
+                return fastcgi.respond(request, &script);
+            }
+        }
+
+        if !self.cgi.is_empty() {
+            if let Some(script) = Script::resolve(&self.document_root, &request.path) {
+                if let Some(mapping) = self.cgi.iter().find(|cgi| cgi.matches(&script.name)) {
+                    return match mapping.run(request, &script, &self.document_root) {
+                        CgiResponse::Document(response) => response,
+                        CgiResponse::LocalRedirect(_) if redirects == MAX_LOCAL_REDIRECTS => {
+                            println!("CGI script {} redirects in a loop.", script.name);
+                            Response::new(500)
+                        }
+                        CgiResponse::LocalRedirect(location) => self.serve_redirected(
+                            &redirected_request(request, &location),
+                            redirects + 1,
+                        ),
+                    };
                 }
-                _ => {
-                    return self.format_response(400, self.get_path("400.html"))
-                } 
-            };
+            }
+        }
 
-            if path.is_file() {
+        if request.method != Method::Get {
+            return self.format_response(501, self.get_path("501.html"));
+        }
+
+        let item = request.path.replace('/', "");
+        let path = self.get_path(&item);
+
+        if path.is_file() {
+            return self.format_response(200, path);
+        } else if path.is_dir() {
+            let path = path.join("index.html");
+            if path.exists() {
                 return self.format_response(200, path);
-            } else if path.is_dir() {
-                let path = path.join("index.html");
-                if path.exists() {
-                    return self.format_response(200, path);
-                }
             }
-            self.format_response(404, self.get_path("404.html"))
-        } else {
-            self.format_response(501, self.get_path("501.html"))
         }
+        self.format_response(404, self.get_path("404.html"))
     }
 
-    pub fn redirect(&self, request: String, destination: String) -> String {
-        if request.starts_with("GET") {
-            let path = request.splitn(3, " ").nth(1).unwrap();
-            format!(
-                "{}\r\nLocation: {destination}{path}\r\n\r\n",
-                self.http_codes.get(301)
-            )
+    pub fn redirect(&self, request: &Request, destination: &str) -> Response {
+        if request.method == Method::Get {
+            Response::new(301)
+                .with_header("Location", &format!("{destination}{}", request.target()))
         } else {
             self.format_response(400, self.get_path("400.html"))
         }
     }
 
+    /// The handler a listener runs: `site`, or a redirect of every request to
+    /// `redirect` if one is configured.
+    pub fn for_listener(
+        handler: Arc<RequestHandler>,
+        site: SharedHandler,
+        redirect: Option<String>,
+    ) -> SharedHandler {
+        match redirect {
+            Some(destination) => {
+                Arc::new(move |request: &Request| handler.redirect(request, &destination))
+            }
+            None => site,
+        }
+    }
+
     fn get_path(&self, file: &str) -> PathBuf {
         self.document_root.join(file)
     }
 
-    fn format_response(&self, code: u16, path: PathBuf) -> String {
-        let content = read_to_string(path).unwrap_or("".to_owned());
-        format!(
-            "{}\r\nContent-Length: {}\r\n\r\n{}\r\n\r\n",
-            self.http_codes.get(code),
-            content.len(),
-            content
-        )
+    fn format_response(&self, code: u16, path: PathBuf) -> Response {
+        let content = read(path).unwrap_or_default();
+        Response::new(code).with_body(content)
+    }
+}
+
+/// The request a local redirect to `location` stands for: a GET of that
+/// path without the original body.
+fn redirected_request(request: &Request, location: &str) -> Request {
+    let mut redirected = request.clone();
+    let (path, query) = match location.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_owned())),
+        None => (location, None),
+    };
+    redirected.method = Method::Get;
+    redirected.path = path.to_owned();
+    redirected.query = query;
+    redirected.body.clear();
+    redirected.headers.retain(|(name, _)| {
+        !name.eq_ignore_ascii_case("Content-Length") && !name.eq_ignore_ascii_case("Content-Type")
+    });
+    redirected
+}
+
+impl Handler for RequestHandler {
+    fn handle(&self, request: &Request) -> Response {
+        self.serve(request)
     }
 }
@@ -0,0 +1,161 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use crate::{
+    handler::{Handler, SharedHandler},
+    http::{Method, Request, Response},
+};
+
+enum Segment {
+    Static(String),
+    /// `:name` matches exactly one path segment.
+    Param(String),
+    /// `*name` matches the rest of the path, possibly empty.
+    Wildcard(String),
+}
+
+struct Route {
+    method: Option<Method>,
+    segments: Vec<Segment>,
+    handler: SharedHandler,
+}
+
+impl Route {
+    fn matches(&self, path: &[&str]) -> Option<BTreeMap<String, String>> {
+        let mut params = BTreeMap::new();
+        let mut path = path.iter();
+        for segment in &self.segments {
+            match segment {
+                Segment::Static(expected) => {
+                    if path.next() != Some(&expected.as_str()) {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    params.insert(name.clone(), path.next()?.to_string());
+                }
+                Segment::Wildcard(name) => {
+                    let rest: Vec<&str> = path.by_ref().copied().collect();
+                    params.insert(name.clone(), rest.join("/"));
+                }
+            }
+        }
+        match path.next() {
+            Some(_) => None,
+            None => Some(params),
+        }
+    }
+}
+
+/// Dispatches requests to handlers by method and path pattern.
+///
+/// Patterns are matched segment by segment, e.g. `/users/:id` or
+/// `/static/*file`. Routes are tried in the order they were added. If a path
+/// matches but no route accepts the method, `405 Method Not Allowed` is
+/// returned; if nothing matches, the fallback handler runs, or `404 Not Found`
+/// if there is none.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    fallback: Option<SharedHandler>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    pub fn route(mut self, method: Method, pattern: &str, handler: impl Handler + 'static) -> Self {
+        self.routes.push(Route {
+            method: Some(method),
+            segments: parse_pattern(pattern),
+            handler: Arc::new(handler),
+        });
+        self
+    }
+
+    /// Adds a route that accepts every method.
+    pub fn any(mut self, pattern: &str, handler: impl Handler + 'static) -> Self {
+        self.routes.push(Route {
+            method: None,
+            segments: parse_pattern(pattern),
+            handler: Arc::new(handler),
+        });
+        self
+    }
+
+    pub fn get(self, pattern: &str, handler: impl Handler + 'static) -> Self {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn post(self, pattern: &str, handler: impl Handler + 'static) -> Self {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    pub fn put(self, pattern: &str, handler: impl Handler + 'static) -> Self {
+        self.route(Method::Put, pattern, handler)
+    }
+
+    pub fn delete(self, pattern: &str, handler: impl Handler + 'static) -> Self {
+        self.route(Method::Delete, pattern, handler)
+    }
+
+    /// Handler for requests no route matched, e.g. a `RequestHandler` serving static files.
+    pub fn fallback(mut self, handler: impl Handler + 'static) -> Self {
+        self.fallback = Some(Arc::new(handler));
+        self
+    }
+}
+
+impl Handler for Router {
+    fn handle(&self, request: &Request) -> Response {
+        let path: Vec<&str> = request.path.split('/').filter(|s| !s.is_empty()).collect();
+
+        let mut allowed = Vec::new();
+        for route in &self.routes {
+            let params = match route.matches(&path) {
+                Some(params) => params,
+                None => continue,
+            };
+            match &route.method {
+                Some(method) if *method != request.method => {
+                    if !allowed.contains(&method.as_str()) {
+                        allowed.push(method.as_str());
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+
+            if params.is_empty() {
+                return route.handler.handle(request);
+            }
+            let mut request = request.clone();
+            request.params = params;
+            return route.handler.handle(&request);
+        }
+
+        if !allowed.is_empty() {
+            return Response::new(405).with_header("Allow", &allowed.join(", "));
+        }
+        match &self.fallback {
+            Some(fallback) => fallback.handle(request),
+            None => Response::new(404),
+        }
+    }
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    pattern
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(|segment| {
+            if let Some(name) = segment.strip_prefix(':') {
+                Segment::Param(name.to_owned())
+            } else if let Some(name) = segment.strip_prefix('*') {
+                Segment::Wildcard(name.to_owned())
+            } else {
+                Segment::Static(segment.to_owned())
+            }
+        })
+        .collect()
+}
@@ -1,11 +1,29 @@
 use serde::Deserialize;
-use std::{error::Error, fs::read_to_string};
+use std::{collections::BTreeMap, error::Error, fs::read_to_string};
+
+use crate::{
+    balancer::Strategy,
+    event_loop::Mode,
+    tls_server::{ClientAuthMode, TlsVersion},
+};
 
 #[derive(Deserialize)]
 pub struct Settings {
     pub server: Server,
     pub https: Https,
     pub http: Http,
+    #[serde(default)]
+    pub middleware: Middleware,
+    #[serde(default)]
+    pub proxy: Vec<Proxy>,
+    #[serde(default)]
+    pub cgi: Vec<Cgi>,
+    #[serde(default)]
+    pub fastcgi: Vec<FastCgi>,
+    #[serde(default)]
+    pub sse: Vec<Sse>,
+    pub security_headers: Option<SecurityHeaders>,
+    pub acme: Option<Acme>,
 }
 
 #[derive(Deserialize)]
@@ -15,19 +33,110 @@ pub struct Server {
     pub document_root: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize)]
 pub struct Https {
     pub port: u16,
     pub redirect: Option<String>,
     #[serde(default = "threads")]
     pub threads: usize,
+    /// Threads kept running while idle, `threads` if unset.
+    pub min_threads: Option<usize>,
+    /// Threads started under load, `threads` if unset.
+    pub max_threads: Option<usize>,
+    /// Seconds threads above `min_threads` wait for work before stopping.
+    #[serde(default = "idle_timeout")]
+    pub idle_timeout: u64,
+    /// Connections waiting for a thread before new ones are turned away.
+    #[serde(default = "queue_size")]
+    pub queue_size: usize,
+    #[serde(default)]
+    pub mode: Mode,
+    /// The default certificate, used when no `certificates` entry matches.
+    pub ssl: Option<SSL>,
+    #[serde(default)]
+    pub certificates: Vec<Certificate>,
+    /// Seconds between checks of the certificate files for changes.
+    pub watch_interval: Option<u64>,
+    #[serde(default)]
+    pub client_auth: ClientAuth,
+    pub hsts: Option<Hsts>,
+    /// Offer HTTP/2 to clients through ALPN.
+    #[serde(default = "http2")]
+    pub http2: bool,
+}
+
+/// The `Strict-Transport-Security` header sent with https responses.
+#[derive(Clone, Deserialize)]
+pub struct Hsts {
+    /// Seconds browsers only use https for the domain.
+    #[serde(default = "hsts_max_age")]
+    pub max_age: u64,
+    #[serde(default)]
+    pub include_subdomains: bool,
+    /// Asks to be added to the browsers' preload lists.
+    #[serde(default)]
+    pub preload: bool,
+}
+
+/// Client certificate verification (mutual TLS).
+#[derive(Clone, Default, Deserialize)]
+pub struct ClientAuth {
+    #[serde(default)]
+    pub mode: ClientAuthMode,
+    /// PEM bundle of the CAs client certificates must be signed by.
+    pub ca: Option<String>,
+    /// Path prefixes answered with 403 unless the client sent a valid certificate.
+    #[serde(default)]
+    pub paths: Vec<String>,
+}
+
+/// A certificate selected by SNI server name.
+#[derive(Clone, Deserialize)]
+pub struct Certificate {
+    /// Names served with this certificate, e.g. `*.example.com`. Taken from
+    /// the certificate itself if empty.
+    #[serde(default)]
+    pub hostnames: Vec<String>,
+    #[serde(flatten)]
     pub ssl: SSL,
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize)]
 pub struct SSL {
-    pub identity: String,
+    /// PKCS#12 file with the certificate chain and key.
+    pub identity: Option<String>,
+    /// Password of the PKCS#12 file, or of an encrypted PEM private key.
+    #[serde(default)]
     pub password: String,
+    /// PEM certificate chain, used with `private_key` instead of `identity`.
+    pub certificate: Option<String>,
+    pub private_key: Option<String>,
+    #[serde(flatten)]
+    pub policy: TlsPolicy,
+}
+
+/// Protocol versions and ciphers offered to clients. Unset values of a
+/// `[[https.certificates]]` entry are taken from `[https.ssl]`.
+#[derive(Clone, Default, Deserialize)]
+pub struct TlsPolicy {
+    pub min_protocol: Option<TlsVersion>,
+    pub max_protocol: Option<TlsVersion>,
+    /// OpenSSL style cipher list, e.g. `ECDHE+AESGCM:ECDHE+CHACHA20`.
+    pub ciphers: Option<String>,
+    /// Key exchange groups, e.g. `X25519:P-256`.
+    pub groups: Option<String>,
+}
+
+impl TlsPolicy {
+    /// Fills the values unset in `self` from `fallback`.
+    pub fn or(&self, fallback: &TlsPolicy) -> TlsPolicy {
+        TlsPolicy {
+            min_protocol: self.min_protocol.or(fallback.min_protocol),
+            max_protocol: self.max_protocol.or(fallback.max_protocol),
+            ciphers: self.ciphers.clone().or_else(|| fallback.ciphers.clone()),
+            groups: self.groups.clone().or_else(|| fallback.groups.clone()),
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -36,22 +145,263 @@ pub struct Http {
     pub redirect: Option<String>,
     #[serde(default = "threads")]
     pub threads: usize,
+    /// Threads kept running while idle, `threads` if unset.
+    pub min_threads: Option<usize>,
+    /// Threads started under load, `threads` if unset.
+    pub max_threads: Option<usize>,
+    /// Seconds threads above `min_threads` wait for work before stopping.
+    #[serde(default = "idle_timeout")]
+    pub idle_timeout: u64,
+    /// Connections waiting for a thread before new ones are turned away.
+    #[serde(default = "queue_size")]
+    pub queue_size: usize,
+    #[serde(default)]
+    pub mode: Mode,
+    /// Serve HTTP/2 to clients starting with its connection preface.
+    #[serde(default)]
+    pub h2c: bool,
+    /// Switch requests carrying `Upgrade: h2c` to HTTP/2.
+    #[serde(default)]
+    pub h2c_upgrade: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Middleware {
+    pub logging: bool,
+    pub compression: bool,
+    pub headers: BTreeMap<String, String>,
+    pub auth: Option<Auth>,
+}
+
+impl Default for Middleware {
+    fn default() -> Self {
+        Middleware {
+            logging: true,
+            compression: false,
+            headers: BTreeMap::new(),
+            auth: None,
+        }
+    }
+}
+
+/// Certificates obtained and renewed from an ACME CA such as Let's Encrypt,
+/// validated through the http listener.
+#[derive(Clone, Deserialize)]
+pub struct Acme {
+    #[serde(default = "acme_directory")]
+    pub directory: String,
+    pub domains: Vec<String>,
+    /// Contact for the account, e.g. `mailto:admin@example.com`.
+    pub contact: Option<String>,
+    /// Created on first use if it doesn't exist.
+    pub account_key: String,
+    /// Where the certificate chain and its key are written, usually the same
+    /// files as `[https.ssl]` uses.
+    pub certificate: String,
+    pub private_key: String,
+    /// Days before expiry the certificate is renewed.
+    #[serde(default = "acme_renew_before")]
+    pub renew_before: u32,
+    /// Seconds between expiry checks.
+    #[serde(default = "acme_check_interval")]
+    pub check_interval: u64,
+    /// PEM bundle trusted for the directory's https besides the bundled
+    /// web PKI roots, e.g. the CA of a local test server.
+    pub ca: Option<String>,
+}
+
+/// Hardening headers added to every response. An empty value leaves the
+/// header out.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct SecurityHeaders {
+    pub frame_options: String,
+    pub referrer_policy: String,
+    pub permissions_policy: String,
+    pub content_security_policy: Option<String>,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        SecurityHeaders {
+            frame_options: "DENY".to_owned(),
+            referrer_policy: "strict-origin-when-cross-origin".to_owned(),
+            permissions_policy: "camera=(), microphone=(), geolocation=()".to_owned(),
+            content_security_policy: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Auth {
+    pub username: String,
+    pub password: String,
+    #[serde(default = "realm")]
+    pub realm: String,
+}
+
+#[derive(Deserialize)]
+pub struct Proxy {
+    pub prefix: String,
+    pub upstream: Option<String>,
+    #[serde(default)]
+    pub upstreams: Vec<String>,
+    #[serde(default)]
+    pub strategy: Strategy,
+    #[serde(default = "max_fails")]
+    pub max_fails: usize,
+    #[serde(default = "fail_timeout")]
+    pub fail_timeout: u64,
+    pub health_check: Option<HealthCheck>,
+    #[serde(default)]
+    pub strip_prefix: bool,
+    #[serde(default)]
+    pub websocket: bool,
+    #[serde(default = "connect_timeout")]
+    pub connect_timeout: u64,
+    #[serde(default = "read_timeout")]
+    pub read_timeout: u64,
+}
+
+#[derive(Deserialize)]
+pub struct HealthCheck {
+    pub path: String,
+    #[serde(default = "health_check_interval")]
+    pub interval: u64,
+    #[serde(default = "health_check_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Deserialize)]
+pub struct Cgi {
+    pub prefix: Option<String>,
+    pub extension: Option<String>,
+    pub interpreter: Option<String>,
+    #[serde(default = "cgi_timeout")]
+    pub timeout: u64,
+    #[serde(default = "cgi_max_output")]
+    pub max_output: usize,
+}
+
+#[derive(Deserialize)]
+pub struct FastCgi {
+    #[serde(default)]
+    pub prefix: String,
+    pub extension: Option<String>,
+    pub index: Option<String>,
+    pub root: Option<String>,
+    pub backend: Option<String>,
+    #[serde(default)]
+    pub backends: Vec<String>,
+    #[serde(default)]
+    pub strategy: Strategy,
+    #[serde(default = "max_fails")]
+    pub max_fails: usize,
+    #[serde(default = "fail_timeout")]
+    pub fail_timeout: u64,
+    #[serde(default = "fastcgi_pool_size")]
+    pub pool_size: usize,
+    #[serde(default = "connect_timeout")]
+    pub connect_timeout: u64,
+    #[serde(default = "read_timeout")]
+    pub read_timeout: u64,
+}
+
+#[derive(Deserialize)]
+pub struct Sse {
+    pub path: String,
+    #[serde(default = "sse_history")]
+    pub history: usize,
+    #[serde(default = "sse_heartbeat")]
+    pub heartbeat: u64,
+    pub retry: Option<u64>,
+}
+
+fn sse_history() -> usize {
+    100
+}
+
+fn sse_heartbeat() -> u64 {
+    15
+}
+
+fn fastcgi_pool_size() -> usize {
+    4
+}
+
+fn cgi_timeout() -> u64 {
+    30
+}
+
+fn cgi_max_output() -> usize {
+    16 * 1024 * 1024
+}
+
+fn max_fails() -> usize {
+    3
+}
+
+fn fail_timeout() -> u64 {
+    10
+}
+
+fn health_check_interval() -> u64 {
+    10
+}
+
+fn health_check_timeout() -> u64 {
+    2
+}
+
+fn connect_timeout() -> u64 {
+    5
+}
+
+fn read_timeout() -> u64 {
+    30
+}
+
+fn acme_directory() -> String {
+    "https://acme-v02.api.letsencrypt.org/directory".to_owned()
+}
+
+fn acme_renew_before() -> u32 {
+    30
+}
+
+fn acme_check_interval() -> u64 {
+    12 * 60 * 60
+}
+
+fn http2() -> bool {
+    true
+}
+
+fn hsts_max_age() -> u64 {
+    365 * 24 * 60 * 60
+}
+
+fn realm() -> String {
+    "my_server".to_owned()
 }
 
 fn threads() -> usize {
     4
 }
 
+fn idle_timeout() -> u64 {
+    60
+}
+
+fn queue_size() -> usize {
+    128
+}
+
 type Result<T> = std::result::Result<T, Box<dyn Error>>;
 
 impl Settings {
     pub fn new(path: &str) -> Result<Self> {
-        match toml::from_str(&match read_to_string(path) {
-            Ok(content) => content,
-            Err(err) => return Err(err.into()),
-        }) {
-            Ok(settings) => Ok(settings),
-            Err(err) => return Err(err.into()),
-        }
+        Ok(toml::from_str(&read_to_string(path)?)?)
     }
 }
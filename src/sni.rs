@@ -0,0 +1,340 @@
+use std::{
+    net::TcpStream,
+    os::fd::AsRawFd,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{tls_server::Acceptor, waker};
+
+/// A TLS record holds at most 16 KiB, plus its 5 byte header.
+const MAX_RECORD: usize = 16 * 1024 + 5;
+
+/// Acceptors for every configured certificate, selected by the server name
+/// a client sends during the handshake.
+pub struct Certificates {
+    default: Arc<Acceptor>,
+    hosts: Vec<(String, Arc<Acceptor>)>,
+}
+
+impl Certificates {
+    /// Uses `default` for clients without SNI or with an unknown server name.
+    pub fn new(default: Arc<Acceptor>) -> Certificates {
+        Certificates {
+            default,
+            hosts: Vec::new(),
+        }
+    }
+
+    /// Adds `acceptor` for `hostname`, which may start with `*.` to match
+    /// exactly one more label.
+    pub fn add(&mut self, hostname: &str, acceptor: Arc<Acceptor>) {
+        self.hosts.push((
+            hostname.trim_end_matches('.').to_ascii_lowercase(),
+            acceptor,
+        ));
+    }
+
+    /// Exact hostnames win over wildcards, and the default is used if
+    /// nothing matches.
+    pub fn select(&self, server_name: Option<&str>) -> &Arc<Acceptor> {
+        let server_name = match server_name {
+            Some(server_name) => server_name.trim_end_matches('.').to_ascii_lowercase(),
+            None => return &self.default,
+        };
+        self.hosts
+            .iter()
+            .find(|(hostname, _)| *hostname == server_name)
+            .or_else(|| {
+                self.hosts
+                    .iter()
+                    .find(|(hostname, _)| matches_wildcard(hostname, &server_name))
+            })
+            .map(|(_, acceptor)| acceptor)
+            .unwrap_or(&self.default)
+    }
+}
+
+fn matches_wildcard(pattern: &str, server_name: &str) -> bool {
+    match (pattern.strip_prefix("*."), server_name.split_once('.')) {
+        (Some(domain), Some((label, rest))) => !label.is_empty() && rest == domain,
+        _ => false,
+    }
+}
+
+/// Reads the server name from the ClientHello waiting on `stream` without
+/// consuming it, so the handshake can still be done by the chosen acceptor.
+pub fn peek_server_name(stream: &TcpStream, timeout: Duration) -> Option<String> {
+    let deadline = Instant::now() + timeout;
+    let mut buffer = vec![0; MAX_RECORD];
+    let mut peeked = 0;
+    let server_name = loop {
+        let read = match stream.peek(&mut buffer) {
+            // Nothing new after waiting means the client closed.
+            Ok(read) if read > peeked => read,
+            _ => break None,
+        };
+        peeked = read;
+        match parse_client_hello(&buffer[..read]) {
+            Some(server_name) => break server_name,
+            // The ClientHello is split over several packets. Peeked data
+            // leaves the socket readable, so wait until the whole record is
+            // there instead.
+            None if read < buffer.len() => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero()
+                    || !set_receive_low_water(stream, record_size(&buffer[..read]))
+                    || !waker::wait_readable(stream.as_raw_fd(), remaining).unwrap_or(false)
+                {
+                    break None;
+                }
+            }
+            None => break None,
+        }
+    };
+    set_receive_low_water(stream, 1);
+    server_name
+}
+
+/// Bytes needed for the first record in `data`, or its header.
+fn record_size(data: &[u8]) -> usize {
+    match data {
+        [_, _, _, high, low, ..] => {
+            (5 + u16::from_be_bytes([*high, *low]) as usize).min(MAX_RECORD)
+        }
+        _ => 5,
+    }
+}
+
+/// Makes `stream` poll readable only once `bytes` are buffered (SO_RCVLOWAT).
+fn set_receive_low_water(stream: &TcpStream, bytes: usize) -> bool {
+    let bytes = bytes as libc::c_int;
+    // SAFETY: the option value points to a c_int of the given size for the
+    // whole call.
+    let result = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVLOWAT,
+            &bytes as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    result == 0
+}
+
+/// Like `peek_server_name`, for a tokio `stream`.
+#[cfg(feature = "async")]
+pub(crate) async fn peek_server_name_async(
+    stream: &tokio::net::TcpStream,
+    timeout: Duration,
+) -> Option<String> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut buffer = vec![0; MAX_RECORD];
+    loop {
+        let read = tokio::time::timeout_at(deadline, stream.peek(&mut buffer))
+            .await
+            .ok()?
+            .ok()?;
+        if read == 0 {
+            return None;
+        }
+        match parse_client_hello(&buffer[..read]) {
+            Some(server_name) => return server_name,
+            None if read < buffer.len() && tokio::time::Instant::now() < deadline => {
+                tokio::time::sleep(Duration::from_millis(5)).await
+            }
+            None => return None,
+        }
+    }
+}
+
+/// Returns `None` if `data` doesn't hold the whole first record yet, and
+/// `Some(None)` if the ClientHello has no server name or isn't one at all.
+fn parse_client_hello(data: &[u8]) -> Option<Option<String>> {
+    // Record header: content type 22 (handshake), version, length.
+    if data.len() < 5 {
+        return None;
+    }
+    if data[0] != 22 {
+        return Some(None);
+    }
+    let length = u16::from_be_bytes([data[3], data[4]]) as usize;
+    let record = data.get(5..5 + length)?;
+    Some(server_name(record))
+}
+
+fn server_name(record: &[u8]) -> Option<String> {
+    let mut hello = Reader(record);
+    // Handshake header: type 1 (ClientHello) and a 3 byte length.
+    if hello.u8()? != 1 {
+        return None;
+    }
+    hello.take(3)?;
+    // Version and random, then session id, cipher suites and compression methods.
+    hello.take(2 + 32)?;
+    let session_id = hello.u8()? as usize;
+    hello.take(session_id)?;
+    let cipher_suites = hello.u16()? as usize;
+    hello.take(cipher_suites)?;
+    let compression = hello.u8()? as usize;
+    hello.take(compression)?;
+
+    let extensions_length = hello.u16()? as usize;
+    let mut extensions = Reader(hello.take(extensions_length)?);
+    while let Some(kind) = extensions.u16() {
+        let length = extensions.u16()? as usize;
+        let mut extension = Reader(extensions.take(length)?);
+        if kind != 0 {
+            continue;
+        }
+        // server_name extension: a list of (type, name) entries, type 0 being a host name.
+        let list_length = extension.u16()? as usize;
+        let mut list = Reader(extension.take(list_length)?);
+        while let Some(name_type) = list.u8() {
+            let name_length = list.u16()? as usize;
+            let name = list.take(name_length)?;
+            if name_type == 0 {
+                return String::from_utf8(name.to_vec()).ok();
+            }
+        }
+        return None;
+    }
+    None
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        if self.0.len() < length {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, net::TcpListener, thread};
+
+    use super::*;
+
+    /// A ClientHello record, with a server_name extension if `server_name` is set.
+    fn client_hello(server_name: Option<&str>) -> Vec<u8> {
+        let mut extensions = Vec::new();
+        // An extension before server_name that must be skipped.
+        extensions.extend([0x00, 0x0b, 0x00, 0x02, 0x01, 0x00]);
+        if let Some(name) = server_name {
+            let name = name.as_bytes();
+            let list_length = name.len() + 3;
+            extensions.extend([0, 0]);
+            extensions.extend(((list_length + 2) as u16).to_be_bytes());
+            extensions.extend((list_length as u16).to_be_bytes());
+            extensions.push(0);
+            extensions.extend((name.len() as u16).to_be_bytes());
+            extensions.extend(name);
+        }
+
+        let mut hello = vec![3, 3];
+        hello.extend([0; 32]);
+        // Session id, one cipher suite and no compression.
+        hello.extend([0, 0, 2, 0x13, 0x01, 1, 0]);
+        hello.extend((extensions.len() as u16).to_be_bytes());
+        hello.extend(extensions);
+
+        let mut handshake = vec![1];
+        handshake.extend(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend(hello);
+        let mut record = vec![22, 3, 1];
+        record.extend((handshake.len() as u16).to_be_bytes());
+        record.extend(handshake);
+        record
+    }
+
+    #[test]
+    fn server_names_are_read_from_client_hellos() {
+        let hello = client_hello(Some("www.example.com"));
+        assert_eq!(
+            parse_client_hello(&hello),
+            Some(Some("www.example.com".to_owned()))
+        );
+        // Anything after the first record is ignored.
+        let mut pipelined = hello.clone();
+        pipelined.extend([23, 3, 3, 0, 0]);
+        assert_eq!(
+            parse_client_hello(&pipelined),
+            Some(Some("www.example.com".to_owned()))
+        );
+        assert_eq!(parse_client_hello(&client_hello(None)), Some(None));
+
+        // Incomplete records need more data.
+        for length in [0, 4, 5, hello.len() - 1] {
+            assert_eq!(parse_client_hello(&hello[..length]), None, "{length}");
+        }
+        // Other records, and malformed hellos, have no server name.
+        assert_eq!(parse_client_hello(b"GET / HTTP/1.1\r\n"), Some(None));
+        let mut malformed = hello.clone();
+        malformed[5] = 2;
+        assert_eq!(parse_client_hello(&malformed), Some(None));
+        let mut truncated = hello;
+        let length = truncated.len() - 10;
+        truncated.truncate(length);
+        truncated[3..5].copy_from_slice(&((length - 5) as u16).to_be_bytes());
+        assert_eq!(parse_client_hello(&truncated), Some(None));
+    }
+
+    #[test]
+    fn wildcards_match_one_label() {
+        assert!(matches_wildcard("*.example.com", "www.example.com"));
+        assert!(!matches_wildcard("*.example.com", "example.com"));
+        assert!(!matches_wildcard("*.example.com", "a.b.example.com"));
+        assert!(!matches_wildcard("*.example.com", ".example.com"));
+        assert!(!matches_wildcard("www.example.com", "www.example.com"));
+    }
+
+    #[test]
+    fn split_client_hellos_are_waited_for() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let hello = client_hello(Some("split.test"));
+        let timeout = Duration::from_secs(5);
+
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        client.write_all(&hello[..20]).unwrap();
+        let rest = hello[20..].to_vec();
+        let sender = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            client.write_all(&rest).unwrap();
+            client
+        });
+        assert_eq!(
+            peek_server_name(&server, timeout).as_deref(),
+            Some("split.test")
+        );
+        // The hello is left for the handshake.
+        let mut buffer = vec![0; hello.len()];
+        assert_eq!(server.peek(&mut buffer).unwrap(), hello.len());
+        drop(sender.join().unwrap());
+
+        // A client closing halfway is given up on at once.
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        client.write_all(&hello[..20]).unwrap();
+        drop(client);
+        let started = Instant::now();
+        assert_eq!(peek_server_name(&server, timeout), None);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
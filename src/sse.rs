@@ -0,0 +1,414 @@
+use std::{
+    collections::VecDeque,
+    io::{ErrorKind, Write},
+    os::fd::RawFd,
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    handler::Handler,
+    http::{Request, Response, Stream},
+    settings,
+    waker::Waker,
+};
+
+/// Subscribers that can't take an event within this time are dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Events a subscriber may fall behind by before it is dropped.
+const QUEUE_SIZE: usize = 64;
+/// How often subscribers without a socket to wait on, e.g. HTTP/2 streams,
+/// are tried again while they have events queued.
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A server-sent event. Ids are assigned by the `Broadcaster` publishing it.
+#[derive(Clone, Debug, Default)]
+pub struct Event {
+    pub event: Option<String>,
+    pub data: String,
+}
+
+impl Event {
+    pub fn new(data: &str) -> Event {
+        Event {
+            event: None,
+            data: data.to_owned(),
+        }
+    }
+
+    /// Sets the event type, dispatched to `addEventListener(name, ...)` in browsers.
+    pub fn with_event(mut self, name: &str) -> Event {
+        self.event = Some(name.to_owned());
+        self
+    }
+
+    fn format(&self, id: u64) -> String {
+        let mut text = format!("id: {id}\n");
+        if let Some(event) = &self.event {
+            text.push_str(&format!("event: {}\n", event.replace(['\r', '\n'], "")));
+        }
+        for line in self.data.split('\n') {
+            text.push_str(&format!("data: {}\n", line.trim_end_matches('\r')));
+        }
+        text.push('\n');
+        text
+    }
+}
+
+/// A subscribed connection, written to without blocking.
+struct Subscriber {
+    id: u64,
+    stream: Box<dyn Stream>,
+    fd: Option<RawFd>,
+    /// Events not yet written, the first one `written` bytes in.
+    queue: VecDeque<Arc<str>>,
+    written: usize,
+    /// Whether the stream holds written bytes it couldn't send yet.
+    unflushed: bool,
+    /// Since when the subscriber has had something to send.
+    waiting_since: Instant,
+}
+
+impl Subscriber {
+    fn has_output(&self) -> bool {
+        !self.queue.is_empty() || self.unflushed
+    }
+
+    fn push(&mut self, text: Arc<str>) {
+        if !self.has_output() {
+            self.waiting_since = Instant::now();
+        }
+        self.queue.push_back(text);
+    }
+
+    /// Writes as much of the queue as the connection takes, returning false
+    /// once the subscriber should be dropped.
+    fn write(&mut self) -> bool {
+        while let Some(text) = self.queue.front() {
+            match self.stream.write(&text.as_bytes()[self.written..]) {
+                Ok(0) => return false,
+                Ok(written) => {
+                    self.written += written;
+                    self.unflushed = true;
+                    self.waiting_since = Instant::now();
+                    if self.written == text.len() {
+                        self.queue.pop_front();
+                        self.written = 0;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return false,
+            }
+        }
+        if self.unflushed {
+            match self.stream.flush() {
+                Ok(()) => self.unflushed = false,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(_) => return false,
+            }
+        }
+        !self.has_output() || self.waiting_since.elapsed() < WRITE_TIMEOUT
+    }
+}
+
+struct Channel {
+    subscribers: Vec<Subscriber>,
+    next_subscriber: u64,
+    /// The last `history_size` events with their ids, replayed to clients
+    /// reconnecting with `Last-Event-ID`.
+    history: VecDeque<(u64, String)>,
+    next_id: u64,
+    heartbeat: Option<Duration>,
+    closed: bool,
+}
+
+impl Channel {
+    /// Queues `text` for every subscriber, dropping those that fell too far
+    /// behind.
+    fn send(&mut self, text: &str) {
+        let text: Arc<str> = text.into();
+        self.subscribers.retain_mut(|subscriber| {
+            subscriber.push(text.clone());
+            subscriber.queue.len() <= QUEUE_SIZE
+        });
+    }
+}
+
+/// What the broadcaster shares with its writer thread.
+struct Shared {
+    channel: Mutex<Channel>,
+    waker: Waker,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Channel> {
+        self.channel.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Holds the connections subscribed to one event stream and publishes
+/// events to all of them.
+///
+/// A single writer thread per broadcaster sends to every subscriber without
+/// blocking, so a stalled client can't hold up the others, and idle
+/// subscribers cost neither a pool worker nor a thread. Subscribers are
+/// dropped once writing to them fails or their client hangs up.
+pub struct Broadcaster {
+    shared: Arc<Shared>,
+    history_size: usize,
+}
+
+impl Broadcaster {
+    pub fn new(history_size: usize) -> Broadcaster {
+        let shared = Arc::new(Shared {
+            channel: Mutex::new(Channel {
+                subscribers: Vec::new(),
+                next_subscriber: 0,
+                history: VecDeque::with_capacity(history_size),
+                next_id: 1,
+                heartbeat: None,
+                closed: false,
+            }),
+            waker: Waker::new().expect("Failed to create Broadcaster waker."),
+        });
+        let writer = shared.clone();
+        thread::spawn(move || write_events(&writer));
+        Broadcaster {
+            shared,
+            history_size,
+        }
+    }
+
+    /// Queues `event` for every subscriber and returns how many will receive it.
+    pub fn publish(&self, event: &Event) -> usize {
+        let mut channel = self.lock();
+        let id = channel.next_id;
+        channel.next_id += 1;
+        let text = event.format(id);
+
+        if self.history_size > 0 {
+            if channel.history.len() == self.history_size {
+                channel.history.pop_front();
+            }
+            channel.history.push_back((id, text.clone()));
+        }
+        channel.send(&text);
+        let received = channel.subscribers.len();
+        drop(channel);
+        self.shared.waker.wake();
+        received
+    }
+
+    /// Adds a connection whose response head has been sent. Events after
+    /// `last_event_id` still in the history are sent first.
+    pub fn subscribe(&self, stream: Box<dyn Stream>, last_event_id: Option<&str>) {
+        if let Err(e) = stream.set_nonblocking(true) {
+            return println!("Could not subscribe to event stream. {e}");
+        }
+        let mut channel = self.lock();
+
+        let last_event_id = last_event_id.and_then(|id| id.trim().parse::<u64>().ok());
+        let replay: String = match last_event_id {
+            Some(last_event_id) => channel
+                .history
+                .iter()
+                .filter(|(id, _)| *id > last_event_id)
+                .map(|(_, text)| text.as_str())
+                .collect(),
+            None => String::new(),
+        };
+        let id = channel.next_subscriber;
+        channel.next_subscriber += 1;
+        let mut subscriber = Subscriber {
+            id,
+            fd: stream.poll_fd(),
+            stream,
+            queue: VecDeque::new(),
+            written: 0,
+            unflushed: false,
+            waiting_since: Instant::now(),
+        };
+        if !replay.is_empty() {
+            subscriber.push(replay.into());
+        }
+        channel.subscribers.push(subscriber);
+        drop(channel);
+        self.shared.waker.wake();
+    }
+
+    pub fn subscribers(&self) -> usize {
+        self.lock().subscribers.len()
+    }
+
+    /// Sends a comment to every subscriber each `interval`, keeping idle
+    /// connections open through proxies and noticing clients that left.
+    pub fn start_heartbeat(&self, interval: Duration) {
+        self.lock().heartbeat = Some(interval);
+        self.shared.waker.wake();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Channel> {
+        self.shared.lock()
+    }
+}
+
+/// Stops the writer thread, which closes the subscribers' connections.
+impl Drop for Broadcaster {
+    fn drop(&mut self) {
+        self.lock().closed = true;
+        self.shared.waker.wake();
+    }
+}
+
+/// Writes queued events to the subscribers of `shared` as their connections
+/// take them, until the broadcaster is dropped.
+fn write_events(shared: &Shared) {
+    let mut next_heartbeat = None;
+    let mut fds = Vec::new();
+    let mut polled = Vec::new();
+    loop {
+        let mut channel = shared.lock();
+        if channel.closed {
+            break;
+        }
+        // Clients that hung up while polled.
+        for (id, fd) in polled.drain(..).zip(&fds) {
+            let fd: &libc::pollfd = fd;
+            if fd.revents & (libc::POLLRDHUP | libc::POLLHUP | libc::POLLERR) != 0 {
+                channel.subscribers.retain(|subscriber| subscriber.id != id);
+            }
+        }
+        let now = Instant::now();
+        next_heartbeat = match channel.heartbeat {
+            Some(interval) => match next_heartbeat {
+                Some(next) if next > now => Some(next),
+                Some(_) => {
+                    channel.send(": heartbeat\n\n");
+                    Some(now + interval)
+                }
+                None => Some(now + interval),
+            },
+            None => None,
+        };
+
+        channel.subscribers.retain_mut(Subscriber::write);
+        fds.clear();
+        let mut timeout = next_heartbeat.map_or(Duration::MAX, |next| next - now);
+        for subscriber in &channel.subscribers {
+            if subscriber.has_output() {
+                let stalled = WRITE_TIMEOUT.saturating_sub(subscriber.waiting_since.elapsed());
+                timeout = timeout.min(match subscriber.fd {
+                    Some(_) => stalled,
+                    None => stalled.min(RETRY_INTERVAL),
+                });
+            }
+            if let Some(fd) = subscriber.fd {
+                let mut events = libc::POLLRDHUP;
+                if subscriber.has_output() {
+                    events |= libc::POLLOUT;
+                }
+                fds.push(libc::pollfd {
+                    fd,
+                    events,
+                    revents: 0,
+                });
+                polled.push(subscriber.id);
+            }
+        }
+        drop(channel);
+
+        if let Err(e) = shared.waker.wait_all(&mut fds, timeout) {
+            println!("Event stream writer failed. {e}");
+            thread::sleep(RETRY_INTERVAL);
+        }
+    }
+}
+
+/// Answers requests with a `text/event-stream` response and hands the
+/// connection to its `Broadcaster`.
+#[derive(Clone)]
+pub struct EventStream {
+    path: String,
+    broadcaster: Arc<Broadcaster>,
+    retry: Option<u64>,
+}
+
+impl EventStream {
+    pub fn new(path: &str, broadcaster: Arc<Broadcaster>) -> EventStream {
+        EventStream {
+            path: path.to_owned(),
+            broadcaster,
+            retry: None,
+        }
+    }
+
+    /// Builds the stream for an `[[sse]]` table and starts its heartbeat.
+    pub fn from_settings(settings: &settings::Sse) -> EventStream {
+        let broadcaster = Arc::new(Broadcaster::new(settings.history));
+        if settings.heartbeat > 0 {
+            broadcaster.start_heartbeat(Duration::from_secs(settings.heartbeat));
+        }
+        let mut stream = EventStream::new(&settings.path, broadcaster);
+        stream.retry = settings.retry;
+        stream
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn broadcaster(&self) -> &Arc<Broadcaster> {
+        &self.broadcaster
+    }
+
+    /// Milliseconds clients should wait before reconnecting.
+    pub fn retry(mut self, retry: u64) -> EventStream {
+        self.retry = Some(retry);
+        self
+    }
+}
+
+impl Handler for EventStream {
+    fn handle(&self, request: &Request) -> Response {
+        let broadcaster = self.broadcaster.clone();
+        let last_event_id = request.header("Last-Event-ID").map(str::to_owned);
+        let retry = self.retry;
+        Response::new(200)
+            .with_header("Content-Type", "text/event-stream")
+            .with_header("Cache-Control", "no-cache")
+            .with_upgrade(move |mut stream| {
+                if let Some(retry) = retry {
+                    if stream
+                        .write_all(format!("retry: {retry}\n\n").as_bytes())
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+                broadcaster.subscribe(stream, last_event_id.as_deref());
+            })
+    }
+}
+
+/// Publishes an event from the IPC `publish` command, given as
+/// `<path> [event=<name>] <data>`.
+pub fn publish(streams: &[EventStream], args: &str) -> String {
+    let (path, rest) = args.split_once(' ').unwrap_or((args, ""));
+    let stream = match streams.iter().find(|stream| stream.path == path) {
+        Some(stream) => stream,
+        None => return format!("No event stream at {path}."),
+    };
+
+    let rest = rest.trim_start();
+    let event = match rest.strip_prefix("event=") {
+        Some(rest) => {
+            let (name, data) = rest.split_once(' ').unwrap_or((rest, ""));
+            Event::new(data).with_event(name)
+        }
+        None => Event::new(rest),
+    };
+    let received = stream.broadcaster.publish(&event);
+    format!("Published to {received} subscribers of {path}.")
+}
@@ -1,56 +1,85 @@
 use std::{
-    io::{BufRead, BufReader, ErrorKind, Write},
+    io::ErrorKind,
     net::{TcpListener, TcpStream},
     sync::{
         atomic::{AtomicBool, Ordering::Relaxed},
         Arc,
     },
     thread,
+    time::Duration,
 };
 
-use crate::{request_handler::RequestHandler, settings::Http, thread_pool::ThreadPool};
-
-type RequestHandlerFn = Arc<dyn Fn(String) -> String + Send + Sync>;
+use crate::{
+    connection::{self, Peer},
+    event_loop::{EventLoop, Mode, Protocol},
+    handler::SharedHandler,
+    http2::{self, Executor},
+    request_handler::RequestHandler,
+    settings::Http,
+    thread_pool::ThreadPool,
+    waker::{Waker, ACCEPT_BACKOFF},
+};
 
 pub struct TcpServer {
     ip: String,
     port: u16,
 
-    request_handler: RequestHandlerFn,
+    handler: SharedHandler,
+    h2c: bool,
+    h2c_upgrade: bool,
+    mode: Mode,
 
     handle: Option<thread::JoinHandle<()>>,
     thread_pool: Arc<ThreadPool>,
 
     running: Arc<AtomicBool>,
+    waker: Arc<Waker>,
 }
 
 impl TcpServer {
+    /// Creates a server serving static files through `rq_handler_obj`, or
+    /// redirecting every request if `http.redirect` is set.
     pub fn new(
         ip: String,
         settings_http: &Http,
         rq_handler_obj: Arc<RequestHandler>,
     ) -> Result<Self, String> {
-        let thread_pool = ThreadPool::new(settings_http.threads);
-        let thread_pool = Arc::new(thread_pool);
+        let handler = RequestHandler::for_listener(
+            rq_handler_obj.clone(),
+            rq_handler_obj,
+            settings_http.redirect.clone(),
+        );
+        Self::with_handler(ip, settings_http, handler)
+    }
 
-        let request_handler: RequestHandlerFn = if let Some(destination) =
-            settings_http.redirect.clone()
-        {
-            Arc::new(move |request: String| rq_handler_obj.redirect(request, destination.clone()))
-        } else {
-            Arc::new(move |request: String| rq_handler_obj.handle(request))
-        };
+    /// Creates a server running `handler` for every request, e.g. a `Router`.
+    pub fn with_handler(
+        ip: String,
+        settings_http: &Http,
+        handler: SharedHandler,
+    ) -> Result<Self, String> {
+        let thread_pool = ThreadPool::new(
+            settings_http.min_threads.unwrap_or(settings_http.threads),
+            settings_http.max_threads.unwrap_or(settings_http.threads),
+            Duration::from_secs(settings_http.idle_timeout),
+            settings_http.queue_size,
+        );
+        let thread_pool = Arc::new(thread_pool);
 
         Ok(TcpServer {
             ip,
             port: settings_http.port,
 
-            request_handler,
+            handler,
+            h2c: settings_http.h2c,
+            h2c_upgrade: settings_http.h2c_upgrade,
+            mode: settings_http.mode,
 
             handle: None,
             thread_pool,
 
             running: Arc::new(AtomicBool::new(false)),
+            waker: Arc::new(Waker::new()?),
         })
     }
 
@@ -58,21 +87,37 @@ impl TcpServer {
         let ip = self.ip.clone();
         let port = self.port;
 
-        let request_handler = self.request_handler.clone();
+        let handler = self.handler.clone();
         let thread_pool = self.thread_pool.clone();
 
         self.running.store(true, Relaxed);
         let running = self.running.clone();
 
+        let handler = if self.h2c_upgrade {
+            let executor = Executor::Pool(thread_pool.clone());
+            http2::upgrade_handler(handler, executor, running.clone())
+        } else {
+            handler
+        };
+        let h2c = self.h2c;
+        let mode = self.mode;
+        let waker = self.waker.clone();
+
         println!("Starting TcpServer thread on {ip}:{port}");
-        self.handle = Some(thread::spawn(move || {
-            Self::run(ip, port, request_handler, thread_pool, running);
+        self.handle = Some(thread::spawn(move || match mode {
+            Mode::Threaded => Self::run(ip, port, handler, h2c, thread_pool, running, waker),
+            Mode::Evented => Self::run_evented(ip, port, handler, h2c, thread_pool, running, waker),
         }));
     }
 
+    /// The pool running this server's connections, e.g. to watch its queue.
+    pub fn thread_pool(&self) -> Arc<ThreadPool> {
+        self.thread_pool.clone()
+    }
+
     pub fn join_thread(&mut self) {
         if let Some(handle) = self.handle.take() {
-            if let Err(_) = handle.join() {
+            if handle.join().is_err() {
                 println!("Error joining TcpServer thread.");
             }
         }
@@ -81,54 +126,100 @@ impl TcpServer {
     fn run(
         ip: String,
         port: u16,
-        request_handler: RequestHandlerFn,
+        handler: SharedHandler,
+        h2c: bool,
         thread_pool: Arc<ThreadPool>,
         running: Arc<AtomicBool>,
+        waker: Arc<Waker>,
     ) {
-        let listener = TcpListener::bind(format!("{}:{}", ip, port))
-            .expect(format!("Failed to bind TcpListener to {ip}:{port}").as_str());
-        listener
-            .set_nonblocking(true)
-            .expect("Failed to set nonblocking TcpListener.");
+        let listener = Self::bind(&ip, port);
 
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    let request_handler = request_handler.clone();
-                    thread_pool.execute(Box::new(move || {
-                        println!("TcpServer recieved new connection.");
-                        Self::handle_client(stream, request_handler);
-                    }));
-                }
-                Err(e) => match e.kind() {
-                    ErrorKind::WouldBlock => {}
-                    e => {
+        while running.load(Relaxed) {
+            if let Err(e) = waker.wait(&listener) {
+                println!("Caught Error in TcpServer: {}. Not Handeled.", e);
+                break;
+            }
+            // Accepts every pending connection, the listener is nonblocking.
+            loop {
+                match listener.accept() {
+                    Ok((stream, _)) if thread_pool.is_full() => {
+                        println!("Thread pool is full, turning client away.");
+                        connection::send(stream, connection::overloaded(), Vec::new());
+                    }
+                    Ok((stream, _)) => {
+                        let handler = handler.clone();
+                        let executor = Executor::Pool(thread_pool.clone());
+                        let running = running.clone();
+                        thread_pool.execute(Box::new(move || {
+                            println!("TcpServer recieved new connection.");
+                            Self::handle_client(stream, handler, executor, h2c, &running);
+                        }));
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) => {
                         println!("Caught Error in TcpServer: {}. Not Handeled.", e);
+                        thread::sleep(ACCEPT_BACKOFF);
+                        break;
                     }
-                },
-            }
-            thread::sleep(std::time::Duration::from_millis(80));
-            if !running.load(Relaxed) {
-                break;
+                }
             }
         }
 
         println!("TcpServer thread exited cleanly.");
     }
 
-    fn handle_client(mut stream: TcpStream, request_handler: RequestHandlerFn) {
-        let mut reader = BufReader::new(&mut stream);
-        let received = reader.fill_buf().unwrap().to_vec();
-        reader.consume(received.len());
-        let request = String::from_utf8_lossy(&received).to_string();
-        if !request.ends_with("\r\n\r\n") {
-            return; // Internal Server Error or smthn
+    /// Serves connections from an event loop on this thread, running only
+    /// handlers on the thread pool.
+    fn run_evented(
+        ip: String,
+        port: u16,
+        handler: SharedHandler,
+        h2c: bool,
+        thread_pool: Arc<ThreadPool>,
+        running: Arc<AtomicBool>,
+        waker: Arc<Waker>,
+    ) {
+        let listener = Self::bind(&ip, port);
+        let protocol = Protocol::Http {
+            handler,
+            h2c,
+            thread_pool,
+        };
+        let served = EventLoop::new(protocol, running, waker)
+            .and_then(|event_loop| event_loop.run(&listener));
+        if let Err(e) = served {
+            println!("Caught Error in TcpServer: {}. Not Handeled.", e);
         }
-        let response = request_handler(request);
-        match stream.write_all(response.as_bytes()) {
-            Ok(_) => println!("Sent response."),
-            Err(e) => println!("Failed to send response. {e:?}")
+
+        println!("TcpServer thread exited cleanly.");
+    }
+
+    fn bind(ip: &str, port: u16) -> TcpListener {
+        let listener = TcpListener::bind(format!("{}:{}", ip, port))
+            .unwrap_or_else(|e| panic!("Failed to bind TcpListener to {ip}:{port}: {e}"));
+        listener
+            .set_nonblocking(true)
+            .expect("Failed to set nonblocking TcpListener.");
+        listener
+    }
+
+    fn handle_client(
+        stream: TcpStream,
+        handler: SharedHandler,
+        executor: Executor,
+        h2c: bool,
+        running: &AtomicBool,
+    ) {
+        let peer = Peer {
+            remote_addr: stream.peer_addr().ok(),
+            ..Peer::default()
         };
+        if h2c && http2::has_preface(&stream) {
+            let _ = stream.set_nodelay(true);
+            http2::serve(stream, handler, executor, peer, running);
+        } else {
+            connection::handle(stream, handler.as_ref(), peer);
+        }
     }
 }
 
@@ -136,6 +227,7 @@ impl Drop for TcpServer {
     fn drop(&mut self) {
         println!("Sending terminate message to TcpServer.");
         self.running.store(false, Relaxed);
+        self.waker.wake();
         self.join_thread();
         println!("TcpServer shut down.");
     }
@@ -1,98 +1,357 @@
 use std::{
+    collections::HashMap,
+    mem,
+    panic::{catch_unwind, AssertUnwindSafe},
     sync::{
-        mpsc::{channel, Receiver, Sender},
-        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering::Relaxed},
+        Arc, Mutex, MutexGuard, PoisonError,
     },
     thread,
+    time::Duration,
 };
 
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender};
+
 type Job = Box<dyn FnOnce() + Send>;
 
 enum Message {
     NewJob(Job),
+    /// Stops the receiving worker if the pool has more than `max` workers.
+    /// Only sent to idle workers, and counted in `retiring` while it waits.
+    Retire,
     Terminate,
 }
 
+/// Runs jobs on between `min` and `max` worker threads. Workers are started
+/// while jobs wait for one, and those above `min` stop after waiting
+/// `idle_timeout` for a job. Workers take jobs from a lock-free queue, so
+/// handing out jobs doesn't serialize them.
 pub struct ThreadPool {
-    workers: Vec<Worker>,
     sender: Sender<Message>,
+    queue_size: usize,
+    shared: Arc<Shared>,
 }
 
-impl ThreadPool {
-    pub fn new(limit: usize) -> ThreadPool {
-        let (sender, receiver) = channel();
-        let receiver = Arc::new(Mutex::new(receiver));
+/// What the workers share with their pool.
+struct Shared {
+    receiver: Receiver<Message>,
+    /// Jobs waiting for a worker.
+    queued: AtomicUsize,
+    /// `Retire` messages waiting for an idle worker, which then won't take a job.
+    retiring: AtomicUsize,
+    /// Workers waiting for a job.
+    idle: AtomicUsize,
+    /// Running workers, kept in step with `workers` for reading it without
+    /// the lock.
+    threads: AtomicUsize,
+    min: AtomicUsize,
+    max: AtomicUsize,
+    idle_timeout: Duration,
+    /// Only locked to start or stop workers.
+    workers: Mutex<Workers>,
+}
 
-        let mut workers = Vec::with_capacity(limit);
-        for _ in 0..limit {
-            workers.push(Worker::new(receiver.clone()));
-        }
+struct Workers {
+    /// The thread of each running worker by id.
+    handles: HashMap<usize, thread::JoinHandle<()>>,
+    next_id: usize,
+}
 
-        ThreadPool { workers, sender }
+impl ThreadPool {
+    /// Starts `min` workers, growing to `max` under load, with at most
+    /// `queue_size` jobs waiting for one.
+    pub fn new(min: usize, max: usize, idle_timeout: Duration, queue_size: usize) -> ThreadPool {
+        let queue_size = queue_size.max(1);
+        let (sender, receiver) = bounded(queue_size);
+        let shared = Arc::new(Shared {
+            receiver,
+            queued: AtomicUsize::new(0),
+            retiring: AtomicUsize::new(0),
+            idle: AtomicUsize::new(0),
+            threads: AtomicUsize::new(0),
+            min: AtomicUsize::new(0),
+            max: AtomicUsize::new(0),
+            idle_timeout,
+            workers: Mutex::new(Workers {
+                handles: HashMap::new(),
+                next_id: 0,
+            }),
+        });
+
+        let pool = ThreadPool {
+            sender,
+            queue_size,
+            shared,
+        };
+        pool.resize(min, max);
+        pool
     }
 
+    /// Queues `job` for the next free worker, blocking while the queue is full.
     pub fn execute(&self, job: Job) {
+        let queued = self.shared.queued.fetch_add(1, Relaxed) + 1;
+        if queued > self.shared.available() && self.threads() < self.max_threads() {
+            let mut workers = self.shared.workers();
+            if workers.handles.len() < self.max_threads() {
+                self.shared.spawn(&mut workers);
+            }
+        }
         self.sender.send(Message::NewJob(job)).unwrap();
     }
+
+    /// Queues `job` only if a worker is idle or can be started for it, so it
+    /// never waits behind busy workers. Returns whether it was queued.
+    pub fn try_execute(&self, job: Job) -> bool {
+        let queued = self.shared.queued.fetch_add(1, Relaxed) + 1;
+        if queued > self.shared.available() {
+            let mut workers = self.shared.workers();
+            if workers.handles.len() >= self.max_threads() {
+                drop(workers);
+                self.shared.queued.fetch_sub(1, Relaxed);
+                return false;
+            }
+            self.shared.spawn(&mut workers);
+        }
+        if self.sender.try_send(Message::NewJob(job)).is_err() {
+            self.shared.queued.fetch_sub(1, Relaxed);
+            return false;
+        }
+        true
+    }
+
+    /// Changes the bounds of the pool, starting workers up to `min` right
+    /// away while idle workers above `max` stop.
+    pub fn resize(&self, min: usize, max: usize) {
+        let max = max.max(min).max(1);
+        let mut workers = self.shared.workers();
+        self.shared.min.store(min, Relaxed);
+        self.shared.max.store(max, Relaxed);
+        while workers.handles.len() < min {
+            self.shared.spawn(&mut workers);
+        }
+        let excess = workers.handles.len().saturating_sub(max);
+        drop(workers);
+        // Busy workers stop once done. Idle ones are asked to now, or stop
+        // after their idle timeout if the queue has no room to ask them.
+        for _ in 0..excess.min(self.shared.available()) {
+            self.shared.retiring.fetch_add(1, Relaxed);
+            if self.sender.try_send(Message::Retire).is_err() {
+                self.shared.retiring.fetch_sub(1, Relaxed);
+            }
+        }
+    }
+
+    /// Number of jobs waiting for a worker.
+    pub fn queued(&self) -> usize {
+        self.shared.queued.load(Relaxed)
+    }
+
+    pub fn queue_size(&self) -> usize {
+        self.queue_size
+    }
+
+    /// Whether `execute` would block, for listeners to turn clients away instead.
+    pub fn is_full(&self) -> bool {
+        self.queued() >= self.queue_size
+    }
+
+    /// Number of running workers.
+    pub fn threads(&self) -> usize {
+        self.shared.threads.load(Relaxed)
+    }
+
+    /// Number of workers waiting for a job.
+    pub fn idle(&self) -> usize {
+        self.shared.idle.load(Relaxed).min(self.threads())
+    }
+
+    /// Number of workers running a job.
+    pub fn active(&self) -> usize {
+        self.threads().saturating_sub(self.idle())
+    }
+
+    pub fn min_threads(&self) -> usize {
+        self.shared.min.load(Relaxed)
+    }
+
+    pub fn max_threads(&self) -> usize {
+        self.shared.max.load(Relaxed)
+    }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
+        // Workers stopping meanwhile find themselves gone and leave quietly.
+        let handles = mem::take(&mut self.shared.workers().handles);
+        self.shared.threads.store(0, Relaxed);
         // println!("Sending terminate message to all workers.");
-        for _ in &self.workers {
-            match self.sender.send(Message::Terminate) {
-                Err(e) => println!("Failed to send terminate message to worker. {e:?}"),
-                _ => {}
-            };
+        for _ in 0..handles.len() {
+            if let Err(e) = self.sender.send(Message::Terminate) {
+                println!("Failed to send terminate message to worker. {e:?}");
+            }
         }
 
-        for worker in &mut self.workers {
-            if let Some(handle) = worker.handle.take() {
-                match handle.join() {
-                    Err(e) => println!("Error joining worker thread. {e:?}"),
-                    _ => {}
-                };
+        for handle in handles.into_values() {
+            if let Err(e) = handle.join() {
+                println!("Error joining worker thread. {e:?}");
             }
         }
         println!("All workers shut down.");
     }
 }
 
+impl Shared {
+    fn workers(&self) -> MutexGuard<'_, Workers> {
+        self.workers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Idle workers not about to take a `Retire` message.
+    fn available(&self) -> usize {
+        self.idle
+            .load(Relaxed)
+            .saturating_sub(self.retiring.load(Relaxed))
+    }
+
+    fn spawn(self: &Arc<Self>, workers: &mut Workers) {
+        let id = workers.next_id;
+        workers.next_id += 1;
+        workers.handles.insert(id, Worker::spawn(id, self.clone()));
+        self.threads.store(workers.handles.len(), Relaxed);
+    }
+}
+
 struct Worker {
-    handle: Option<thread::JoinHandle<()>>,
+    id: usize,
+    shared: Arc<Shared>,
 }
 
 impl Worker {
-    fn new(receiver: Arc<Mutex<Receiver<Message>>>) -> Self {
-        let handle = thread::spawn(move || loop {
-            let receiver = match receiver.lock() {
-                Ok(receiver) => receiver,
-                Err(_) => {
-                    println!("Couldn't lock thread for worker.");
-                    continue;
+    fn spawn(id: usize, shared: Arc<Shared>) -> thread::JoinHandle<()> {
+        thread::spawn(move || Worker { id, shared }.run())
+    }
+
+    fn run(&self) {
+        loop {
+            self.shared.idle.fetch_add(1, Relaxed);
+            let received = self.shared.receiver.recv_timeout(self.shared.idle_timeout);
+            self.shared.idle.fetch_sub(1, Relaxed);
+            match received {
+                Ok(Message::NewJob(job)) => {
+                    self.shared.queued.fetch_sub(1, Relaxed);
+                    if catch_unwind(AssertUnwindSafe(job)).is_err() {
+                        println!("Job panicked, worker {} continues.", self.id);
+                    }
+                    let shared = &self.shared;
+                    if shared.threads.load(Relaxed) > shared.max.load(Relaxed)
+                        && self.retire(&shared.max)
+                    {
+                        break;
+                    }
                 }
-            };
-            let message = match receiver.recv() {
-                Ok(message) => message,
-                Err(_) => {
-                    println!("Couldn't receive message for worker.");
-                    continue;
+                Ok(Message::Retire) => {
+                    self.shared.retiring.fetch_sub(1, Relaxed);
+                    if self.retire(&self.shared.max) {
+                        break;
+                    }
                 }
-            };
-            match message {
-                Message::NewJob(job) => {
-                    println!("Worker got a job.");
-                    job();
-                    println!("Job done.");
+                Err(RecvTimeoutError::Timeout) => {
+                    if self.retire(&self.shared.min) {
+                        break;
+                    }
                 }
-                Message::Terminate => {
+                Ok(Message::Terminate) | Err(RecvTimeoutError::Disconnected) => {
                     break;
                 }
             }
-        });
+        }
+    }
+
+    /// Removes the worker from the pool if it has more workers than `limit`,
+    /// returning whether it should stop.
+    fn retire(&self, limit: &AtomicUsize) -> bool {
+        let mut workers = self.shared.workers();
+        if workers.handles.len() <= limit.load(Relaxed) {
+            return false;
+        }
+        workers.handles.remove(&self.id);
+        self.shared.threads.store(workers.handles.len(), Relaxed);
+        true
+    }
+}
+
+/// Starts a replacement if the worker's thread dies anyway, from a panic
+/// outside of a job.
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            return;
+        }
+        let mut workers = self.shared.workers();
+        if let Some(handle) = workers.handles.get_mut(&self.id) {
+            println!("Worker {} died, starting a new one.", self.id);
+            *handle = Worker::spawn(self.id, self.shared.clone());
+        }
+    }
+}
+
+/// Describes the queue of each named pool, for the `queues` IPC command.
+pub fn status(pools: &[(&str, Arc<ThreadPool>)]) -> String {
+    if pools.is_empty() {
+        return "No servers running.".to_owned();
+    }
+    pools
+        .iter()
+        .map(|(name, pool)| {
+            format!(
+                "{name}: {} of {} queued\n",
+                pool.queued(),
+                pool.queue_size()
+            )
+        })
+        .collect()
+}
+
+/// Describes the workers of each named pool, for the `threads` IPC command.
+pub fn workers(pools: &[(&str, Arc<ThreadPool>)]) -> String {
+    if pools.is_empty() {
+        return "No servers running.".to_owned();
+    }
+    pools
+        .iter()
+        .map(|(name, pool)| {
+            format!(
+                "{name}: {} active, {} idle, {} to {} threads\n",
+                pool.active(),
+                pool.idle(),
+                pool.min_threads(),
+                pool.max_threads()
+            )
+        })
+        .collect()
+}
 
-        Worker {
-            handle: Some(handle),
+/// Resizes a named pool with `args` of the form `<name> <min> <max>`, for the
+/// `resize` IPC command.
+pub fn resize(pools: &[(&str, Arc<ThreadPool>)], args: &str) -> String {
+    let usage = "Usage: resize <name> <min> <max>";
+    let mut args = args.split_whitespace();
+    let (Some(name), Some(min), Some(max), None) =
+        (args.next(), args.next(), args.next(), args.next())
+    else {
+        return usage.to_owned();
+    };
+    let (Ok(min), Ok(max)) = (min.parse(), max.parse()) else {
+        return usage.to_owned();
+    };
+    match pools.iter().find(|(pool_name, _)| *pool_name == name) {
+        Some((_, pool)) => {
+            pool.resize(min, max);
+            format!(
+                "Resized {name} to {} to {} threads.",
+                pool.min_threads(),
+                pool.max_threads()
+            )
         }
+        None => format!("No server named {name}."),
     }
 }
@@ -1,191 +1,806 @@
-use native_tls::{Identity, TlsAcceptor, TlsStream};
+use serde::Deserialize;
 
 use std::{
-    fs::read,
-    io::{BufRead, BufReader, ErrorKind, Write},
+    fmt,
+    fs::metadata,
+    io::ErrorKind,
     net::{TcpListener, TcpStream},
     sync::{
         atomic::{AtomicBool, Ordering::Relaxed},
-        Arc,
+        Arc, RwLock, Weak,
     },
     thread,
+    time::{Duration, Instant, SystemTime},
 };
 
-use crate::{request_handler::RequestHandler, settings::Https, thread_pool::ThreadPool};
+use crate::{
+    connection::{self, Peer},
+    event_loop::{self, EventLoop, Mode, Parker, Parsed, Protocol, RequestParser, TIMEOUT},
+    handler::{catch_panic, SharedHandler},
+    http::{decode_path, ClientCertificate, Request, Response, Stream},
+    http2::{self, Executor},
+    middleware::Chain,
+    request_handler::RequestHandler,
+    settings::{ClientAuth, Https, TlsPolicy, SSL},
+    sni::{self, Certificates},
+    thread_pool::ThreadPool,
+    waker::{Waker, ACCEPT_BACKOFF},
+};
+
+#[cfg(not(any(feature = "openssl", feature = "rustls", feature = "native-tls")))]
+compile_error!("Enable the openssl, rustls or native-tls feature to select a TLS backend.");
+
+#[cfg(all(
+    feature = "native-tls",
+    not(any(feature = "openssl", feature = "rustls"))
+))]
+mod native_tls_backend;
+#[cfg(all(
+    feature = "native-tls",
+    not(any(feature = "openssl", feature = "rustls"))
+))]
+pub use native_tls_backend::Acceptor;
+
+#[cfg(all(feature = "openssl", not(feature = "rustls")))]
+mod openssl_backend;
+#[cfg(all(feature = "openssl", not(feature = "rustls")))]
+pub use openssl_backend::Acceptor;
+
+#[cfg(feature = "rustls")]
+mod rustls_backend;
+#[cfg(feature = "rustls")]
+pub use rustls_backend::Acceptor;
+
+/// A connection accepted by the selected backend.
+type Connection = <Acceptor as TlsAcceptor>::Connection;
+
+/// An HTTP/1.1 request on a TLS connection, read as it arrives.
+struct PendingRequest {
+    stream: Connection,
+    peer: Peer,
+    buffer: Vec<u8>,
+    parser: RequestParser,
+    /// When the connection is closed if the request is still incomplete.
+    deadline: Instant,
+}
+
+/// The TLS configuration for one certificate, implemented by each backend.
+trait TlsAcceptor: Sized {
+    type Connection: TlsConnection;
+
+    /// Builds the acceptor and returns it with the DNS names the certificate
+    /// is valid for. With `http2` set, `h2` is offered through ALPN.
+    fn from_settings(
+        ssl: &SSL,
+        policy: &TlsPolicy,
+        client_auth: &ClientAuth,
+        http2: bool,
+    ) -> Result<(Self, Vec<String>), String>;
+
+    /// Runs the server side of the handshake on `stream`.
+    fn handshake(&self, stream: TcpStream) -> Result<Self::Connection, String>;
+
+    #[cfg(feature = "async")]
+    type AsyncConnection: AsyncTlsConnection;
+
+    /// Runs the server side of the handshake on a tokio `stream`.
+    #[cfg(feature = "async")]
+    async fn handshake_async(
+        &self,
+        stream: tokio::net::TcpStream,
+    ) -> Result<Self::AsyncConnection, String>;
+}
+
+/// An established TLS connection.
+trait TlsConnection: Stream {
+    fn tcp_stream(&self) -> &TcpStream;
+
+    /// The certificate the client sent, if it was verified against the CA bundle.
+    fn client_certificate(&self) -> Option<ClientCertificate>;
+
+    /// The protocol agreed on through ALPN, e.g. `h2`.
+    fn alpn_protocol(&self) -> Option<Vec<u8>>;
+}
+
+/// An established TLS connection driven by tokio.
+#[cfg(feature = "async")]
+pub(crate) trait AsyncTlsConnection:
+    tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static
+{
+    fn tcp_stream(&self) -> &tokio::net::TcpStream;
 
-type RequestHandlerFn = Arc<dyn Fn(String) -> String + Send + Sync>;
+    /// The certificate the client sent, if it was verified against the CA bundle.
+    fn client_certificate(&self) -> Option<ClientCertificate>;
+
+    /// The protocol agreed on through ALPN, e.g. `h2`.
+    fn alpn_protocol(&self) -> Option<Vec<u8>>;
+}
+
+/// Runs the handshake of `acceptor` on a tokio `stream`.
+#[cfg(feature = "async")]
+pub(crate) async fn handshake_async(
+    acceptor: &Acceptor,
+    stream: tokio::net::TcpStream,
+) -> Result<impl AsyncTlsConnection, String> {
+    acceptor.handshake_async(stream).await
+}
+
+/// Protocols offered through ALPN, most preferred first.
+const ALPN_PROTOCOLS: [&[u8]; 2] = [b"h2", b"http/1.1"];
+
+/// How long a client may take to send its ClientHello.
+pub(crate) const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    #[serde(rename = "1.0")]
+    Tls10,
+    #[serde(rename = "1.1")]
+    Tls11,
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl fmt::Display for TlsVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TlsVersion::Tls10 => "TLS 1.0",
+            TlsVersion::Tls11 => "TLS 1.1",
+            TlsVersion::Tls12 => "TLS 1.2",
+            TlsVersion::Tls13 => "TLS 1.3",
+        })
+    }
+}
+
+/// Whether clients are asked for a certificate during the handshake.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuthMode {
+    #[default]
+    Off,
+    /// Clients may send a certificate, which must then be valid.
+    Optional,
+    /// Handshakes without a valid client certificate fail.
+    Required,
+}
 
 pub struct TlsServer {
     ip: String,
     port: u16,
 
-    acceptor: Arc<TlsAcceptor>,
-    request_handler: RequestHandlerFn,
+    reloader: CertificateReloader,
+    handler: SharedHandler,
 
     handle: Option<thread::JoinHandle<()>>,
     thread_pool: Arc<ThreadPool>,
 
     running: Arc<AtomicBool>,
+    waker: Arc<Waker>,
 }
 
 impl TlsServer {
+    /// Creates a server serving static files through `rq_handler_obj`, or
+    /// redirecting every request if `https.redirect` is set.
     pub fn new(
         ip: String,
         settings_https: &Https,
         rq_handler_obj: Arc<RequestHandler>,
     ) -> Result<Self, String> {
-        let identity = match Identity::from_pkcs12(
-            match &read(&settings_https.ssl.identity) {
-                Ok(identity) => identity,
-                Err(e) => {
-                    return Result::Err(format!(
-                        "Could not read identity file {} for TlsServer: {e}",
-                        &settings_https.ssl.identity
-                    ));
-                }
-            },
-            &settings_https.ssl.password,
-        ) {
-            Ok(identity) => identity,
-            Err(e) => {
-                return Result::Err(format!(
-                    "Could not create identity for TlsServer. Got error: {e}"
-                ));
-            }
-        };
+        let handler = RequestHandler::for_listener(
+            rq_handler_obj.clone(),
+            rq_handler_obj,
+            settings_https.redirect.clone(),
+        );
+        Self::with_handler(ip, settings_https, handler)
+    }
 
-        let acceptor = match TlsAcceptor::new(identity) {
-            Ok(acceptor) => acceptor,
-            Err(e) => {
-                return Result::Err(format!(
-                    "Could not create TlsAcceptor for TlsServer. Got error: {e}"
-                ));
-            }
-        };
-        let acceptor = Arc::new(acceptor);
+    /// Creates a server running `handler` for every request, e.g. a `Router`.
+    pub fn with_handler(
+        ip: String,
+        settings_https: &Https,
+        handler: SharedHandler,
+    ) -> Result<Self, String> {
+        Self::with_chain(ip, settings_https, Chain::new(handler))
+    }
 
-        let thread_pool = ThreadPool::new(settings_https.threads);
-        let thread_pool = Arc::new(thread_pool);
+    /// Creates a server running `chain` for every request. Requests failing
+    /// the certificate checks are answered inside the chain's middleware.
+    pub fn with_chain(ip: String, settings_https: &Https, chain: Chain) -> Result<Self, String> {
+        let reloader = CertificateReloader::from_settings(settings_https)?;
+        let handler =
+            Arc::new(chain.wrap_handler(|handler| Self::secure_handler(handler, reloader.clone())));
 
-        let request_handler: RequestHandlerFn = if let Some(destination) =
-            settings_https.redirect.clone()
-        {
-            Arc::new(move |request: String| rq_handler_obj.redirect(request, destination.clone()))
-        } else {
-            Arc::new(move |request: String| rq_handler_obj.handle(request))
-        };
+        let thread_pool = ThreadPool::new(
+            settings_https.min_threads.unwrap_or(settings_https.threads),
+            settings_https.max_threads.unwrap_or(settings_https.threads),
+            Duration::from_secs(settings_https.idle_timeout),
+            settings_https.queue_size,
+        );
+        let thread_pool = Arc::new(thread_pool);
 
         Ok(TlsServer {
             ip,
             port: settings_https.port,
 
-            acceptor,
-            request_handler,
+            reloader,
+            handler,
 
             handle: None,
             thread_pool,
 
             running: Arc::new(AtomicBool::new(false)),
+            waker: Arc::new(Waker::new()?),
         })
     }
 
+    /// Handle for replacing the certificates while the server is running.
+    pub fn reloader(&self) -> CertificateReloader {
+        self.reloader.clone()
+    }
+
+    /// Builds an acceptor for every `[[https.certificates]]` entry, keyed by
+    /// its hostnames, with `[https.ssl]` or else the first entry as default.
+    fn load_certificates(settings_https: &Https) -> Result<Certificates, String> {
+        let client_auth = &settings_https.client_auth;
+        match (client_auth.mode, &client_auth.ca) {
+            (ClientAuthMode::Off, _) if !client_auth.paths.is_empty() => {
+                return Err(
+                    "client_auth.paths need client_auth.mode optional or required.".to_owned(),
+                )
+            }
+            (ClientAuthMode::Optional | ClientAuthMode::Required, None) => {
+                return Err("client_auth.ca is needed to verify client certificates.".to_owned())
+            }
+            _ => {}
+        }
+
+        let mut loaded = Vec::new();
+        for certificate in &settings_https.certificates {
+            let policy = match &settings_https.ssl {
+                Some(ssl) => certificate.ssl.policy.or(&ssl.policy),
+                None => certificate.ssl.policy.clone(),
+            };
+            let (acceptor, names) = Acceptor::from_settings(
+                &certificate.ssl,
+                &policy,
+                client_auth,
+                settings_https.http2,
+            )?;
+            let hostnames = if certificate.hostnames.is_empty() {
+                names
+            } else {
+                certificate.hostnames.clone()
+            };
+            if hostnames.is_empty() {
+                return Err(
+                    "Could not find a hostname for a certificate, set hostnames.".to_owned(),
+                );
+            }
+            loaded.push((hostnames, Arc::new(acceptor)));
+        }
+
+        let default = match (&settings_https.ssl, loaded.first()) {
+            (Some(ssl), _) => Arc::new(
+                Acceptor::from_settings(ssl, &ssl.policy, client_auth, settings_https.http2)?.0,
+            ),
+            (None, Some((_, acceptor))) => acceptor.clone(),
+            (None, None) => return Err("No certificate configured for TlsServer.".to_owned()),
+        };
+        let mut certificates = Certificates::new(default);
+        for (hostnames, acceptor) in loaded {
+            println!("Using certificate for {}.", hostnames.join(", "));
+            for hostname in hostnames {
+                certificates.add(&hostname, acceptor.clone());
+            }
+        }
+        Ok(certificates)
+    }
+
     pub fn start_thread(&mut self) {
         let ip = self.ip.clone();
         let port = self.port;
 
-        let acceptor = self.acceptor.clone();
-        let request_handler = self.request_handler.clone();
+        let reloader = self.reloader.clone();
+        let handler = self.handler.clone();
 
         let thread_pool = self.thread_pool.clone();
 
         self.running.store(true, Relaxed);
         let running = self.running.clone();
+        let waker = self.waker.clone();
 
         println!("Starting TlsServer thread on {ip}:{port}.");
         self.handle = Some(thread::spawn(move || {
-            Self::run(ip, port, acceptor, request_handler, thread_pool, running);
+            Self::run(ip, port, reloader, handler, thread_pool, running, waker);
         }));
     }
 
+    /// The pool running this server's connections, e.g. to watch its queue.
+    pub fn thread_pool(&self) -> Arc<ThreadPool> {
+        self.thread_pool.clone()
+    }
+
     pub fn join_thread(&mut self) {
         if let Some(handle) = self.handle.take() {
-            match handle.join() {
-                Err(e) => println!("Error joining TlsServer thread. {e:?}"),
-                _ => {}
-            };
+            if let Err(e) = handle.join() {
+                println!("Error joining TlsServer thread. {e:?}");
+            }
         }
     }
 
     fn run(
         ip: String,
         port: u16,
-        acceptor: Arc<TlsAcceptor>,
-        request_handler: RequestHandlerFn,
+        reloader: CertificateReloader,
+        handler: SharedHandler,
         thread_pool: Arc<ThreadPool>,
         running: Arc<AtomicBool>,
+        waker: Arc<Waker>,
     ) {
         let listener = TcpListener::bind(format!("{}:{}", ip, port))
-            .expect(format!("Failed to bind TlsListener to {ip}:{port}").as_str());
+            .unwrap_or_else(|e| panic!("Failed to bind TlsListener to {ip}:{port}: {e}"));
         listener
             .set_nonblocking(true)
             .expect("Failed to set nonblocking TlsListener.");
 
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    let acceptor = acceptor.clone();
-                    let request_handler = request_handler.clone();
-                    thread_pool.execute(Box::new(move || {
-                        let stream = match acceptor.accept(stream) {
-                            Ok(stream) => stream,
+        let mode = reloader.settings.mode;
+        let dispatch = Self::dispatcher(reloader, handler, thread_pool, running.clone());
+        match mode {
+            Mode::Threaded => {
+                while running.load(Relaxed) {
+                    if let Err(e) = waker.wait(&listener) {
+                        println!("Caught Error in TlsServer: {}. Not Handeled.", e);
+                        break;
+                    }
+                    // Accepts every pending connection, the listener is nonblocking.
+                    loop {
+                        match listener.accept() {
+                            Ok((stream, _)) => dispatch(stream, None),
+                            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
                             Err(e) => {
-                                println!("Handshake error: {}. Not Handeled.", e);
-                                return;
+                                println!("Caught Error in TlsServer: {}. Not Handeled.", e);
+                                thread::sleep(ACCEPT_BACKOFF);
+                                break;
                             }
-                        };
-                        println!(
-                            "TlsServer recieved new connection.",
-                        );
-                        Self::handle_client(stream, request_handler);
-                    }));
-                }
-                Err(e) => match e.kind() {
-                    ErrorKind::WouldBlock => {}
-                    e => {
-                        println!("Caught Error in TlsServer: {}. Not Handeled.", e);
+                        }
                     }
-                },
+                }
             }
-            thread::sleep(std::time::Duration::from_millis(80));
-            if !running.load(Relaxed) {
-                break;
+            // Connections are only handed to a worker once the client starts
+            // its handshake, and wait in the event loop for their request.
+            Mode::Evented => {
+                let dispatch = move |stream, parker: &Parker| dispatch(stream, Some(parker));
+                let served = EventLoop::new(Protocol::Handoff(Box::new(dispatch)), running, waker)
+                    .and_then(|event_loop| event_loop.run(&listener));
+                if let Err(e) = served {
+                    println!("Caught Error in TlsServer: {}. Not Handeled.", e);
+                }
             }
         }
 
         println!("TlsServer thread exited cleanly.");
     }
 
-    fn handle_client(mut stream: TlsStream<TcpStream>, request_handler: RequestHandlerFn) {
-        let mut reader = BufReader::new(&mut stream);
-        let received = reader.fill_buf().unwrap().to_vec();
-        reader.consume(received.len());
-        let request = String::from_utf8_lossy(&received).to_string();
-        if !request.ends_with("\r\n\r\n") {
-            return; // Internal Server Error or smthn
+    /// Schedules the handshake and request of each new connection on
+    /// `thread_pool`. With a `Parker`, HTTP/1.1 requests are read whenever
+    /// they arrive instead of by a waiting worker.
+    fn dispatcher(
+        reloader: CertificateReloader,
+        handler: SharedHandler,
+        thread_pool: Arc<ThreadPool>,
+        running: Arc<AtomicBool>,
+    ) -> impl Fn(TcpStream, Option<&Parker>) {
+        move |stream, parker| {
+            // There is no answering with a 503 before the handshake.
+            if thread_pool.is_full() {
+                println!("Thread pool is full, closing connection.");
+                return;
+            }
+            // Handshakes in progress keep the certificates they started with.
+            let certificates = reloader.current();
+            let handler = handler.clone();
+            let running = running.clone();
+            let parker = parker.cloned();
+            let pool = thread_pool.clone();
+            let deadline = Instant::now() + TIMEOUT;
+            thread_pool.execute(Box::new(move || {
+                let _ = stream.set_read_timeout(Some(HELLO_TIMEOUT));
+                let server_name = sni::peek_server_name(&stream, HELLO_TIMEOUT);
+                let acceptor = certificates.select(server_name.as_deref());
+                let stream = match acceptor.handshake(stream) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        println!("Handshake error: {}. Not Handeled.", e);
+                        return;
+                    }
+                };
+                let _ = stream.tcp_stream().set_read_timeout(None);
+                println!("TlsServer recieved new connection.");
+                let peer = Peer {
+                    remote_addr: stream.tcp_stream().peer_addr().ok(),
+                    secure: true,
+                    server_name,
+                    client_certificate: stream.client_certificate(),
+                };
+                if stream.alpn_protocol().as_deref() == Some(b"h2") {
+                    // Small frames like WINDOW_UPDATE answers must not wait for ACKs.
+                    let _ = stream.tcp_stream().set_nodelay(true);
+                    http2::serve(stream, handler, Executor::Pool(pool), peer, &running);
+                    return;
+                }
+                match parker {
+                    Some(parker) => {
+                        let request = PendingRequest {
+                            stream,
+                            peer,
+                            buffer: Vec::new(),
+                            parser: RequestParser::default(),
+                            deadline,
+                        };
+                        Self::read_request(request, handler, pool, parker);
+                    }
+                    None => connection::handle(stream, &handler, peer),
+                }
+            }));
+        }
+    }
+
+    /// Reads what has arrived of `request` and answers it once complete, or
+    /// parks the connection until the client sends more.
+    fn read_request(
+        mut request: PendingRequest,
+        handler: SharedHandler,
+        thread_pool: Arc<ThreadPool>,
+        parker: Parker,
+    ) {
+        let socket = request.stream.tcp_stream();
+        if socket.set_nonblocking(true).is_err() {
+            return;
+        }
+        let closed = event_loop::fill(&mut request.stream, &mut request.buffer);
+        let _ = request.stream.tcp_stream().set_nonblocking(false);
+        let closed = match closed {
+            Ok(closed) => closed,
+            Err(e) => return println!("Could not read request. {e}"),
+        };
+
+        let (response, buffered) = match request.parser.parse(&request.buffer, closed) {
+            Parsed::Incomplete if closed => return,
+            Parsed::Incomplete => {
+                let socket = match request.stream.tcp_stream().try_clone() {
+                    Ok(socket) => socket,
+                    Err(e) => return println!("Could not park connection. {e}"),
+                };
+                let deadline = request.deadline;
+                let resume = {
+                    let parker = parker.clone();
+                    move || {
+                        if thread_pool.is_full() {
+                            println!("Thread pool is full, closing connection.");
+                            return;
+                        }
+                        let pool = thread_pool.clone();
+                        thread_pool.execute(Box::new(move || {
+                            Self::read_request(request, handler, pool, parker)
+                        }));
+                    }
+                };
+                parker.park(socket, deadline, Box::new(resume));
+                return;
+            }
+            Parsed::Complete(complete, buffered) => {
+                let mut complete = *complete;
+                let peer = request.peer;
+                complete.remote_addr = peer.remote_addr;
+                complete.secure = peer.secure;
+                complete.server_name = peer.server_name;
+                complete.client_certificate = peer.client_certificate;
+                (catch_panic(handler.as_ref(), &complete), buffered)
+            }
+            Parsed::Invalid(e) => {
+                println!("Could not parse request. {e}");
+                (Response::new(400), Vec::new())
+            }
+        };
+        connection::send(request.stream, response, buffered);
+    }
+
+    /// Wraps `handler` with the checks for requests on a TLS connection: the
+    /// `Host` must be served with the certificate picked by the client's SNI,
+    /// and `client_auth.paths` need a client certificate.
+    pub(crate) fn secure_handler(
+        handler: SharedHandler,
+        reloader: CertificateReloader,
+    ) -> SharedHandler {
+        // A client may reuse a connection for any host its certificate
+        // covered, but not for hosts served with a different certificate.
+        Arc::new(move |request: &Request| {
+            let host = request
+                .header("Host")
+                .map(|host| host.rsplit_once(':').map_or(host, |(name, _)| name));
+            if let (Some(server_name), Some(host)) = (&request.server_name, host) {
+                let certificates = reloader.current();
+                if !Arc::ptr_eq(
+                    certificates.select(Some(server_name)),
+                    certificates.select(Some(host)),
+                ) {
+                    return Response::new(421);
+                }
+            }
+            let paths = &reloader.settings.client_auth.paths;
+            if request.client_certificate.is_none() && !paths.is_empty() {
+                match decode_path(&request.path) {
+                    Some(path) if Self::requires_certificate(paths, &path) => {
+                        return Response::new(403)
+                    }
+                    Some(_) => {}
+                    None => return Response::new(400),
+                }
+            }
+            handler.handle(request)
+        })
+    }
+
+    /// Whether the decoded `path` is below one of the `client_auth.paths`
+    /// prefixes. Both are compared by segment and ignoring case, without the
+    /// empty and `.` segments the `Router` and file system ignore, and with
+    /// `..` applied.
+    fn requires_certificate(paths: &[String], path: &str) -> bool {
+        let path = segments(path);
+        paths
+            .iter()
+            .any(|prefix| path.starts_with(&segments(prefix)))
+    }
+}
+
+/// Replaces the certificates of a running `TlsServer`. New handshakes use
+/// the new certificates, and the old ones are kept if loading fails.
+#[derive(Clone)]
+pub struct CertificateReloader {
+    certificates: Arc<RwLock<Arc<Certificates>>>,
+    pub(crate) settings: Arc<Https>,
+}
+
+impl CertificateReloader {
+    /// Loads the certificates of `settings_https`, and starts watching their
+    /// files if `watch_interval` is set.
+    pub fn from_settings(settings_https: &Https) -> Result<CertificateReloader, String> {
+        let certificates = Arc::new(TlsServer::load_certificates(settings_https)?);
+        let reloader = CertificateReloader {
+            certificates: Arc::new(RwLock::new(certificates)),
+            settings: Arc::new(settings_https.clone()),
+        };
+        if let Some(interval) = settings_https.watch_interval {
+            reloader.watch(Duration::from_secs(interval));
         }
-        let response = request_handler(request);
-        match stream.write_all(response.as_bytes()) {
-            Ok(_) => println!("Sent response."),
-            Err(e) => println!("Failed to send response. {e:?}")
+        Ok(reloader)
+    }
+
+    /// Loads every configured certificate file again.
+    pub fn reload(&self) -> Result<(), String> {
+        let certificates = TlsServer::load_certificates(&self.settings)?;
+        *self.certificates.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(certificates);
+        println!("Reloaded TLS certificates.");
+        Ok(())
+    }
+
+    pub(crate) fn current(&self) -> Arc<Certificates> {
+        self.certificates
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Spawns a thread reloading the certificates whenever one of their
+    /// files changes. The thread stops once the server is dropped.
+    pub fn watch(&self, interval: Duration) {
+        let certificates: Weak<RwLock<Arc<Certificates>>> = Arc::downgrade(&self.certificates);
+        let settings = self.settings.clone();
+        let files: Vec<String> = settings
+            .ssl
+            .iter()
+            .chain(
+                settings
+                    .certificates
+                    .iter()
+                    .map(|certificate| &certificate.ssl),
+            )
+            .flat_map(|ssl| [&ssl.identity, &ssl.certificate, &ssl.private_key])
+            .flatten()
+            .cloned()
+            .collect();
+        let modified = move || -> Vec<Option<SystemTime>> {
+            files
+                .iter()
+                .map(|file| metadata(file).and_then(|m| m.modified()).ok())
+                .collect()
         };
+
+        let mut last = modified();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let certificates = match certificates.upgrade() {
+                Some(certificates) => certificates,
+                None => break,
+            };
+            let current = modified();
+            if current == last {
+                continue;
+            }
+            // Remember the new times even on failure, so a broken file is
+            // reported once and retried when it changes again.
+            last = current;
+            let reloader = CertificateReloader {
+                certificates,
+                settings: settings.clone(),
+            };
+            if let Err(e) = reloader.reload() {
+                println!("Could not reload TLS certificates, keeping the previous ones: {e}");
+            }
+        });
+    }
+}
+
+/// The lowercase segments of a URL path, normalized like a file system path.
+fn segments(path: &str) -> Vec<String> {
+    let mut segments = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment.to_lowercase()),
+        }
+    }
+    segments
+}
+
+/// The protocol versions `policy` allows, TLS 1.2 and 1.3 by default.
+fn protocol_range(policy: &TlsPolicy) -> Result<(TlsVersion, TlsVersion), String> {
+    let min = policy.min_protocol.unwrap_or(TlsVersion::Tls12);
+    let max = policy.max_protocol.unwrap_or(TlsVersion::Tls13);
+    if min > max {
+        return Err(format!(
+            "min_protocol {min} is above max_protocol {max} for TlsServer."
+        ));
+    }
+    Ok((min, max))
+}
+
+fn log_policy(names: &[String], min: TlsVersion, max: TlsVersion, policy: &TlsPolicy) {
+    let names = match names {
+        [] => "the certificate".to_owned(),
+        names => names.join(", "),
+    };
+    println!(
+        "TLS policy for {names}: {min} to {max}, ciphers {}, groups {}.",
+        policy.ciphers.as_deref().unwrap_or("default"),
+        policy.groups.as_deref().unwrap_or("default"),
+    );
+}
+
+/// The subject alternative DNS names of the DER certificate `leaf`, or its
+/// common name if it has none. Warns if the certificate has expired.
+#[cfg(any(
+    feature = "rustls",
+    all(feature = "native-tls", not(feature = "openssl"))
+))]
+fn certificate_names(leaf: &[u8], certificate: &str) -> Result<Vec<String>, String> {
+    use x509_parser::{
+        certificate::X509Certificate, extensions::GeneralName, prelude::FromDer, time::ASN1Time,
+    };
+
+    let (_, leaf) = X509Certificate::from_der(leaf)
+        .map_err(|e| format!("Could not parse certificate file {certificate}: {e}"))?;
+    let not_after = leaf.validity().not_after;
+    if not_after < ASN1Time::now() {
+        println!("Warning: certificate {certificate} expired on {not_after}.");
     }
+
+    let names: Vec<String> = match leaf.subject_alternative_name() {
+        Ok(Some(extension)) => extension
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(name) => Some(name.to_string()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    if !names.is_empty() {
+        return Ok(names);
+    }
+    Ok(leaf
+        .subject()
+        .iter_common_name()
+        .filter_map(|name| name.as_str().ok().map(str::to_owned))
+        .collect())
+}
+
+/// Formats a certificate digest as colon separated hex.
+#[cfg(any(feature = "openssl", feature = "rustls"))]
+fn fingerprint(digest: &[u8]) -> String {
+    digest
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect::<Vec<String>>()
+        .join(":")
 }
 
 impl Drop for TlsServer {
     fn drop(&mut self) {
         println!("Sending terminate message to TlsServer.");
         self.running.store(false, Relaxed);
+        self.waker.wake();
         self.join_thread();
         println!("TlsServer shut down.");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn certificate_paths_match_normalized_paths() {
+        let paths = ["/admin/".to_owned()];
+        for path in [
+            "/admin",
+            "/admin/",
+            "/admin/users",
+            "//admin",
+            "/./admin",
+            "/admin//users",
+            "/public/../admin",
+        ] {
+            assert!(TlsServer::requires_certificate(&paths, path), "{path}");
+        }
+        for path in ["/", "/administrator", "/public/admin", "/admin/../public"] {
+            assert!(!TlsServer::requires_certificate(&paths, path), "{path}");
+        }
+        assert!(TlsServer::requires_certificate(&["/".to_owned()], "/any"));
+    }
+
+    #[test]
+    fn certificate_paths_match_decoded_paths() {
+        let paths = ["/api/admin".to_owned()];
+        for path in [
+            "/api/%61dmin",
+            "/API/Admin",
+            "/api/%41DMIN/users",
+            "/api/public/%2e%2e/admin",
+            "/api/./public/../ADMIN",
+        ] {
+            let decoded = decode_path(path).unwrap();
+            assert!(TlsServer::requires_certificate(&paths, &decoded), "{path}");
+        }
+        for path in ["/api/%61dministrator", "/api/admin%2e%2e/x"] {
+            let decoded = decode_path(path).unwrap();
+            assert!(!TlsServer::requires_certificate(&paths, &decoded), "{path}");
+        }
+    }
+
+    #[test]
+    fn undecodable_paths_are_rejected() {
+        for path in [
+            "/api%2fadmin",
+            "/api%2Fadmin",
+            "/api%5cadmin",
+            "/api%00",
+            "/%zz",
+            "/%4",
+            "/%ff",
+        ] {
+            assert_eq!(decode_path(path), None, "{path}");
+        }
+        assert_eq!(
+            decode_path("/caf%C3%A9%20bar").as_deref(),
+            Some("/café bar")
+        );
+    }
+}
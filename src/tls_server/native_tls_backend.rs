@@ -0,0 +1,184 @@
+use native_tls::{Identity, Protocol, TlsStream};
+use x509_parser::pem::Pem;
+
+use std::{
+    fs::read,
+    io,
+    net::TcpStream,
+    os::fd::{AsRawFd, RawFd},
+    time::Duration,
+};
+
+#[cfg(feature = "async")]
+use super::AsyncTlsConnection;
+use super::{ClientAuthMode, TlsAcceptor, TlsConnection, TlsVersion};
+use crate::{
+    http::{ClientCertificate, Stream},
+    settings::{ClientAuth, TlsPolicy, SSL},
+};
+
+pub use native_tls::TlsAcceptor as Acceptor;
+
+impl TlsAcceptor for Acceptor {
+    type Connection = TlsStream<TcpStream>;
+
+    fn from_settings(
+        ssl: &SSL,
+        policy: &TlsPolicy,
+        client_auth: &ClientAuth,
+        http2: bool,
+    ) -> Result<(Acceptor, Vec<String>), String> {
+        if client_auth.mode != ClientAuthMode::Off {
+            return Err("client_auth is not supported by the native-tls backend.".to_owned());
+        }
+        if policy.ciphers.is_some() || policy.groups.is_some() {
+            return Err(
+                "ciphers and groups are not supported by the native-tls backend.".to_owned(),
+            );
+        }
+        let (identity, names) = load_identity(ssl)?;
+        let (min, max) = super::protocol_range(policy)?;
+
+        let mut builder = Acceptor::builder(identity);
+        builder
+            .min_protocol_version(Some(protocol(min)))
+            .max_protocol_version(Some(protocol(max)));
+        if http2 {
+            let protocols: Vec<&str> = super::ALPN_PROTOCOLS
+                .iter()
+                .filter_map(|protocol| std::str::from_utf8(protocol).ok())
+                .collect();
+            builder.accept_alpn(&protocols);
+        }
+        let acceptor = builder
+            .build()
+            .map_err(|e| format!("Could not create TlsAcceptor for TlsServer. Got error: {e}"))?;
+
+        super::log_policy(&names, min, max, policy);
+        Ok((acceptor, names))
+    }
+
+    fn handshake(&self, stream: TcpStream) -> Result<TlsStream<TcpStream>, String> {
+        self.accept(stream).map_err(|e| e.to_string())
+    }
+
+    #[cfg(feature = "async")]
+    type AsyncConnection = tokio_native_tls::TlsStream<tokio::net::TcpStream>;
+
+    #[cfg(feature = "async")]
+    async fn handshake_async(
+        &self,
+        stream: tokio::net::TcpStream,
+    ) -> Result<Self::AsyncConnection, String> {
+        tokio_native_tls::TlsAcceptor::from(self.clone())
+            .accept(stream)
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// native-tls never asks clients for a certificate.
+impl TlsConnection for TlsStream<TcpStream> {
+    fn tcp_stream(&self) -> &TcpStream {
+        self.get_ref()
+    }
+
+    fn client_certificate(&self) -> Option<ClientCertificate> {
+        None
+    }
+
+    fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        self.negotiated_alpn().ok().flatten()
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncTlsConnection for tokio_native_tls::TlsStream<tokio::net::TcpStream> {
+    fn tcp_stream(&self) -> &tokio::net::TcpStream {
+        self.get_ref().get_ref().get_ref()
+    }
+
+    fn client_certificate(&self) -> Option<ClientCertificate> {
+        None
+    }
+
+    fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        self.get_ref().negotiated_alpn().ok().flatten()
+    }
+}
+
+impl Stream for TlsStream<TcpStream> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.get_ref().set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.get_ref().set_write_timeout(timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.get_ref().set_nonblocking(nonblocking)
+    }
+
+    fn poll_fd(&self) -> Option<RawFd> {
+        Some(self.get_ref().as_raw_fd())
+    }
+
+    fn buffered(&self) -> bool {
+        self.buffered_read_size().is_ok_and(|size| size > 0)
+    }
+}
+
+/// Loads either the PKCS#12 `identity` or the PEM `certificate` and
+/// `private_key`, with the DNS names of the certificate. native-tls can't
+/// look inside a PKCS#12 file, so those entries need `hostnames`.
+fn load_identity(ssl: &SSL) -> Result<(Identity, Vec<String>), String> {
+    match (&ssl.identity, &ssl.certificate, &ssl.private_key) {
+        (Some(_), Some(_), _) | (Some(_), _, Some(_)) => Err(
+            "Set either identity or certificate and private_key for TlsServer, not both."
+                .to_owned(),
+        ),
+        (Some(identity), None, None) => {
+            let pkcs12 = read(identity).map_err(|e| {
+                format!("Could not read identity file {identity} for TlsServer: {e}")
+            })?;
+            let identity = Identity::from_pkcs12(&pkcs12, &ssl.password)
+                .map_err(|e| format!("Could not create identity for TlsServer. Got error: {e}"))?;
+            Ok((identity, Vec::new()))
+        }
+        (None, Some(certificate), Some(private_key)) => {
+            let chain = read(certificate)
+                .map_err(|e| format!("Could not read certificate file {certificate}: {e}"))?;
+            let names = match Pem::iter_from_buffer(&chain).next() {
+                Some(Ok(leaf)) => super::certificate_names(&leaf.contents, certificate)?,
+                Some(Err(e)) => {
+                    return Err(format!(
+                        "Could not parse certificate file {certificate}: {e}"
+                    ))
+                }
+                None => return Err(format!("No certificate found in {certificate}.")),
+            };
+            let key = read(private_key)
+                .map_err(|e| format!("Could not read private key file {private_key}: {e}"))?;
+            let identity = Identity::from_pkcs8(&chain, &key).map_err(|e| {
+                format!("Could not use private key {private_key} with {certificate}, it must be an unencrypted PKCS#8 key: {e}")
+            })?;
+            Ok((identity, names))
+        }
+        (None, Some(_), None) | (None, None, Some(_)) => {
+            Err("Both certificate and private_key are needed for TlsServer.".to_owned())
+        }
+        (None, None, None) => {
+            Err("No identity or certificate configured for TlsServer.".to_owned())
+        }
+    }
+}
+
+fn protocol(version: TlsVersion) -> Protocol {
+    match version {
+        TlsVersion::Tls10 => Protocol::Tlsv10,
+        TlsVersion::Tls11 => Protocol::Tlsv11,
+        TlsVersion::Tls12 => Protocol::Tlsv12,
+        TlsVersion::Tls13 => Protocol::Tlsv13,
+    }
+}
@@ -0,0 +1,336 @@
+use openssl::{
+    asn1::Asn1Time,
+    error::ErrorStack,
+    hash::MessageDigest,
+    nid::Nid,
+    pkcs12::Pkcs12,
+    pkey::{PKey, Private},
+    ssl::{
+        AlpnError, SslAcceptor, SslAcceptorBuilder, SslMethod, SslRef, SslStream, SslVerifyMode,
+        SslVersion,
+    },
+    x509::{X509Name, X509NameRef, X509VerifyResult, X509},
+};
+
+use std::{
+    fs::read,
+    io,
+    net::TcpStream,
+    os::fd::{AsRawFd, RawFd},
+    time::Duration,
+};
+
+#[cfg(feature = "async")]
+use super::AsyncTlsConnection;
+use super::{ClientAuthMode, TlsAcceptor, TlsConnection, TlsVersion};
+use crate::{
+    http::{ClientCertificate, Stream},
+    settings::{ClientAuth, TlsPolicy, SSL},
+};
+
+pub use openssl::ssl::SslAcceptor as Acceptor;
+
+/// A certificate chain with its private key.
+struct KeyPair {
+    key: PKey<Private>,
+    leaf: X509,
+    chain: Vec<X509>,
+}
+
+impl TlsAcceptor for SslAcceptor {
+    type Connection = SslStream<TcpStream>;
+
+    fn from_settings(
+        ssl: &SSL,
+        policy: &TlsPolicy,
+        client_auth: &ClientAuth,
+        http2: bool,
+    ) -> Result<(SslAcceptor, Vec<String>), String> {
+        let key_pair = load_key_pair(ssl)?;
+        let names = certificate_names(&key_pair.leaf);
+        let (min, max) = super::protocol_range(policy)?;
+
+        let error =
+            |e: ErrorStack| format!("Could not create SslAcceptor for TlsServer. Got error: {e}");
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).map_err(error)?;
+        builder.set_private_key(&key_pair.key).map_err(error)?;
+        builder.set_certificate(&key_pair.leaf).map_err(error)?;
+        for certificate in key_pair.chain {
+            builder.add_extra_chain_cert(certificate).map_err(error)?;
+        }
+        builder.check_private_key().map_err(error)?;
+
+        builder
+            .set_min_proto_version(Some(protocol(min)))
+            .map_err(error)?;
+        builder
+            .set_max_proto_version(Some(protocol(max)))
+            .map_err(error)?;
+        if let Some(ciphers) = &policy.ciphers {
+            builder
+                .set_cipher_list(ciphers)
+                .map_err(|e| format!("Invalid ciphers \"{ciphers}\" for TlsServer: {e}"))?;
+        }
+        if let Some(groups) = &policy.groups {
+            builder
+                .set_groups_list(groups)
+                .map_err(|e| format!("Invalid groups \"{groups}\" for TlsServer: {e}"))?;
+        }
+        verify_clients(&mut builder, client_auth)?;
+        if http2 {
+            builder.set_alpn_select_callback(|_, offered| {
+                select_protocol(offered).ok_or(AlpnError::NOACK)
+            });
+        }
+
+        super::log_policy(&names, min, max, policy);
+        Ok((builder.build(), names))
+    }
+
+    fn handshake(&self, stream: TcpStream) -> Result<SslStream<TcpStream>, String> {
+        self.accept(stream).map_err(|e| e.to_string())
+    }
+
+    #[cfg(feature = "async")]
+    type AsyncConnection = tokio_openssl::SslStream<tokio::net::TcpStream>;
+
+    #[cfg(feature = "async")]
+    async fn handshake_async(
+        &self,
+        stream: tokio::net::TcpStream,
+    ) -> Result<Self::AsyncConnection, String> {
+        let ssl = openssl::ssl::Ssl::new(self.context()).map_err(|e| e.to_string())?;
+        let mut stream = tokio_openssl::SslStream::new(ssl, stream).map_err(|e| e.to_string())?;
+        std::pin::Pin::new(&mut stream)
+            .accept()
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(stream)
+    }
+}
+
+impl TlsConnection for SslStream<TcpStream> {
+    fn tcp_stream(&self) -> &TcpStream {
+        self.get_ref()
+    }
+
+    fn client_certificate(&self) -> Option<ClientCertificate> {
+        client_certificate(self.ssl())
+    }
+
+    fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        self.ssl().selected_alpn_protocol().map(<[u8]>::to_vec)
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncTlsConnection for tokio_openssl::SslStream<tokio::net::TcpStream> {
+    fn tcp_stream(&self) -> &tokio::net::TcpStream {
+        self.get_ref()
+    }
+
+    fn client_certificate(&self) -> Option<ClientCertificate> {
+        client_certificate(self.ssl())
+    }
+
+    fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        self.ssl().selected_alpn_protocol().map(<[u8]>::to_vec)
+    }
+}
+
+impl Stream for SslStream<TcpStream> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.get_ref().set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.get_ref().set_write_timeout(timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.get_ref().set_nonblocking(nonblocking)
+    }
+
+    fn poll_fd(&self) -> Option<RawFd> {
+        Some(self.get_ref().as_raw_fd())
+    }
+
+    fn buffered(&self) -> bool {
+        self.ssl().pending() > 0
+    }
+}
+
+fn client_certificate(ssl: &SslRef) -> Option<ClientCertificate> {
+    let certificate = ssl.peer_certificate()?;
+    if ssl.verify_result() != X509VerifyResult::OK {
+        return None;
+    }
+    let digest = certificate.digest(MessageDigest::sha256()).ok()?;
+    Some(ClientCertificate {
+        subject: name(certificate.subject_name()),
+        issuer: name(certificate.issuer_name()),
+        fingerprint: super::fingerprint(&digest),
+    })
+}
+
+/// Asks clients for a certificate signed by the `client_auth.ca` bundle.
+fn verify_clients(
+    builder: &mut SslAcceptorBuilder,
+    client_auth: &ClientAuth,
+) -> Result<(), String> {
+    let mode = match client_auth.mode {
+        ClientAuthMode::Off => return Ok(()),
+        ClientAuthMode::Optional => SslVerifyMode::PEER,
+        ClientAuthMode::Required => SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+    };
+    let ca = client_auth.ca.as_deref().unwrap_or_default();
+    let error = |e: ErrorStack| format!("Could not load client CA bundle {ca}: {e}");
+    builder.set_ca_file(ca).map_err(error)?;
+    builder.set_client_ca_list(X509Name::load_client_ca_file(ca).map_err(error)?);
+    // Resumed sessions must carry the verification result along.
+    builder
+        .set_session_id_context(b"my_server")
+        .map_err(error)?;
+    builder.set_verify(mode);
+    Ok(())
+}
+
+/// The first of `ALPN_PROTOCOLS` in the client's length prefixed list.
+fn select_protocol(offered: &[u8]) -> Option<&[u8]> {
+    let mut protocols = Vec::new();
+    let mut rest = offered;
+    while let Some((&length, tail)) = rest.split_first() {
+        protocols.push(tail.get(..length as usize)?);
+        rest = &tail[length as usize..];
+    }
+    super::ALPN_PROTOCOLS.iter().find_map(|wanted| {
+        protocols
+            .iter()
+            .find(|protocol| *protocol == wanted)
+            .copied()
+    })
+}
+
+fn protocol(version: TlsVersion) -> SslVersion {
+    match version {
+        TlsVersion::Tls10 => SslVersion::TLS1,
+        TlsVersion::Tls11 => SslVersion::TLS1_1,
+        TlsVersion::Tls12 => SslVersion::TLS1_2,
+        TlsVersion::Tls13 => SslVersion::TLS1_3,
+    }
+}
+
+/// The subject alternative DNS names of `leaf`, or its common name if it has none.
+fn certificate_names(leaf: &X509) -> Vec<String> {
+    let names: Vec<String> = leaf
+        .subject_alt_names()
+        .map(|names| {
+            names
+                .iter()
+                .filter_map(|name| name.dnsname().map(str::to_owned))
+                .collect()
+        })
+        .unwrap_or_default();
+    if !names.is_empty() {
+        return names;
+    }
+    leaf.subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .filter_map(|entry| entry.data().to_string().ok())
+        .collect()
+}
+
+/// Formats `name` as `CN=admin,O=Example`.
+fn name(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|entry| {
+            let field = entry.object().nid().short_name().unwrap_or("?");
+            let value = entry.data().to_string().unwrap_or_default();
+            format!("{field}={value}")
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// Loads either the PKCS#12 `identity` or the PEM `certificate` and
+/// `private_key` of `ssl`.
+fn load_key_pair(ssl: &SSL) -> Result<KeyPair, String> {
+    match (&ssl.identity, &ssl.certificate, &ssl.private_key) {
+        (Some(_), Some(_), _) | (Some(_), _, Some(_)) => Err(
+            "Set either identity or certificate and private_key for TlsServer, not both."
+                .to_owned(),
+        ),
+        (Some(identity), None, None) => {
+            let pkcs12 = match read(identity) {
+                Ok(pkcs12) => pkcs12,
+                Err(e) => {
+                    return Err(format!(
+                        "Could not read identity file {identity} for TlsServer: {e}"
+                    ));
+                }
+            };
+            let parsed = Pkcs12::from_der(&pkcs12)
+                .and_then(|parsed| parsed.parse2(&ssl.password))
+                .map_err(|e| format!("Could not create identity for TlsServer. Got error: {e}"))?;
+            match (parsed.pkey, parsed.cert) {
+                (Some(key), Some(leaf)) => Ok(KeyPair {
+                    key,
+                    leaf,
+                    chain: parsed
+                        .ca
+                        .map(|ca| ca.into_iter().collect())
+                        .unwrap_or_default(),
+                }),
+                _ => Err(format!(
+                    "Could not read the certificate and key in {identity}."
+                )),
+            }
+        }
+        (None, Some(certificate), Some(private_key)) => {
+            pem_key_pair(certificate, private_key, &ssl.password)
+        }
+        (None, Some(_), None) | (None, None, Some(_)) => {
+            Err("Both certificate and private_key are needed for TlsServer.".to_owned())
+        }
+        (None, None, None) => {
+            Err("No identity or certificate configured for TlsServer.".to_owned())
+        }
+    }
+}
+
+/// Reads a PEM certificate chain, leaf first, and a PKCS#8, RSA or EC
+/// private key, checking that the two belong together.
+fn pem_key_pair(certificate: &str, private_key: &str, password: &str) -> Result<KeyPair, String> {
+    let chain = read(certificate)
+        .map_err(|e| format!("Could not read certificate file {certificate}: {e}"))?;
+    let key = read(private_key)
+        .map_err(|e| format!("Could not read private key file {private_key}: {e}"))?;
+
+    let mut chain = X509::stack_from_pem(&chain)
+        .map_err(|e| format!("Could not parse certificate file {certificate}: {e}"))?;
+    if chain.is_empty() {
+        return Err(format!("No certificate found in {certificate}."));
+    }
+    let leaf = chain.remove(0);
+    // The passphrase is ignored for unencrypted keys.
+    let key = PKey::private_key_from_pem_passphrase(&key, password.as_bytes())
+        .map_err(|e| format!("Could not parse private key file {private_key}: {e}"))?;
+
+    let public_key = leaf
+        .public_key()
+        .map_err(|e| format!("Could not read public key of {certificate}: {e}"))?;
+    if !public_key.public_eq(&key) {
+        return Err(format!(
+            "Private key {private_key} does not match the first certificate in {certificate}."
+        ));
+    }
+    if let Ok(now) = Asn1Time::days_from_now(0) {
+        if leaf.not_after() < now {
+            println!(
+                "Warning: certificate {certificate} expired on {}.",
+                leaf.not_after()
+            );
+        }
+    }
+    Ok(KeyPair { key, leaf, chain })
+}
@@ -0,0 +1,306 @@
+use ring::digest::{digest, SHA256};
+use rustls::{
+    crypto::{ring::default_provider, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{danger::ClientCertVerifier, WebPkiClientVerifier},
+    version::{TLS12, TLS13},
+    RootCertStore, ServerConfig, ServerConnection, StreamOwned, SupportedProtocolVersion,
+};
+use x509_parser::{
+    certificate::X509Certificate,
+    objects::{oid2abbrev, oid_registry},
+    prelude::FromDer,
+    x509::X509Name,
+};
+
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    os::fd::{AsRawFd, RawFd},
+    sync::Arc,
+    time::Duration,
+};
+
+#[cfg(feature = "async")]
+use super::AsyncTlsConnection;
+use super::{ClientAuthMode, TlsAcceptor, TlsConnection, TlsVersion};
+use crate::{
+    http::{ClientCertificate, Stream},
+    settings::{ClientAuth, TlsPolicy, SSL},
+};
+
+/// A rustls server configuration for one certificate.
+pub struct Acceptor {
+    config: Arc<ServerConfig>,
+}
+
+/// A connection whose handshake has completed.
+pub struct TlsStream(StreamOwned<ServerConnection, TcpStream>);
+
+impl TlsAcceptor for Acceptor {
+    type Connection = TlsStream;
+
+    fn from_settings(
+        ssl: &SSL,
+        policy: &TlsPolicy,
+        client_auth: &ClientAuth,
+        http2: bool,
+    ) -> Result<(Acceptor, Vec<String>), String> {
+        let (certificate, private_key) = match (&ssl.identity, &ssl.certificate, &ssl.private_key) {
+            (Some(_), _, _) => {
+                return Err(
+                    "PKCS#12 identity files need the openssl or native-tls backend, set certificate and private_key instead."
+                        .to_owned(),
+                )
+            }
+            (None, Some(certificate), Some(private_key)) => (certificate, private_key),
+            (None, Some(_), None) | (None, None, Some(_)) => {
+                return Err(
+                    "Both certificate and private_key are needed for TlsServer.".to_owned(),
+                )
+            }
+            (None, None, None) => {
+                return Err("No identity or certificate configured for TlsServer.".to_owned())
+            }
+        };
+
+        let chain = CertificateDer::pem_file_iter(certificate)
+            .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("Could not parse certificate file {certificate}: {e}"))?;
+        let names = match chain.first() {
+            Some(leaf) => super::certificate_names(leaf, certificate)?,
+            None => return Err(format!("No certificate found in {certificate}.")),
+        };
+        let key = PrivateKeyDer::from_pem_file(private_key).map_err(|e| {
+            if ssl.password.is_empty() {
+                format!("Could not parse private key file {private_key}: {e}")
+            } else {
+                format!("Could not parse private key file {private_key}, encrypted keys need the openssl backend: {e}")
+            }
+        })?;
+
+        let (min, max) = super::protocol_range(policy)?;
+        if min < TlsVersion::Tls12 {
+            return Err(format!(
+                "min_protocol {min} is not supported by the rustls backend."
+            ));
+        }
+        let versions: Vec<&'static SupportedProtocolVersion> =
+            [(TlsVersion::Tls12, &TLS12), (TlsVersion::Tls13, &TLS13)]
+                .into_iter()
+                .filter(|(version, _)| (min..=max).contains(version))
+                .map(|(_, supported)| supported)
+                .collect();
+
+        let mut provider = default_provider();
+        if let Some(ciphers) = &policy.ciphers {
+            provider.cipher_suites = select(&provider.cipher_suites, ciphers, "cipher", |suite| {
+                format!("{:?}", suite.suite())
+            })?;
+        }
+        if let Some(groups) = &policy.groups {
+            provider.kx_groups = select(&provider.kx_groups, groups, "group", |group| {
+                format!("{:?}", group.name())
+            })?;
+        }
+        let provider = Arc::new(provider);
+
+        let error = |e: rustls::Error| {
+            format!("Could not create ServerConfig for TlsServer. Got error: {e}")
+        };
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&versions)
+            .map_err(error)?;
+        let builder = match verify_clients(client_auth, provider)? {
+            Some(verifier) => builder.with_client_cert_verifier(verifier),
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_single_cert(chain, key).map_err(|e| {
+            format!(
+                "Could not use private key {private_key} with the first certificate in {certificate}: {e}"
+            )
+        })?;
+
+        if http2 {
+            config.alpn_protocols = super::ALPN_PROTOCOLS
+                .iter()
+                .map(|protocol| protocol.to_vec())
+                .collect();
+        }
+
+        super::log_policy(&names, min, max, policy);
+        Ok((
+            Acceptor {
+                config: Arc::new(config),
+            },
+            names,
+        ))
+    }
+
+    fn handshake(&self, stream: TcpStream) -> Result<TlsStream, String> {
+        let connection = ServerConnection::new(self.config.clone()).map_err(|e| e.to_string())?;
+        let mut stream = StreamOwned::new(connection, stream);
+        while stream.conn.is_handshaking() {
+            stream
+                .conn
+                .complete_io(&mut stream.sock)
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(TlsStream(stream))
+    }
+
+    #[cfg(feature = "async")]
+    type AsyncConnection = tokio_rustls::server::TlsStream<tokio::net::TcpStream>;
+
+    #[cfg(feature = "async")]
+    async fn handshake_async(
+        &self,
+        stream: tokio::net::TcpStream,
+    ) -> Result<Self::AsyncConnection, String> {
+        tokio_rustls::TlsAcceptor::from(self.config.clone())
+            .accept(stream)
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl TlsConnection for TlsStream {
+    fn tcp_stream(&self) -> &TcpStream {
+        &self.0.sock
+    }
+
+    fn client_certificate(&self) -> Option<ClientCertificate> {
+        client_certificate(&self.0.conn)
+    }
+
+    fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        self.0.conn.alpn_protocol().map(<[u8]>::to_vec)
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncTlsConnection for tokio_rustls::server::TlsStream<tokio::net::TcpStream> {
+    fn tcp_stream(&self) -> &tokio::net::TcpStream {
+        self.get_ref().0
+    }
+
+    fn client_certificate(&self) -> Option<ClientCertificate> {
+        client_certificate(self.get_ref().1)
+    }
+
+    fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        self.get_ref().1.alpn_protocol().map(<[u8]>::to_vec)
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Stream for TlsStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.sock.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.sock.set_write_timeout(timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.0.sock.set_nonblocking(nonblocking)
+    }
+
+    fn poll_fd(&self) -> Option<RawFd> {
+        Some(self.0.sock.as_raw_fd())
+    }
+
+    /// rustls only stops wanting to read while it holds decrypted data.
+    fn buffered(&self) -> bool {
+        !self.0.conn.wants_read()
+    }
+}
+
+/// rustls fails the handshake for invalid certificates, so any certificate
+/// still present was verified.
+fn client_certificate(connection: &ServerConnection) -> Option<ClientCertificate> {
+    let certificate = connection.peer_certificates()?.first()?;
+    let (_, parsed) = X509Certificate::from_der(certificate).ok()?;
+    Some(ClientCertificate {
+        subject: name(parsed.subject()),
+        issuer: name(parsed.issuer()),
+        fingerprint: super::fingerprint(digest(&SHA256, certificate).as_ref()),
+    })
+}
+
+/// Asks clients for a certificate signed by the `client_auth.ca` bundle.
+fn verify_clients(
+    client_auth: &ClientAuth,
+    provider: Arc<CryptoProvider>,
+) -> Result<Option<Arc<dyn ClientCertVerifier>>, String> {
+    if client_auth.mode == ClientAuthMode::Off {
+        return Ok(None);
+    }
+    let ca = client_auth.ca.as_deref().unwrap_or_default();
+    let error = |e: String| format!("Could not load client CA bundle {ca}: {e}");
+    let certificates = CertificateDer::pem_file_iter(ca)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| error(e.to_string()))?;
+    let mut roots = RootCertStore::empty();
+    for certificate in certificates {
+        roots.add(certificate).map_err(|e| error(e.to_string()))?;
+    }
+
+    let mut builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+    if client_auth.mode == ClientAuthMode::Optional {
+        builder = builder.allow_unauthenticated();
+    }
+    builder.build().map(Some).map_err(|e| error(e.to_string()))
+}
+
+/// Picks the entries of `available` named in the `:` separated `list`, in
+/// the order of the list. Names are compared case insensitively, and the
+/// OpenSSL group names `P-256` and `P-384` are accepted as well.
+fn select<T: Copy>(
+    available: &[T],
+    list: &str,
+    kind: &str,
+    name: impl Fn(&T) -> String,
+) -> Result<Vec<T>, String> {
+    list.split(':')
+        .map(|wanted| {
+            let wanted = match wanted.trim() {
+                "P-256" | "prime256v1" => "secp256r1",
+                "P-384" => "secp384r1",
+                wanted => wanted,
+            };
+            available
+                .iter()
+                .find(|item| name(item).eq_ignore_ascii_case(wanted))
+                .copied()
+                .ok_or_else(|| format!("Unknown {kind} {wanted} for the rustls backend."))
+        })
+        .collect()
+}
+
+/// Formats `name` as `CN=admin,O=Example`.
+fn name(name: &X509Name) -> String {
+    name.iter_attributes()
+        .map(|attribute| {
+            let field = oid2abbrev(attribute.attr_type(), oid_registry()).unwrap_or("?");
+            format!("{field}={}", attribute.as_str().unwrap_or_default())
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
@@ -0,0 +1,125 @@
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::UnixStream,
+    },
+    time::Duration,
+};
+
+/// How long listeners wait before accepting again after an error, e.g. while
+/// out of file descriptors.
+pub(crate) const ACCEPT_BACKOFF: Duration = Duration::from_millis(80);
+
+/// Lets another thread interrupt a listener blocked in `wait`, e.g. to stop
+/// it. Waking writes to one end of a socket pair, which `wait` polls together
+/// with the listener.
+pub(crate) struct Waker {
+    reader: UnixStream,
+    writer: UnixStream,
+}
+
+impl Waker {
+    pub fn new() -> Result<Waker, String> {
+        let (reader, writer) =
+            UnixStream::pair().map_err(|e| format!("Could not create waker: {e}"))?;
+        for stream in [&reader, &writer] {
+            stream
+                .set_nonblocking(true)
+                .map_err(|e| format!("Could not create waker: {e}"))?;
+        }
+        Ok(Waker { reader, writer })
+    }
+
+    /// Makes the current or next `wait` return.
+    pub fn wake(&self) {
+        // A full buffer means a wakeup is already pending.
+        let _ = (&self.writer).write(&[1]);
+    }
+
+    /// Blocks until `source` is readable or the waker is woken, returning
+    /// whether `source` is readable.
+    pub fn wait(&self, source: &impl AsRawFd) -> io::Result<bool> {
+        self.poll(source.as_raw_fd(), -1)
+    }
+
+    /// Like `wait`, but also returns false once `timeout` passes.
+    pub fn wait_timeout(&self, source: RawFd, timeout: Duration) -> io::Result<bool> {
+        self.poll(source, timeout.as_millis().try_into().unwrap_or(i32::MAX))
+    }
+
+    /// Blocks until one of `fds` has one of its events, the waker is woken or
+    /// `timeout` passes, filling in their `revents`.
+    pub fn wait_all(&self, fds: &mut Vec<libc::pollfd>, timeout: Duration) -> io::Result<()> {
+        fds.push(libc::pollfd {
+            fd: self.reader.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        });
+        let polled = poll(fds, timeout.as_millis().try_into().unwrap_or(i32::MAX));
+        if fds.pop().is_some_and(|waker| waker.revents != 0) {
+            self.reset();
+        }
+        polled
+    }
+
+    fn poll(&self, source: RawFd, timeout: i32) -> io::Result<bool> {
+        let mut fds = [
+            libc::pollfd {
+                fd: source,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: self.reader.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        poll(&mut fds, timeout)?;
+        if fds[1].revents != 0 {
+            self.reset();
+        }
+        Ok(fds[0].revents != 0)
+    }
+
+    /// Consumes pending wakeups, for callers polling the waker themselves.
+    pub fn reset(&self) {
+        let mut buffer = [0; 64];
+        while matches!((&self.reader).read(&mut buffer), Ok(n) if n > 0) {}
+    }
+}
+
+/// Blocks until `source` is readable or `timeout` passes, for callers that
+/// have nothing to be woken by. Returns whether `source` is readable.
+pub(crate) fn wait_readable(source: RawFd, timeout: Duration) -> io::Result<bool> {
+    let mut fds = [libc::pollfd {
+        fd: source,
+        events: libc::POLLIN,
+        revents: 0,
+    }];
+    poll(&mut fds, timeout.as_millis().try_into().unwrap_or(i32::MAX))?;
+    Ok(fds[0].revents != 0)
+}
+
+/// Calls poll(2), retrying when interrupted.
+fn poll(fds: &mut [libc::pollfd], timeout: i32) -> io::Result<()> {
+    loop {
+        // SAFETY: `fds` is a valid array of pollfd for the whole call.
+        let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+        if ready >= 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+/// The end that becomes readable when woken.
+impl AsRawFd for Waker {
+    fn as_raw_fd(&self) -> RawFd {
+        self.reader.as_raw_fd()
+    }
+}
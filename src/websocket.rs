@@ -0,0 +1,623 @@
+use std::{
+    error::Error,
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+    os::fd::RawFd,
+    sync::Arc,
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha1::{Digest, Sha1};
+
+use crate::{
+    handler::Handler,
+    http::{Method, Request, Response, Stream},
+};
+
+/// Appended to `Sec-WebSocket-Key` before hashing, see RFC 6455 section 1.3.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Default upper limit for a message, including all of its fragments.
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+/// How long `relay` waits on one side before checking the other, for streams
+/// without a socket to poll.
+const RELAY_POLL: Duration = Duration::from_millis(10);
+
+/// Status codes sent in close frames, see RFC 6455 section 7.4.1.
+pub mod close_codes {
+    pub const NORMAL: u16 = 1000;
+    pub const GOING_AWAY: u16 = 1001;
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    pub const UNSUPPORTED_DATA: u16 = 1003;
+    pub const INVALID_DATA: u16 = 1007;
+    pub const POLICY_VIOLATION: u16 = 1008;
+    pub const MESSAGE_TOO_BIG: u16 = 1009;
+    pub const INTERNAL_ERROR: u16 = 1011;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    fn parse(opcode: u8) -> Option<Opcode> {
+        match opcode {
+            0x0 => Some(Opcode::Continuation),
+            0x1 => Some(Opcode::Text),
+            0x2 => Some(Opcode::Binary),
+            0x8 => Some(Opcode::Close),
+            0x9 => Some(Opcode::Ping),
+            0xA => Some(Opcode::Pong),
+            _ => None,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xA,
+        }
+    }
+
+    pub fn is_control(self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+/// A violation of the protocol by the peer, answered with a close frame.
+#[derive(Debug)]
+struct Violation {
+    code: u16,
+    reason: &'static str,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.reason, self.code)
+    }
+}
+
+impl Error for Violation {}
+
+fn violation(code: u16, reason: &'static str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, Violation { code, reason })
+}
+
+/// A single frame on the wire.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: Opcode,
+    /// Whether the frame was masked. Frames from clients always are.
+    pub masked: bool,
+    /// The payload, already unmasked.
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(opcode: Opcode, payload: Vec<u8>) -> Frame {
+        Frame {
+            fin: true,
+            opcode,
+            masked: false,
+            payload,
+        }
+    }
+
+    /// Reads and unmasks one frame, rejecting payloads over `max_size` bytes.
+    pub fn read_from<R: Read>(reader: &mut R, max_size: usize) -> io::Result<Frame> {
+        let mut head = [0; 2];
+        reader.read_exact(&mut head)?;
+        let fin = head[0] & 0x80 != 0;
+        if head[0] & 0x70 != 0 {
+            return Err(violation(
+                close_codes::PROTOCOL_ERROR,
+                "Reserved bits set without an extension.",
+            ));
+        }
+        let opcode = Opcode::parse(head[0] & 0x0F)
+            .ok_or_else(|| violation(close_codes::PROTOCOL_ERROR, "Unknown opcode."))?;
+        let masked = head[1] & 0x80 != 0;
+
+        let length = match head[1] & 0x7F {
+            126 => {
+                let mut length = [0; 2];
+                reader.read_exact(&mut length)?;
+                u16::from_be_bytes(length) as u64
+            }
+            127 => {
+                let mut length = [0; 8];
+                reader.read_exact(&mut length)?;
+                if length[0] & 0x80 != 0 {
+                    return Err(violation(
+                        close_codes::PROTOCOL_ERROR,
+                        "Frame length has the most significant bit set.",
+                    ));
+                }
+                u64::from_be_bytes(length)
+            }
+            length => length as u64,
+        };
+        if opcode.is_control() && (length > 125 || !fin) {
+            return Err(violation(
+                close_codes::PROTOCOL_ERROR,
+                "Control frames must be short and unfragmented.",
+            ));
+        }
+        if length > max_size as u64 {
+            return Err(violation(
+                close_codes::MESSAGE_TOO_BIG,
+                "Frame is too large.",
+            ));
+        }
+
+        let mut mask = [0; 4];
+        if masked {
+            reader.read_exact(&mut mask)?;
+        }
+        let mut payload = vec![0; length as usize];
+        reader.read_exact(&mut payload)?;
+        if masked {
+            apply_mask(&mut payload, mask);
+        }
+
+        Ok(Frame {
+            fin,
+            opcode,
+            masked,
+            payload,
+        })
+    }
+
+    /// Writes the frame, masked with `mask` if given. Servers never mask.
+    pub fn write_to<W: Write>(&self, writer: &mut W, mask: Option<[u8; 4]>) -> io::Result<()> {
+        let mut head = Vec::with_capacity(14);
+        head.push(if self.fin { 0x80 } else { 0 } | self.opcode.as_u8());
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        match self.payload.len() {
+            length @ 0..=125 => head.push(mask_bit | length as u8),
+            length @ 126..=0xFFFF => {
+                head.push(mask_bit | 126);
+                head.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                head.push(mask_bit | 127);
+                head.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+
+        match mask {
+            Some(mask) => {
+                head.extend_from_slice(&mask);
+                let mut payload = self.payload.clone();
+                apply_mask(&mut payload, mask);
+                writer.write_all(&head)?;
+                writer.write_all(&payload)?;
+            }
+            None => {
+                writer.write_all(&head)?;
+                writer.write_all(&self.payload)?;
+            }
+        }
+        writer.flush()
+    }
+}
+
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+/// A complete message, reassembled from its fragments.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The close code and reason, `None` if the peer sent no code.
+    Close(Option<(u16, String)>),
+}
+
+/// The server side of an established WebSocket connection.
+///
+/// Pings are answered automatically, and a close frame from the client is
+/// echoed before `recv` returns it. Protocol errors close the connection
+/// with the matching status code.
+pub struct WebSocket {
+    stream: Box<dyn Stream>,
+    protocol: Option<String>,
+    max_message_size: usize,
+    /// Opcode and payload of a fragmented message still being received.
+    partial: Option<(Opcode, Vec<u8>)>,
+    close_sent: bool,
+}
+
+impl WebSocket {
+    pub fn new(stream: Box<dyn Stream>) -> WebSocket {
+        WebSocket {
+            stream,
+            protocol: None,
+            max_message_size: MAX_MESSAGE_SIZE,
+            partial: None,
+            close_sent: false,
+        }
+    }
+
+    /// The subprotocol agreed on during the handshake.
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    /// Blocks until the next message arrives.
+    pub fn recv(&mut self) -> io::Result<Message> {
+        let result = self.read_message();
+        if let Err(e) = &result {
+            if let Some(violation) = e.get_ref().and_then(|e| e.downcast_ref::<Violation>()) {
+                let _ = self.close(violation.code, violation.reason);
+            }
+        }
+        result
+    }
+
+    pub fn send(&mut self, message: Message) -> io::Result<()> {
+        match message {
+            Message::Text(text) => self.send_frame(Frame::new(Opcode::Text, text.into_bytes())),
+            Message::Binary(data) => self.send_frame(Frame::new(Opcode::Binary, data)),
+            Message::Ping(data) => self.send_frame(Frame::new(Opcode::Ping, data)),
+            Message::Pong(data) => self.send_frame(Frame::new(Opcode::Pong, data)),
+            Message::Close(Some((code, reason))) => self.close(code, &reason),
+            Message::Close(None) => {
+                self.send_frame(Frame::new(Opcode::Close, Vec::new()))?;
+                self.close_sent = true;
+                Ok(())
+            }
+        }
+    }
+
+    /// Starts the closing handshake. Keep calling `recv` until the client's
+    /// close frame arrives, or just drop the socket.
+    pub fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        payload.truncate(125);
+        self.send_frame(Frame::new(Opcode::Close, payload))?;
+        self.close_sent = true;
+        Ok(())
+    }
+
+    fn send_frame(&mut self, frame: Frame) -> io::Result<()> {
+        if self.close_sent {
+            return Err(io::Error::new(
+                ErrorKind::NotConnected,
+                "WebSocket is closed.",
+            ));
+        }
+        frame.write_to(&mut self.stream, None)
+    }
+
+    fn read_message(&mut self) -> io::Result<Message> {
+        loop {
+            let frame = Frame::read_from(&mut self.stream, self.max_message_size)?;
+            if !frame.masked {
+                return Err(violation(
+                    close_codes::PROTOCOL_ERROR,
+                    "Client frames must be masked.",
+                ));
+            }
+
+            match frame.opcode {
+                Opcode::Ping => {
+                    if !self.close_sent {
+                        self.send_frame(Frame::new(Opcode::Pong, frame.payload.clone()))?;
+                    }
+                    return Ok(Message::Ping(frame.payload));
+                }
+                Opcode::Pong => return Ok(Message::Pong(frame.payload)),
+                Opcode::Close => {
+                    let close = parse_close(&frame.payload)?;
+                    if !self.close_sent {
+                        // Echo the status code back, as RFC 6455 section 5.5.1 asks.
+                        let payload = frame.payload.get(..2).unwrap_or_default().to_vec();
+                        self.send_frame(Frame::new(Opcode::Close, payload))?;
+                        self.close_sent = true;
+                    }
+                    return Ok(Message::Close(close));
+                }
+                Opcode::Text | Opcode::Binary => {
+                    if self.partial.is_some() {
+                        return Err(violation(
+                            close_codes::PROTOCOL_ERROR,
+                            "Expected a continuation frame.",
+                        ));
+                    }
+                    if frame.fin {
+                        return to_message(frame.opcode, frame.payload);
+                    }
+                    self.partial = Some((frame.opcode, frame.payload));
+                }
+                Opcode::Continuation => {
+                    let (opcode, mut payload) = self.partial.take().ok_or_else(|| {
+                        violation(
+                            close_codes::PROTOCOL_ERROR,
+                            "Unexpected continuation frame.",
+                        )
+                    })?;
+                    if payload.len() + frame.payload.len() > self.max_message_size {
+                        return Err(violation(
+                            close_codes::MESSAGE_TOO_BIG,
+                            "Message is too large.",
+                        ));
+                    }
+                    payload.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        return to_message(opcode, payload);
+                    }
+                    self.partial = Some((opcode, payload));
+                }
+            }
+        }
+    }
+}
+
+fn to_message(opcode: Opcode, payload: Vec<u8>) -> io::Result<Message> {
+    match opcode {
+        Opcode::Text => String::from_utf8(payload)
+            .map(Message::Text)
+            .map_err(|_| violation(close_codes::INVALID_DATA, "Text message is not UTF-8.")),
+        _ => Ok(Message::Binary(payload)),
+    }
+}
+
+fn parse_close(payload: &[u8]) -> io::Result<Option<(u16, String)>> {
+    match payload {
+        [] => Ok(None),
+        [_] => Err(violation(
+            close_codes::PROTOCOL_ERROR,
+            "Close frame with a truncated code.",
+        )),
+        [high, low, reason @ ..] => {
+            let code = u16::from_be_bytes([*high, *low]);
+            // 1004-1006 and 1015 are reserved for reporting, never sent.
+            let valid = matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999);
+            if !valid {
+                return Err(violation(
+                    close_codes::PROTOCOL_ERROR,
+                    "Invalid close code.",
+                ));
+            }
+            let reason = String::from_utf8(reason.to_vec())
+                .map_err(|_| violation(close_codes::INVALID_DATA, "Close reason is not UTF-8."))?;
+            Ok(Some((code, reason)))
+        }
+    }
+}
+
+/// Whether `request` asks to switch to the WebSocket protocol.
+pub fn is_upgrade(request: &Request) -> bool {
+    let connection_upgrade = request.header("Connection").is_some_and(|connection| {
+        connection
+            .split(',')
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+    });
+    connection_upgrade
+        && request
+            .header("Upgrade")
+            .is_some_and(|upgrade| upgrade.trim().eq_ignore_ascii_case("websocket"))
+}
+
+/// The `Sec-WebSocket-Accept` value for a client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.trim().as_bytes());
+    hasher.update(GUID.as_bytes());
+    STANDARD.encode(hasher.finalize())
+}
+
+/// Validates an opening handshake and builds the `101 Switching Protocols`
+/// response, choosing the first of the client's subprotocols found in
+/// `protocols`. Returns the error response to send otherwise.
+pub fn handshake(request: &Request, protocols: &[String]) -> Result<Response, Response> {
+    if request.method != Method::Get {
+        return Err(Response::new(405).with_header("Allow", "GET"));
+    }
+    if !is_upgrade(request) {
+        return Err(Response::new(426)
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade"));
+    }
+    if request.header("Sec-WebSocket-Version").map(str::trim) != Some("13") {
+        return Err(Response::new(426).with_header("Sec-WebSocket-Version", "13"));
+    }
+    let key = match request.header("Sec-WebSocket-Key") {
+        Some(key) if STANDARD.decode(key.trim()).is_ok_and(|key| key.len() == 16) => key,
+        _ => return Err(Response::new(400)),
+    };
+
+    let mut response = Response::new(101)
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", &accept_key(key));
+    let offered = request.header("Sec-WebSocket-Protocol").unwrap_or_default();
+    if let Some(protocol) = offered
+        .split(',')
+        .map(str::trim)
+        .find(|offered| protocols.iter().any(|protocol| protocol == offered))
+    {
+        response.set_header("Sec-WebSocket-Protocol", protocol);
+    }
+    Ok(response)
+}
+
+type OnConnect = dyn Fn(&Request, WebSocket) + Send + Sync;
+
+/// Accepts WebSocket connections and runs `on_connect` for each, on the
+/// worker thread that handled the handshake.
+///
+/// ```no_run
+/// use my_server::{router::Router, websocket::{Message, WebSocketHandler}};
+///
+/// let router = Router::new().get(
+///     "/echo",
+///     WebSocketHandler::new(|_, mut socket| {
+///         while let Ok(message) = socket.recv() {
+///             match message {
+///                 Message::Text(_) | Message::Binary(_) => {
+///                     if socket.send(message).is_err() {
+///                         break;
+///                     }
+///                 }
+///                 Message::Close(_) => break,
+///                 _ => {}
+///             }
+///         }
+///     }),
+/// );
+/// ```
+#[derive(Clone)]
+pub struct WebSocketHandler {
+    on_connect: Arc<OnConnect>,
+    protocols: Vec<String>,
+    max_message_size: usize,
+}
+
+impl WebSocketHandler {
+    pub fn new(
+        on_connect: impl Fn(&Request, WebSocket) + Send + Sync + 'static,
+    ) -> WebSocketHandler {
+        WebSocketHandler {
+            on_connect: Arc::new(on_connect),
+            protocols: Vec::new(),
+            max_message_size: MAX_MESSAGE_SIZE,
+        }
+    }
+
+    /// Subprotocols the server speaks, in order of preference.
+    pub fn protocols(mut self, protocols: &[&str]) -> WebSocketHandler {
+        self.protocols = protocols.iter().map(|p| p.to_string()).collect();
+        self
+    }
+
+    pub fn max_message_size(mut self, max_message_size: usize) -> WebSocketHandler {
+        self.max_message_size = max_message_size;
+        self
+    }
+}
+
+impl Handler for WebSocketHandler {
+    fn handle(&self, request: &Request) -> Response {
+        let response = match handshake(request, &self.protocols) {
+            Ok(response) => response,
+            Err(response) => return response,
+        };
+
+        let on_connect = self.on_connect.clone();
+        let request = request.clone();
+        let protocol = response.header("Sec-WebSocket-Protocol").map(str::to_owned);
+        let max_message_size = self.max_message_size;
+        response.with_upgrade(move |stream| {
+            let mut socket = WebSocket::new(stream);
+            socket.protocol = protocol;
+            socket.max_message_size = max_message_size;
+            println!("WebSocket opened for {}.", request.path);
+            on_connect(&request, socket);
+            println!("WebSocket closed for {}.", request.path);
+        })
+    }
+}
+
+/// Copies bytes both ways between `client` and `upstream` until either side
+/// closes the connection. Used to proxy WebSocket connections.
+pub fn relay(mut client: Box<dyn Stream>, mut upstream: Box<dyn Stream>) -> io::Result<()> {
+    let (Some(client_fd), Some(upstream_fd)) = (client.poll_fd(), upstream.poll_fd()) else {
+        return relay_polling(client, upstream);
+    };
+    let mut buffer = vec![0; 16 * 1024];
+    loop {
+        let (client_ready, upstream_ready) = match (client.buffered(), upstream.buffered()) {
+            (false, false) => wait_readable(client_fd, upstream_fd)?,
+            buffered => buffered,
+        };
+        if client_ready && !pump(&mut client, &mut upstream, &mut buffer)? {
+            return Ok(());
+        }
+        if upstream_ready && !pump(&mut upstream, &mut client, &mut buffer)? {
+            return Ok(());
+        }
+    }
+}
+
+/// Blocks until either socket is readable or closed, returning which are.
+fn wait_readable(first: RawFd, second: RawFd) -> io::Result<(bool, bool)> {
+    let mut fds = [first, second].map(|fd| libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    });
+    loop {
+        // SAFETY: `fds` is a valid array of two pollfd for the whole call.
+        let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+        if ready >= 0 {
+            return Ok((fds[0].revents != 0, fds[1].revents != 0));
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+/// `relay` for streams without a socket, e.g. HTTP/2 streams, which takes
+/// turns reading each side with a short timeout.
+fn relay_polling(mut client: Box<dyn Stream>, mut upstream: Box<dyn Stream>) -> io::Result<()> {
+    client.set_read_timeout(Some(RELAY_POLL))?;
+    upstream.set_read_timeout(Some(RELAY_POLL))?;
+    let mut buffer = vec![0; 16 * 1024];
+    loop {
+        if !pump(&mut client, &mut upstream, &mut buffer)?
+            || !pump(&mut upstream, &mut client, &mut buffer)?
+        {
+            return Ok(());
+        }
+    }
+}
+
+/// Forwards whatever `from` has available. Returns false once it is closed.
+fn pump(
+    from: &mut Box<dyn Stream>,
+    to: &mut Box<dyn Stream>,
+    buffer: &mut [u8],
+) -> io::Result<bool> {
+    match from.read(buffer) {
+        Ok(0) => Ok(false),
+        Ok(read) => {
+            to.write_all(&buffer[..read])?;
+            to.flush()?;
+            Ok(true)
+        }
+        Err(e)
+            if matches!(
+                e.kind(),
+                ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+            ) =>
+        {
+            Ok(true)
+        }
+        Err(e) => Err(e),
+    }
+}
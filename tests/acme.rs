@@ -0,0 +1,349 @@
+#![cfg(feature = "acme")]
+
+use std::{
+    fs,
+    io::{BufReader, Write},
+    net::{TcpListener, TcpStream},
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use my_server::{
+    acme::{AcmeClient, Challenges},
+    handler::{Handler, SharedHandler},
+    http::{read_request, Body, Method, Request, Response},
+    settings,
+};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, CertificateSigningRequestParams,
+    ExtendedKeyUsagePurpose, IsCa, KeyPair,
+};
+use ring::{
+    digest::{digest, SHA256},
+    signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED},
+};
+use rustls::{
+    crypto::ring::default_provider,
+    pki_types::{CertificateSigningRequestDer, PrivateKeyDer},
+    ServerConfig, ServerConnection, StreamOwned,
+};
+use serde_json::{json, Value};
+
+const DOMAIN: &str = "a.test";
+const TOKEN: &str = "token";
+
+/// The state of a fake ACME CA (RFC 8555) handing out one certificate per
+/// order. It checks the JWS signatures and nonces, answers the first signed
+/// request with `badNonce`, and validates the HTTP-01 challenge by calling
+/// the client's `Challenges` handler directly.
+struct FakeCa {
+    base: String,
+    ca: Certificate,
+    ca_key: KeyPair,
+    challenges: SharedHandler,
+    nonces: Vec<String>,
+    rejected_nonce: bool,
+    jwk: Option<Value>,
+    /// The status of the order's authorization.
+    authorization: &'static str,
+    chain: Option<String>,
+}
+
+impl FakeCa {
+    fn handle(&mut self, request: &Request) -> (u16, Vec<(&'static str, String)>, Vec<u8>) {
+        let nonce = format!("nonce-{}", self.nonces.len());
+        self.nonces.push(nonce.clone());
+        let mut headers = vec![("Replay-Nonce", nonce)];
+        let path = request.path.as_str();
+        let base = self.base.clone();
+        if request.method != Method::Post {
+            let body = match path {
+                "/directory" => json!({
+                    "newNonce": format!("{base}/nonce"),
+                    "newAccount": format!("{base}/account"),
+                    "newOrder": format!("{base}/new-order"),
+                })
+                .to_string(),
+                _ => String::new(),
+            };
+            return (200, headers, body.into_bytes());
+        }
+
+        let payload = match self.verify(request) {
+            Ok(payload) => payload,
+            Err(kind) => {
+                let problem = json!({ "type": format!("urn:ietf:params:acme:error:{kind}") });
+                return (400, headers, problem.to_string().into_bytes());
+            }
+        };
+        let order = |status: &str, certificate: Option<String>| {
+            json!({
+                "status": status,
+                "authorizations": [format!("{base}/authz")],
+                "finalize": format!("{base}/finalize"),
+                "certificate": certificate,
+            })
+        };
+        let body = match path {
+            "/account" => {
+                headers.push(("Location", format!("{base}/account/1")));
+                json!({ "status": "valid" })
+            }
+            "/new-order" => {
+                assert_eq!(payload["identifiers"][0]["value"], DOMAIN);
+                headers.push(("Location", format!("{base}/order")));
+                self.authorization = "pending";
+                order("pending", None)
+            }
+            "/authz" => json!({
+                "status": self.authorization,
+                "identifier": { "type": "dns", "value": DOMAIN },
+                "challenges": [{
+                    "type": "http-01",
+                    "url": format!("{base}/challenge"),
+                    "token": TOKEN,
+                }],
+            }),
+            "/challenge" => {
+                self.authorization =
+                    if self.key_authorization() == Some(self.expected_authorization()) {
+                        "valid"
+                    } else {
+                        "invalid"
+                    };
+                json!({})
+            }
+            "/finalize" => {
+                assert_eq!(self.authorization, "valid");
+                let csr = URL_SAFE_NO_PAD
+                    .decode(payload["csr"].as_str().unwrap())
+                    .unwrap();
+                let csr = CertificateSigningRequestParams::from_der(
+                    &CertificateSigningRequestDer::from(csr),
+                )
+                .unwrap();
+                let certificate = csr.signed_by(&self.ca, &self.ca_key).unwrap();
+                self.chain = Some(certificate.pem() + &self.ca.pem());
+                order("valid", Some(format!("{base}/certificate")))
+            }
+            "/order" => order(
+                if self.chain.is_some() {
+                    "valid"
+                } else {
+                    "pending"
+                },
+                Some(format!("{base}/certificate")),
+            ),
+            "/certificate" => return (200, headers, self.chain.clone().unwrap().into_bytes()),
+            _ => return (404, headers, Vec::new()),
+        };
+        (200, headers, body.to_string().into_bytes())
+    }
+
+    /// Checks the nonce, url and signature of a JWS request and returns its
+    /// payload, or the ACME error type to answer with.
+    fn verify(&mut self, request: &Request) -> Result<Value, &'static str> {
+        let jws: Value = serde_json::from_slice(&request.body).map_err(|_| "malformed")?;
+        let field = |name: &str| jws[name].as_str().unwrap_or_default().to_owned();
+        let (protected, payload) = (field("protected"), field("payload"));
+        let header: Value =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(&protected).unwrap()).unwrap();
+
+        let nonce = header["nonce"].as_str().unwrap_or_default();
+        if !self.nonces.iter().any(|issued| issued == nonce) {
+            return Err("badNonce");
+        }
+        self.nonces.retain(|issued| issued != nonce);
+        if !self.rejected_nonce {
+            self.rejected_nonce = true;
+            return Err("badNonce");
+        }
+        assert_eq!(header["url"], format!("{}{}", self.base, request.path));
+        assert_eq!(header["alg"], "ES256");
+        if header["jwk"].is_object() {
+            self.jwk = Some(header["jwk"].clone());
+        } else {
+            assert_eq!(header["kid"], format!("{}/account/1", self.base));
+        }
+
+        let jwk = self.jwk.as_ref().ok_or("accountDoesNotExist")?;
+        let coordinate = |name: &str| URL_SAFE_NO_PAD.decode(jwk[name].as_str().unwrap()).unwrap();
+        let point = [vec![0x04], coordinate("x"), coordinate("y")].concat();
+        let signature = URL_SAFE_NO_PAD.decode(field("signature")).unwrap();
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, point)
+            .verify(format!("{protected}.{payload}").as_bytes(), &signature)
+            .map_err(|_| "unauthorized")?;
+
+        if payload.is_empty() {
+            return Ok(Value::Null);
+        }
+        Ok(serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).unwrap()).unwrap())
+    }
+
+    /// What the client's http listener serves for the challenge.
+    fn key_authorization(&self) -> Option<String> {
+        let path = format!("/.well-known/acme-challenge/{TOKEN}");
+        let request = Request::parse(format!("GET {path} HTTP/1.1\r\n\r\n").as_bytes()).unwrap();
+        match self.challenges.handle(&request) {
+            Response {
+                status: 200,
+                body: Body::Full(body),
+                ..
+            } => String::from_utf8(body).ok(),
+            _ => None,
+        }
+    }
+
+    /// The token and the RFC 7638 thumbprint of the account key.
+    fn expected_authorization(&self) -> String {
+        let jwk = self.jwk.as_ref().unwrap();
+        let canonical = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":{},"y":{}}}"#,
+            jwk["x"], jwk["y"]
+        );
+        let thumbprint = digest(&SHA256, canonical.as_bytes());
+        format!("{TOKEN}.{}", URL_SAFE_NO_PAD.encode(thumbprint))
+    }
+}
+
+/// Starts a fake CA for `challenges` and returns its directory URL and the
+/// path of its root certificate.
+fn start_ca(challenges: &Arc<Challenges>, directory: &Path) -> (String, String) {
+    let ca_key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = params.self_signed(&ca_key).unwrap();
+    let ca_file = directory.join("ca.pem").to_str().unwrap().to_owned();
+    fs::write(&ca_file, ca.pem()).unwrap();
+
+    let server_key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(vec!["localhost".to_owned()]).unwrap();
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    let server = params.signed_by(&server_key, &ca, &ca_key).unwrap();
+    let config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![server.der().clone()],
+            PrivateKeyDer::Pkcs8(server_key.serialize_der().into()),
+        )
+        .unwrap();
+    let config = Arc::new(config);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base = format!(
+        "https://localhost:{}",
+        listener.local_addr().unwrap().port()
+    );
+    let not_found: SharedHandler = Arc::new(|_: &Request| Response::new(404));
+    let ca = Mutex::new(FakeCa {
+        base: base.clone(),
+        ca,
+        ca_key,
+        challenges: challenges.handler(not_found),
+        nonces: Vec::new(),
+        rejected_nonce: false,
+        jwk: None,
+        authorization: "pending",
+        chain: None,
+    });
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let _ = serve(stream.unwrap(), &config, &ca);
+        }
+    });
+    (format!("{base}/directory"), ca_file)
+}
+
+/// Answers the one request of a connection.
+fn serve(stream: TcpStream, config: &Arc<ServerConfig>, ca: &Mutex<FakeCa>) -> Result<(), String> {
+    let connection = ServerConnection::new(config.clone()).map_err(|e| e.to_string())?;
+    let mut reader = BufReader::new(StreamOwned::new(connection, stream));
+    let request = read_request(&mut reader)?.ok_or("No request")?;
+    let (status, headers, body) = ca.lock().unwrap().handle(&request);
+
+    let mut response = format!("HTTP/1.1 {status} Status\r\n");
+    for (name, value) in headers {
+        response.push_str(&format!("{name}: {value}\r\n"));
+    }
+    response.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+    let stream = reader.get_mut();
+    stream
+        .write_all(response.as_bytes())
+        .map_err(|e| e.to_string())?;
+    stream.write_all(&body).map_err(|e| e.to_string())?;
+    stream.conn.send_close_notify();
+    stream.flush().map_err(|e| e.to_string())
+}
+
+/// Settings for a fake CA with all files in a fresh directory.
+fn settings(name: &str, challenges: &Arc<Challenges>) -> settings::Acme {
+    let directory =
+        std::env::temp_dir().join(format!("my_server_acme_{name}_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir_all(&directory).unwrap();
+    let (url, ca) = start_ca(challenges, &directory);
+    let file = |name: &str| directory.join(name).to_str().unwrap().to_owned();
+    settings::Acme {
+        directory: url,
+        domains: vec![DOMAIN.to_owned()],
+        contact: None,
+        account_key: file("account.key"),
+        certificate: file("certificate.pem"),
+        private_key: file("private.key"),
+        renew_before: 30,
+        check_interval: 3600,
+        ca: Some(ca),
+    }
+}
+
+fn mode(path: &str) -> u32 {
+    fs::metadata(path).unwrap().permissions().mode() & 0o777
+}
+
+#[test]
+fn obtains_certificate_from_ca() {
+    let challenges = Arc::new(Challenges::default());
+    let settings = settings("obtain", &challenges);
+    assert!(AcmeClient::needs_renewal(&settings));
+
+    let mut client = AcmeClient::new(&settings, challenges.clone()).unwrap();
+    client.obtain().unwrap();
+    assert!(!AcmeClient::needs_renewal(&settings));
+    for file in [
+        &settings.account_key,
+        &settings.certificate,
+        &settings.private_key,
+    ] {
+        assert_eq!(mode(file), 0o600, "{file}");
+    }
+
+    // The saved account key is reused by the next client.
+    let account_key = fs::read(&settings.account_key).unwrap();
+    let mut client = AcmeClient::new(&settings, challenges).unwrap();
+    client.obtain().unwrap();
+    assert_eq!(fs::read(&settings.account_key).unwrap(), account_key);
+}
+
+#[test]
+fn placeholder_is_replaced_in_background() {
+    let challenges = Arc::new(Challenges::default());
+    let settings = settings("placeholder", &challenges);
+    AcmeClient::write_placeholder(&settings).unwrap();
+    let placeholder = fs::read(&settings.certificate).unwrap();
+    assert!(AcmeClient::needs_renewal(&settings));
+    assert_eq!(mode(&settings.private_key), 0o600);
+
+    let (renewed, received) = mpsc::channel();
+    AcmeClient::start_renewal(settings.clone(), challenges, move || {
+        let _ = renewed.send(());
+    });
+    received.recv_timeout(Duration::from_secs(30)).unwrap();
+    assert!(!AcmeClient::needs_renewal(&settings));
+    assert_ne!(fs::read(&settings.certificate).unwrap(), placeholder);
+}
@@ -0,0 +1,182 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{IpAddr, TcpListener},
+    os::unix::net::UnixListener,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use my_server::{
+    balancer::{Balancer, HealthCheck, Strategy},
+    proxy::Upstream,
+};
+
+fn balancer(strategy: Strategy, max_fails: usize, fail_timeout: Duration) -> Balancer {
+    let upstreams = ["a:80", "b:80", "c:80"].map(Upstream::parse).to_vec();
+    Balancer::new(upstreams, strategy, max_fails, fail_timeout)
+}
+
+fn picked(balancer: &Balancer, client: Option<IpAddr>) -> String {
+    balancer.pick(client, &[]).unwrap().upstream().to_string()
+}
+
+/// Marks `upstream` down through passive failure reports.
+fn fail(balancer: &Balancer, upstream: &str) {
+    let upstream = Upstream::parse(upstream);
+    let others: Vec<Upstream> = balancer
+        .upstreams()
+        .iter()
+        .map(|state| state.upstream.clone())
+        .filter(|other| *other != upstream)
+        .collect();
+    let lease = balancer.pick(None, &others).unwrap();
+    balancer.report(&lease, false);
+}
+
+fn eventually(condition: impl Fn() -> bool) {
+    let start = Instant::now();
+    while !condition() {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn round_robin_takes_turns() {
+    let balancer = balancer(Strategy::RoundRobin, 1, Duration::from_secs(60));
+    let picks: Vec<String> = (0..6).map(|_| picked(&balancer, None)).collect();
+    assert_eq!(picks, ["a:80", "b:80", "c:80", "a:80", "b:80", "c:80"]);
+
+    let excluded = balancer.pick(None, &[Upstream::parse("a:80"), Upstream::parse("b:80")]);
+    assert_eq!(excluded.unwrap().upstream().to_string(), "c:80");
+}
+
+#[test]
+fn least_connections_picks_the_least_busy() {
+    let balancer = balancer(Strategy::LeastConnections, 1, Duration::from_secs(60));
+    let first = balancer.pick(None, &[]).unwrap();
+    let second = balancer.pick(None, &[]).unwrap();
+    assert_ne!(first.upstream(), second.upstream());
+    let third = balancer.pick(None, &[]).unwrap();
+    let idle = third.upstream().clone();
+    drop(third);
+
+    for _ in 0..3 {
+        assert_eq!(*balancer.pick(None, &[]).unwrap().upstream(), idle);
+    }
+    assert_eq!(
+        balancer
+            .upstreams()
+            .iter()
+            .map(|state| state.active_connections())
+            .sum::<usize>(),
+        2
+    );
+}
+
+#[test]
+fn ip_hash_keeps_clients_on_their_upstream() {
+    let balancer = balancer(Strategy::IpHash, 1, Duration::from_secs(60));
+    let clients: Vec<Option<IpAddr>> = (1..=32)
+        .map(|i| Some(format!("10.0.0.{i}").parse().unwrap()))
+        .collect();
+    let before: Vec<String> = clients
+        .iter()
+        .map(|client| picked(&balancer, *client))
+        .collect();
+    assert!(clients
+        .iter()
+        .zip(&before)
+        .all(|(client, upstream)| picked(&balancer, *client) == *upstream));
+
+    assert!(before.iter().any(|upstream| upstream == "b:80"));
+    // Only the clients of the upstream that went down move, each to the
+    // upstream after it.
+    fail(&balancer, "b:80");
+    for (client, upstream) in clients.iter().zip(&before) {
+        let expected = match upstream.as_str() {
+            "b:80" => "c:80",
+            upstream => upstream,
+        };
+        assert_eq!(picked(&balancer, *client), expected);
+    }
+}
+
+#[test]
+fn failing_upstreams_are_skipped_for_fail_timeout() {
+    let balancer = balancer(Strategy::RoundRobin, 2, Duration::from_millis(200));
+    fail(&balancer, "a:80");
+    assert!(balancer.upstreams()[0].is_available());
+    fail(&balancer, "a:80");
+    assert!(!balancer.upstreams()[0].is_available());
+    for _ in 0..4 {
+        assert_ne!(picked(&balancer, None), "a:80");
+    }
+
+    eventually(|| balancer.upstreams()[0].is_available());
+    let picks: Vec<String> = (0..3).map(|_| picked(&balancer, None)).collect();
+    assert!(picks.iter().any(|upstream| upstream == "a:80"));
+
+    // A success in between resets the count.
+    let only_a = [Upstream::parse("b:80"), Upstream::parse("c:80")];
+    balancer.report(&balancer.pick(None, &only_a).unwrap(), false);
+    balancer.report(&balancer.pick(None, &only_a).unwrap(), true);
+    balancer.report(&balancer.pick(None, &only_a).unwrap(), false);
+    assert!(balancer.upstreams()[0].is_available());
+}
+
+#[test]
+fn health_checks_take_upstreams_out_and_back() {
+    // Answers the check with the status in `status`, read on each request.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let status = Arc::new(std::sync::Mutex::new(200));
+    let answer = status.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut line = String::new();
+            let mut reader = BufReader::new(&stream);
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            let status = *answer.lock().unwrap();
+            let _ = write!(stream, "HTTP/1.1 {status} X\r\nContent-Length: 0\r\n\r\n");
+        }
+    });
+    // Accepts, but never answers.
+    let dir = std::env::temp_dir().join(format!("balancer-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let socket = dir.join("hung.sock");
+    let _ = std::fs::remove_file(&socket);
+    let hung = UnixListener::bind(&socket).unwrap();
+    let _accepted = thread::spawn(move || hung.incoming().collect::<Vec<_>>());
+
+    let balancer = Arc::new(Balancer::new(
+        vec![
+            Upstream::parse(&address),
+            Upstream::parse(&format!("unix:{}", socket.display())),
+        ],
+        Strategy::RoundRobin,
+        1,
+        Duration::from_secs(60),
+    ));
+    balancer.start_health_checks(HealthCheck {
+        path: "/health".to_owned(),
+        interval: Duration::from_millis(20),
+        timeout: Duration::from_millis(200),
+    });
+
+    let states = balancer.upstreams();
+    eventually(|| !states[1].is_available());
+    assert!(states[0].is_available());
+
+    *status.lock().unwrap() = 500;
+    eventually(|| !states[0].is_available());
+    assert!(balancer.pick(None, &[]).is_none());
+
+    *status.lock().unwrap() = 204;
+    eventually(|| states[0].is_available());
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,229 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use my_server::{
+    cgi::{CgiMapping, CgiResponse, Script},
+    http::{Request, Response},
+    request_handler::RequestHandler,
+};
+
+/// A document root with `scripts` written below `cgi-bin`.
+fn document_root(name: &str, scripts: &[(&str, &str)]) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("my_server_cgi_{name}_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("cgi-bin")).unwrap();
+    for (name, script) in scripts {
+        fs::write(root.join("cgi-bin").join(name), script).unwrap();
+    }
+    root
+}
+
+fn mapping(timeout: Duration, max_output: usize) -> CgiMapping {
+    CgiMapping {
+        prefix: Some("/cgi-bin".to_owned()),
+        extension: None,
+        interpreter: Some("/bin/sh".to_owned()),
+        timeout,
+        max_output,
+    }
+}
+
+fn run(root: &Path, mapping: &CgiMapping, request: &str) -> CgiResponse {
+    let request = Request::parse(request.as_bytes()).unwrap();
+    let script = Script::resolve(root, &request.path).unwrap();
+    mapping.run(&request, &script, root)
+}
+
+fn document(response: CgiResponse) -> Response {
+    match response {
+        CgiResponse::Document(response) => response,
+        CgiResponse::LocalRedirect(location) => panic!("redirected to {location}"),
+    }
+}
+
+fn body(response: &Response) -> String {
+    String::from_utf8(response.body.as_bytes().unwrap().to_vec()).unwrap()
+}
+
+#[test]
+fn scripts_get_the_request_as_meta_variables() {
+    let root = document_root(
+        "env",
+        &[(
+            "env.sh",
+            "printf 'Content-Type: text/plain\\n\\n'\n\
+             for name in REQUEST_METHOD QUERY_STRING SCRIPT_NAME PATH_INFO CONTENT_LENGTH \
+             CONTENT_TYPE HTTP_X_TRACE HTTP_AUTHORIZATION SERVER_NAME SERVER_PORT; do\n\
+             eval \"echo $name=\\${$name-unset}\"\n\
+             done\n\
+             echo body=$(cat)\n",
+        )],
+    );
+    let response = document(run(
+        &root,
+        &mapping(Duration::from_secs(5), 4096),
+        "POST /cgi-bin/env.sh/extra/path?a=1&b=2 HTTP/1.1\r\nHost: example.com:8080\r\n\
+         X-Trace: abc\r\nAuthorization: Basic c2VjcmV0\r\nContent-Type: text/plain\r\n\
+         Content-Length: 5\r\n\r\nhello",
+    ));
+
+    assert_eq!(response.status, 200);
+    assert_eq!(
+        body(&response),
+        "REQUEST_METHOD=POST\nQUERY_STRING=a=1&b=2\nSCRIPT_NAME=/cgi-bin/env.sh\n\
+         PATH_INFO=/extra/path\nCONTENT_LENGTH=5\nCONTENT_TYPE=text/plain\nHTTP_X_TRACE=abc\n\
+         HTTP_AUTHORIZATION=unset\nSERVER_NAME=example.com\nSERVER_PORT=8080\nbody=hello\n"
+    );
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn headers_and_status_come_from_the_script() {
+    let root = document_root(
+        "headers",
+        &[
+            (
+                "created.sh",
+                "printf 'Status: 201 Created\\r\\nContent-Type: text/plain\\r\\nX-Id: 7\\r\\n\\r\\nmade'",
+            ),
+            ("moved.sh", "printf 'Location: https://example.com/\\n\\n'"),
+            ("local.sh", "printf 'Location: /index.html?from=cgi\\n\\n'"),
+            ("empty.sh", "printf 'X-Id: 7\\n\\n'"),
+            ("unterminated.sh", "printf 'Content-Type: text/plain\\n'"),
+        ],
+    );
+    let cgi = mapping(Duration::from_secs(5), 4096);
+    let get = |script: &str| {
+        run(
+            &root,
+            &cgi,
+            &format!("GET /cgi-bin/{script} HTTP/1.1\r\n\r\n"),
+        )
+    };
+
+    let response = document(get("created.sh"));
+    assert_eq!(response.status, 201);
+    assert_eq!(response.header("Content-Type"), Some("text/plain"));
+    assert_eq!(response.header("X-Id"), Some("7"));
+    assert_eq!(response.header("Status"), None);
+    assert_eq!(body(&response), "made");
+
+    let response = document(get("moved.sh"));
+    assert_eq!(response.status, 302);
+    assert_eq!(response.header("Location"), Some("https://example.com/"));
+
+    assert!(matches!(
+        get("local.sh"),
+        CgiResponse::LocalRedirect(location) if location == "/index.html?from=cgi"
+    ));
+    assert_eq!(document(get("empty.sh")).status, 502);
+    assert_eq!(document(get("unterminated.sh")).status, 502);
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn local_redirects_are_served_by_the_handler() {
+    let root = document_root(
+        "redirect",
+        &[
+            ("local.sh", "printf 'Location: /page.html\\n\\n'"),
+            ("loop.sh", "printf 'Location: /cgi-bin/loop.sh\\n\\n'"),
+        ],
+    );
+    fs::write(root.join("page.html"), "page").unwrap();
+    let handler = RequestHandler::new(root.display().to_string())
+        .unwrap()
+        .with_cgi(vec![mapping(Duration::from_secs(5), 4096)]);
+    let serve = |request: &str| handler.serve(&Request::parse(request.as_bytes()).unwrap());
+
+    let response = serve("POST /cgi-bin/local.sh HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi");
+    assert_eq!(response.status, 200);
+    assert_eq!(body(&response), "page");
+    assert_eq!(serve("GET /cgi-bin/loop.sh HTTP/1.1\r\n\r\n").status, 500);
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn scripts_are_stopped_at_the_timeout() {
+    let root = document_root(
+        "timeout",
+        &[
+            ("slow.sh", "sleep 10"),
+            // Exits at once, but leaves a process holding stdout open.
+            (
+                "background.sh",
+                "printf 'Content-Type: text/plain\\n\\n'\nsleep 10 &\n",
+            ),
+        ],
+    );
+    let cgi = mapping(Duration::from_millis(500), 4096);
+    for script in ["slow.sh", "background.sh"] {
+        let started = Instant::now();
+        let response = document(run(
+            &root,
+            &cgi,
+            &format!("GET /cgi-bin/{script} HTTP/1.1\r\n\r\n"),
+        ));
+        assert_eq!(response.status, 504, "{script}");
+        assert!(started.elapsed() < Duration::from_secs(3), "{script}");
+    }
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn output_is_limited_to_max_output() {
+    let root = document_root(
+        "output",
+        &[
+            (
+                "large.sh",
+                "printf 'Content-Type: text/plain\\n\\n'\nhead -c 100000 /dev/zero",
+            ),
+            (
+                "endless.sh",
+                "printf 'Content-Type: text/plain\\n\\n'\nexec cat /dev/zero",
+            ),
+            ("small.sh", "printf 'Content-Type: text/plain\\n\\nok'"),
+        ],
+    );
+    let cgi = mapping(Duration::from_secs(5), 1000);
+    let get = |script: &str| {
+        run(
+            &root,
+            &cgi,
+            &format!("GET /cgi-bin/{script} HTTP/1.1\r\n\r\n"),
+        )
+    };
+
+    assert_eq!(document(get("large.sh")).status, 502);
+    let started = Instant::now();
+    assert_eq!(document(get("endless.sh")).status, 502);
+    assert!(started.elapsed() < Duration::from_secs(3));
+    assert_eq!(body(&document(get("small.sh"))), "ok");
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn scripts_resolve_only_inside_the_document_root() {
+    let root = document_root("resolve", &[("inside.sh", "")]);
+    let outside = document_root("resolve_outside", &[("outside.sh", "")]);
+    std::os::unix::fs::symlink(
+        outside.join("cgi-bin/outside.sh"),
+        root.join("cgi-bin/link.sh"),
+    )
+    .unwrap();
+    std::os::unix::fs::symlink(outside.join("cgi-bin"), root.join("linked")).unwrap();
+
+    let script = Script::resolve(&root, "/cgi-bin/inside.sh/a/b").unwrap();
+    assert_eq!(script.name, "/cgi-bin/inside.sh");
+    assert_eq!(script.path_info, "/a/b");
+    assert!(Script::resolve(&root, "/cgi-bin/link.sh").is_none());
+    assert!(Script::resolve(&root, "/linked/outside.sh").is_none());
+    assert!(Script::resolve(&root, "/cgi-bin/../cgi-bin/inside.sh").is_none());
+    assert!(Script::resolve(&root, "/cgi-bin/missing.sh").is_none());
+    let _ = fs::remove_dir_all(&root);
+    let _ = fs::remove_dir_all(&outside);
+}
@@ -0,0 +1,213 @@
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering::SeqCst},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use my_server::{
+    balancer::{Balancer, Strategy},
+    fastcgi::FastCgi,
+    http::{Body, Request, Response},
+    proxy::Upstream,
+    settings,
+};
+
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const END_REQUEST: u8 = 3;
+
+/// A FastCGI responder answering every request with `stdout`, then closing
+/// each connection after `requests_per_connection` requests. Returns its
+/// address and the number of connections it accepted.
+fn backend(stdout: Vec<u8>, requests_per_connection: usize) -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let accepted = connections.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            accepted.fetch_add(1, SeqCst);
+            let stdout = stdout.clone();
+            thread::spawn(move || {
+                let mut stream = stream.unwrap();
+                for _ in 0..requests_per_connection {
+                    if !read_request(&mut stream) {
+                        return;
+                    }
+                    for chunk in stdout.chunks(0xffff) {
+                        write_record(&mut stream, STDOUT, chunk);
+                    }
+                    write_record(&mut stream, STDOUT, &[]);
+                    write_record(&mut stream, END_REQUEST, &[0; 8]);
+                }
+            });
+        }
+    });
+    (addr, connections)
+}
+
+/// Reads records up to the empty stdin record ending a request, returning
+/// false if the connection closed first.
+fn read_request(stream: &mut TcpStream) -> bool {
+    loop {
+        let mut header = [0; 8];
+        if stream.read_exact(&mut header).is_err() {
+            return false;
+        }
+        let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+        let mut content = vec![0; length + header[6] as usize];
+        if stream.read_exact(&mut content).is_err() {
+            return false;
+        }
+        if header[1] == STDIN && length == 0 {
+            return true;
+        }
+    }
+}
+
+fn write_record(stream: &mut TcpStream, kind: u8, content: &[u8]) {
+    let mut record = vec![1, kind, 0, 1];
+    record.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    record.extend_from_slice(&[0, 0]);
+    record.extend_from_slice(content);
+    stream.write_all(&record).unwrap();
+}
+
+fn fastcgi(addr: SocketAddr) -> FastCgi {
+    let balancer = Balancer::new(
+        vec![Upstream::parse(&addr.to_string())],
+        Strategy::RoundRobin,
+        1,
+        Duration::ZERO,
+    );
+    FastCgi::new("/app", Arc::new(balancer), PathBuf::from("/srv"))
+}
+
+fn get(fastcgi: &FastCgi, method: &str) -> Response {
+    let request = Request::parse(
+        format!("{method} /app/index HTTP/1.1\r\nHost: example.com\r\nContent-Length: 0\r\n\r\n")
+            .as_bytes(),
+    )
+    .unwrap();
+    let script = fastcgi.script(&request.path).unwrap();
+    fastcgi.respond(&request, &script)
+}
+
+fn body(response: Response) -> Vec<u8> {
+    match response.body {
+        Body::Full(bytes) => bytes,
+        Body::Stream(mut stream) => {
+            let mut bytes = Vec::new();
+            stream.read_to_end(&mut bytes).unwrap();
+            bytes
+        }
+    }
+}
+
+#[test]
+fn round_trip_reuses_connection() {
+    let (addr, connections) = backend(b"Status: 201\r\nX-Backend: yes\r\n\r\nhello".to_vec(), 10);
+    let fastcgi = fastcgi(addr);
+
+    for _ in 0..3 {
+        let response = get(&fastcgi, "GET");
+        assert_eq!(response.status, 201);
+        assert_eq!(response.header("X-Backend"), Some("yes"));
+        assert_eq!(body(response), b"hello");
+    }
+    assert_eq!(connections.load(SeqCst), 1);
+}
+
+#[test]
+fn closed_pooled_connection_is_retried_for_get() {
+    let (addr, connections) = backend(b"Content-Type: text/plain\r\n\r\nhello".to_vec(), 1);
+    let fastcgi = fastcgi(addr);
+
+    assert_eq!(body(get(&fastcgi, "GET")), b"hello");
+    // The backend has closed the pooled connection by now.
+    thread::sleep(Duration::from_millis(50));
+    let response = get(&fastcgi, "GET");
+    assert_eq!(response.status, 200);
+    assert_eq!(body(response), b"hello");
+    assert_eq!(connections.load(SeqCst), 2);
+}
+
+#[test]
+fn post_does_not_use_pooled_connection() {
+    let (addr, connections) = backend(b"Content-Type: text/plain\r\n\r\nhello".to_vec(), 10);
+    let fastcgi = fastcgi(addr);
+
+    assert_eq!(body(get(&fastcgi, "GET")), b"hello");
+    assert_eq!(body(get(&fastcgi, "POST")), b"hello");
+    assert_eq!(connections.load(SeqCst), 2);
+}
+
+#[test]
+fn oversized_head_is_bad_gateway() {
+    let mut stdout = b"X-Filler: ".to_vec();
+    stdout.extend(std::iter::repeat_n(b'x', 200 * 1024));
+    let (addr, _) = backend(stdout, 1);
+
+    assert_eq!(get(&fastcgi(addr), "GET").status, 502);
+}
+
+/// A route for `.php` scripts below a new document root holding `index.php`.
+fn php(addr: SocketAddr, name: &str) -> (FastCgi, PathBuf) {
+    let root =
+        std::env::temp_dir().join(format!("my_server_fastcgi_{name}_{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("index.php"), "").unwrap();
+    let settings: settings::FastCgi = toml::from_str(&format!(
+        "extension = \"php\"\nindex = \"index.php\"\nbackend = \"{addr}\"\nroot = \"{}\"",
+        root.display()
+    ))
+    .unwrap();
+    (FastCgi::from_settings(&settings, &root).unwrap(), root)
+}
+
+#[test]
+fn path_info_is_normalised() {
+    let (scripts, root) = php("127.0.0.1:9".parse().unwrap(), "path_info");
+
+    let script = scripts.script("/index.php/a/./b//c").unwrap();
+    assert_eq!(script.name, "/index.php");
+    assert_eq!(script.path_info, "/a/b/c");
+    assert_eq!(
+        scripts
+            .script("/index.php/../../etc/passwd")
+            .unwrap()
+            .path_info,
+        "/etc/passwd"
+    );
+    assert_eq!(scripts.script("/index.php/a/..").unwrap().path_info, "");
+    assert!(scripts.script("/../index.php").is_none());
+
+    let app = fastcgi("127.0.0.1:9".parse().unwrap());
+    assert_eq!(app.script("/app/x/../../y").unwrap().path_info, "/y");
+    let _ = std::fs::remove_dir_all(&root);
+}
+
+#[test]
+fn missing_scripts_are_not_found_locally() {
+    let (addr, connections) = backend(b"Content-Type: text/plain\r\n\r\nhello".to_vec(), 10);
+    let (fastcgi, root) = php(addr, "missing");
+    let get = |path: &str| {
+        let request = Request::parse(format!("GET {path} HTTP/1.1\r\n\r\n").as_bytes()).unwrap();
+        let script = fastcgi.script(&request.path).unwrap();
+        fastcgi.respond(&request, &script)
+    };
+
+    assert_eq!(get("/missing.php").status, 404);
+    assert_eq!(get("/missing.php/index.php").status, 404);
+    assert_eq!(connections.load(SeqCst), 0);
+    assert_eq!(body(get("/index.php/extra")), b"hello");
+    assert_eq!(body(get("/")), b"hello");
+    assert_eq!(connections.load(SeqCst), 1);
+    let _ = std::fs::remove_dir_all(&root);
+}
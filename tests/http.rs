@@ -0,0 +1,74 @@
+use std::io::Read;
+
+use my_server::http::{read_request, ChunkedReader, Response};
+
+fn read(request: &str) -> Result<Vec<u8>, String> {
+    let mut reader = request.as_bytes();
+    read_request(&mut reader).map(|request| request.unwrap().body)
+}
+
+fn written(response: Response) -> String {
+    let mut output = Vec::new();
+    response.write_to(&mut output).unwrap();
+    String::from_utf8(output).unwrap()
+}
+
+#[test]
+fn bodies_are_read_by_content_length_or_chunks() {
+    assert_eq!(
+        read("POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nabcdef").unwrap(),
+        b"abcd"
+    );
+    assert_eq!(
+        read("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nabcd\r\n0\r\n\r\n")
+            .unwrap(),
+        b"abcd"
+    );
+    assert_eq!(read("GET / HTTP/1.1\r\n\r\n").unwrap(), b"");
+}
+
+#[test]
+fn ambiguous_framing_is_rejected() {
+    let heads = [
+        "Transfer-Encoding: gzip, chunked",
+        "Transfer-Encoding: gzip",
+        "Transfer-Encoding: chunked\r\nTransfer-Encoding: chunked",
+        "Transfer-Encoding: chunked\r\nContent-Length: 4",
+        "Content-Length: 4\r\nContent-Length: 4",
+        "Content-Length: 4\r\nContent-Length: 5",
+        "Content-Length: 4, 4",
+        "Content-Length: -4",
+    ];
+    for head in heads {
+        let request = format!("POST / HTTP/1.1\r\n{head}\r\n\r\n4\r\nabcd\r\n0\r\n\r\n");
+        assert!(read(&request).is_err(), "{head:?}");
+    }
+}
+
+#[test]
+fn bodiless_statuses_get_no_content_length() {
+    for status in [100, 204, 304] {
+        let output = written(Response::new(status));
+        assert!(!output.contains("Content-Length"), "{output:?}");
+        assert!(output.ends_with("\r\n\r\n"), "{output:?}");
+    }
+    let output = written(Response::new(200));
+    assert!(output.contains("Content-Length: 0\r\n"), "{output:?}");
+}
+
+#[test]
+fn chunk_data_must_end_with_crlf() {
+    for body in ["4\r\nabcdXY0\r\n\r\n", "4\r\nabcd\n0\r\n\r\n"] {
+        let mut decoded = Vec::new();
+        let read = ChunkedReader::new(body.as_bytes()).read_to_end(&mut decoded);
+        assert!(read.is_err(), "{body:?}");
+
+        let request = format!("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{body}");
+        assert!(read_request(&mut request.as_bytes()).is_err(), "{body:?}");
+    }
+    let mut decoded = Vec::new();
+    ChunkedReader::new(&b"4\r\nabcd\r\n0\r\n\r\n"[..])
+        .read_to_end(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, b"abcd");
+}
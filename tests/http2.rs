@@ -0,0 +1,196 @@
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering::SeqCst},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use my_server::{
+    event_loop::Mode,
+    handler::SharedHandler,
+    http::{Request, Response},
+    settings::Http,
+    tcp_server::TcpServer,
+};
+
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const GOAWAY: u8 = 0x7;
+const END_STREAM_HEADERS: u8 = 0x5;
+const CANCEL: u32 = 0x8;
+const REFUSED_STREAM: u32 = 0x7;
+const ENHANCE_YOUR_CALM: u32 = 0xB;
+
+fn frame(kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+    bytes.extend([kind, flags]);
+    bytes.extend(stream_id.to_be_bytes());
+    bytes.extend(payload);
+    bytes
+}
+
+/// Starts an h2c server running `handler`, returning its address.
+fn start_server(handler: SharedHandler) -> String {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let settings = Http {
+        port,
+        redirect: None,
+        threads: 2,
+        min_threads: None,
+        max_threads: None,
+        idle_timeout: 60,
+        queue_size: 16,
+        mode: Mode::Threaded,
+        h2c: true,
+        h2c_upgrade: false,
+    };
+    let mut server = TcpServer::with_handler("127.0.0.1".to_owned(), &settings, handler).unwrap();
+    server.start_thread();
+    // Leaked so the server keeps running for the rest of the test.
+    std::mem::forget(server);
+
+    let address = format!("127.0.0.1:{port}");
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while TcpStream::connect(&address).is_err() {
+        assert!(Instant::now() < deadline, "server did not start");
+        thread::sleep(Duration::from_millis(10));
+    }
+    address
+}
+
+/// Reads frames until `until` returns true for one, returning them all as
+/// (type, stream, payload).
+fn read_frames(
+    stream: &mut TcpStream,
+    until: impl Fn(u8, u32, &[u8]) -> bool,
+) -> Vec<(u8, u32, Vec<u8>)> {
+    let mut frames = Vec::new();
+    loop {
+        let mut head = [0; 9];
+        stream.read_exact(&mut head).unwrap();
+        let length = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+        let stream_id = u32::from_be_bytes(head[5..].try_into().unwrap()) & 0x7FFF_FFFF;
+        let mut payload = vec![0; length];
+        stream.read_exact(&mut payload).unwrap();
+        let done = until(head[3], stream_id, &payload);
+        frames.push((head[3], stream_id, payload));
+        if done {
+            return frames;
+        }
+    }
+}
+
+/// Opening streams and resetting them at once must not start more handlers
+/// than the connection allows streams, see CVE-2023-44487.
+#[test]
+fn rapid_reset_is_refused_past_the_stream_limit() {
+    let running = Arc::new(AtomicUsize::new(0));
+    let most = Arc::new(AtomicUsize::new(0));
+    let (counter, peak) = (running.clone(), most.clone());
+    let address = start_server(Arc::new(move |_: &Request| {
+        peak.fetch_max(counter.fetch_add(1, SeqCst) + 1, SeqCst);
+        thread::sleep(Duration::from_millis(500));
+        counter.fetch_sub(1, SeqCst);
+        Response::new(200)
+    }));
+
+    let mut client = TcpStream::connect(address).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let mut bytes = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+    bytes.extend(frame(SETTINGS, 0, 0, &[]));
+    // GET http://localhost/ with a literal :authority.
+    let mut block = vec![0x82, 0x86, 0x84, 0x41, 9];
+    block.extend(b"localhost");
+    let last = 2 * 300 - 1;
+    for stream_id in (1..=last).step_by(2) {
+        bytes.extend(frame(HEADERS, END_STREAM_HEADERS, stream_id, &block));
+        bytes.extend(frame(RST_STREAM, 0, stream_id, &CANCEL.to_be_bytes()));
+    }
+    client.write_all(&bytes).unwrap();
+
+    let frames = read_frames(&mut client, |kind, stream_id, _| {
+        kind == RST_STREAM && stream_id == last
+    });
+    let refused = frames
+        .iter()
+        .filter(|(kind, _, payload)| {
+            *kind == RST_STREAM && payload[..] == REFUSED_STREAM.to_be_bytes()
+        })
+        .count();
+    assert!(refused >= 200, "only {refused} streams refused");
+    assert!(
+        most.load(SeqCst) <= 100,
+        "{} handlers ran",
+        most.load(SeqCst)
+    );
+}
+
+/// Stream handlers share the server's thread pool, so streams beyond its
+/// free workers are refused instead of starting threads of their own.
+#[test]
+fn streams_are_refused_while_the_pool_is_busy() {
+    let address = start_server(Arc::new(|_: &Request| {
+        thread::sleep(Duration::from_millis(300));
+        Response::new(200)
+    }));
+    let mut client = TcpStream::connect(address).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let mut bytes = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+    bytes.extend(frame(SETTINGS, 0, 0, &[]));
+    let mut block = vec![0x82, 0x86, 0x84, 0x41, 9];
+    block.extend(b"localhost");
+    for stream_id in [1, 3, 5] {
+        bytes.extend(frame(HEADERS, END_STREAM_HEADERS, stream_id, &block));
+    }
+    client.write_all(&bytes).unwrap();
+
+    // One of the two workers serves the connection, the other stream 1.
+    let frames = read_frames(&mut client, |kind, stream_id, _| {
+        kind == HEADERS && stream_id == 1
+    });
+    let refused: Vec<u32> = frames
+        .iter()
+        .filter(|(kind, _, payload)| {
+            *kind == RST_STREAM && payload[..] == REFUSED_STREAM.to_be_bytes()
+        })
+        .map(|(_, stream_id, _)| *stream_id)
+        .collect();
+    assert_eq!(refused, [3, 5]);
+}
+
+/// A block of references to one large table entry must not be expanded
+/// without limit, the "HPACK bomb".
+#[test]
+fn header_lists_past_the_limit_end_the_connection() {
+    let address = start_server(Arc::new(|_: &Request| Response::new(200)));
+    let mut client = TcpStream::connect(address).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+
+    let mut bytes = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+    bytes.extend(frame(SETTINGS, 0, 0, &[]));
+    // x: 4000 bytes with incremental indexing, then 12000 references to it.
+    let mut block = vec![0x82, 0x86, 0x84, 0x40, 0x01, b'x', 0x7F, 0xA1, 0x1E];
+    block.extend(vec![b'v'; 4000]);
+    block.extend(vec![0xBE; 12000]);
+    bytes.extend(frame(HEADERS, END_STREAM_HEADERS, 1, &block));
+    client.write_all(&bytes).unwrap();
+
+    let frames = read_frames(&mut client, |kind, _, _| kind == GOAWAY);
+    let (_, _, payload) = frames.last().unwrap();
+    assert_eq!(payload[4..8], ENHANCE_YOUR_CALM.to_be_bytes());
+}
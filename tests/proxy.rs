@@ -0,0 +1,117 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener},
+    thread,
+    time::Duration,
+};
+
+use my_server::{
+    handler::Handler,
+    http::{Body, Request},
+    proxy::{ReverseProxy, Upstream},
+};
+
+/// Accepts one connection, reads the request head and answers with `reply`,
+/// returning the head it received.
+fn upstream(reply: &'static str) -> (SocketAddr, thread::JoinHandle<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handle = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut head = String::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            head.push_str(&line);
+            if line == "\r\n" || line.is_empty() {
+                break;
+            }
+        }
+        reader.get_mut().write_all(reply.as_bytes()).unwrap();
+        head
+    });
+    (addr, handle)
+}
+
+fn body(body: Body) -> Vec<u8> {
+    match body {
+        Body::Full(bytes) => bytes,
+        Body::Stream(mut stream) => {
+            let mut bytes = Vec::new();
+            stream.read_to_end(&mut bytes).unwrap();
+            bytes
+        }
+    }
+}
+
+#[test]
+fn forwards_to_upstream() {
+    let (addr, head) =
+        upstream("HTTP/1.1 200 OK\r\nContent-Length: 5\r\nX-Upstream: yes\r\n\r\nhello");
+    let proxy = ReverseProxy::new("/api", Upstream::parse(&addr.to_string())).strip_prefix(true);
+    let request =
+        Request::parse(b"GET /api/items?page=2 HTTP/1.1\r\nHost: example.com\r\n\r\n").unwrap();
+
+    let response = proxy.handle(&request);
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("X-Upstream"), Some("yes"));
+    assert_eq!(body(response.body), b"hello");
+
+    let head = head.join().unwrap();
+    assert!(head.starts_with("GET /items?page=2 HTTP/1.1\r\n"), "{head}");
+    assert!(
+        head.to_ascii_lowercase()
+            .contains("x-forwarded-host: example.com"),
+        "{head}"
+    );
+}
+
+#[test]
+fn refused_upstream_is_bad_gateway() {
+    // Bind and drop a listener for a port nothing listens on.
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let proxy = ReverseProxy::new("/", Upstream::parse(&addr.to_string()));
+    let request = Request::parse(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").unwrap();
+
+    assert_eq!(proxy.handle(&request).status, 502);
+}
+
+#[test]
+fn silent_upstream_is_gateway_timeout() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let proxy = ReverseProxy::new("/", Upstream::parse(&addr.to_string()))
+        .timeouts(Duration::from_secs(1), Duration::from_millis(100));
+    let request = Request::parse(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").unwrap();
+
+    // The connection is accepted by the backlog but never answered.
+    assert_eq!(proxy.handle(&request).status, 504);
+    drop(listener);
+}
+
+#[test]
+fn headers_named_in_connection_are_not_forwarded() {
+    let (addr, head) = upstream(
+        "HTTP/1.1 200 OK\r\nConnection: X-Hop, close\r\nX-Hop: upstream\r\n\
+         X-Upstream: yes\r\nContent-Length: 0\r\n\r\n",
+    );
+    let proxy = ReverseProxy::new("/", Upstream::parse(&addr.to_string()));
+    let request = Request::parse(
+        b"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: keep-alive, X-Secret\r\n\
+          X-Secret: client\r\nX-Kept: yes\r\n\r\n",
+    )
+    .unwrap();
+
+    let response = proxy.handle(&request);
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("X-Hop"), None);
+    assert_eq!(response.header("X-Upstream"), Some("yes"));
+
+    let head = head.join().unwrap().to_ascii_lowercase();
+    assert!(!head.contains("x-secret"), "{head}");
+    assert!(head.contains("x-kept: yes"), "{head}");
+}
@@ -0,0 +1,98 @@
+use my_server::{
+    handler::Handler,
+    http::{Request, Response},
+    router::Router,
+};
+
+fn request(method: &str, path: &str) -> Request {
+    Request::parse(format!("{method} {path} HTTP/1.1\r\nHost: example.com\r\n\r\n").as_bytes())
+        .unwrap()
+}
+
+fn body(response: &Response) -> &str {
+    std::str::from_utf8(response.body.as_bytes().unwrap()).unwrap()
+}
+
+fn echo(names: &'static [&'static str]) -> impl Fn(&Request) -> Response {
+    move |request: &Request| {
+        let params: Vec<&str> = names
+            .iter()
+            .map(|name| request.param(name).unwrap_or("-"))
+            .collect();
+        Response::new(200).with_body(params.join(" "))
+    }
+}
+
+#[test]
+fn params_are_taken_from_path_segments() {
+    let router = Router::new().get("/users/:id/posts/:post", echo(&["id", "post"]));
+
+    let response = router.handle(&request("GET", "/users/7/posts/42"));
+    assert_eq!(response.status, 200);
+    assert_eq!(body(&response), "7 42");
+
+    assert_eq!(router.handle(&request("GET", "/users/7/posts")).status, 404);
+    assert_eq!(
+        router.handle(&request("GET", "/users/7/posts/42/x")).status,
+        404
+    );
+}
+
+#[test]
+fn wildcards_match_the_rest_of_the_path() {
+    let router = Router::new().get("/static/*file", echo(&["file"]));
+
+    assert_eq!(
+        body(&router.handle(&request("GET", "/static/css/site.css"))),
+        "css/site.css"
+    );
+    assert_eq!(body(&router.handle(&request("GET", "/static"))), "");
+    assert_eq!(
+        router.handle(&request("GET", "/other/site.css")).status,
+        404
+    );
+}
+
+#[test]
+fn routes_are_tried_in_order() {
+    let router = Router::new()
+        .get("/users/me", |_: &Request| {
+            Response::new(200).with_body("me")
+        })
+        .get("/users/:id", echo(&["id"]));
+
+    assert_eq!(body(&router.handle(&request("GET", "/users/me"))), "me");
+    assert_eq!(body(&router.handle(&request("GET", "/users/3"))), "3");
+}
+
+#[test]
+fn other_methods_are_answered_with_allow() {
+    let router = Router::new()
+        .get("/items/:id", echo(&["id"]))
+        .put("/items/:id", echo(&["id"]))
+        .get("/items/*rest", echo(&["rest"]))
+        .delete("/items", echo(&[]));
+
+    let response = router.handle(&request("POST", "/items/1"));
+    assert_eq!(response.status, 405);
+    assert_eq!(response.header("Allow"), Some("GET, PUT"));
+
+    assert_eq!(router.handle(&request("PUT", "/items/1")).status, 200);
+}
+
+#[test]
+fn unmatched_requests_go_to_the_fallback() {
+    let router = Router::new()
+        .post("/form", echo(&[]))
+        .any("/api/*path", echo(&["path"]))
+        .fallback(|request: &Request| Response::new(200).with_body(request.path.clone()));
+
+    assert_eq!(
+        body(&router.handle(&request("GET", "/index.html"))),
+        "/index.html"
+    );
+    assert_eq!(body(&router.handle(&request("DELETE", "/api/v1"))), "v1");
+    // A matching path with another method is a 405, not the fallback.
+    assert_eq!(router.handle(&request("GET", "/form")).status, 405);
+    assert_eq!(Router::new().handle(&request("GET", "/")).status, 404);
+}
@@ -0,0 +1,77 @@
+use std::{
+    io::{BufRead, BufReader},
+    os::unix::net::UnixStream,
+    thread,
+    time::{Duration, Instant},
+};
+
+use my_server::sse::{Broadcaster, Event};
+
+/// Reads events from `stream` until one with `data`, returning their ids.
+fn read_ids(stream: UnixStream, data: &str) -> Vec<u64> {
+    let mut ids = Vec::new();
+    for line in BufReader::new(stream).lines() {
+        let line = line.unwrap();
+        if let Some(id) = line.strip_prefix("id: ") {
+            ids.push(id.parse().unwrap());
+        }
+        if line == format!("data: {data}") {
+            break;
+        }
+    }
+    ids
+}
+
+#[test]
+fn history_is_replayed_after_last_event_id() {
+    let broadcaster = Broadcaster::new(3);
+    for data in ["a", "b", "c", "d"] {
+        broadcaster.publish(&Event::new(data));
+    }
+
+    let (server, client) = UnixStream::pair().unwrap();
+    broadcaster.subscribe(Box::new(server), Some("2"));
+    broadcaster.publish(&Event::new("e"));
+    assert_eq!(read_ids(client, "e"), [3, 4, 5]);
+}
+
+#[test]
+fn stalled_subscriber_does_not_block_publishing() {
+    let broadcaster = Broadcaster::new(0);
+    // Never read, so its socket buffer fills and it falls behind.
+    let (stalled, _stalled_client) = UnixStream::pair().unwrap();
+    broadcaster.subscribe(Box::new(stalled), None);
+    let (server, client) = UnixStream::pair().unwrap();
+    broadcaster.subscribe(Box::new(server), None);
+    let reader = thread::spawn(move || read_ids(client, "last"));
+
+    let data = "x".repeat(16 * 1024);
+    let start = Instant::now();
+    for _ in 0..200 {
+        broadcaster.publish(&Event::new(&data));
+        // Let the healthy subscriber keep up with its queue.
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(broadcaster.subscribers(), 1);
+    let received = broadcaster.publish(&Event::new("last"));
+    assert!(start.elapsed() < Duration::from_secs(4));
+
+    assert_eq!(received, 1);
+    assert_eq!(reader.join().unwrap().len(), 201);
+}
+
+#[test]
+fn subscribers_that_hang_up_are_dropped() {
+    let broadcaster = Broadcaster::new(0);
+    let (server, client) = UnixStream::pair().unwrap();
+    broadcaster.subscribe(Box::new(server), None);
+    assert_eq!(broadcaster.subscribers(), 1);
+
+    // Noticed without publishing anything or a heartbeat.
+    drop(client);
+    let start = Instant::now();
+    while broadcaster.subscribers() > 0 {
+        assert!(start.elapsed() < Duration::from_secs(2));
+        thread::sleep(Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,221 @@
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    panic::panic_any,
+    sync::{
+        mpsc::{channel, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use my_server::{
+    event_loop::Mode,
+    http::{Request, Response},
+    settings::Http,
+    tcp_server::TcpServer,
+    thread_pool::ThreadPool,
+};
+
+/// Queues a job that runs until the returned sender is dropped.
+fn blocking_job(pool: &ThreadPool) -> Sender<()> {
+    let (release, wait) = channel::<()>();
+    pool.execute(Box::new(move || {
+        let _ = wait.recv();
+    }));
+    release
+}
+
+/// Waits up to five seconds for `condition`.
+fn eventually(condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !condition() {
+        assert!(Instant::now() < deadline, "condition not met in time");
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[test]
+fn full_queue_blocks_execute_until_a_worker_is_free() {
+    let pool = ThreadPool::new(2, 2, Duration::from_secs(60), 2);
+    let running = [blocking_job(&pool), blocking_job(&pool)];
+    eventually(|| pool.queued() == 0);
+    let queued = [blocking_job(&pool), blocking_job(&pool)];
+    assert_eq!(pool.queued(), 2);
+    assert!(pool.is_full());
+
+    let (done, executed) = channel();
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            pool.execute(Box::new(|| {}));
+            done.send(()).unwrap();
+        });
+        assert!(executed.recv_timeout(Duration::from_millis(200)).is_err());
+
+        drop(running);
+        executed.recv_timeout(Duration::from_secs(5)).unwrap();
+    });
+    drop(queued);
+    eventually(|| pool.queued() == 0 && !pool.is_full());
+}
+
+#[test]
+fn shrinking_does_not_take_queue_slots() {
+    let pool = ThreadPool::new(2, 2, Duration::from_secs(60), 1);
+    let running = [blocking_job(&pool), blocking_job(&pool)];
+    eventually(|| pool.queued() == 0);
+
+    // Both workers are busy, so neither can stop yet.
+    pool.resize(1, 1);
+    assert_eq!(pool.queued(), 0);
+    assert!(!pool.is_full());
+    let (done, executed) = channel();
+    let (sent, returned) = channel();
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            pool.execute(Box::new(move || done.send(()).unwrap()));
+            sent.send(()).unwrap();
+        });
+        // Queued without waiting for the busy workers.
+        returned.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(pool.queued(), 1);
+        assert!(pool.is_full());
+
+        drop(running);
+        executed.recv_timeout(Duration::from_secs(5)).unwrap();
+    });
+    eventually(|| pool.threads() == 1);
+}
+
+#[test]
+fn shrinking_stops_idle_workers_at_once() {
+    let pool = ThreadPool::new(4, 4, Duration::from_secs(60), 3);
+    eventually(|| pool.idle() == 4);
+
+    // Well before the idle timeout. The messages asking the workers to stop
+    // fill the queue, but aren't jobs waiting for a worker.
+    pool.resize(1, 1);
+    assert_eq!(pool.queued(), 0);
+    assert!(!pool.is_full());
+    eventually(|| pool.threads() == 1);
+    eventually(|| pool.queued() == 0 && !pool.is_full());
+}
+
+#[test]
+fn workers_start_while_jobs_wait() {
+    let pool = ThreadPool::new(0, 3, Duration::from_millis(100), 8);
+    assert_eq!(pool.threads(), 0);
+    let jobs: Vec<_> = (0..5).map(|_| blocking_job(&pool)).collect();
+    eventually(|| pool.queued() == 2);
+    assert_eq!(pool.threads(), 3);
+
+    drop(jobs);
+    // Idle workers above `min` stop after `idle_timeout`.
+    eventually(|| pool.threads() == 0);
+}
+
+#[test]
+fn try_execute_only_queues_for_free_workers() {
+    let pool = ThreadPool::new(0, 1, Duration::from_secs(60), 8);
+    let (done, executed) = channel();
+    let first = done.clone();
+    assert!(pool.try_execute(Box::new(move || first.send(()).unwrap())));
+    executed.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    let running = blocking_job(&pool);
+    eventually(|| pool.queued() == 0);
+    assert!(!pool.try_execute(Box::new(|| panic!("refused job ran"))));
+    assert_eq!(pool.queued(), 0);
+
+    drop(running);
+    eventually(|| pool.idle() == 1);
+    assert!(pool.try_execute(Box::new(move || done.send(()).unwrap())));
+    executed.recv_timeout(Duration::from_secs(5)).unwrap();
+}
+
+#[test]
+fn panicking_jobs_leave_workers_running() {
+    let pool = ThreadPool::new(2, 2, Duration::from_secs(60), 16);
+    for _ in 0..10 {
+        pool.execute(Box::new(|| panic!("job failed")));
+    }
+    let (done, executed) = channel();
+    for _ in 0..10 {
+        let done = done.clone();
+        pool.execute(Box::new(move || done.send(()).unwrap()));
+    }
+    for _ in 0..10 {
+        executed.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+    assert_eq!(pool.threads(), 2);
+}
+
+/// Panics when dropped, which happens in the worker after `catch_unwind`.
+struct PanicOnDrop;
+
+impl Drop for PanicOnDrop {
+    fn drop(&mut self) {
+        panic!("payload dropped");
+    }
+}
+
+#[test]
+fn dead_workers_are_replaced() {
+    let pool = ThreadPool::new(1, 1, Duration::from_secs(60), 4);
+    pool.execute(Box::new(|| panic_any(PanicOnDrop)));
+
+    let (done, executed) = channel();
+    pool.execute(Box::new(move || done.send(()).unwrap()));
+    executed.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(pool.threads(), 1);
+}
+
+#[test]
+fn panicking_handlers_are_answered_with_500() {
+    for mode in [Mode::Threaded, Mode::Evented] {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let settings = Http {
+            port,
+            redirect: None,
+            threads: 1,
+            min_threads: None,
+            max_threads: None,
+            idle_timeout: 60,
+            queue_size: 16,
+            mode,
+            h2c: false,
+            h2c_upgrade: false,
+        };
+        let handler = Arc::new(|request: &Request| match request.path.as_str() {
+            "/panic" => panic!("handler failed"),
+            _ => Response::new(204),
+        });
+        let mut server =
+            TcpServer::with_handler("127.0.0.1".to_owned(), &settings, handler).unwrap();
+        server.start_thread();
+
+        let status = |path: &str| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            let mut stream = loop {
+                match TcpStream::connect(("127.0.0.1", port)) {
+                    Ok(stream) => break stream,
+                    Err(e) => assert!(Instant::now() < deadline, "{e}"),
+                }
+                thread::sleep(Duration::from_millis(10));
+            };
+            write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response.split(' ').nth(1).unwrap_or_default().to_owned()
+        };
+        // The only worker survives to answer the next request.
+        assert_eq!(status("/panic"), "500", "{mode:?}");
+        assert_eq!(status("/"), "204", "{mode:?}");
+        assert_eq!(server.thread_pool().threads(), 1);
+    }
+}
@@ -0,0 +1,252 @@
+#![cfg(feature = "openssl")]
+
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use my_server::{
+    http::{Request, Response},
+    settings::Https,
+    tls_server::TlsServer,
+};
+use openssl::ssl::{SslConnector, SslMethod, SslStream, SslVerifyMode, SslVersion};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair};
+
+/// A directory for one test's certificate files.
+fn directory(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("my_server_tls_{name}_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn certificate_authority() -> (Certificate, KeyPair) {
+    let key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(Vec::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    (params.self_signed(&key).unwrap(), key)
+}
+
+/// Writes `<name>.pem` and `<name>.key` for a certificate valid for `names`,
+/// signed by `issuer` or else self-signed, followed by the issuer in the
+/// chain file. Returns the TOML lines pointing at them.
+fn write_certificate(
+    dir: &Path,
+    name: &str,
+    names: &[&str],
+    issuer: Option<&(Certificate, KeyPair)>,
+) -> String {
+    let key = KeyPair::generate().unwrap();
+    let params = CertificateParams::new(
+        names
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>(),
+    )
+    .unwrap();
+    let chain = match issuer {
+        Some((ca, ca_key)) => {
+            let leaf = params.signed_by(&key, ca, ca_key).unwrap();
+            format!("{}{}", leaf.pem(), ca.pem())
+        }
+        None => params.self_signed(&key).unwrap().pem(),
+    };
+    let certificate = dir.join(format!("{name}.pem"));
+    let private_key = dir.join(format!("{name}.key"));
+    fs::write(&certificate, chain).unwrap();
+    fs::write(&private_key, key.serialize_pem()).unwrap();
+    format!(
+        "certificate = \"{}\"\nprivate_key = \"{}\"\n",
+        certificate.display(),
+        private_key.display()
+    )
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// The `[https]` settings for `port` with `rest` appended.
+fn https(port: u16, rest: &str) -> Result<Https, toml::de::Error> {
+    toml::from_str(&format!(
+        "port = {port}\nthreads = 2\nhttp2 = false\n{rest}"
+    ))
+}
+
+fn start(settings: &Https) -> TlsServer {
+    let handler = Arc::new(|_: &Request| Response::new(200).with_body("ok"));
+    let mut server = TlsServer::with_handler("127.0.0.1".to_owned(), settings, handler).unwrap();
+    server.start_thread();
+    let started = Instant::now();
+    while TcpStream::connect(("127.0.0.1", settings.port)).is_err() {
+        assert!(started.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(10));
+    }
+    server
+}
+
+/// Handshakes with the server on `port`, sending `server_name` through SNI
+/// and allowing at most `max_version`.
+fn connect(
+    port: u16,
+    server_name: Option<&str>,
+    max_version: Option<SslVersion>,
+) -> Result<SslStream<TcpStream>, String> {
+    let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
+    builder.set_verify(SslVerifyMode::NONE);
+    builder.set_max_proto_version(max_version).unwrap();
+    let mut config = builder.build().configure().unwrap();
+    config.set_use_server_name_indication(server_name.is_some());
+    config.set_verify_hostname(false);
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    config
+        .connect(server_name.unwrap_or("unused"), stream)
+        .map_err(|e| e.to_string())
+}
+
+/// The first DNS name of the certificate the server sent.
+fn served_name(port: u16, server_name: Option<&str>) -> String {
+    let stream = connect(port, server_name, None).unwrap();
+    let certificate = stream.ssl().peer_certificate().unwrap();
+    let names = certificate.subject_alt_names().unwrap();
+    names[0].dnsname().unwrap().to_owned()
+}
+
+#[test]
+fn pem_chains_are_sent_with_the_leaf() {
+    let dir = directory("chain");
+    let ca = certificate_authority();
+    let ssl = write_certificate(&dir, "leaf", &["example.com"], Some(&ca));
+    let port = free_port();
+    let _server = start(&https(port, &format!("[ssl]\n{ssl}")).unwrap());
+
+    let stream = connect(port, Some("example.com"), None).unwrap();
+    let chain = stream.ssl().peer_cert_chain().unwrap();
+    assert_eq!(chain.len(), 2);
+    assert_eq!(served_name(port, None), "example.com");
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn keys_must_match_their_certificate() {
+    let dir = directory("mismatch");
+    write_certificate(&dir, "a", &["a.test"], None);
+    write_certificate(&dir, "b", &["b.test"], None);
+    let ssl = format!(
+        "[ssl]\ncertificate = \"{}\"\nprivate_key = \"{}\"\n",
+        dir.join("a.pem").display(),
+        dir.join("b.key").display()
+    );
+    let handler = Arc::new(|_: &Request| Response::new(200));
+    let settings = https(free_port(), &ssl).unwrap();
+    assert!(TlsServer::with_handler("127.0.0.1".to_owned(), &settings, handler.clone()).is_err());
+
+    let missing = format!(
+        "[ssl]\ncertificate = \"{}\"\nprivate_key = \"{}\"\n",
+        dir.join("a.pem").display(),
+        dir.join("missing.key").display()
+    );
+    let settings = https(free_port(), &missing).unwrap();
+    assert!(TlsServer::with_handler("127.0.0.1".to_owned(), &settings, handler).is_err());
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn certificates_are_selected_by_server_name() {
+    let dir = directory("sni");
+    let default = write_certificate(&dir, "default", &["default.test"], None);
+    let exact = write_certificate(&dir, "exact", &["www.example.org"], None);
+    let wildcard = write_certificate(&dir, "wildcard", &["*.example.org"], None);
+    let port = free_port();
+    let settings = https(
+        port,
+        &format!("[ssl]\n{default}[[certificates]]\n{wildcard}[[certificates]]\n{exact}"),
+    )
+    .unwrap();
+    let _server = start(&settings);
+
+    assert_eq!(
+        served_name(port, Some("www.example.org")),
+        "www.example.org"
+    );
+    assert_eq!(
+        served_name(port, Some("WWW.Example.org.")),
+        "www.example.org"
+    );
+    assert_eq!(served_name(port, Some("api.example.org")), "*.example.org");
+    // Wildcards match exactly one label.
+    assert_eq!(served_name(port, Some("a.b.example.org")), "default.test");
+    assert_eq!(served_name(port, Some("example.org")), "default.test");
+    assert_eq!(served_name(port, Some("unknown.test")), "default.test");
+    assert_eq!(served_name(port, None), "default.test");
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn reloading_swaps_certificates_of_running_servers() {
+    let dir = directory("reload");
+    let ssl = write_certificate(&dir, "site", &["old.test"], None);
+    let port = free_port();
+    let server = start(&https(port, &format!("[ssl]\n{ssl}")).unwrap());
+    assert_eq!(served_name(port, None), "old.test");
+
+    // A connection made before the reload keeps working.
+    let mut before = connect(port, None, None).unwrap();
+    write_certificate(&dir, "site", &["new.test"], None);
+    server.reloader().reload().unwrap();
+    assert_eq!(served_name(port, None), "new.test");
+    before
+        .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    before.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+
+    // A broken file keeps the certificates in use.
+    fs::write(dir.join("site.key"), "not a key").unwrap();
+    assert!(server.reloader().reload().is_err());
+    assert_eq!(served_name(port, None), "new.test");
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn protocol_versions_are_limited_by_the_policy() {
+    let dir = directory("protocols");
+    let ssl = write_certificate(&dir, "site", &["site.test"], None);
+
+    let port = free_port();
+    let _modern = start(&https(port, &format!("[ssl]\n{ssl}min_protocol = \"1.3\"\n")).unwrap());
+    assert!(connect(port, None, Some(SslVersion::TLS1_2)).is_err());
+    let stream = connect(port, None, None).unwrap();
+    assert_eq!(stream.ssl().version_str(), "TLSv1.3");
+
+    let port = free_port();
+    let _legacy = start(&https(port, &format!("[ssl]\n{ssl}max_protocol = \"1.2\"\n")).unwrap());
+    let stream = connect(port, None, None).unwrap();
+    assert_eq!(stream.ssl().version_str(), "TLSv1.2");
+
+    // Unknown versions don't parse, and empty ranges are refused.
+    assert!(https(port, &format!("[ssl]\n{ssl}min_protocol = \"1.4\"\n")).is_err());
+    assert!(https(port, &format!("[ssl]\n{ssl}max_protocol = \"3\"\n")).is_err());
+    let empty = https(
+        free_port(),
+        &format!("[ssl]\n{ssl}min_protocol = \"1.3\"\nmax_protocol = \"1.2\"\n"),
+    )
+    .unwrap();
+    let handler = Arc::new(|_: &Request| Response::new(200));
+    let error = TlsServer::with_handler("127.0.0.1".to_owned(), &empty, handler)
+        .err()
+        .unwrap();
+    assert!(error.contains("min_protocol"), "{error}");
+    let _ = fs::remove_dir_all(&dir);
+}
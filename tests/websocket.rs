@@ -0,0 +1,154 @@
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    os::unix::net::UnixStream,
+    thread,
+};
+
+use my_server::websocket::{close_codes, relay, Frame, Message, Opcode, WebSocket};
+
+const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+fn read(bytes: &[u8], max_size: usize) -> std::io::Result<Frame> {
+    Frame::read_from(&mut &bytes[..], max_size)
+}
+
+#[test]
+fn masked_frame_round_trips() {
+    let mut bytes = Vec::new();
+    Frame::new(Opcode::Text, b"Hello".to_vec())
+        .write_to(&mut bytes, Some(MASK))
+        .unwrap();
+    // The example from RFC 6455 section 5.7.
+    assert_eq!(
+        bytes,
+        [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58]
+    );
+
+    let frame = read(&bytes, 125).unwrap();
+    assert!(frame.fin && frame.masked);
+    assert_eq!(frame.opcode, Opcode::Text);
+    assert_eq!(frame.payload, b"Hello");
+}
+
+#[test]
+fn extended_lengths_are_read() {
+    for length in [125, 126, 0xffff, 0x10000] {
+        let mut bytes = Vec::new();
+        Frame::new(Opcode::Binary, vec![7; length])
+            .write_to(&mut bytes, None)
+            .unwrap();
+        let frame = read(&bytes, 0x10000).unwrap();
+        assert_eq!(frame.payload.len(), length);
+    }
+}
+
+#[test]
+fn malformed_frames_are_rejected() {
+    let cases: [(&[u8], usize); 6] = [
+        // Reserved bit set.
+        (&[0xc1, 0x00], 125),
+        // Opcode 3 is reserved.
+        (&[0x83, 0x00], 125),
+        // Fragmented ping.
+        (&[0x09, 0x00], 125),
+        // Ping longer than 125 bytes.
+        (&[0x89, 0x7e, 0x00, 0x7e], 1024),
+        // Larger than max_size.
+        (&[0x82, 0x7e, 0x01, 0x00], 255),
+        // Length with the most significant bit set.
+        (&[0x82, 0x7f, 0x80, 0, 0, 0, 0, 0, 0, 0], usize::MAX),
+    ];
+    for (bytes, max_size) in cases {
+        let error = read(bytes, max_size).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData, "{bytes:x?}");
+    }
+}
+
+#[test]
+fn truncated_frames_are_eof() {
+    for bytes in [&[0x81][..], &[0x81, 0x85, 0x37], &[0x82, 0x03, 1, 2]] {
+        let error = read(bytes, 125).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof, "{bytes:x?}");
+    }
+}
+
+/// Sends a masked close frame with `payload` to a `WebSocket`, returning what
+/// `recv` made of it and the close frame the server answered with.
+fn close_with(payload: &[u8]) -> (std::io::Result<Message>, Frame) {
+    let (server, mut client) = UnixStream::pair().unwrap();
+    let mut socket = WebSocket::new(Box::new(server));
+    Frame::new(Opcode::Close, payload.to_vec())
+        .write_to(&mut client, Some(MASK))
+        .unwrap();
+    let received = socket.recv();
+    let answer = Frame::read_from(&mut client, 125).unwrap();
+    assert_eq!(answer.opcode, Opcode::Close);
+    (received, answer)
+}
+
+fn code(frame: &Frame) -> u16 {
+    u16::from_be_bytes([frame.payload[0], frame.payload[1]])
+}
+
+#[test]
+fn close_frames_are_echoed() {
+    let (received, answer) = close_with(&[]);
+    assert_eq!(received.unwrap(), Message::Close(None));
+    assert!(answer.payload.is_empty());
+
+    let mut payload = 1000u16.to_be_bytes().to_vec();
+    payload.extend_from_slice(b"bye");
+    let (received, answer) = close_with(&payload);
+    assert_eq!(
+        received.unwrap(),
+        Message::Close(Some((1000, "bye".to_owned())))
+    );
+    assert_eq!(answer.payload, 1000u16.to_be_bytes());
+}
+
+#[test]
+fn invalid_close_frames_are_protocol_errors() {
+    let mut invalid_reason = 1000u16.to_be_bytes().to_vec();
+    invalid_reason.extend_from_slice(&[0xff, 0xfe]);
+    let cases: [(&[u8], u16); 5] = [
+        (&[0x03], close_codes::PROTOCOL_ERROR),
+        (&1005u16.to_be_bytes(), close_codes::PROTOCOL_ERROR),
+        (&1015u16.to_be_bytes(), close_codes::PROTOCOL_ERROR),
+        (&999u16.to_be_bytes(), close_codes::PROTOCOL_ERROR),
+        (&invalid_reason, close_codes::INVALID_DATA),
+    ];
+    for (payload, expected) in cases {
+        let (received, answer) = close_with(payload);
+        assert_eq!(received.unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(code(&answer), expected, "{payload:x?}");
+    }
+}
+
+/// A connected pair of TCP streams.
+fn tcp_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    (client, server)
+}
+
+#[test]
+fn relay_copies_both_ways_until_closed() {
+    let (mut client, client_side) = tcp_pair();
+    let (upstream_side, mut upstream) = tcp_pair();
+    let relayed =
+        thread::spawn(move || relay(Box::new(client_side), Box::new(upstream_side)).unwrap());
+
+    let mut buffer = [0; 5];
+    client.write_all(b"hello").unwrap();
+    upstream.read_exact(&mut buffer).unwrap();
+    assert_eq!(&buffer, b"hello");
+    upstream.write_all(b"world").unwrap();
+    client.read_exact(&mut buffer).unwrap();
+    assert_eq!(&buffer, b"world");
+
+    drop(upstream);
+    relayed.join().unwrap();
+    assert_eq!(client.read(&mut buffer).unwrap(), 0);
+}